@@ -35,6 +35,12 @@ pub async fn start_backup(
     State(app_state): State<super::AppState>,
     Json(req): Json<StartBackupRequest>,
 ) -> Result<Json<StartBackupResponse>, StatusCode> {
+    // Backups only ever go to the configured server
+    if req.server_url.trim_end_matches('/') != app_state.server_url.trim_end_matches('/') {
+        tracing::warn!("Refused backup job {} to unknown server {}", req.job_id, req.server_url);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     tracing::info!("Starting backup job: {} for paths: {:?}", req.job_id, req.paths);
 
     // Convert paths to PathBuf
@@ -47,10 +53,17 @@ pub async fn start_backup(
     let job = crate::executor::BackupJob {
         job_id: req.job_id.clone(),
         paths,
+        labels: Vec::new(),
         destination,
         server_url: req.server_url,
         incremental: false,
         manifest_url: None,
+        binary_manifest: false,
+        carry_forward: false,
+        detect_renames: false,
+        compression: None,
+        max_concurrency: None,
+        max_file_errors: None,
     };
 
     // Create cancellation token shared between executor and tracker
@@ -115,3 +128,22 @@ pub async fn cancel_backup(
         Err(StatusCode::NOT_FOUND)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start_backup_refuses_another_server() {
+        let state = super::super::create_app_state("http://backup:3000/".into());
+        let result = start_backup(State(state.clone()), Json(StartBackupRequest {
+            job_id: "j1".into(),
+            paths: vec!["/etc".into()],
+            server_url: "http://attacker:3000".into(),
+            token: None,
+        }))
+        .await;
+        assert_eq!(result.err(), Some(StatusCode::FORBIDDEN));
+        assert!(!state.job_tracker.cancel("j1").await);
+    }
+}
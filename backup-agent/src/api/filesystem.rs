@@ -3,9 +3,12 @@
 //! Provides directory listing for the remote file explorer UI,
 //! replacing SSH-based file browsing.
 
-use axum::{extract::Query, Json, http::StatusCode};
+use crate::policy::{self, PathPolicy, PolicyViolation};
+use axum::{extract::{Query, State}, Json, http::StatusCode};
+use backup_protocol::path as path_encoding;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use thiserror::Error;
 use tracing::error;
 
 #[derive(Debug, Deserialize)]
@@ -13,26 +16,32 @@ pub struct BrowseQuery {
     pub path: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FsEntry {
-    pub name: String,
-    pub path: String,
-    #[serde(rename = "type")]
-    pub entry_type: String,
-    pub size: u64,
-}
+pub use backup_protocol::FsEntry;
 
 #[derive(Debug, Serialize)]
 pub struct BrowseResponse {
     pub entries: Vec<FsEntry>,
 }
 
+#[derive(Debug, Error)]
+pub enum BrowseError {
+    #[error(transparent)]
+    Policy(#[from] PolicyViolation),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
 /// GET /fs/browse?path=/ - Browse local filesystem
 pub async fn browse(
+    State(app_state): State<super::AppState>,
     Query(query): Query<BrowseQuery>,
 ) -> Result<Json<BrowseResponse>, StatusCode> {
     match browse_path(&query.path) {
         Ok(entries) => Ok(Json(BrowseResponse { entries })),
+        Err(BrowseError::Policy(violation)) => {
+            policy::report(&app_state.ws_state, "browse", None, &violation).await;
+            Err(StatusCode::FORBIDDEN)
+        }
         Err(e) => {
             error!("Failed to browse path {}: {}", query.path, e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -40,17 +49,26 @@ pub async fn browse(
     }
 }
 
-/// Browse a directory and return its entries.
-/// Used both by the HTTP endpoint and the WebSocket command handler.
-pub fn browse_path(dir: &str) -> std::io::Result<Vec<FsEntry>> {
-    let path = Path::new(dir);
+/// Browse a directory and return its entries allowed by the path policy.
+/// Used both by the HTTP endpoint and the WebSocket command handler. `dir`
+/// and the entries' paths are encoded, so names that are not UTF-8 can be
+/// browsed into.
+pub fn browse_path(dir: &str) -> Result<Vec<FsEntry>, BrowseError> {
+    browse_path_with(&policy::current(), dir)
+}
+
+fn browse_path_with(policy: &PathPolicy, dir: &str) -> Result<Vec<FsEntry>, BrowseError> {
+    let path = policy.check_browse(&path_encoding::decode_path(dir))?;
     let mut entries = Vec::new();
 
-    for entry in std::fs::read_dir(path)? {
+    for entry in std::fs::read_dir(&path)? {
         let entry = entry?;
+        if !policy.is_visible(&entry.path()) {
+            continue;
+        }
         let metadata = entry.metadata()?;
-        let name = entry.file_name().to_string_lossy().to_string();
-        let full_path = entry.path().to_string_lossy().to_string();
+        let name = path_encoding::encode(entry.file_name().as_bytes());
+        let full_path = path_encoding::encode_path(&entry.path());
 
         let entry_type = if metadata.is_dir() {
             "directory"
@@ -61,7 +79,8 @@ pub fn browse_path(dir: &str) -> std::io::Result<Vec<FsEntry>> {
         };
 
         entries.push(FsEntry {
-            name,
+            name: path_encoding::display(&name),
+            display_path: path_encoding::display(&full_path),
             path: full_path,
             entry_type: entry_type.to_string(),
             size: if metadata.is_file() { metadata.len() } else { 0 },
@@ -94,4 +113,41 @@ mod tests {
         let result = browse_path("/nonexistent_path_12345");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_browse_respects_policy() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("public")).unwrap();
+        std::fs::create_dir(root.join("private")).unwrap();
+        let root_str = root.to_str().unwrap().to_string();
+
+        let policy = PathPolicy::new(std::slice::from_ref(&root_str), &[format!("{}/private", root_str)], true).unwrap();
+        let names: Vec<String> = browse_path_with(&policy, &root_str).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["public"]);
+
+        let denied = browse_path_with(&policy, &format!("{}/private", root_str));
+        assert!(matches!(denied, Err(BrowseError::Policy(PolicyViolation::Denied { .. }))));
+        let outside = browse_path_with(&policy, "/usr");
+        assert!(matches!(outside, Err(BrowseError::Policy(PolicyViolation::OutsideAllowedRoots(_)))));
+    }
+
+    #[test]
+    fn test_browse_names_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let raw = std::ffi::OsString::from_vec(b"bad\xffname".to_vec());
+        std::fs::create_dir(dir.path().join(&raw)).unwrap();
+        std::fs::write(dir.path().join(&raw).join("inner.txt"), b"x").unwrap();
+        let dir_path = path_encoding::encode_path(dir.path());
+
+        let entries = browse_path_with(&PathPolicy::default(), &dir_path).unwrap();
+        assert_eq!(entries[0].name, "bad\u{fffd}name");
+        assert_eq!(entries[0].path, format!("{}/bad%FFname", dir_path));
+
+        // Its encoded path browses into it
+        let inner = browse_path_with(&PathPolicy::default(), &entries[0].path).unwrap();
+        assert_eq!(inner[0].name, "inner.txt");
+    }
 }
@@ -36,6 +36,7 @@ pub async fn version() -> impl IntoResponse {
     Json(json!({
         "version": env!("CARGO_PKG_VERSION"),
         "build": "dev",
-        "features": ["zstd"],
+        "protocol_version": crate::ws::protocol::PROTOCOL_VERSION,
+        "features": crate::ws::protocol::capabilities(),
     }))
 }
@@ -2,6 +2,7 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tokio::task::AbortHandle;
 use tokio_util::sync::CancellationToken;
@@ -10,6 +11,7 @@ use tokio_util::sync::CancellationToken;
 struct TrackedJob {
     abort_handle: AbortHandle,
     cancel_token: CancellationToken,
+    started: Instant,
 }
 
 /// Tracks running backup jobs and provides cancellation mechanism
@@ -31,6 +33,7 @@ impl JobTracker {
         jobs.insert(job_id, TrackedJob {
             abort_handle: handle,
             cancel_token: token,
+            started: Instant::now(),
         });
     }
 
@@ -54,6 +57,18 @@ impl JobTracker {
         jobs.remove(job_id);
     }
 
+    /// IDs of all running jobs
+    pub async fn job_ids(&self) -> Vec<String> {
+        let jobs = self.jobs.read().await;
+        jobs.keys().cloned().collect()
+    }
+
+    /// Running jobs with how long each has been running
+    pub async fn running(&self) -> Vec<(String, Duration)> {
+        let jobs = self.jobs.read().await;
+        jobs.iter().map(|(id, job)| (id.clone(), job.started.elapsed())).collect()
+    }
+
     /// Get count of running jobs
     pub async fn running_count(&self) -> usize {
         let jobs = self.jobs.read().await;
@@ -18,19 +18,22 @@ use tokio::sync::RwLock;
 pub struct AppState {
     pub ws_state: Arc<RwLock<crate::ws::WsState>>,
     pub job_tracker: job_tracker::JobTracker,
+    /// The configured backup server, the only one backups may upload to.
+    pub server_url: String,
 }
 
 /// Create shared application state
-pub fn create_app_state() -> AppState {
+pub fn create_app_state(server_url: String) -> AppState {
     AppState {
         ws_state: Arc::new(RwLock::new(crate::ws::WsState::new())),
         job_tracker: job_tracker::JobTracker::new(),
+        server_url,
     }
 }
 
 /// Create the API router with all endpoints
-pub fn create_router() -> Router {
-    create_router_with_state(create_app_state())
+pub fn create_router(server_url: String) -> Router {
+    create_router_with_state(create_app_state(server_url))
 }
 
 /// Create the API router with a pre-existing state (allows sharing state with WS client)
@@ -3,6 +3,7 @@
 //! Loads configuration from TOML file with environment variable overrides.
 
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,8 +24,18 @@ pub struct AgentConfig {
     /// HTTP/WebSocket server port
     pub port: u16,
 
+    /// Address the HTTP API listens on (default: 127.0.0.1). The API is not
+    /// authenticated, so only widen it behind a firewall.
+    #[serde(default = "default_listen")]
+    pub listen: IpAddr,
+
     /// Working directory for temporary files
     pub data_dir: PathBuf,
+
+    /// Path policy limiting what the server may browse and back up
+    /// (default: /etc/backup-agent/policy.toml if it exists)
+    #[serde(default)]
+    pub policy_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +49,24 @@ pub struct ServerConfig {
     /// Server ID this agent is associated with (set during deployment)
     #[serde(default)]
     pub server_id: Option<String>,
+
+    /// SHA-256 fingerprint of the server's TLS certificate. When set, only
+    /// that certificate is accepted for `https://` and `wss://` connections.
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
+
+    /// Client certificate (PEM) presented to the server for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for `client_cert`
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+
+    /// Hex-encoded Ed25519 key the server signs agent releases with.
+    /// Self-updates are refused without it.
+    #[serde(default)]
+    pub release_public_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,11 +75,12 @@ pub struct SyncConfig {
     #[serde(default = "default_chunk_size")]
     pub chunk_size: usize,
 
-    /// Compression algorithm (zstd, gzip, none)
+    /// Compression algorithm (zstd, lz4, gzip, none), when the server does
+    /// not set one for the job
     #[serde(default = "default_compression")]
     pub compression: String,
 
-    /// Compression level (1-22 for zstd)
+    /// Compression level (1-22 for zstd, 1-9 for gzip)
     #[serde(default = "default_compression_level")]
     pub compression_level: i32,
 }
@@ -64,6 +94,19 @@ pub struct LogConfig {
     /// Log output (journald, file, stdout)
     #[serde(default = "default_log_output")]
     pub output: String,
+
+    /// Log file for `output = "file"`. Its directory is handed to
+    /// `daemon.user` so the log can be rotated after dropping root.
+    #[serde(default = "default_log_file")]
+    pub file: PathBuf,
+
+    /// Rotate the log file when it reaches this size (MB)
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+
+    /// Number of rotated log files to keep
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,11 +114,11 @@ pub struct DaemonConfig {
     /// PID file location
     pub pid_file: PathBuf,
 
-    /// User to run as
+    /// User to run as (default: root). Any other user cannot self-update.
     #[serde(default = "default_user")]
     pub user: String,
 
-    /// Group to run as
+    /// Group to run as (default: root)
     #[serde(default = "default_group")]
     pub group: String,
 }
@@ -92,6 +135,10 @@ pub struct PerformanceConfig {
 }
 
 // Default values
+fn default_listen() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
 fn default_chunk_size() -> usize {
     1024 * 1024 // 1MB
 }
@@ -112,12 +159,24 @@ fn default_log_output() -> String {
     "stdout".to_string()
 }
 
+fn default_log_file() -> PathBuf {
+    PathBuf::from("/var/log/backup-agent/agent.log")
+}
+
+fn default_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
 fn default_user() -> String {
-    "backup".to_string()
+    "root".to_string()
 }
 
 fn default_group() -> String {
-    "backup".to_string()
+    "root".to_string()
 }
 
 fn default_max_concurrent_jobs() -> usize {
@@ -145,12 +204,18 @@ impl Config {
                     .and_then(|h| h.into_string().ok())
                     .unwrap_or_else(|| "backup-agent-01".to_string()),
                 port: 9990,
+                listen: default_listen(),
                 data_dir: PathBuf::from("/var/lib/backup-agent"),
+                policy_file: None,
             },
             server: ServerConfig {
                 url: "http://localhost:3000".to_string(),
                 token: "".to_string(),
                 server_id: None,
+                cert_fingerprint: None,
+                client_cert: None,
+                client_key: None,
+                release_public_key: None,
             },
             sync: SyncConfig {
                 chunk_size: default_chunk_size(),
@@ -160,6 +225,9 @@ impl Config {
             log: LogConfig {
                 level: default_log_level(),
                 output: default_log_output(),
+                file: default_log_file(),
+                max_size_mb: default_log_max_size_mb(),
+                max_files: default_log_max_files(),
             },
             daemon: DaemonConfig {
                 pid_file: PathBuf::from("/var/run/backup-agent.pid"),
@@ -173,3 +241,22 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-updates replace the binary, which only root can do.
+    #[test]
+    fn test_default_config_can_self_update() {
+        assert_eq!(Config::default().daemon.user, "root");
+
+        let shipped: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        assert_eq!(shipped.daemon.user, "root");
+
+        let mut minimal: toml::Table = toml::from_str(include_str!("../config.toml")).unwrap();
+        minimal["daemon"].as_table_mut().unwrap().retain(|key, _| key == "pid_file");
+        let minimal: Config = minimal.try_into().unwrap();
+        assert_eq!(minimal.daemon.user, "root");
+    }
+}
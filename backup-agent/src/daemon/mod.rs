@@ -2,4 +2,5 @@
 
 pub mod signals;
 pub mod pid;
+pub mod privileges;
 pub mod shutdown;
@@ -1,3 +1,106 @@
 //! PID file management.
+//!
+//! The PID file is locked with `flock` for as long as the agent runs, so a
+//! second instance started with the same configuration refuses to start
+//! instead of running backups twice. The lock (not the file's existence) is
+//! what counts: a file left behind by a crashed agent is simply taken over.
 
-// TODO: Implement PID file management in Week 4
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A locked PID file, released and removed on drop.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    _lock: Flock<File>,
+}
+
+impl PidFile {
+    /// Lock `path` and write the current PID into it. Fails if another
+    /// process holds the lock.
+    pub fn acquire(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Cannot open PID file {}: {}", path.display(), e))?;
+
+        let mut lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((mut file, Errno::EWOULDBLOCK)) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                anyhow::bail!(
+                    "Another backup-agent is already running (pid {}, PID file {})",
+                    pid.trim(),
+                    path.display()
+                );
+            }
+            Err((_, errno)) => anyhow::bail!("Cannot lock PID file {}: {}", path.display(), errno),
+        };
+
+        lock.set_len(0)?;
+        lock.seek(SeekFrom::Start(0))?;
+        writeln!(lock, "{}", std::process::id())?;
+        lock.sync_all()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Best effort: after dropping privileges the directory may no longer
+        // be writable, and the released lock is what matters anyway.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_instance_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("run/agent.pid");
+
+        let pid_file = PidFile::acquire(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        let err = PidFile::acquire(&path).unwrap_err().to_string();
+        assert!(err.contains("already running"), "{}", err);
+
+        drop(pid_file);
+        assert!(!path.exists());
+        assert!(PidFile::acquire(&path).is_ok());
+    }
+
+    #[test]
+    fn test_stale_pid_file_is_taken_over() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("agent.pid");
+        std::fs::write(&path, "999999\n").unwrap();
+
+        let _pid_file = PidFile::acquire(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+    }
+}
@@ -0,0 +1,77 @@
+//! Dropping root privileges.
+//!
+//! Started as root, the agent switches to `daemon.user`/`daemon.group` and
+//! keeps a single capability, `CAP_DAC_READ_SEARCH`, which lets it read every
+//! file and list every directory for backups without being able to write
+//! anywhere it could not as that user.
+//!
+//! Capabilities are per thread, so this must run before the async runtime
+//! starts any worker threads.
+
+use caps::{CapSet, Capability, CapsHashSet};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::path::Path;
+use tracing::{info, warn};
+
+const KEPT: Capability = Capability::CAP_DAC_READ_SEARCH;
+
+/// Whether the process has given up root.
+pub fn is_unprivileged() -> bool {
+    !Uid::effective().is_root()
+}
+
+/// Switch to `user`/`group`, keeping only `CAP_DAC_READ_SEARCH`. Directories
+/// in `writable` are handed to the new user first. Does nothing when not
+/// running as root or when `user` is `root`.
+pub fn drop_privileges(user: &str, group: &str, writable: &[&Path]) -> anyhow::Result<()> {
+    if is_unprivileged() {
+        info!(uid = %Uid::effective(), "Not running as root; keeping current user");
+        return Ok(());
+    }
+    if user == "root" {
+        warn!("Running as root (daemon.user = \"root\")");
+        return Ok(());
+    }
+
+    let uid = User::from_name(user)?
+        .ok_or_else(|| anyhow::anyhow!("daemon.user {} does not exist", user))?
+        .uid;
+    let gid = Group::from_name(group)?
+        .ok_or_else(|| anyhow::anyhow!("daemon.group {} does not exist", group))?
+        .gid;
+
+    for dir in writable {
+        std::fs::create_dir_all(dir)?;
+        nix::unistd::chown(*dir, Some(uid), Some(gid))
+            .map_err(|e| anyhow::anyhow!("Cannot hand {} to {}: {}", dir.display(), user, e))?;
+    }
+
+    switch_user(uid, gid).map_err(|e| anyhow::anyhow!("Failed to drop privileges to {}:{}: {}", user, group, e))?;
+
+    if nix::unistd::setuid(Uid::from_raw(0)).is_ok() {
+        anyhow::bail!("Privilege drop failed: the process can still become root");
+    }
+    info!(user, group, "Dropped root privileges, keeping CAP_DAC_READ_SEARCH");
+    Ok(())
+}
+
+fn switch_user(uid: Uid, gid: Gid) -> anyhow::Result<()> {
+    let kept: CapsHashSet = [KEPT].into_iter().collect();
+
+    // Nothing dropped from the bounding set can come back, even via setuid binaries
+    for cap in caps::all().difference(&kept) {
+        caps::drop(None, CapSet::Bounding, *cap)?;
+    }
+
+    // Keep the permitted set across setuid, then reduce it to the one capability
+    caps::securebits::set_keepcaps(true)?;
+    nix::unistd::setgroups(&[gid])?;
+    nix::unistd::setgid(gid)?;
+    nix::unistd::setuid(uid)?;
+    caps::securebits::set_keepcaps(false)?;
+
+    caps::set(None, CapSet::Permitted, &kept)?;
+    caps::set(None, CapSet::Effective, &kept)?;
+    caps::clear(None, CapSet::Inheritable)?;
+    Ok(())
+}
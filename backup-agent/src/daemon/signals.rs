@@ -1,3 +1,223 @@
-//! Signal handling (SIGTERM, SIGINT).
+//! Signal handling for the running daemon (SIGHUP, SIGUSR1).
+//!
+//! - SIGHUP re-reads the configuration file and applies what can change at
+//!   runtime (log level, path policy). Running jobs are left alone; settings
+//!   that need a restart are reported instead of applied.
+//! - SIGUSR1 logs the agent's state and the progress of every running job.
+//!
+//! SIGTERM and SIGINT are handled by [`super::shutdown`].
 
-// TODO: Implement signal handling in Week 4
+use crate::api::AppState;
+use crate::config::Config;
+use crate::policy;
+use crate::utils::logger;
+use crate::ws::{BackupProgressPayload, WsEvent};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// What SIGHUP reloads from, and what it must leave alone.
+pub struct ReloadSource {
+    /// Configuration file given with `--config` (nothing to reload without it)
+    pub config_path: Option<PathBuf>,
+    /// The configuration the agent is currently running with
+    pub config: Config,
+    /// `--log-level` was given on the command line and wins over the file
+    pub log_level_fixed: bool,
+}
+
+/// Handle SIGHUP and SIGUSR1 until `shutdown` is cancelled.
+pub async fn run(app_state: AppState, mut source: ReloadSource, shutdown: CancellationToken) {
+    let (mut hup, mut usr1) = match (signal(SignalKind::hangup()), signal(SignalKind::user_defined1())) {
+        (Ok(hup), Ok(usr1)) => (hup, usr1),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to install SIGHUP/SIGUSR1 handlers: {}", e);
+            return;
+        }
+    };
+    let mut events = app_state.ws_state.read().await.tx.subscribe();
+    // Last progress report of each running job, for the SIGUSR1 dump
+    let mut jobs: HashMap<String, BackupProgressPayload> = HashMap::new();
+    let started = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = hup.recv() => reload(&mut source),
+            _ = usr1.recv() => dump_status(&app_state, &jobs, started).await,
+            event = events.recv() => match event {
+                Ok(event) => track(&mut jobs, event),
+                // Missed some progress updates; the next ones catch up
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+fn track(jobs: &mut HashMap<String, BackupProgressPayload>, event: WsEvent) {
+    match event {
+        WsEvent::BackupProgress(progress) => {
+            jobs.insert(progress.job_id.clone(), progress);
+        }
+        WsEvent::BackupCompleted(p) => {
+            jobs.remove(&p.job_id);
+        }
+        WsEvent::BackupFailed { job_id, .. } => {
+            jobs.remove(&job_id);
+        }
+        _ => {}
+    }
+}
+
+async fn dump_status(app_state: &AppState, jobs: &HashMap<String, BackupProgressPayload>, started: Instant) {
+    let running = app_state.job_tracker.running().await;
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        uptime_secs = started.elapsed().as_secs(),
+        running_jobs = running.len(),
+        "Status (SIGUSR1)"
+    );
+
+    for (job_id, elapsed) in &running {
+        match jobs.get(job_id) {
+            Some(p) => info!(
+                job_id = %job_id,
+                running_secs = elapsed.as_secs(),
+                percent = format!("{:.1}", p.percent),
+                files = format!("{}/{}", p.files_processed, p.total_files),
+                bytes = format!("{}/{}", p.transferred_bytes, p.total_bytes),
+                speed = %p.speed,
+                concurrency = p.concurrency,
+                current_file = p.current_file.as_deref().unwrap_or("-"),
+                "Job status"
+            ),
+            None => info!(
+                job_id = %job_id,
+                running_secs = elapsed.as_secs(),
+                "Job status: scanning, no progress reported yet"
+            ),
+        }
+    }
+}
+
+fn reload(source: &mut ReloadSource) {
+    let Some(path) = &source.config_path else {
+        warn!("SIGHUP received but the agent was started without --config; nothing to reload");
+        return;
+    };
+    info!(path = %path.display(), "SIGHUP received, reloading configuration");
+
+    let config = match Config::from_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Reload failed, keeping the current configuration: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = policy::init(&config) {
+        error!("Reload failed, keeping the current configuration: path policy: {}", e);
+        return;
+    }
+
+    if source.log_level_fixed {
+        if config.log.level != source.config.log.level {
+            warn!("log.level changed but --log-level was given on the command line; ignoring");
+        }
+    } else if let Err(e) = logger::set_level(&config.log.level) {
+        error!(level = %config.log.level, "Invalid log level, keeping the current one: {}", e);
+    }
+
+    for section in restart_required(&source.config, &config) {
+        warn!("[{}] changed; restart the agent to apply it", section);
+    }
+
+    source.config = config;
+    info!("Configuration reloaded");
+}
+
+/// Sections that changed but are only read at startup.
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    fn differs<T: serde::Serialize>(a: &T, b: &T) -> bool {
+        serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+    }
+
+    let mut agent_old = old.agent.clone();
+    agent_old.policy_file = new.agent.policy_file.clone();
+
+    let mut changed = Vec::new();
+    if differs(&agent_old, &new.agent) {
+        changed.push("agent");
+    }
+    if differs(&old.server, &new.server) {
+        changed.push("server");
+    }
+    if differs(&old.sync, &new.sync) {
+        changed.push("sync");
+    }
+    let mut log_old = old.log.clone();
+    log_old.level = new.log.level.clone();
+    if differs(&log_old, &new.log) {
+        changed.push("log");
+    }
+    if differs(&old.daemon, &new.daemon) {
+        changed.push("daemon");
+    }
+    if differs(&old.performance, &new.performance) {
+        changed.push("performance");
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_required_ignores_reloadable_settings() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.log.level = "debug".into();
+        new.agent.policy_file = Some(PathBuf::from("/etc/backup-agent/policy.toml"));
+        assert!(restart_required(&old, &new).is_empty());
+
+        new.server.url = "https://other:3000".into();
+        new.daemon.user = "nobody".into();
+        assert_eq!(restart_required(&old, &new), vec!["server", "daemon"]);
+    }
+
+    #[test]
+    fn test_finished_jobs_are_forgotten() {
+        let progress = |job_id: &str| {
+            WsEvent::BackupProgress(BackupProgressPayload {
+                job_id: job_id.into(),
+                percent: 10.0,
+                transferred_bytes: 1,
+                total_bytes: 10,
+                bytes_per_second: 1,
+                eta_seconds: 9,
+                current_file: None,
+                files_processed: 1,
+                total_files: 10,
+                speed: "1 B/s".into(),
+                current_file_bytes: 0,
+                current_file_total: 0,
+                current_file_percent: 0.0,
+                active_files: Vec::new(),
+                skipped_files: 0,
+                skipped_bytes: 0,
+                backup_type: "full".into(),
+                concurrency: 4,
+            })
+        };
+        let mut jobs = HashMap::new();
+        track(&mut jobs, progress("j1"));
+        track(&mut jobs, progress("j2"));
+        track(&mut jobs, WsEvent::BackupFailed { job_id: "j1".into(), error: "boom".into() });
+        assert_eq!(jobs.keys().collect::<Vec<_>>(), vec!["j2"]);
+    }
+}
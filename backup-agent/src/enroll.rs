@@ -0,0 +1,199 @@
+//! Pull-based enrollment.
+//!
+//! `backup-agent enroll --server URL --token T` exchanges a single-use token
+//! minted by the backup server for this machine's `server_id` (and a client
+//! certificate when the server uses mutual TLS), writes the agent config and
+//! installs the systemd unit. No SSH access to the machine is needed.
+
+use crate::config::{Config, ServerConfig};
+use crate::tls;
+use backup_protocol::{EnrollRequest, EnrollResponse};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/backup-agent/config.toml";
+const SYSTEMD_SERVICE_PATH: &str = "/etc/systemd/system/backup-agent.service";
+const CLIENT_CERT_FILE: &str = "client.pem";
+const CLIENT_KEY_FILE: &str = "client-key.pem";
+
+pub struct EnrollOptions {
+    pub server_url: String,
+    pub token: String,
+    /// Server certificate fingerprint to pin for the enrollment request
+    pub fingerprint: Option<String>,
+    pub config_path: PathBuf,
+    pub install_service: bool,
+}
+
+/// Enroll with the server, then write the config and (optionally) install the service.
+pub async fn enroll(opts: &EnrollOptions) -> anyhow::Result<()> {
+    let server_url = opts.server_url.trim_end_matches('/').to_string();
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // The enrollment request is already pinned when a fingerprint is given
+    tls::init(&ServerConfig {
+        url: server_url.clone(),
+        token: String::new(),
+        server_id: None,
+        cert_fingerprint: opts.fingerprint.clone(),
+        client_cert: None,
+        client_key: None,
+        release_public_key: None,
+    })?;
+
+    tracing::info!(server = %server_url, hostname = %hostname, "Enrolling with backup server");
+    let response = tls::http_client()
+        .post(format!("{}/api/agent/enroll", server_url))
+        .json(&EnrollRequest { token: opts.token.clone(), hostname: hostname.clone() })
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        let error = body.get("error").and_then(|e| e.as_str()).unwrap_or("no details");
+        anyhow::bail!("Enrollment rejected ({}): {}", status, error);
+    }
+    let enrolled: EnrollResponse = response.json().await?;
+
+    let config_dir = opts
+        .config_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    std::fs::create_dir_all(&config_dir)?;
+
+    let client_cert = match (&enrolled.client_cert, &enrolled.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert_path = config_dir.join(CLIENT_CERT_FILE);
+            let key_path = config_dir.join(CLIENT_KEY_FILE);
+            std::fs::write(&cert_path, cert)?;
+            write_private(&key_path, key)?;
+            Some((cert_path, key_path))
+        }
+        _ => None,
+    };
+
+    let config = build_config(&hostname, &server_url, &enrolled, client_cert);
+    write_private(&opts.config_path, &toml::to_string_pretty(&config)?)?;
+    tracing::info!(
+        server_id = %enrolled.server_id,
+        name = %enrolled.server_name,
+        config = %opts.config_path.display(),
+        "Enrolled; config written"
+    );
+
+    if opts.install_service {
+        install_service(&opts.config_path)?;
+        tracing::info!("backup-agent service installed and started");
+    }
+    Ok(())
+}
+
+fn build_config(
+    hostname: &str,
+    server_url: &str,
+    enrolled: &EnrollResponse,
+    client_cert: Option<(PathBuf, PathBuf)>,
+) -> Config {
+    let mut config = Config::default();
+    config.agent.id = hostname.to_string();
+    config.server.url = server_url.to_string();
+    config.server.server_id = Some(enrolled.server_id.clone());
+    config.server.cert_fingerprint = enrolled.cert_fingerprint.clone();
+    config.server.release_public_key = enrolled.release_public_key.clone();
+    if let Some((cert, key)) = client_cert {
+        config.server.client_cert = Some(cert);
+        config.server.client_key = Some(key);
+    }
+    config
+}
+
+fn systemd_unit(exe: &Path, config_path: &Path) -> String {
+    format!(
+        r#"[Unit]
+Description=Backup Agent
+After=network.target
+
+[Service]
+Type=simple
+ExecStart={} --config {}
+Restart=always
+RestartSec=5
+User=root
+Environment=RUST_LOG=info
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        exe.display(),
+        config_path.display()
+    )
+}
+
+/// Install this binary where self-updates expect it, then the unit.
+fn install_service(config_path: &Path) -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    let installed = Path::new(crate::update::INSTALL_PATH);
+    if exe != installed {
+        std::fs::copy(&exe, installed)?;
+    }
+    std::fs::write(SYSTEMD_SERVICE_PATH, systemd_unit(installed, config_path))?;
+
+    for args in [
+        &["daemon-reload"][..],
+        &["enable", "backup-agent"],
+        &["restart", "backup-agent"],
+    ] {
+        let status = std::process::Command::new("systemctl").args(args).status()?;
+        if !status.success() {
+            anyhow::bail!("systemctl {} failed: {}", args.join(" "), status);
+        }
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::write(path, content)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> EnrollResponse {
+        serde_json::from_value(serde_json::json!({
+            "serverId": "srv-1",
+            "serverName": "web-01",
+            "certFingerprint": "ab:cd",
+            "clientCert": null,
+            "clientKey": null,
+            "releasePublicKey": "00ff",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_config_round_trips() {
+        let certs = Some((PathBuf::from("/etc/backup-agent/client.pem"), PathBuf::from("/etc/backup-agent/client-key.pem")));
+        let config = build_config("web-01", "https://backup:3000", &response(), certs);
+
+        let parsed: Config = toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(parsed.server.url, "https://backup:3000");
+        assert_eq!(parsed.server.server_id.as_deref(), Some("srv-1"));
+        assert_eq!(parsed.server.cert_fingerprint.as_deref(), Some("ab:cd"));
+        assert_eq!(parsed.server.client_key, Some(PathBuf::from("/etc/backup-agent/client-key.pem")));
+        assert_eq!(parsed.server.release_public_key.as_deref(), Some("00ff"));
+        assert_eq!(parsed.daemon.user, "root");
+    }
+
+    #[test]
+    fn test_systemd_unit_uses_config_path() {
+        let unit = systemd_unit(Path::new("/usr/local/bin/backup-agent"), Path::new("/etc/backup-agent/config.toml"));
+        assert!(unit.contains("ExecStart=/usr/local/bin/backup-agent --config /etc/backup-agent/config.toml"));
+    }
+}
@@ -0,0 +1,326 @@
+//! Adaptive upload concurrency.
+//!
+//! The uploads of a run share a [`Limiter`] whose limit follows what the
+//! server and the network take, AIMD-style. Each completed upload is a sample
+//! of throughput and latency; about once a second the limit is adjusted:
+//! doubled at first (slow start) and then raised by one while all uploads
+//! allowed are in flight, and halved when an upload fails on the server or
+//! the network, or when latency rises without throughput following, which
+//! means the extra uploads only wait on each other. The job's
+//! `max_concurrency` caps it.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+/// Uploads allowed at once when the server sets no maximum
+pub const MAX_CONCURRENCY: usize = 64;
+
+/// Uploads allowed at once when a run starts
+const INITIAL_CONCURRENCY: usize = 4;
+
+/// How often the limit is adjusted, at most
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latency this many times the lowest seen is congestion, unless
+/// throughput is at its highest
+const MAX_LATENCY_GROWTH: f64 = 2.0;
+
+/// Throughput must be this much above the highest seen to count as a gain
+const MIN_THROUGHPUT_GAIN: f64 = 1.05;
+
+/// How far the lowest latency and highest throughput seen drift towards the
+/// current ones each adjustment, so that old measures do not hold forever
+const BASELINE_DRIFT: f64 = 1.1;
+
+/// Bytes a request costs besides its body, so that the latency of small and
+/// large uploads compares
+const REQUEST_OVERHEAD_BYTES: u64 = 64 * 1024;
+
+/// A completed upload
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// It failed on the server or the network
+    pub congested: bool,
+}
+
+/// Samples since the last adjustment
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    bytes: u64,
+    uploads: usize,
+    /// Sum of seconds per byte (with the request overhead) of the uploads
+    latency: f64,
+    congested: bool,
+    /// Every upload allowed was in flight at some point
+    saturated: bool,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Self { started, bytes: 0, uploads: 0, latency: 0.0, congested: false, saturated: false }
+    }
+}
+
+/// The limit and how it is adjusted, apart from the permits it sizes
+#[derive(Debug)]
+struct Aimd {
+    limit: usize,
+    max: usize,
+    slow_start: bool,
+    window: Window,
+    /// Lowest mean latency and highest throughput seen, drifting
+    min_latency: Option<f64>,
+    max_throughput: f64,
+}
+
+impl Aimd {
+    fn new(max: usize, now: Instant) -> Self {
+        let max = max.clamp(1, MAX_CONCURRENCY);
+        Self {
+            limit: INITIAL_CONCURRENCY.min(max),
+            max,
+            slow_start: true,
+            window: Window::new(now),
+            min_latency: None,
+            max_throughput: 0.0,
+        }
+    }
+
+    /// `in_flight` uploads are running
+    fn in_flight(&mut self, in_flight: usize) {
+        if in_flight >= self.limit {
+            self.window.saturated = true;
+        }
+    }
+
+    /// Record a completed upload; returns the limit, adjusted if it was time to
+    fn record(&mut self, sample: Sample, now: Instant) -> usize {
+        let window = &mut self.window;
+        window.bytes += sample.bytes;
+        window.uploads += 1;
+        window.latency += sample.elapsed.as_secs_f64() / (sample.bytes + REQUEST_OVERHEAD_BYTES) as f64;
+        window.congested |= sample.congested;
+
+        let elapsed = now.duration_since(window.started);
+        if elapsed >= ADJUST_INTERVAL {
+            self.adjust(elapsed.as_secs_f64(), now);
+        }
+        self.limit
+    }
+
+    fn adjust(&mut self, elapsed: f64, now: Instant) {
+        let window = std::mem::replace(&mut self.window, Window::new(now));
+        let throughput = window.bytes as f64 / elapsed;
+        let latency = window.latency / window.uploads as f64;
+
+        let gain = throughput > self.max_throughput * MIN_THROUGHPUT_GAIN;
+        let queueing = self.min_latency.is_some_and(|min| latency > min * MAX_LATENCY_GROWTH) && !gain;
+        if window.congested || queueing {
+            self.limit = (self.limit / 2).max(1);
+            self.slow_start = false;
+        } else if window.saturated {
+            let raised = if self.slow_start { self.limit * 2 } else { self.limit + 1 };
+            self.limit = raised.min(self.max);
+        }
+
+        self.min_latency = Some(self.min_latency.map_or(latency, |min| latency.min(min * BASELINE_DRIFT)));
+        self.max_throughput = throughput.max(self.max_throughput / BASELINE_DRIFT);
+    }
+}
+
+struct State {
+    aimd: Aimd,
+    in_flight: usize,
+    /// Permits to forget as uploads end, the limit having dropped below the
+    /// uploads in flight
+    debt: usize,
+}
+
+/// Upload permits of a run, as many as the current limit
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<State>,
+    limit: AtomicUsize,
+}
+
+impl Limiter {
+    /// A limiter for at most `max` uploads at once
+    pub fn new(max: usize) -> Arc<Self> {
+        let aimd = Aimd::new(max, Instant::now());
+        let limit = aimd.limit;
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new(State { aimd, in_flight: 0, debt: 0 }),
+            limit: AtomicUsize::new(limit),
+        })
+    }
+
+    /// Uploads currently allowed at once
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Wait until another upload is allowed
+    pub async fn acquire(self: &Arc<Self>) -> Result<Permit, AcquireError> {
+        let permit = Arc::clone(&self.semaphore).acquire_owned().await?;
+        let mut state = self.lock();
+        state.in_flight += 1;
+        let in_flight = state.in_flight;
+        state.aimd.in_flight(in_flight);
+        Ok(Permit { limiter: Arc::clone(self), permit: Some(permit), started: Instant::now() })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Resize the permits from `from` to `to`
+    fn resize(&self, state: &mut State, from: usize, to: usize) {
+        if to > from {
+            let paid = state.debt.min(to - from);
+            state.debt -= paid;
+            self.semaphore.add_permits(to - from - paid);
+        } else if to < from {
+            let forgotten = self.semaphore.forget_permits(from - to);
+            state.debt += from - to - forgotten;
+        }
+        self.limit.store(to, Ordering::Relaxed);
+    }
+
+    fn release(&self, permit: OwnedSemaphorePermit) {
+        let mut state = self.lock();
+        state.in_flight -= 1;
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+}
+
+/// An upload allowed by a [`Limiter`], which ends when dropped
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    permit: Option<OwnedSemaphorePermit>,
+    started: Instant,
+}
+
+impl Permit {
+    /// End the upload, which sent `bytes`; `congested` if it failed on the
+    /// server or the network
+    pub fn finish(self, bytes: u64, congested: bool) {
+        let limiter = &self.limiter;
+        let mut state = limiter.lock();
+        let from = state.aimd.limit;
+        let sample = Sample { bytes, elapsed: self.started.elapsed(), congested };
+        let to = state.aimd.record(sample, Instant::now());
+        if to != from {
+            tracing::debug!("Upload concurrency {} -> {}", from, to);
+            limiter.resize(&mut state, from, to);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.limiter.release(permit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// One window of `uploads` uploads of `bytes` each, taking `latency` each
+    fn window(aimd: &mut Aimd, at: &mut Instant, uploads: usize, bytes: u64, latency: Duration, congested: bool) -> usize {
+        aimd.in_flight(aimd.limit);
+        for _ in 0..uploads - 1 {
+            aimd.record(Sample { bytes, elapsed: latency, congested }, *at);
+        }
+        *at += ADJUST_INTERVAL;
+        aimd.record(Sample { bytes, elapsed: latency, congested }, *at)
+    }
+
+    #[test]
+    fn test_slow_start_then_additive_increase() {
+        let mut at = Instant::now();
+        let mut aimd = Aimd::new(64, at);
+        assert_eq!(aimd.limit, INITIAL_CONCURRENCY);
+        let second = Duration::from_secs(1);
+        // Throughput grows with the limit while latency holds
+        assert_eq!(window(&mut aimd, &mut at, 4, MB, second, false), 8);
+        assert_eq!(window(&mut aimd, &mut at, 8, MB, second, false), 16);
+        // A failed upload halves it, and it grows by one from there
+        assert_eq!(window(&mut aimd, &mut at, 16, MB, second, true), 8);
+        assert_eq!(window(&mut aimd, &mut at, 8, MB, second, false), 9);
+        assert_eq!(window(&mut aimd, &mut at, 9, MB, second, false), 10);
+    }
+
+    #[test]
+    fn test_queueing_halves_the_limit() {
+        let mut at = Instant::now();
+        let mut aimd = Aimd::new(64, at);
+        let second = Duration::from_secs(1);
+        assert_eq!(window(&mut aimd, &mut at, 4, MB, second, false), 8);
+        // Twice the uploads, each taking three times as long: the link is full
+        assert_eq!(window(&mut aimd, &mut at, 4, MB, second * 3, false), 4);
+        // Small files compare with large ones by their latency per byte
+        assert_eq!(window(&mut aimd, &mut at, 40, 1024, Duration::from_millis(80), false), 5);
+    }
+
+    #[test]
+    fn test_limit_stays_within_bounds() {
+        let mut at = Instant::now();
+        let mut aimd = Aimd::new(6, at);
+        let second = Duration::from_secs(1);
+        assert_eq!(window(&mut aimd, &mut at, 4, MB, second, false), 6);
+        assert_eq!(window(&mut aimd, &mut at, 12, MB, second, false), 6);
+        for _ in 0..4 {
+            window(&mut aimd, &mut at, 1, MB, second, true);
+        }
+        assert_eq!(aimd.limit, 1);
+
+        assert_eq!(Aimd::new(0, at).limit, 1);
+        assert_eq!(Aimd::new(1000, at).max, MAX_CONCURRENCY);
+    }
+
+    #[test]
+    fn test_no_increase_while_uploads_do_not_fill_the_limit() {
+        let mut at = Instant::now();
+        let mut aimd = Aimd::new(64, at);
+        for _ in 0..2 {
+            aimd.record(Sample { bytes: MB, elapsed: Duration::from_secs(1), congested: false }, at);
+        }
+        at += ADJUST_INTERVAL;
+        let limit = aimd.record(Sample { bytes: MB, elapsed: Duration::from_secs(1), congested: false }, at);
+        assert_eq!(limit, INITIAL_CONCURRENCY);
+    }
+
+    #[tokio::test]
+    async fn test_permits_follow_the_limit() {
+        let limiter = Limiter::new(64);
+        let mut permits = Vec::new();
+        for _ in 0..INITIAL_CONCURRENCY {
+            permits.push(limiter.acquire().await.unwrap());
+        }
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+
+        // Every upload fails on the server: the limit halves once the
+        // interval is over, and the permits of uploads still running are
+        // forgotten as they end
+        limiter.lock().aimd.window.started -= ADJUST_INTERVAL;
+        permits.pop().unwrap().finish(0, true);
+        assert_eq!(limiter.limit(), INITIAL_CONCURRENCY / 2);
+        permits.clear();
+        assert_eq!(limiter.semaphore.available_permits(), INITIAL_CONCURRENCY / 2);
+        assert_eq!(limiter.lock().in_flight, 0);
+    }
+}
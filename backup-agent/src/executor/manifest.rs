@@ -1,24 +1,395 @@
-//! Manifest types for incremental backup support.
+//! Manifests on the agent side, for incremental backup support.
 //!
-//! A manifest records every file in a backup version with its size and mtime,
-//! allowing the agent to diff against it and only transfer changed files.
-
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-
-/// Backup manifest — serialized as `.backup-manifest.json` in each version directory.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Manifest {
-    pub version: u32,
-    pub job_id: String,
-    pub files: HashMap<String, ManifestEntry>,
-    pub total_files: usize,
-    pub total_bytes: u64,
-}
-
-/// Metadata for a single file in the manifest.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ManifestEntry {
-    pub size: u64,
-    pub mtime: i64,
+//! The previous version's manifest is read while the scan runs and merged
+//! against it: both come in [`backup_protocol::path::compare`] order, so each
+//! scanned file is only compared with the next previous entry and neither
+//! side is held in memory. The new version's manifest is written to a
+//! temporary file as files are found. The formats live in
+//! [`backup_protocol::manifest`].
+
+use super::BackupJob;
+use crate::fs::walker::FileInfo;
+use backup_protocol::manifest::{
+    FileId, JsonManifest, ManifestEntry, ManifestHeader, ManifestReader, ManifestWriter, CONTENT_TYPE,
+    CONTENT_TYPE_V2, FILE_NAME, JSON_FILE_NAME,
+};
+use backup_protocol::path;
+use backup_protocol::rest::{HEADER_JOB_ID, HEADER_RELATIVE_PATH, HEADER_TOTAL_SIZE};
+use futures_util::TryStreamExt;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek};
+use tokio::sync::mpsc;
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+use tracing::{info, warn};
+
+/// Previous manifest entries read ahead of the scan
+const PREVIOUS_QUEUE_SIZE: usize = 4096;
+
+/// Deleted files kept for rename detection; files deleted past this are not
+/// matched, and their new paths uploaded again
+const MAX_DELETED_ENTRIES: usize = 250_000;
+
+/// How a scanned file compares with the previous version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Unchanged,
+    Modified,
+    /// Not in the previous version: new, or moved there from a deleted path
+    Added,
+}
+
+type PreviousEntry = std::io::Result<(String, ManifestEntry)>;
+
+/// Files of the previous version the scan has not gone past yet
+pub struct PreviousFiles {
+    entries: mpsc::Receiver<PreviousEntry>,
+    next: Option<(String, ManifestEntry)>,
+    read: usize,
+    deleted: usize,
+    /// Paths of the files not found again, when kept
+    deleted_paths: Option<Vec<String>>,
+    /// The same with their entries, when kept for rename detection
+    deleted_entries: Option<Vec<(String, ManifestEntry)>>,
+}
+
+impl PreviousFiles {
+    /// Fetch the previous backup manifest from the server, asking for the
+    /// binary one if `binary`, with file ids. Returns None on any error (caller should fall
+    /// back to full backup).
+    pub async fn fetch(server_url: &str, manifest_url: &str, binary: bool) -> Option<Self> {
+        let url = format!("{}{}", server_url, manifest_url);
+        let accept = if binary { CONTENT_TYPE_V2 } else { "application/json" };
+        let client = crate::tls::http_client();
+
+        let resp = match client.get(&url).header(reqwest::header::ACCEPT, accept).send().await {
+            Ok(resp) if resp.status().is_success() => resp,
+            Ok(resp) => {
+                info!("No manifest available (status {}), will do full backup", resp.status());
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to fetch manifest: {}", e);
+                return None;
+            }
+        };
+
+        let is_binary = resp.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(CONTENT_TYPE));
+        let entries = if is_binary {
+            info!("Streaming previous manifest");
+            stream(resp)
+        } else {
+            match resp.json::<JsonManifest>().await {
+                Ok(manifest) => {
+                    info!("Fetched manifest: {} files, {} bytes", manifest.total_files, manifest.total_bytes);
+                    sorted(manifest.into_sorted().1)
+                }
+                Err(e) => {
+                    warn!("Failed to parse manifest: {}", e);
+                    return None;
+                }
+            }
+        };
+
+        // A manifest that cannot even be started is no manifest
+        match Self::start(entries).await {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                warn!("Failed to read manifest: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Files already in manifest order
+    pub async fn from_sorted(files: Vec<(String, ManifestEntry)>) -> Self {
+        let mut entries = sorted(files);
+        let next = entries.recv().await.and_then(Result::ok);
+        Self::with(entries, next)
+    }
+
+    async fn start(mut entries: mpsc::Receiver<PreviousEntry>) -> std::io::Result<Self> {
+        let next = entries.recv().await.transpose()?;
+        Ok(Self::with(entries, next))
+    }
+
+    fn with(entries: mpsc::Receiver<PreviousEntry>, next: Option<(String, ManifestEntry)>) -> Self {
+        Self { entries, read: usize::from(next.is_some()), next, deleted: 0, deleted_paths: None, deleted_entries: None }
+    }
+
+    /// Keep the paths of deleted files for [`PreviousFiles::take_deleted`]
+    pub fn keep_deleted_paths(&mut self) {
+        self.deleted_paths.get_or_insert_with(Vec::new);
+    }
+
+    /// Paths of the files found deleted since the last call
+    pub fn take_deleted(&mut self) -> Vec<String> {
+        self.deleted_paths.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Keep deleted files and their entries for
+    /// [`PreviousFiles::take_deleted_entries`]
+    pub fn keep_deleted_entries(&mut self) {
+        self.deleted_entries.get_or_insert_with(Vec::new);
+    }
+
+    /// Deleted files and their entries, once the scan is complete
+    pub fn take_deleted_entries(&mut self) -> Vec<(String, ManifestEntry)> {
+        self.deleted_entries.take().unwrap_or_default()
+    }
+
+    fn deleted(&mut self, path: String, entry: ManifestEntry) {
+        self.deleted += 1;
+        if let Some(entries) = &mut self.deleted_entries {
+            if entries.len() < MAX_DELETED_ENTRIES {
+                entries.push((path.clone(), entry));
+            }
+        }
+        if let Some(paths) = &mut self.deleted_paths {
+            paths.push(path);
+        }
+    }
+
+    /// How `file`, stored at `rel`, changed since the previous version.
+    /// Uses size + mtime as the change detection heuristic (same as rsync default).
+    /// Files must be passed in manifest order; previous files sorting before
+    /// `rel` were not found again.
+    pub async fn change(&mut self, rel: &str, file: &FileInfo) -> Change {
+        while let Some((path, entry)) = self.next.take() {
+            match path::compare(&path, rel) {
+                Ordering::Less => {
+                    self.deleted(path, entry);
+                    self.advance().await;
+                }
+                Ordering::Equal => {
+                    self.advance().await;
+                    return if entry.size == file.size && entry.mtime == file.mtime {
+                        Change::Unchanged
+                    } else {
+                        Change::Modified
+                    };
+                }
+                Ordering::Greater => {
+                    self.next = Some((path, entry));
+                    return Change::Added;
+                }
+            }
+        }
+        Change::Added
+    }
+
+    async fn advance(&mut self) {
+        self.next = match self.entries.recv().await {
+            Some(Ok(entry)) => Some(entry),
+            Some(Err(e)) => {
+                warn!("Previous manifest unreadable after {} files, uploading the rest: {}", self.read, e);
+                None
+            }
+            None => None,
+        };
+        self.read += usize::from(self.next.is_some());
+    }
+
+    /// Files of the previous version that were not found again, once the
+    /// scan is complete
+    pub async fn finish(&mut self) -> usize {
+        while let Some((path, entry)) = self.next.take() {
+            self.deleted(path, entry);
+            self.advance().await;
+        }
+        self.deleted
+    }
+}
+
+/// The new version's manifest, written as the scan finds files
+pub enum NewManifest {
+    /// Sorted binary manifest, for servers that take it, in an unnamed
+    /// temporary file so that it is never held in memory
+    Binary(ManifestWriter<BufWriter<File>>),
+    /// Files of a [`JsonManifest`], for older servers and scans that are not
+    /// in manifest order
+    Json(Vec<(String, ManifestEntry)>),
+    /// Writing failed: no manifest is uploaded, so the next run is full
+    Failed,
+}
+
+impl NewManifest {
+    pub fn new(job: &BackupJob, binary: bool) -> Self {
+        if !binary {
+            return Self::Json(Vec::new());
+        }
+        match tempfile::tempfile().and_then(|file| ManifestWriter::new(BufWriter::new(file), &header(job))) {
+            Ok(writer) => Self::Binary(writer),
+            Err(e) => {
+                warn!("Failed to start manifest: {}", e);
+                Self::Failed
+            }
+        }
+    }
+
+    pub fn add(&mut self, rel: &str, file: &FileInfo) {
+        let id = (file.ino != 0).then_some(FileId { dev: file.dev, ino: file.ino });
+        let entry = ManifestEntry { size: file.size, mtime: file.mtime, id };
+        match self {
+            Self::Binary(writer) => {
+                if let Err(e) = writer.add(rel, entry) {
+                    warn!("Failed to write manifest: {}", e);
+                    *self = Self::Failed;
+                }
+            }
+            Self::Json(files) => files.push((rel.to_string(), entry)),
+            Self::Failed => {}
+        }
+    }
+
+    /// Upload the manifest, leaving out the `failed` paths (those recorded
+    /// as their uploads failed) so the next incremental run retries them.
+    pub async fn upload(
+        self,
+        job: &BackupJob,
+        failed: HashSet<String>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (name, body, size, total_files) = match self {
+            Self::Binary(writer) => {
+                let (file, total_files) = tokio::task::spawn_blocking(move || finish(writer, &failed)).await??;
+                let size = file.metadata()?.len();
+                let body = reqwest::Body::wrap_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+                (FILE_NAME, body, size, total_files)
+            }
+            Self::Json(files) => {
+                let manifest = JsonManifest::new(
+                    header(job),
+                    files.into_iter().filter(|(path, _)| !failed.contains(path)),
+                );
+                let bytes = serde_json::to_vec(&manifest)?;
+                let size = bytes.len() as u64;
+                (JSON_FILE_NAME, bytes.into(), size, manifest.total_files as u64)
+            }
+            Self::Failed => return Ok(()),
+        };
+
+        let upload_url = format!("{}/api/files/upload", job.server_url);
+        let client = crate::tls::http_client();
+        let resp = client.post(&upload_url)
+            .header(HEADER_JOB_ID, &job.job_id)
+            .header(HEADER_RELATIVE_PATH, name)
+            .header(HEADER_TOTAL_SIZE, size.to_string())
+            .header("content-type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await?;
+
+        if resp.status().is_success() {
+            info!("Uploaded manifest: {} files, {} bytes", total_files, size);
+        } else {
+            warn!("Manifest upload failed with status {}", resp.status());
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a binary manifest on a blocking thread as it downloads
+fn stream(resp: reqwest::Response) -> mpsc::Receiver<PreviousEntry> {
+    let (tx, entries) = mpsc::channel(PREVIOUS_QUEUE_SIZE);
+    let body = SyncIoBridge::new(StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other)));
+    tokio::task::spawn_blocking(move || {
+        let reader = match ManifestReader::new(body) {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e));
+                return;
+            }
+        };
+        for entry in reader {
+            if tx.blocking_send(entry).is_err() {
+                break;
+            }
+        }
+    });
+    entries
+}
+
+fn sorted(files: Vec<(String, ManifestEntry)>) -> mpsc::Receiver<PreviousEntry> {
+    let (tx, entries) = mpsc::channel(PREVIOUS_QUEUE_SIZE);
+    tokio::spawn(async move {
+        for file in files {
+            if tx.send(Ok(file)).await.is_err() {
+                break;
+            }
+        }
+    });
+    entries
+}
+
+/// Which source root each top-level directory holds
+fn header(job: &BackupJob) -> ManifestHeader {
+    ManifestHeader {
+        job_id: job.job_id.clone(),
+        roots: job.labels.iter()
+            .zip(&job.paths)
+            .map(|(label, path)| (label.clone(), path.display().to_string()))
+            .collect(),
+    }
+}
+
+/// Write the end of the binary manifest and rewind it for upload, leaving
+/// out the `failed` paths (copied to another temporary file, entry by entry).
+/// Returns it with the number of files it has.
+fn finish(writer: ManifestWriter<BufWriter<File>>, failed: &HashSet<String>) -> std::io::Result<(File, u64)> {
+    let total_files = writer.total_files();
+    let mut file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.rewind()?;
+    if failed.is_empty() {
+        return Ok((file, total_files));
+    }
+
+    let reader = ManifestReader::new(BufReader::new(file))?;
+    let mut kept = ManifestWriter::new(BufWriter::new(tempfile::tempfile()?), &reader.header().clone())?;
+    for entry in reader {
+        let (path, entry) = entry?;
+        if !failed.contains(&path) {
+            kept.add(&path, entry)?;
+        }
+    }
+    let total_files = kept.total_files();
+    let mut file = kept.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.rewind()?;
+    Ok((file, total_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_finish_leaves_out_failed_paths() {
+        let header = ManifestHeader { job_id: "j1".into(), ..Default::default() };
+        let entry = ManifestEntry { size: 1, mtime: 2, id: None };
+        let new = || {
+            let mut writer = ManifestWriter::new(BufWriter::new(tempfile::tempfile().unwrap()), &header).unwrap();
+            for path in ["a", "b", "c"] {
+                writer.add(path, entry).unwrap();
+            }
+            writer
+        };
+        let paths = |mut file: File| {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            ManifestReader::new(&bytes[..]).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>()
+        };
+
+        let (file, total_files) = finish(new(), &HashSet::new()).unwrap();
+        assert_eq!(total_files, 3);
+        assert_eq!(paths(file), vec!["a", "b", "c"]);
+
+        let (file, total_files) = finish(new(), &HashSet::from(["b".to_string()])).unwrap();
+        assert_eq!(total_files, 2);
+        assert_eq!(paths(file), vec!["a", "c"]);
+    }
 }
@@ -7,57 +7,88 @@
 //! - Progress tracking
 //! - WebSocket event emission
 
+pub mod concurrency;
 pub mod manifest;
+pub mod renames;
 
-use crate::fs::walker::{walk_directory, WalkOptions, FileInfo};
+use crate::fs::walker::{walk_directory_parallel, WalkItem, WalkOptions, FileInfo};
+use crate::policy;
+use crate::transfer::compression;
 use crate::transfer::progress::format_speed;
 use crate::transfer::progress_stream::ProgressStream;
-use crate::ws::{WsState, WsEvent, BackupProgressPayload, ActiveFileProgress};
-use manifest::Manifest;
+use crate::ws::{
+    WsState, WsEvent, BackupCompletedPayload, BackupProgressPayload, ActiveFileProgress, FileErrorPayload,
+    ScanProgressPayload,
+};
+use backup_protocol::compression::{Algorithm, Compression};
+use backup_protocol::path as path_encoding;
+use backup_protocol::ChangesRequest;
+use backup_protocol::rest::{HEADER_JOB_ID, HEADER_RELATIVE_PATH, HEADER_TOTAL_SIZE};
+use concurrency::Limiter;
+use futures_util::StreamExt;
+use manifest::{Change, NewManifest, PreviousFiles};
+use renames::HeldFiles;
 use std::collections::{HashMap, HashSet};
-use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use tokio::sync::{RwLock, Semaphore};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{info, warn, error};
+use tracing::{info, warn, error, Instrument};
 use tokio_util::io::ReaderStream;
 
-/// Concurrency weight budget — total permits in the semaphore.
-/// Small files take 1 permit (up to 64 concurrent), large files take all 64 (sequential).
-const CONCURRENCY_BUDGET: usize = 64;
-
-/// Returns the number of semaphore permits a file should acquire based on its size.
-/// This automatically adapts parallelism: many small files run concurrently,
-/// large files limit concurrency to avoid bandwidth saturation.
-///
-/// | File size       | Permits | Effective concurrency |
-/// |-----------------|---------|----------------------|
-/// | < 10 MB         | 1       | 64                   |
-/// | 10 – 100 MB     | 2       | 32                   |
-/// | 100 – 500 MB    | 16      | 4                    |
-/// | 500 MB – 1 GB   | 32      | 2                    |
-/// | > 1 GB          | 64      | 1                    |
-fn concurrency_weight(file_size: u64) -> u32 {
-    match file_size {
-        0..=10_485_759              => 1,  // < 10 MB
-        10_485_760..=104_857_599    => 2,  // 10 – 100 MB
-        104_857_600..=524_287_999   => 16, // 100 – 500 MB
-        524_288_000..=1_073_741_823 => 32, // 500 MB – 1 GB
-        _ => 64,                           // > 1 GB
-    }
-}
+/// Policy violations reported per scanned root; the rest are only counted in the log.
+const MAX_REPORTED_VIOLATIONS: usize = 50;
+
+/// File errors reported per run; the rest are only counted.
+const MAX_REPORTED_FILE_ERRORS: usize = 1000;
+
+/// Threads walking the source tree.
+const SCAN_THREADS: usize = 8;
+
+/// Scanned files waiting to be diffed; the scan pauses while this many are queued.
+const SCAN_QUEUE_SIZE: usize = 4096;
+
+/// How often scan progress is sent while the scan runs.
+const SCAN_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Unchanged files per hardlink request.
+const HARDLINK_BATCH_SIZE: usize = 5000;
+
+/// Changed and deleted paths per `/api/files/changes` request.
+const CHANGES_BATCH_SIZE: usize = 5000;
+
+/// Largest file compressed for servers that do not set compression per job:
+/// they hold compressed uploads in memory.
+const MAX_COMPRESS_SIZE: u64 = 500 * 1024 * 1024;
 
 /// Backup job configuration
 #[derive(Debug, Clone)]
 pub struct BackupJob {
     pub job_id: String,
     pub paths: Vec<PathBuf>,
+    /// Directory each root's files are stored under in the version, in the
+    /// order of `paths`; empty to store files relative to their own root
+    pub labels: Vec<String>,
     pub destination: PathBuf,
     pub server_url: String,
     pub incremental: bool,
     pub manifest_url: Option<String>,
+    /// The server takes binary manifests ([`backup_protocol::manifest`])
+    pub binary_manifest: bool,
+    /// Report changed and deleted paths instead of requesting hardlinks for
+    /// unchanged ones; the server carries those forward itself
+    pub carry_forward: bool,
+    /// Match new files against deleted ones and have the server link those
+    /// that were moved ([`renames`])
+    pub detect_renames: bool,
+    /// How to compress uploads; None if the server does not set it per job
+    pub compression: Option<Compression>,
+    /// Most uploads at once; [`concurrency::MAX_CONCURRENCY`] if unset
+    pub max_concurrency: Option<usize>,
+    /// Fail the run once more files than this could not be backed up
+    pub max_file_errors: Option<u64>,
 }
 
 /// Backup execution result
@@ -70,20 +101,70 @@ pub struct BackupResult {
     pub unchanged_files: usize,
     pub unchanged_bytes: u64,
     pub deleted_files: usize,
+    pub failed_files: usize,
     pub backup_type: String,
     pub duration_secs: u64,
 }
 
-/// Result of diffing scanned files against a manifest
-struct DiffResult {
-    /// Files to upload (new or modified)
-    changed_files: Vec<FileInfo>,
-    _changed_bytes: u64,
-    /// Relative paths of unchanged files (to hardlink on server)
-    unchanged_paths: Vec<String>,
-    unchanged_bytes: u64,
-    /// Count of files in manifest but not on filesystem
-    deleted_count: usize,
+/// What the scan threads pass to the executor
+enum ScanItem {
+    File(FileInfo),
+    /// An entry that could not be read; reported as a file error
+    Error(PathBuf, std::io::Error),
+    Violation(policy::PolicyViolation),
+}
+
+/// Running totals of the scan, shared with the progress broadcaster. The
+/// `changed_*` totals are also what there is to upload, and grow until the
+/// scan is done.
+#[derive(Default)]
+struct ScanStats {
+    scanned_files: AtomicUsize,
+    scanned_bytes: AtomicU64,
+    changed_files: AtomicUsize,
+    changed_bytes: AtomicU64,
+    unchanged_files: AtomicUsize,
+    unchanged_bytes: AtomicU64,
+    current_path: std::sync::Mutex<Option<String>>,
+    done: AtomicBool,
+}
+
+impl ScanStats {
+    fn scanned(&self, file: &FileInfo, unchanged: bool) {
+        self.scanned_files.fetch_add(1, Ordering::Relaxed);
+        self.scanned_bytes.fetch_add(file.size, Ordering::Relaxed);
+        if unchanged {
+            self.unchanged_files.fetch_add(1, Ordering::Relaxed);
+            self.unchanged_bytes.fetch_add(file.size, Ordering::Relaxed);
+        } else {
+            self.changed_files.fetch_add(1, Ordering::Relaxed);
+            self.changed_bytes.fetch_add(file.size, Ordering::Relaxed);
+        }
+        if let Ok(mut current) = self.current_path.lock() {
+            *current = Some(file.path.display().to_string());
+        }
+    }
+
+    /// A changed file turned out to be moved, and was linked instead of uploaded
+    fn moved(&self, file: &FileInfo) {
+        self.changed_files.fetch_sub(1, Ordering::Relaxed);
+        self.changed_bytes.fetch_sub(file.size, Ordering::Relaxed);
+        self.unchanged_files.fetch_add(1, Ordering::Relaxed);
+        self.unchanged_bytes.fetch_add(file.size, Ordering::Relaxed);
+    }
+
+    fn payload(&self, job_id: &str) -> ScanProgressPayload {
+        ScanProgressPayload {
+            job_id: job_id.to_string(),
+            scanned_files: self.scanned_files.load(Ordering::Relaxed),
+            scanned_bytes: self.scanned_bytes.load(Ordering::Relaxed),
+            changed_files: self.changed_files.load(Ordering::Relaxed),
+            changed_bytes: self.changed_bytes.load(Ordering::Relaxed),
+            unchanged_files: self.unchanged_files.load(Ordering::Relaxed),
+            unchanged_bytes: self.unchanged_bytes.load(Ordering::Relaxed),
+            current_path: self.current_path.lock().ok().and_then(|current| current.clone()),
+        }
+    }
 }
 
 /// Tracks an active file transfer (shared between upload task and progress broadcaster)
@@ -93,6 +174,220 @@ struct ActiveFileState {
     transferred: AtomicU64,
 }
 
+/// Why a single file could not be backed up
+#[derive(Debug)]
+struct FileFailure {
+    /// `open`, `read` or `upload` (see [`FileErrorPayload::operation`])
+    operation: &'static str,
+    error: Box<dyn std::error::Error + Send + Sync>,
+    /// It failed on the server or the network rather than on this file, so
+    /// the next files would fail as well: it fails the run
+    transport: bool,
+}
+
+impl FileFailure {
+    fn new(operation: &'static str, error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self { operation, error: error.into(), transport: false }
+    }
+
+    fn transport(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self { operation: "upload", error: error.into(), transport: true }
+    }
+}
+
+impl std::fmt::Display for FileFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.operation, self.error)
+    }
+}
+
+/// The OS error behind `error`, if any.
+fn os_error<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a std::io::Error> {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return Some(io);
+        }
+        current = e.source();
+    }
+    None
+}
+
+/// Files of a run that could not be backed up. Each is reported to the server
+/// as it happens; past the job's `max_file_errors`, or at the first upload
+/// failing on the server or the network, the run is stopped.
+struct FileErrors {
+    job_id: String,
+    ws_state: Arc<RwLock<WsState>>,
+    max: Option<u64>,
+    cancel_token: CancellationToken,
+    count: AtomicUsize,
+    exceeded: AtomicBool,
+    transport_error: std::sync::Mutex<Option<String>>,
+}
+
+impl FileErrors {
+    async fn record(&self, path: &Path, operation: &str, error: &(dyn std::error::Error + Send + Sync + 'static)) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if count <= MAX_REPORTED_FILE_ERRORS {
+            let state = self.ws_state.read().await;
+            state.broadcast(WsEvent::BackupFileError(FileErrorPayload {
+                job_id: self.job_id.clone(),
+                path: path.display().to_string(),
+                operation: operation.to_string(),
+                errno: os_error(error).and_then(|e| e.raw_os_error()),
+                message: error.to_string(),
+            }));
+        }
+
+        if self.max.is_some_and(|max| count as u64 > max) && !self.exceeded.swap(true, Ordering::Relaxed) {
+            warn!("More than {} files could not be backed up, stopping the run", self.max.unwrap_or_default());
+            self.cancel_token.cancel();
+        }
+    }
+
+    /// Stop the run: an upload failed on the server or the network
+    fn fail_run(&self, path: &Path, error: &(dyn std::error::Error + Send + Sync + 'static)) {
+        let mut transport_error = self.transport_error.lock().unwrap_or_else(|e| e.into_inner());
+        if transport_error.is_none() {
+            warn!("Uploading {} failed on the server or the network, stopping the run", path.display());
+            *transport_error = Some(format!("Uploading {} failed: {}", path.display(), error));
+        }
+        self.cancel_token.cancel();
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The error to fail the run with, once an upload failed on the server or
+    /// the network or the limit is exceeded
+    fn run_error(&self) -> Option<String> {
+        if let Some(error) = self.transport_error.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            return Some(error);
+        }
+        self.exceeded.load(Ordering::Relaxed).then(|| {
+            format!(
+                "Too many file errors: more than {} files could not be backed up",
+                self.max.unwrap_or_default()
+            )
+        })
+    }
+}
+
+type UploadResult = Result<u64, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Starts the upload tasks of a run, which share its counters and limiter
+struct Uploader {
+    job_id: String,
+    server_url: String,
+    limiter: Arc<Limiter>,
+    completed_bytes: Arc<AtomicU64>,
+    completed_files: Arc<AtomicUsize>,
+    active_files: Arc<RwLock<HashMap<usize, Arc<ActiveFileState>>>>,
+    file_errors: Arc<FileErrors>,
+    failed_uploads: Arc<std::sync::Mutex<HashSet<String>>>,
+    cancel: CancellationToken,
+    compression: Option<Compression>,
+    next: usize,
+}
+
+impl Uploader {
+    /// Start uploading `file_info` once the limiter allows another upload; false
+    /// if the run was cancelled first.
+    async fn start(
+        &mut self,
+        uploads: &mut JoinSet<UploadResult>,
+        file_info: FileInfo,
+    ) -> Result<bool, tokio::sync::AcquireError> {
+        let permit = tokio::select! {
+            permit = self.limiter.acquire() => permit?,
+            _ = self.cancel.cancelled() => return Ok(false),
+        };
+
+        let idx = self.next;
+        self.next += 1;
+        let job_id = self.job_id.clone();
+        let server_url = self.server_url.clone();
+        let global_completed_bytes = Arc::clone(&self.completed_bytes);
+        let global_completed_files = Arc::clone(&self.completed_files);
+        let active_map = Arc::clone(&self.active_files);
+        let cancel = self.cancel.clone();
+        let file_errors = Arc::clone(&self.file_errors);
+        let failed_uploads = Arc::clone(&self.failed_uploads);
+        let compression = upload_compression(self.compression, file_info.size);
+
+        uploads.spawn(async move {
+            // Check cancellation after acquiring permit
+            if cancel.is_cancelled() {
+                drop(permit);
+                return Err::<u64, Box<dyn std::error::Error + Send + Sync>>("Cancelled".into());
+            }
+
+            // Register in active files map
+            let file_state = Arc::new(ActiveFileState {
+                path: file_info.path.display().to_string(),
+                total_bytes: file_info.size,
+                transferred: AtomicU64::new(0),
+            });
+            {
+                let mut map = active_map.write().await;
+                map.insert(idx, Arc::clone(&file_state));
+            }
+
+            info!("Processing file: {}", file_info.path.display());
+
+            let result = upload_file(
+                &job_id,
+                &server_url,
+                &file_info,
+                compression,
+                &file_state,
+                &cancel,
+            ).await;
+
+            // Remove from active files map
+            {
+                let mut map = active_map.write().await;
+                map.remove(&idx);
+            }
+
+            // Uploads and failures on the server or the network tune the
+            // limiter; ending the permit allows the next upload
+            match &result {
+                Ok(bytes) => permit.finish(*bytes, false),
+                Err(e) if e.transport && !cancel.is_cancelled() => permit.finish(0, true),
+                Err(_) => drop(permit),
+            }
+
+            match result {
+                Ok(bytes) => {
+                    global_completed_bytes.fetch_add(bytes, Ordering::Relaxed);
+                    global_completed_files.fetch_add(1, Ordering::Relaxed);
+                    Ok(bytes)
+                }
+                Err(e) => {
+                    global_completed_files.fetch_add(1, Ordering::Relaxed);
+                    if cancel.is_cancelled() {
+                        return Err(e.error);
+                    }
+                    if e.transport {
+                        file_errors.fail_run(&file_info.path, e.error.as_ref());
+                        return Err(e.error);
+                    }
+                    warn!("Failed to process file {}: {}", file_info.path.display(), e);
+                    if let Ok(mut failed) = failed_uploads.lock() {
+                        failed.insert(path_encoding::encode_path(&file_info.relative_path));
+                    }
+                    file_errors.record(&file_info.path, e.operation, e.error.as_ref()).await;
+                    Err(e.error)
+                }
+            }
+        }.in_current_span());
+        Ok(true)
+    }
+}
+
 /// Main backup executor
 pub struct BackupExecutor {
     ws_state: Arc<RwLock<WsState>>,
@@ -120,92 +415,75 @@ impl BackupExecutor {
     pub async fn execute(&mut self, job: BackupJob) -> Result<BackupResult, Box<dyn std::error::Error + Send + Sync>> {
         let start_time = std::time::Instant::now();
 
-        info!("Starting backup execution for job: {} (adaptive concurrency, budget: {})", job.job_id, CONCURRENCY_BUDGET);
+        info!("Starting backup execution for job: {}", job.job_id);
 
         // Send backup:started event
         self.broadcast_event(WsEvent::BackupStarted {
             job_id: job.job_id.clone(),
         }).await;
 
-        // Collect all files to backup
-        let mut all_files = Vec::new();
-        let mut total_size = 0u64;
-
+        // Refuse roots the local path policy does not allow, whatever the server asked for
+        let mut roots = Vec::with_capacity(job.paths.len());
         for path in &job.paths {
-            // Check cancellation before scanning
-            if self.cancel_token.is_cancelled() {
-                return Err("Backup cancelled".into());
-            }
-
-            match self.scan_path(path, &mut all_files, &mut total_size).await {
-                Ok(_) => {
-                    info!("Scanned path: {} ({} files, {} bytes)",
-                          path.display(), all_files.len(), total_size);
-                }
-                Err(e) => {
-                    error!("Failed to scan path {}: {}", path.display(), e);
+            match policy::current().check(path) {
+                Ok(root) => roots.push(root),
+                Err(violation) => {
+                    policy::report(&self.ws_state, "backup", Some(&job.job_id), &violation).await;
                     self.broadcast_event(WsEvent::BackupFailed {
                         job_id: job.job_id.clone(),
-                        error: format!("Failed to scan {}: {}", path.display(), e),
+                        error: violation.to_string(),
                     }).await;
-                    return Err(e);
+                    return Err(violation.into());
                 }
             }
         }
 
-        let all_files_count = all_files.len();
-        let all_files_bytes = total_size;
-
-        // Snapshot for manifest generation (needs source mtimes)
-        let all_files_snapshot: Vec<(String, u64, i64)> = all_files.iter().map(|f| {
-            let mtime = std::fs::metadata(&f.path)
-                .ok()
-                .map(|m| m.mtime())
-                .unwrap_or(0);
-            (f.relative_path.to_string_lossy().to_string(), f.size, mtime)
-        }).collect();
-
-        // Incremental diff: compare against previous manifest
-        let (files_to_upload, unchanged_files_count, unchanged_bytes, deleted_count, backup_type) =
-            if job.incremental {
-                match self.try_incremental_diff(&job, all_files.clone()).await {
-                    Some(diff) => {
-                        let uc = diff.unchanged_paths.len();
-                        let ub = diff.unchanged_bytes;
-                        let dc = diff.deleted_count;
-
-                        info!(
-                            "Incremental diff: {} changed, {} unchanged, {} deleted",
-                            diff.changed_files.len(), uc, dc
-                        );
-
-                        // Request hardlinks for unchanged files
-                        if !diff.unchanged_paths.is_empty() {
-                            self.request_hardlinks(&job.server_url, &job.job_id, &diff.unchanged_paths).await;
-                        }
-
-                        (diff.changed_files, uc, ub, dc, "incremental".to_string())
-                    }
-                    None => {
-                        info!("Incremental diff failed, falling back to full backup");
-                        (all_files, 0, 0, 0, "full".to_string())
-                    }
-                }
-            } else {
-                (all_files, 0, 0, 0, "full".to_string())
-            };
-
-        // Sort files smallest-first for optimal concurrency
-        let mut files_to_upload = files_to_upload;
-        files_to_upload.sort_by_key(|f| f.size);
+        let file_errors = Arc::new(FileErrors {
+            job_id: job.job_id.clone(),
+            ws_state: Arc::clone(&self.ws_state),
+            max: job.max_file_errors,
+            cancel_token: self.cancel_token.clone(),
+            count: AtomicUsize::new(0),
+            exceeded: AtomicBool::new(false),
+            transport_error: std::sync::Mutex::new(None),
+        });
 
-        let upload_files_count = files_to_upload.len();
-        let upload_total_size: u64 = files_to_upload.iter().map(|f| f.size).sum();
+        // Incremental: each scanned file is diffed against the previous
+        // manifest, which takes the scan being in manifest order
+        let sorted_scan = !job.labels.is_empty() || roots.len() <= 1;
+        let mut previous = match (job.incremental, &job.manifest_url) {
+            (true, Some(_)) if !sorted_scan => {
+                info!("Paths without labels are not scanned in manifest order, falling back to full backup");
+                None
+            }
+            (true, Some(manifest_url)) => {
+                PreviousFiles::fetch(&job.server_url, manifest_url, job.binary_manifest).await
+            }
+            _ => None,
+        };
+        if job.incremental && sorted_scan && previous.is_none() {
+            info!("No previous manifest, falling back to full backup");
+        }
+        let backup_type = if previous.is_some() { "incremental" } else { "full" }.to_string();
+        // Without a previous manifest every file is uploaded, and nothing is carried forward
+        let mut changes = match previous.as_mut() {
+            Some(previous) if job.carry_forward => {
+                previous.keep_deleted_paths();
+                Some(ChangesRequest { job_id: job.job_id.clone(), ..Default::default() })
+            }
+            _ => None,
+        };
+        let mut changes_error = None;
+        // New files wait for the scan to end, to be matched against deleted ones
+        let mut held = match previous.as_mut() {
+            Some(previous) if job.detect_renames => {
+                previous.keep_deleted_entries();
+                Some(HeldFiles::new())
+            }
+            _ => None,
+        };
 
-        info!(
-            "Total files to backup: {}, to transfer: {}, total size: {} bytes (sorted by size, smallest first)",
-            all_files_count, upload_files_count, upload_total_size
-        );
+        let stats = Arc::new(ScanStats::default());
 
         // Shared counters for completed work
         let completed_bytes = Arc::new(AtomicU64::new(0));
@@ -215,8 +493,9 @@ impl BackupExecutor {
         let active_files: Arc<RwLock<HashMap<usize, Arc<ActiveFileState>>>> =
             Arc::new(RwLock::new(HashMap::new()));
 
-        // Weighted semaphore: total budget = CONCURRENCY_BUDGET permits.
-        let semaphore = Arc::new(Semaphore::new(CONCURRENCY_BUDGET));
+        // Uploads at once, tuned from their throughput and latency
+        let max_concurrency = job.max_concurrency.unwrap_or(concurrency::MAX_CONCURRENCY);
+        let limiter = Limiter::new(max_concurrency);
 
         // Spawn a single progress broadcast task that reads all shared state
         let progress_ws = Arc::clone(&self.ws_state);
@@ -225,16 +504,15 @@ impl BackupExecutor {
         let progress_completed_files = Arc::clone(&completed_files);
         let progress_active_files = Arc::clone(&active_files);
         let progress_cancel = self.cancel_token.clone();
-        let progress_total_bytes = upload_total_size;
-        let progress_total_files = upload_files_count;
-        let progress_skipped_files = unchanged_files_count;
-        let progress_skipped_bytes = unchanged_bytes;
+        let progress_stats = Arc::clone(&stats);
         let progress_backup_type = backup_type.clone();
+        let progress_limiter = Arc::clone(&limiter);
 
         let progress_task = tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(250));
             let mut last_total = 0u64;
             let mut last_time = std::time::Instant::now();
+            let mut last_scan_report: Option<std::time::Instant> = None;
 
             loop {
                 tokio::select! {
@@ -246,6 +524,18 @@ impl BackupExecutor {
                     break;
                 }
 
+                // Totals grow while the scan is still finding files
+                let progress_total_bytes = progress_stats.changed_bytes.load(Ordering::Relaxed);
+                let progress_total_files = progress_stats.changed_files.load(Ordering::Relaxed);
+
+                if !progress_stats.done.load(Ordering::Relaxed)
+                    && last_scan_report.is_none_or(|at| at.elapsed() >= SCAN_PROGRESS_INTERVAL)
+                {
+                    last_scan_report = Some(std::time::Instant::now());
+                    let state = progress_ws.read().await;
+                    state.broadcast(WsEvent::BackupScanProgress(progress_stats.payload(&progress_job_id)));
+                }
+
                 let done_bytes = progress_completed_bytes.load(Ordering::Relaxed);
                 let done_files = progress_completed_files.load(Ordering::Relaxed);
 
@@ -319,9 +609,10 @@ impl BackupExecutor {
                     current_file_total: cf_total,
                     current_file_percent: cf_percent,
                     active_files: file_list,
-                    skipped_files: progress_skipped_files,
-                    skipped_bytes: progress_skipped_bytes,
+                    skipped_files: progress_stats.unchanged_files.load(Ordering::Relaxed),
+                    skipped_bytes: progress_stats.unchanged_bytes.load(Ordering::Relaxed),
                     backup_type: progress_backup_type.clone(),
+                    concurrency: progress_limiter.limit(),
                 };
 
                 let state = progress_ws.read().await;
@@ -329,118 +620,200 @@ impl BackupExecutor {
             }
         });
 
-        // Spawn parallel file upload tasks with adaptive concurrency
-        info!("Starting parallel file processing: {} files, adaptive concurrency (budget: {})", upload_files_count, CONCURRENCY_BUDGET);
-
-        let mut handles = Vec::with_capacity(files_to_upload.len());
-
-        for (idx, file_info) in files_to_upload.into_iter().enumerate() {
-            let sem = Arc::clone(&semaphore);
-            let job_id = job.job_id.clone();
-            let server_url = job.server_url.clone();
-            let global_completed_bytes = Arc::clone(&completed_bytes);
-            let global_completed_files = Arc::clone(&completed_files);
-            let active_map = Arc::clone(&active_files);
-            let cancel = self.cancel_token.clone();
-
-            let handle = tokio::spawn(async move {
-                // Check cancellation before acquiring permit
-                if cancel.is_cancelled() {
-                    return Err::<u64, Box<dyn std::error::Error + Send + Sync>>(
-                        "Cancelled".into()
-                    );
-                }
+        // Scan on blocking threads; each file is diffed and its upload started
+        // as soon as it arrives. A full queue holds the scan back, and waiting
+        // for upload permits below holds back the queue.
+        let (scan_tx, mut scan_rx) = mpsc::channel(SCAN_QUEUE_SIZE);
+        let scan_paths = roots.clone();
+        let scan_labels = job.labels.clone();
+        let scan_span = tracing::Span::current();
+        let scan_task = tokio::task::spawn_blocking(move || {
+            let _entered = scan_span.enter();
+            scan_roots(&scan_paths, &scan_labels, &scan_tx)
+        });
 
-                // Acquire weighted semaphore permits based on file size
-                let weight = concurrency_weight(file_info.size);
-                let permit = tokio::select! {
-                    result = sem.acquire_many(weight) => {
-                        result.map_err(|e| -> Box<dyn std::error::Error + Send + Sync> {
-                            Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Semaphore closed: {}", e)))
-                        })?
-                    }
-                    _ = cancel.cancelled() => {
-                        return Err("Cancelled".into());
-                    }
-                };
+        info!("Scanning and uploading with adaptive concurrency (at most {} uploads)", max_concurrency);
 
-                // Check cancellation after acquiring permit
-                if cancel.is_cancelled() {
-                    drop(permit);
-                    return Err("Cancelled".into());
-                }
+        let mut uploads = JoinSet::new();
+        let mut total_processed = 0usize;
+        let mut scan_complete = false;
+        let mut new_manifest = NewManifest::new(&job, job.binary_manifest && sorted_scan);
+        let mut unchanged_paths: Vec<String> = Vec::new();
 
-                // Register in active files map
-                let file_state = Arc::new(ActiveFileState {
-                    path: file_info.path.display().to_string(),
-                    total_bytes: file_info.size,
-                    transferred: AtomicU64::new(0),
-                });
-                {
-                    let mut map = active_map.write().await;
-                    map.insert(idx, Arc::clone(&file_state));
-                }
+        // Relative paths that could not be uploaded; left out of the manifest
+        // so the next incremental run retries them
+        let failed_uploads: Arc<std::sync::Mutex<HashSet<String>>> = Arc::default();
 
-                info!("Processing file: {}", file_info.path.display());
+        let mut uploader = Uploader {
+            job_id: job.job_id.clone(),
+            server_url: job.server_url.clone(),
+            limiter: Arc::clone(&limiter),
+            completed_bytes: Arc::clone(&completed_bytes),
+            completed_files: Arc::clone(&completed_files),
+            active_files: Arc::clone(&active_files),
+            file_errors: Arc::clone(&file_errors),
+            failed_uploads: Arc::clone(&failed_uploads),
+            cancel: self.cancel_token.clone(),
+            compression: job.compression,
+            next: 0,
+        };
 
-                let result = upload_file(
-                    &job_id,
-                    &server_url,
-                    &file_info,
-                    &file_state,
-                    &cancel,
-                ).await;
+        loop {
+            let item = tokio::select! {
+                item = scan_rx.recv() => item,
+                _ = self.cancel_token.cancelled() => break,
+            };
+            let file_info = match item {
+                Some(ScanItem::File(file)) => file,
+                Some(ScanItem::Error(path, e)) => {
+                    warn!("Cannot read {}: {}", path.display(), e);
+                    file_errors.record(&path, "scan", &e).await;
+                    continue;
+                }
+                Some(ScanItem::Violation(violation)) => {
+                    policy::report(&self.ws_state, "backup", Some(&job.job_id), &violation).await;
+                    continue;
+                }
+                None => {
+                    scan_complete = true;
+                    break;
+                }
+            };
 
-                // Remove from active files map
-                {
-                    let mut map = active_map.write().await;
-                    map.remove(&idx);
+            let rel = path_encoding::encode_path(&file_info.relative_path);
+            let change = match previous.as_mut() {
+                Some(previous) => previous.change(&rel, &file_info).await,
+                None => Change::Added,
+            };
+            let unchanged = change == Change::Unchanged;
+            stats.scanned(&file_info, unchanged);
+            new_manifest.add(&rel, &file_info);
+
+            if let (Some(changes), Some(previous)) = (changes.as_mut(), previous.as_mut()) {
+                changes.deleted.extend(previous.take_deleted());
+                if !unchanged {
+                    changes.changed.push(rel.clone());
+                }
+                if changes.changed.len() + changes.deleted.len() >= CHANGES_BATCH_SIZE {
+                    if let Err(e) = self.report_changes(&job.server_url, changes).await {
+                        changes_error = Some(e);
+                        break;
+                    }
                 }
+            } else if unchanged {
+                unchanged_paths.push(rel);
+                if unchanged_paths.len() >= HARDLINK_BATCH_SIZE {
+                    self.request_hardlinks(&job.server_url, &job.job_id, &std::mem::take(&mut unchanged_paths)).await;
+                }
+                continue;
+            }
+            if unchanged {
+                continue;
+            }
+            let file_info = match held.as_mut() {
+                Some(held) if change == Change::Added => match held.hold(rel, file_info) {
+                    Some(file_info) => file_info,
+                    None => continue,
+                },
+                _ => file_info,
+            };
 
-                // Drop permit to allow next task
-                drop(permit);
+            if !uploader.start(&mut uploads, file_info).await? {
+                break;
+            }
 
-                match result {
-                    Ok(bytes) => {
-                        global_completed_bytes.fetch_add(bytes, Ordering::Relaxed);
-                        global_completed_files.fetch_add(1, Ordering::Relaxed);
-                        Ok(bytes)
-                    }
-                    Err(e) => {
-                        warn!("Failed to process file {}: {}", file_info.path.display(), e);
-                        global_completed_files.fetch_add(1, Ordering::Relaxed);
-                        Err(e)
-                    }
+            // Reap finished uploads so the set only holds those in flight
+            while let Some(result) = uploads.try_join_next() {
+                if self.upload_succeeded(result) {
+                    total_processed += 1;
                 }
-            });
+            }
+        }
 
-            handles.push(handle);
+        // Stops the scan threads if the loop ended early
+        drop(scan_rx);
+        let mut scan_result = match scan_task.await {
+            Ok(result) => result.map_err(|(path, e)| format!("Failed to scan {}: {}", path.display(), e)),
+            Err(e) => Err(format!("Scan task failed: {}", e)),
         }
+        .and_then(|()| changes_error.map_or(Ok(()), Err));
+        let scan_complete = scan_complete && scan_result.is_ok();
 
-        // Wait for all tasks to complete
-        let mut total_processed = 0usize;
-        for handle in handles {
-            match handle.await {
-                Ok(Ok(_bytes)) => {
-                    total_processed += 1;
+        let deleted_count = match previous.as_mut() {
+            Some(previous) if scan_complete => previous.finish().await,
+            _ => 0,
+        };
+        if let (Some(mut changes), true) = (changes.take(), scan_complete) {
+            if let Some(previous) = previous.as_mut() {
+                changes.deleted.extend(previous.take_deleted());
+            }
+            changes.done = true;
+            changes.unchanged_files = Some(stats.unchanged_files.load(Ordering::Relaxed) as u64);
+            if let Err(e) = self.report_changes(&job.server_url, &mut changes).await {
+                scan_result = Err(e);
+            }
+        }
+
+        if let Err(error) = scan_result {
+            error!("{}", error);
+            self.cancel_token.cancel();
+            uploads.shutdown().await;
+            let _ = progress_task.await;
+            self.broadcast_event(WsEvent::BackupFailed { job_id: job.job_id.clone(), error: error.clone() }).await;
+            return Err(error.into());
+        }
+
+        // Moved files are linked by the server; the other held files are uploaded
+        let mut moved_files = 0;
+        if let (Some(held), Some(previous), true) = (held.take(), previous.as_mut(), scan_complete) {
+            let (moved, mut upload) = held.matches(previous.take_deleted_entries()).await;
+            if !moved.is_empty() {
+                let (linked, rejected) = renames::send(&job.server_url, &job.job_id, moved).await;
+                for file in &linked {
+                    stats.moved(file);
                 }
-                Ok(Err(e)) => {
-                    if self.cancel_token.is_cancelled() {
-                        info!("Task cancelled: {}", e);
-                    } else {
-                        warn!("File upload task failed: {}", e);
-                    }
+                moved_files = linked.len();
+                upload.extend(rejected);
+            }
+            for file_info in upload {
+                if !uploader.start(&mut uploads, file_info).await? {
+                    break;
                 }
-                Err(e) => {
-                    if e.is_cancelled() {
-                        info!("Task was aborted");
-                    } else {
-                        warn!("File upload task panicked: {}", e);
+                while let Some(result) = uploads.try_join_next() {
+                    if self.upload_succeeded(result) {
+                        total_processed += 1;
                     }
                 }
             }
         }
 
+        stats.done.store(true, Ordering::Relaxed);
+        let all_files_count = stats.scanned_files.load(Ordering::Relaxed);
+        let all_files_bytes = stats.scanned_bytes.load(Ordering::Relaxed);
+        let upload_files_count = stats.changed_files.load(Ordering::Relaxed);
+        let upload_total_size = stats.changed_bytes.load(Ordering::Relaxed);
+        let unchanged_files_count = stats.unchanged_files.load(Ordering::Relaxed);
+        let unchanged_bytes = stats.unchanged_bytes.load(Ordering::Relaxed);
+
+        if scan_complete {
+            if !unchanged_paths.is_empty() {
+                self.request_hardlinks(&job.server_url, &job.job_id, &unchanged_paths).await;
+            }
+            self.broadcast_event(WsEvent::BackupScanCompleted(stats.payload(&job.job_id))).await;
+            info!(
+                "Scan completed: {} files, {} bytes; {} changed ({} bytes), {} unchanged ({} moved), {} deleted",
+                all_files_count, all_files_bytes, upload_files_count, upload_total_size, unchanged_files_count, moved_files,
+                deleted_count
+            );
+        }
+
+        // Wait for the remaining uploads
+        while let Some(result) = uploads.join_next().await {
+            if self.upload_succeeded(result) {
+                total_processed += 1;
+            }
+        }
+
         // Check if cancellation was requested by the user (before we cancel the progress task)
         let was_cancelled_by_user = self.cancel_token.is_cancelled();
 
@@ -455,8 +828,16 @@ impl BackupExecutor {
         let duration = start_time.elapsed();
         let duration_secs = duration.as_secs();
 
+        // An upload failed on the server or the network, or too many files
+        // failed: the run was stopped, not cancelled by the user
+        if let Some(error) = file_errors.run_error() {
+            info!("Backup stopped: {} files processed out of {}", total_processed, upload_files_count);
+            self.broadcast_event(WsEvent::BackupFailed { job_id: job.job_id.clone(), error: error.clone() }).await;
+            return Err(error.into());
+        }
+
         // Check if we were cancelled by the user
-        if was_cancelled_by_user && total_processed < upload_files_count {
+        if was_cancelled_by_user && (!scan_complete || total_processed < upload_files_count) {
             info!("Backup cancelled: {} files processed out of {}", total_processed, upload_files_count);
 
             self.broadcast_event(WsEvent::BackupFailed {
@@ -492,68 +873,76 @@ impl BackupExecutor {
                 skipped_files: unchanged_files_count,
                 skipped_bytes: unchanged_bytes,
                 backup_type: backup_type.clone(),
+                concurrency: 0,
             };
             let state = self.ws_state.read().await;
             state.broadcast(WsEvent::BackupProgress(payload));
         }
 
         // Upload manifest with source mtimes for future incremental backups
-        if let Err(e) = upload_manifest(&job.server_url, &job.job_id, &all_files_snapshot).await {
+        let failed = failed_uploads.lock().map(|mut failed| std::mem::take(&mut *failed)).unwrap_or_default();
+        if let Err(e) = new_manifest.upload(&job, failed).await {
             warn!("Failed to upload manifest: {}", e);
         }
 
         // Send backup:completed event with full stats
-        self.broadcast_event(WsEvent::BackupCompleted {
+        self.broadcast_event(WsEvent::BackupCompleted(BackupCompletedPayload {
             job_id: job.job_id.clone(),
             total_bytes: all_files_bytes,
             total_files: all_files_count,
             transferred_bytes: final_transferred,
-            transferred_files: final_files,
+            transferred_files: total_processed,
             unchanged_files: unchanged_files_count,
             unchanged_bytes: unchanged_bytes,
             deleted_files: deleted_count,
             backup_type: backup_type.clone(),
-        }).await;
+            failed_files: file_errors.count(),
+        })).await;
 
         Ok(BackupResult {
             total_files: all_files_count,
             total_bytes: all_files_bytes,
-            transferred_files: final_files,
+            transferred_files: total_processed,
             transferred_bytes: final_transferred,
             unchanged_files: unchanged_files_count,
             unchanged_bytes: unchanged_bytes,
             deleted_files: deleted_count,
+            failed_files: file_errors.count(),
             backup_type,
             duration_secs,
         })
     }
 
-    /// Attempt incremental diff against previous manifest.
-    /// Returns None if manifest fetch fails (caller should fall back to full backup).
-    async fn try_incremental_diff(
-        &self,
-        job: &BackupJob,
-        all_files: Vec<FileInfo>,
-    ) -> Option<DiffResult> {
-        let manifest_url = job.manifest_url.as_ref()?;
-        let manifest = fetch_manifest(&job.server_url, manifest_url).await?;
-
-        // Diff in a blocking task (filesystem metadata reads)
-        let result = tokio::task::spawn_blocking(move || {
-            diff_files_against_manifest(all_files, &manifest)
-        }).await.ok()?;
-
-        Some(result)
+    /// Log how an upload task ended; true if the file was uploaded.
+    fn upload_succeeded(&self, result: Result<Result<u64, Box<dyn std::error::Error + Send + Sync>>, tokio::task::JoinError>) -> bool {
+        match result {
+            Ok(Ok(_bytes)) => true,
+            Ok(Err(e)) => {
+                if self.cancel_token.is_cancelled() {
+                    info!("Task cancelled: {}", e);
+                } else {
+                    warn!("File upload task failed: {}", e);
+                }
+                false
+            }
+            Err(e) => {
+                if e.is_cancelled() {
+                    info!("Task was aborted");
+                } else {
+                    warn!("File upload task panicked: {}", e);
+                }
+                false
+            }
+        }
     }
 
     /// Send a hardlink request to the server for unchanged files.
     async fn request_hardlinks(&self, server_url: &str, job_id: &str, unchanged_paths: &[String]) {
         let url = format!("{}/api/files/hardlink", server_url);
-        let client = reqwest::Client::new();
+        let client = crate::tls::http_client();
 
         // Send in batches to avoid oversized requests
-        const BATCH_SIZE: usize = 5000;
-        for chunk in unchanged_paths.chunks(BATCH_SIZE) {
+        for chunk in unchanged_paths.chunks(HARDLINK_BATCH_SIZE) {
             let body = serde_json::json!({
                 "job_id": job_id,
                 "files": chunk,
@@ -578,37 +967,38 @@ impl BackupExecutor {
         }
     }
 
-    /// Scan a source path and collect all files
-    async fn scan_path(
-        &self,
-        path: &Path,
-        all_files: &mut Vec<FileInfo>,
-        total_size: &mut u64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let walk_options = WalkOptions {
-            follow_links: false,
-            max_depth: None,
-            exclude_patterns: vec![
-                ".git".to_string(),
-                "node_modules".to_string(),
-                ".DS_Store".to_string(),
-            ],
-        };
-
-        // Use blocking task for CPU-intensive directory walk
-        let path_owned = path.to_path_buf();
-        let files = tokio::task::spawn_blocking(move || {
-            walk_directory(&path_owned, walk_options)
-        }).await??;
+    /// Send the changed and deleted paths gathered so far to the server, in
+    /// batches; `done` goes with the last one. Unchanged files of the previous
+    /// version that are not reported would be carried into the new one, so
+    /// failing to report is fatal.
+    async fn report_changes(&self, server_url: &str, changes: &mut ChangesRequest) -> Result<(), String> {
+        let url = format!("{}/api/files/changes", server_url);
+        let client = crate::tls::http_client();
+
+        loop {
+            let changed_batch = changes.changed.len().min(CHANGES_BATCH_SIZE);
+            let deleted_batch = changes.deleted.len().min(CHANGES_BATCH_SIZE - changed_batch);
+            let last = changed_batch == changes.changed.len() && deleted_batch == changes.deleted.len();
+            let batch = ChangesRequest {
+                job_id: changes.job_id.clone(),
+                changed: changes.changed.drain(..changed_batch).collect(),
+                deleted: changes.deleted.drain(..deleted_batch).collect(),
+                done: last && changes.done,
+                unchanged_files: if last { changes.unchanged_files } else { None },
+            };
 
-        for file in files {
-            if !file.is_dir {
-                *total_size += file.size;
-                all_files.push(file);
+            match client.post(&url).json(&batch).send().await {
+                Ok(resp) if resp.status().is_success() => {}
+                Ok(resp) => return Err(format!("Reporting changes failed with status {}", resp.status())),
+                Err(e) => return Err(format!("Reporting changes failed: {}", e)),
+            }
+            if last {
+                if batch.done {
+                    info!("Reported changes; {} scanned files were unchanged", batch.unchanged_files.unwrap_or(0));
+                }
+                return Ok(());
             }
         }
-
-        Ok(())
     }
 
     /// Broadcast an event to all WebSocket clients
@@ -618,153 +1008,145 @@ impl BackupExecutor {
     }
 }
 
-/// Fetch the previous backup manifest from the server.
-/// Returns None on any error (caller should fall back to full backup).
-async fn fetch_manifest(server_url: &str, manifest_url: &str) -> Option<Manifest> {
-    let url = format!("{}{}", server_url, manifest_url);
-    let client = reqwest::Client::new();
-
-    match client.get(&url).send().await {
-        Ok(resp) if resp.status().is_success() => {
-            match resp.json::<Manifest>().await {
-                Ok(manifest) => {
-                    info!("Fetched manifest: {} files, {} bytes", manifest.total_files, manifest.total_bytes);
-                    Some(manifest)
-                }
-                Err(e) => {
-                    warn!("Failed to parse manifest: {}", e);
-                    None
-                }
-            }
-        }
-        Ok(resp) => {
-            info!("No manifest available (status {}), will do full backup", resp.status());
-            None
-        }
-        Err(e) => {
-            warn!("Failed to fetch manifest: {}", e);
-            None
-        }
+/// Where a file of the root labelled `label` is stored in the version. A root
+/// that is a single file has an empty relative path and keeps its name.
+fn labelled_path(label: &str, root: &Path, relative: &Path) -> PathBuf {
+    let label = Path::new(label);
+    match root.file_name() {
+        Some(name) if relative.as_os_str().is_empty() => label.join(name),
+        _ => label.join(relative),
     }
 }
 
-/// Compare scanned files against a manifest to determine what changed.
-/// Uses size + mtime as the change detection heuristic (same as rsync default).
-fn diff_files_against_manifest(all_files: Vec<FileInfo>, manifest: &Manifest) -> DiffResult {
-    let mut changed_files = Vec::new();
-    let mut changed_bytes = 0u64;
-    let mut unchanged_paths = Vec::new();
-    let mut unchanged_bytes = 0u64;
-    let mut seen_paths = HashSet::new();
-
-    for file in all_files {
-        let rel = file.relative_path.to_string_lossy().to_string();
-        seen_paths.insert(rel.clone());
-
-        if let Some(entry) = manifest.files.get(&rel) {
-            // Check if file is unchanged (same size and mtime)
-            let mtime = std::fs::metadata(&file.path)
-                .ok()
-                .map(|m| m.mtime())
-                .unwrap_or(0);
-
-            if entry.size == file.size && entry.mtime == mtime {
-                unchanged_paths.push(rel);
-                unchanged_bytes += file.size;
-                continue;
-            }
-        }
-
-        changed_bytes += file.size;
-        changed_files.push(file);
-    }
+/// Walk every root in turn, passing what is found to the executor through
+/// `tx`. Labelled roots are walked in label order, so the files of the whole
+/// version come in manifest order. Denied subtrees are skipped; symlinks are
+/// checked where they lead.
+/// Stops early once the executor stops receiving. Only an unreadable root is
+/// an error.
+fn scan_roots(
+    roots: &[PathBuf],
+    labels: &[String],
+    tx: &mpsc::Sender<ScanItem>,
+) -> Result<(), (PathBuf, std::io::Error)> {
+    let policy = policy::current();
+    let walk_options = WalkOptions {
+        follow_links: false,
+        max_depth: None,
+        exclude_patterns: vec![
+            ".git".to_string(),
+            "node_modules".to_string(),
+            ".DS_Store".to_string(),
+        ],
+    };
 
-    // Count deleted files (in manifest but not on filesystem)
-    let deleted_count = manifest.files.keys()
-        .filter(|k| !seen_paths.contains(*k))
-        .count();
+    let mut order: Vec<usize> = (0..roots.len()).collect();
+    let label = |index: usize| labels.get(index).map_or("", String::as_str);
+    order.sort_by(|&a, &b| path_encoding::compare(label(a), label(b)));
+
+    for index in order {
+        let root = &roots[index];
+        let label = labels.get(index).map(String::as_str);
+        let violations = AtomicUsize::new(0);
+        walk_directory_parallel(
+            root,
+            &walk_options,
+            SCAN_THREADS,
+            |path, is_symlink| {
+                let checked = if is_symlink {
+                    policy.check(path).map(|_| ())
+                } else {
+                    policy.check_resolved(path)
+                };
+                checked.map_err(|violation| {
+                    if violations.fetch_add(1, Ordering::Relaxed) < MAX_REPORTED_VIOLATIONS {
+                        let _ = tx.blocking_send(ScanItem::Violation(violation));
+                    }
+                }).is_ok()
+            },
+            |item| {
+                let item = match item {
+                    WalkItem::File(mut file) => {
+                        if let Some(label) = label {
+                            file.relative_path = labelled_path(label, root, &file.relative_path);
+                        }
+                        ScanItem::File(file)
+                    }
+                    WalkItem::Error(path, e) => ScanItem::Error(path, e),
+                };
+                tx.blocking_send(item).is_ok()
+            },
+        ).map_err(|e| (root.clone(), e))?;
 
-    DiffResult {
-        changed_files,
-        _changed_bytes: changed_bytes,
-        unchanged_paths,
-        unchanged_bytes,
-        deleted_count,
+        let violations = violations.into_inner();
+        if violations > 0 {
+            warn!("Skipped {} path(s) under {} denied by the agent path policy", violations, root.display());
+        }
+        if tx.is_closed() {
+            break;
+        }
+        info!("Scanned path: {}", root.display());
     }
-}
-
-/// Upload a manifest file containing source file metadata (size + mtime from agent).
-/// This is uploaded as `.backup-manifest.json` via the normal upload route.
-async fn upload_manifest(
-    server_url: &str,
-    job_id: &str,
-    files: &[(String, u64, i64)],
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut file_entries = HashMap::new();
-    let mut total_files = 0usize;
-    let mut total_bytes = 0u64;
-
-    for (rel_path, size, mtime) in files {
-        file_entries.insert(rel_path.clone(), serde_json::json!({
-            "size": size,
-            "mtime": mtime,
-        }));
-        total_files += 1;
-        total_bytes += size;
-    }
-
-    let manifest = serde_json::json!({
-        "version": 1,
-        "job_id": job_id,
-        "files": file_entries,
-        "total_files": total_files,
-        "total_bytes": total_bytes,
-    });
 
-    let manifest_json = serde_json::to_string(&manifest)?;
-    let upload_url = format!("{}/api/files/upload", server_url);
+    Ok(())
+}
 
-    let client = reqwest::Client::new();
-    let resp = client.post(&upload_url)
-        .header("x-job-id", job_id)
-        .header("x-relative-path", ".backup-manifest.json")
-        .header("x-total-size", manifest_json.len().to_string())
-        .header("content-type", "application/octet-stream")
-        .body(manifest_json)
-        .send()
-        .await?;
-
-    if resp.status().is_success() {
-        info!("Uploaded manifest: {} files, {} bytes", total_files, total_bytes);
-    } else {
-        warn!("Manifest upload failed with status {}", resp.status());
+/// How to compress a file of `size` for a job compressed with `job`. Servers
+/// that do not set compression per job only decode zstd.
+fn upload_compression(job: Option<Compression>, size: u64) -> Compression {
+    match job {
+        Some(compression) => compression,
+        None => {
+            let configured = compression::configured();
+            if configured.algorithm == Algorithm::Zstd && size < MAX_COMPRESS_SIZE {
+                configured
+            } else {
+                Compression { algorithm: Algorithm::None, level: None }
+            }
+        }
     }
-
-    Ok(())
 }
 
-/// Upload a single file to the backup server
+/// Upload a single file to the backup server, compressed with `compression`
+/// unless it would not shrink
 async fn upload_file(
     job_id: &str,
     server_url: &str,
     file_info: &FileInfo,
+    compression: Compression,
     file_state: &Arc<ActiveFileState>,
     cancel: &CancellationToken,
-) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<u64, FileFailure> {
+    use tokio::io::AsyncReadExt;
+
     // Open the file for reading
-    let file = match tokio::fs::File::open(&file_info.path).await {
+    let mut file = match tokio::fs::File::open(&file_info.path).await {
         Ok(f) => f,
         Err(e) => {
             error!("Failed to open file {}: {}", file_info.path.display(), e);
-            return Err(Box::new(e));
+            return Err(FileFailure::new("open", e));
         }
     };
 
-    // Only compress files smaller than 500 MB to avoid memory issues
-    const MAX_COMPRESS_SIZE: u64 = 500 * 1024 * 1024;
-    let use_compression = file_info.size < MAX_COMPRESS_SIZE;
+    // Files of compressed formats are sent as they are; others are sampled,
+    // and sent as they are if the sample looks compressed
+    let mut sample = Vec::new();
+    let compression = if compression.algorithm == Algorithm::None || compression::compressed_by_name(&file_info.path) {
+        Compression { algorithm: Algorithm::None, level: None }
+    } else {
+        (&mut file)
+            .take(compression::SAMPLE_SIZE as u64)
+            .read_to_end(&mut sample)
+            .await
+            .map_err(|e| FileFailure::new("read", e))?;
+        if compression::looks_compressed(&sample) {
+            Compression { algorithm: Algorithm::None, level: None }
+        } else {
+            compression
+        }
+    };
 
-    let client = reqwest::Client::new();
+    let client = crate::tls::http_client();
     let upload_url = format!("{}/api/files/upload", server_url);
 
     // Progress callback updates the shared atomic
@@ -773,45 +1155,34 @@ async fn upload_file(
         file_state_clone.transferred.store(bytes, Ordering::Relaxed);
     });
 
-    // Build the request (with or without compression)
-    let request_future = if use_compression {
-        use async_compression::tokio::bufread::ZstdEncoder;
-        use tokio::io::BufReader;
-
-        let buf_reader = BufReader::new(file);
-        let compressed = ZstdEncoder::with_quality(buf_reader, async_compression::Level::Default);
-        let stream = ReaderStream::new(compressed);
-        let progress_stream = ProgressStream::new(stream, progress_callback);
-        let body = reqwest::Body::wrap_stream(progress_stream);
-
-        client
-            .post(&upload_url)
-            .header("x-job-id", job_id)
-            .header("x-relative-path", file_info.relative_path.display().to_string())
-            .header("x-total-size", file_info.size.to_string())
-            .header("content-encoding", "zstd")
-            .body(body)
-            .send()
-    } else {
-        let stream = ReaderStream::new(file);
-        let progress_stream = ProgressStream::new(stream, progress_callback);
-        let body = reqwest::Body::wrap_stream(progress_stream);
-
-        client
-            .post(&upload_url)
-            .header("x-job-id", job_id)
-            .header("x-relative-path", file_info.relative_path.display().to_string())
-            .header("x-total-size", file_info.size.to_string())
-            .body(body)
-            .send()
-    };
+    // The sample is sent ahead of the rest of the file. Errors of the body
+    // stream come from reading the file, any other from sending it
+    let reader = tokio::io::BufReader::new(std::io::Cursor::new(sample).chain(file));
+    let read_failed = Arc::new(AtomicBool::new(false));
+    let read_failed_clone = Arc::clone(&read_failed);
+    let stream = ReaderStream::new(compression::encode(reader, compression)).map(move |chunk| {
+        if chunk.is_err() {
+            read_failed_clone.store(true, Ordering::Relaxed);
+        }
+        chunk
+    });
+    let progress_stream = ProgressStream::new(stream, progress_callback);
+    let mut request = client
+        .post(&upload_url)
+        .header(HEADER_JOB_ID, job_id)
+        .header(HEADER_RELATIVE_PATH, path_encoding::encode_path(&file_info.relative_path))
+        .header(HEADER_TOTAL_SIZE, file_info.size.to_string());
+    if let Some(encoding) = compression.algorithm.content_encoding() {
+        request = request.header(reqwest::header::CONTENT_ENCODING, encoding);
+    }
+    let request_future = request.body(reqwest::Body::wrap_stream(progress_stream)).send();
 
     // Execute with cancellation support
     let response = tokio::select! {
         result = request_future => result,
         _ = cancel.cancelled() => {
             info!("Upload cancelled for {}", file_info.path.display());
-            return Err("Cancelled".into());
+            return Err(FileFailure::new("upload", "Cancelled"));
         }
     };
 
@@ -836,7 +1207,14 @@ async fn upload_file(
                 file_info.path.display(),
                 error_text
             );
-            Err(format!("Upload failed: {} - {}", status, error_text).into())
+            let error = format!("Upload failed: {} - {}", status, error_text);
+            // The server rejected this file (its size changed while it was
+            // read, its name is invalid); other statuses would fail any file
+            if status == reqwest::StatusCode::BAD_REQUEST {
+                Err(FileFailure::new("upload", error))
+            } else {
+                Err(FileFailure::transport(error))
+            }
         }
         Err(e) => {
             error!(
@@ -844,7 +1222,11 @@ async fn upload_file(
                 file_info.path.display(),
                 e
             );
-            Err(Box::new(e))
+            if read_failed.load(Ordering::Relaxed) {
+                Err(FileFailure::new("read", e))
+            } else {
+                Err(FileFailure::transport(e))
+            }
         }
     }
 }
@@ -852,16 +1234,24 @@ async fn upload_file(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::MetadataExt;
 
     #[test]
     fn test_backup_job_creation() {
         let job = BackupJob {
             job_id: "test-job".to_string(),
             paths: vec![PathBuf::from("/tmp")],
+            labels: Vec::new(),
             destination: PathBuf::from("/backup"),
             server_url: "http://localhost:3000".to_string(),
             incremental: false,
             manifest_url: None,
+            binary_manifest: false,
+            carry_forward: false,
+            detect_renames: false,
+            compression: None,
+            max_concurrency: None,
+            max_file_errors: None,
         };
 
         assert_eq!(job.job_id, "test-job");
@@ -869,68 +1259,245 @@ mod tests {
     }
 
     #[test]
-    fn test_concurrency_weight() {
-        assert_eq!(concurrency_weight(0), 1);              // 0 bytes → 1 permit (64 concurrent)
-        assert_eq!(concurrency_weight(512_000), 1);        // 500 KB → 1 permit (64 concurrent)
-        assert_eq!(concurrency_weight(1_048_576), 1);      // 1 MB → 1 permit (64 concurrent)
-        assert_eq!(concurrency_weight(5_000_000), 1);      // 5 MB → 1 permit (64 concurrent)
-        assert_eq!(concurrency_weight(50_000_000), 2);     // 50 MB → 2 permits (32 concurrent)
-        assert_eq!(concurrency_weight(200_000_000), 16);   // 200 MB → 16 permits (4 concurrent)
-        assert_eq!(concurrency_weight(800_000_000), 32);   // 800 MB → 32 permits (2 concurrent)
-        assert_eq!(concurrency_weight(2_000_000_000), 64); // 2 GB → 64 permits (1 concurrent)
+    fn test_os_error_is_found_in_source_chain() {
+        let io = std::io::Error::from_raw_os_error(13);
+        let failure = FileFailure::new("open", io);
+        assert_eq!(os_error(failure.error.as_ref()).and_then(|e| e.raw_os_error()), Some(13));
+
+        let failure = FileFailure::new("upload", "Upload failed: 500");
+        assert!(os_error(failure.error.as_ref()).is_none());
     }
 
-    #[test]
-    fn test_diff_files_against_manifest() {
-        let mut files_map = HashMap::new();
-        files_map.insert("file1.txt".to_string(), ManifestEntry { size: 100, mtime: 1000 });
-        files_map.insert("file2.txt".to_string(), ManifestEntry { size: 200, mtime: 2000 });
-        files_map.insert("deleted.txt".to_string(), ManifestEntry { size: 50, mtime: 500 });
-
-        let manifest = Manifest {
-            version: 1,
-            job_id: "test".to_string(),
-            files: files_map,
-            total_files: 3,
-            total_bytes: 350,
+    #[tokio::test]
+    async fn test_file_error_limit_stops_run() {
+        let ws_state = Arc::new(RwLock::new(WsState::new()));
+        let mut rx = ws_state.read().await.tx.subscribe();
+        let errors = FileErrors {
+            job_id: "j1".into(),
+            ws_state,
+            max: Some(1),
+            cancel_token: CancellationToken::new(),
+            count: AtomicUsize::new(0),
+            exceeded: AtomicBool::new(false),
+            transport_error: std::sync::Mutex::new(None),
         };
 
-        // file1.txt unchanged, file2.txt modified (different size), new_file.txt is new, deleted.txt is gone
-        // Note: we can't easily test mtime matching without real files, so this tests the structure
-        let all_files = vec![
-            FileInfo {
-                path: PathBuf::from("/data/file1.txt"),
-                relative_path: PathBuf::from("file1.txt"),
-                size: 100,
-                is_dir: false,
-                is_symlink: false,
-                depth: 0,
-            },
-            FileInfo {
-                path: PathBuf::from("/data/file2.txt"),
-                relative_path: PathBuf::from("file2.txt"),
-                size: 250, // different size
-                is_dir: false,
-                is_symlink: false,
-                depth: 0,
-            },
-            FileInfo {
-                path: PathBuf::from("/data/new_file.txt"),
-                relative_path: PathBuf::from("new_file.txt"),
-                size: 300,
-                is_dir: false,
-                is_symlink: false,
-                depth: 0,
-            },
+        let gone = std::io::Error::from_raw_os_error(2);
+        errors.record(Path::new("/srv/a"), "open", &gone).await;
+        assert!(errors.run_error().is_none());
+        assert!(!errors.cancel_token.is_cancelled());
+
+        errors.record(Path::new("/srv/b"), "open", &gone).await;
+        assert_eq!(errors.count(), 2);
+        assert!(errors.run_error().unwrap().contains("more than 1 files"));
+        assert!(errors.cancel_token.is_cancelled());
+
+        let WsEvent::BackupFileError(first) = rx.recv().await.unwrap() else { panic!("not a file error") };
+        assert_eq!((first.path.as_str(), first.operation.as_str(), first.errno), ("/srv/a", "open", Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_run_fails_when_every_upload_fails() {
+        let source = tempfile::TempDir::new().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(source.path().join(name), name).unwrap();
+        }
+        // Nothing listens on the port any more: every upload fails to connect
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let job = BackupJob {
+            job_id: "j1".to_string(),
+            paths: vec![source.path().to_path_buf()],
+            labels: Vec::new(),
+            destination: PathBuf::from("/backup"),
+            server_url: format!("http://127.0.0.1:{}", port),
+            incremental: false,
+            manifest_url: None,
+            binary_manifest: false,
+            carry_forward: false,
+            detect_renames: false,
+            compression: None,
+            max_concurrency: None,
+            max_file_errors: None,
+        };
+        let ws_state = Arc::new(RwLock::new(WsState::new()));
+        let mut rx = ws_state.read().await.tx.subscribe();
+
+        let error = BackupExecutor::new(Arc::clone(&ws_state)).execute(job).await.unwrap_err();
+        assert!(error.to_string().starts_with("Uploading"), "{}", error);
+
+        // The run fails instead of completing with a warning per file
+        let mut failed = false;
+        loop {
+            match rx.try_recv() {
+                Ok(WsEvent::BackupFailed { error, .. }) => failed = error.starts_with("Uploading"),
+                Ok(WsEvent::BackupFileError(e)) => panic!("reported as a file error: {:?}", e),
+                Ok(WsEvent::BackupCompleted(_)) => panic!("run completed"),
+                Ok(_) | Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        assert!(failed);
+    }
+
+    #[test]
+    fn test_servers_without_compression_settings_get_zstd_or_nothing() {
+        let lz4 = Compression { algorithm: Algorithm::Lz4, level: None };
+        assert_eq!(upload_compression(Some(lz4), 2 * MAX_COMPRESS_SIZE), lz4);
+        assert_eq!(upload_compression(None, 1).algorithm, Algorithm::Zstd);
+        assert_eq!(upload_compression(None, MAX_COMPRESS_SIZE).algorithm, Algorithm::None);
+    }
+
+    fn file(name: &str, size: u64, mtime: i64) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from("/data").join(name),
+            relative_path: PathBuf::from(name),
+            size,
+            is_dir: false,
+            is_symlink: false,
+            depth: 1,
+            mtime,
+            dev: 0,
+            ino: 0,
+        }
+    }
+
+    fn entry(size: u64, mtime: i64) -> backup_protocol::manifest::ManifestEntry {
+        backup_protocol::manifest::ManifestEntry { size, mtime, id: None }
+    }
+
+    #[tokio::test]
+    async fn test_diff_files_against_manifest() {
+        let mut previous = PreviousFiles::from_sorted(vec![
+            ("deleted.txt".to_string(), entry(50, 500)),
+            ("file1.txt".to_string(), entry(100, 1000)),
+            ("file2.txt".to_string(), entry(200, 2000)),
+            ("file3.txt".to_string(), entry(300, 3000)),
+            ("zz.txt".to_string(), entry(1, 1)),
+        ]).await;
+        previous.keep_deleted_paths();
+        previous.keep_deleted_entries();
+
+        // file1.txt unchanged, file2.txt resized, file3.txt touched, new_file.txt is new,
+        // deleted.txt and zz.txt are gone
+        let scanned = [
+            (file("file1.txt", 100, 1000), Change::Unchanged),
+            (file("file2.txt", 250, 2000), Change::Modified),
+            (file("file3.txt", 300, 3001), Change::Modified),
+            (file("new_file.txt", 300, 4000), Change::Added),
         ];
+        for (file, change) in &scanned {
+            let rel = path_encoding::encode_path(&file.relative_path);
+            assert_eq!(previous.change(&rel, file).await, *change, "{}", rel);
+        }
+        assert_eq!(previous.take_deleted(), vec!["deleted.txt"]);
+        assert_eq!(previous.finish().await, 2);
+        assert_eq!(previous.take_deleted(), vec!["zz.txt"]);
+        assert_eq!(previous.take_deleted_entries(), vec![
+            ("deleted.txt".to_string(), entry(50, 500)),
+            ("zz.txt".to_string(), entry(1, 1)),
+        ]);
+    }
 
-        let result = diff_files_against_manifest(all_files, &manifest);
+    async fn scan(roots: Vec<PathBuf>, labels: Vec<String>) -> (Vec<FileInfo>, usize) {
+        let (tx, mut rx) = mpsc::channel(4);
+        let task = tokio::task::spawn_blocking(move || scan_roots(&roots, &labels, &tx));
+        let mut files = Vec::new();
+        let mut errors = 0;
+        while let Some(item) = rx.recv().await {
+            match item {
+                ScanItem::File(file) => files.push(file),
+                ScanItem::Error(..) => errors += 1,
+                ScanItem::Violation(_) => {}
+            }
+        }
+        task.await.unwrap().unwrap();
+        (files, errors)
+    }
+
+    #[tokio::test]
+    async fn test_scan_stores_roots_under_their_labels() {
+        let etc = tempfile::TempDir::new().unwrap();
+        let opt = tempfile::TempDir::new().unwrap();
+        std::fs::write(etc.path().join("config.yml"), "a").unwrap();
+        std::fs::write(opt.path().join("config.yml"), "bb").unwrap();
+        let single = opt.path().join("app.conf");
+        std::fs::write(&single, "ccc").unwrap();
+
+        let roots = vec![etc.path().to_path_buf(), single, opt.path().to_path_buf()];
+        let labels = vec!["etc_app".to_string(), "single".to_string(), "opt_app".to_string()];
+        let (files, errors) = scan(roots, labels).await;
+
+        // In manifest order, whatever the order of the roots
+        let paths: Vec<_> = files.iter().map(|f| f.relative_path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["etc_app/config.yml", "opt_app/app.conf", "opt_app/config.yml", "single/app.conf"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[tokio::test]
+    async fn test_scan_streams_through_a_small_queue_and_stops_with_the_receiver() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for d in 0..10 {
+            let sub = dir.path().join(format!("d{}", d));
+            std::fs::create_dir(&sub).unwrap();
+            for f in 0..50 {
+                std::fs::write(sub.join(format!("f{}", f)), "x").unwrap();
+            }
+        }
+
+        let (files, _) = scan(vec![dir.path().to_path_buf()], Vec::new()).await;
+        assert_eq!(files.len(), 500);
+        assert!(files.iter().all(|f| f.size == 1 && f.mtime > 0));
+
+        // Dropping the receiver part-way ends the scan instead of blocking it
+        let (tx, mut rx) = mpsc::channel(4);
+        let roots = vec![dir.path().to_path_buf()];
+        let task = tokio::task::spawn_blocking(move || scan_roots(&roots, &[], &tx));
+        for _ in 0..10 {
+            rx.recv().await.unwrap();
+        }
+        drop(rx);
+        task.await.unwrap().unwrap();
 
-        // file2.txt changed (size differs), new_file.txt is new
-        // file1.txt will be "changed" too because mtime won't match (no real file)
-        // deleted.txt is in manifest but not in scanned files
-        assert_eq!(result.deleted_count, 1);
-        // Total scanned = 3 files, all will be changed because mtime can't match without real filesystem
-        assert_eq!(result.changed_files.len() + result.unchanged_paths.len(), 3);
+        let missing = dir.path().join("missing");
+        let (tx, _rx) = mpsc::channel(4);
+        assert!(tokio::task::spawn_blocking(move || scan_roots(&[missing], &[], &tx)).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_diff_matches_unusual_names_byte_exactly() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let names: [&[u8]; 3] = [b"caf\xc3\xa9", b"bad\xffname", b"new\nline 100%"];
+        let mut all_files = Vec::new();
+        let mut files_map = HashMap::new();
+        for name in names {
+            let path = dir.path().join(std::ffi::OsStr::from_bytes(name));
+            std::fs::write(&path, "x").unwrap();
+            let mtime = std::fs::metadata(&path).unwrap().mtime();
+            files_map.insert(path_encoding::encode(name), entry(1, mtime));
+            all_files.push(FileInfo {
+                relative_path: PathBuf::from(std::ffi::OsStr::from_bytes(name)),
+                path,
+                size: 1,
+                is_dir: false,
+                is_symlink: false,
+                depth: 1,
+                mtime,
+                dev: 0,
+                ino: 0,
+            });
+        }
+        let manifest = backup_protocol::manifest::JsonManifest::new(Default::default(), files_map);
+        let mut previous = PreviousFiles::from_sorted(manifest.into_sorted().1).await;
+
+        all_files.sort_by_key(|file| path_encoding::encode_path(&file.relative_path));
+        let mut unchanged = Vec::new();
+        for file in &all_files {
+            let rel = path_encoding::encode_path(&file.relative_path);
+            assert_eq!(previous.change(&rel, file).await, Change::Unchanged, "{}", rel);
+            unchanged.push(rel);
+        }
+        assert_eq!(previous.finish().await, 0);
+        assert_eq!(unchanged, vec!["bad%FFname", "caf%C3%A9", "new%0Aline%20100%25"]);
     }
 }
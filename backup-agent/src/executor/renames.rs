@@ -0,0 +1,261 @@
+//! Rename and move detection in incremental runs.
+//!
+//! To the manifest diff a moved file is a new path plus a deleted one. New
+//! files are held back while the scan runs; once it is done they are matched
+//! against the previous version's deleted files, by device and inode first (a
+//! rename keeps both) and then by size and content hash (moved across
+//! filesystems, or copied and removed). Matches are sent to
+//! `POST /api/files/renames` and the server links them from the previous
+//! version; everything else, including what the server rejects, is uploaded.
+
+use crate::fs::walker::FileInfo;
+use backup_protocol::manifest::{FileId, ManifestEntry};
+use backup_protocol::{Rename, RenamesRequest, RenamesResponse};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// New files held back for matching; past this they are uploaded right away
+const MAX_HELD_FILES: usize = 250_000;
+
+/// Renames per `/api/files/renames` request
+const RENAMES_BATCH_SIZE: usize = 5000;
+
+/// A new file found to be a previous file moved
+#[derive(Debug)]
+pub struct Moved {
+    pub rename: Rename,
+    pub file: FileInfo,
+}
+
+/// New files of a run, held back until the scan is done
+#[derive(Default)]
+pub struct HeldFiles {
+    files: Vec<(String, FileInfo)>,
+}
+
+impl HeldFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold back `file`, stored at `rel`. Gives it back, to upload now, when
+    /// too many are held already.
+    pub fn hold(&mut self, rel: String, file: FileInfo) -> Option<FileInfo> {
+        if self.files.len() >= MAX_HELD_FILES {
+            return Some(file);
+        }
+        self.files.push((rel, file));
+        None
+    }
+
+    /// Match the held files against the previous version's `deleted` files,
+    /// hashing those matched by size. Returns the moved files and those to
+    /// upload.
+    pub async fn matches(self, deleted: Vec<(String, ManifestEntry)>) -> (Vec<Moved>, Vec<FileInfo>) {
+        let (mut moved, candidates, mut upload) = match_files(self.files, deleted);
+        if candidates.is_empty() {
+            return (moved, upload);
+        }
+
+        let paths: Vec<_> = candidates.iter().map(|(_, _, file)| file.path.clone()).collect();
+        let span = tracing::Span::current();
+        let hashes = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            paths.iter().map(|path| sha256_hex(path).ok()).collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+
+        for (index, (from, to, file)) in candidates.into_iter().enumerate() {
+            match hashes.get(index).cloned().flatten() {
+                Some(sha256) => moved.push(Moved { rename: Rename { from, to, sha256: Some(sha256) }, file }),
+                None => upload.push(file),
+            }
+        }
+        (moved, upload)
+    }
+}
+
+/// A held file that may be a deleted one moved: `from`, `to` and the file
+type Candidate = (String, String, FileInfo);
+
+/// Pair held files with deleted ones. The same device, inode, size and mtime
+/// is a rename; otherwise a deleted file of the same size, preferably with
+/// the same mtime, is a candidate whose content decides. Returns the renames,
+/// the candidates and the files without a match.
+fn match_files(
+    held: Vec<(String, FileInfo)>,
+    deleted: Vec<(String, ManifestEntry)>,
+) -> (Vec<Moved>, Vec<Candidate>, Vec<FileInfo>) {
+    let by_id: HashMap<FileId, usize> = deleted.iter()
+        .enumerate()
+        .filter_map(|(index, (_, entry))| entry.id.map(|id| (id, index)))
+        .collect();
+    let mut taken = vec![false; deleted.len()];
+    let mut moved = Vec::new();
+    let mut rest = Vec::new();
+
+    for (to, file) in held {
+        let same_file = by_id.get(&FileId { dev: file.dev, ino: file.ino }).copied().filter(|&index| {
+            let entry = &deleted[index].1;
+            !taken[index] && entry.size == file.size && entry.mtime == file.mtime
+        });
+        match same_file {
+            Some(index) => {
+                taken[index] = true;
+                moved.push(Moved { rename: Rename { from: deleted[index].0.clone(), to, sha256: None }, file });
+            }
+            None => rest.push((to, file)),
+        }
+    }
+
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (index, (_, entry)) in deleted.iter().enumerate() {
+        if !taken[index] && entry.size > 0 {
+            by_size.entry(entry.size).or_default().push(index);
+        }
+    }
+    let mut candidates = Vec::new();
+    let mut unmatched = Vec::new();
+    for (to, file) in rest {
+        let Some(same_size) = by_size.get_mut(&file.size).filter(|same_size| !same_size.is_empty()) else {
+            unmatched.push(file);
+            continue;
+        };
+        let pick = same_size.iter().position(|&index| deleted[index].1.mtime == file.mtime).unwrap_or(0);
+        let index = same_size.swap_remove(pick);
+        candidates.push((deleted[index].0.clone(), to, file));
+    }
+
+    (moved, candidates, unmatched)
+}
+
+/// Hex SHA-256 of a file's content
+fn sha256_hex(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Have the server link `moved` files from the previous version, in batches.
+/// Returns the files it linked and those to upload instead: rejected, or in
+/// a batch that failed.
+pub async fn send(server_url: &str, job_id: &str, mut moved: Vec<Moved>) -> (Vec<FileInfo>, Vec<FileInfo>) {
+    let url = format!("{}/api/files/renames", server_url);
+    let client = crate::tls::http_client();
+    let mut linked = Vec::new();
+    let mut upload = Vec::new();
+
+    while !moved.is_empty() {
+        let batch: Vec<Moved> = moved.drain(..moved.len().min(RENAMES_BATCH_SIZE)).collect();
+        let request = RenamesRequest {
+            job_id: job_id.to_string(),
+            renames: batch.iter().map(|m| m.rename.clone()).collect(),
+        };
+
+        let response = match client.post(&url).json(&request).send().await {
+            Ok(resp) if resp.status().is_success() => resp.json::<RenamesResponse>().await.map_err(|e| e.to_string()),
+            Ok(resp) => Err(format!("status {}", resp.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        match response {
+            Ok(response) => {
+                info!("Moved files linked: {} linked, {} rejected (batch of {})", response.linked, response.rejected.len(), batch.len());
+                let rejected: HashSet<String> = response.rejected.into_iter().collect();
+                for m in batch {
+                    if rejected.contains(&m.rename.to) {
+                        upload.push(m.file);
+                    } else {
+                        linked.push(m.file);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Linking moved files failed ({}), uploading them instead", e);
+                upload.extend(batch.into_iter().map(|m| m.file));
+            }
+        }
+    }
+
+    (linked, upload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn file(name: &str, size: u64, mtime: i64, ino: u64) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from("/data").join(name),
+            relative_path: PathBuf::from(name),
+            size,
+            is_dir: false,
+            is_symlink: false,
+            depth: 1,
+            mtime,
+            dev: 1,
+            ino,
+        }
+    }
+
+    fn entry(size: u64, mtime: i64, ino: Option<u64>) -> ManifestEntry {
+        ManifestEntry { size, mtime, id: ino.map(|ino| FileId { dev: 1, ino }) }
+    }
+
+    #[test]
+    fn test_matches_by_inode_then_by_size() {
+        let held = [
+            file("new/renamed", 10, 100, 7),
+            file("new/touched", 10, 101, 8),
+            file("new/copied", 20, 200, 9),
+            file("new/fresh", 30, 300, 10),
+            file("new/empty", 0, 400, 11),
+        ];
+        let deleted = vec![
+            ("old/renamed".to_string(), entry(10, 100, Some(7))),
+            ("old/touched".to_string(), entry(10, 100, Some(8))),
+            ("old/other".to_string(), entry(20, 1, None)),
+            ("old/copied".to_string(), entry(20, 200, Some(99))),
+            ("old/empty".to_string(), entry(0, 400, None)),
+        ];
+        let held = held.into_iter().map(|f| (f.relative_path.display().to_string(), f)).collect();
+        let (moved, candidates, unmatched) = match_files(held, deleted);
+
+        let renames: Vec<_> = moved.iter().map(|m| (m.rename.from.as_str(), m.rename.to.as_str())).collect();
+        assert_eq!(renames, vec![("old/renamed", "new/renamed")]);
+        // A touched file is only a candidate; same-size files prefer the same mtime
+        let candidates: Vec<_> = candidates.iter().map(|(from, to, _)| (from.as_str(), to.as_str())).collect();
+        assert_eq!(candidates, vec![("old/touched", "new/touched"), ("old/copied", "new/copied")]);
+        let unmatched: Vec<_> = unmatched.iter().map(|f| f.relative_path.to_str().unwrap()).collect();
+        assert_eq!(unmatched, vec!["new/fresh", "new/empty"]);
+    }
+
+    #[tokio::test]
+    async fn test_candidates_carry_their_hash() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("moved");
+        std::fs::write(&path, "content").unwrap();
+        let mut moved_file = file("moved", 7, 1, 2);
+        moved_file.path = path;
+
+        let mut held = HeldFiles::new();
+        assert!(held.hold("moved".into(), moved_file).is_none());
+        assert!(held.hold("gone".into(), file("gone", 7, 1, 3)).is_none());
+        let deleted = vec![("a".to_string(), entry(7, 5, None)), ("b".to_string(), entry(7, 6, None))];
+        let (moved, upload) = held.matches(deleted).await;
+
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].rename.from, "a");
+        assert_eq!(
+            moved[0].rename.sha256.as_deref(),
+            Some("ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73")
+        );
+        // Unreadable files are uploaded, which reports why they failed
+        assert_eq!(upload.len(), 1);
+        assert_eq!(upload[0].relative_path, PathBuf::from("gone"));
+    }
+}
@@ -3,8 +3,15 @@
 //! This module provides efficient directory traversal with full metadata
 //! preservation for backup operations.
 
+use std::collections::VecDeque;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use walkdir::{DirEntry, WalkDir};
+use std::sync::{mpsc, Mutex};
+
+use backup_protocol::path as path_encoding;
+pub use walkdir::DirEntry;
+use walkdir::WalkDir;
 
 /// Options for directory walking
 #[derive(Debug, Clone)]
@@ -54,6 +61,14 @@ pub struct FileInfo {
 
     /// File depth from root
     pub depth: usize,
+
+    /// Modification time (seconds since the epoch); a symlink's is its target's
+    pub mtime: i64,
+
+    /// Device and inode, which identify the file across renames; a symlink's
+    /// are its target's
+    pub dev: u64,
+    pub ino: u64,
 }
 
 impl FileInfo {
@@ -61,22 +76,32 @@ impl FileInfo {
     /// For symlinks, resolves to the target to get the real file size.
     /// Returns None if the symlink target is a directory or cannot be resolved.
     fn from_entry(entry: &DirEntry, root: &Path) -> std::io::Result<Option<Self>> {
-        let raw_metadata = entry.metadata()?;
-        let path = entry.path().to_path_buf();
+        Self::from_metadata(entry.path(), root, entry.metadata()?, entry.depth())
+    }
+
+    /// Like [`FileInfo::from_entry`], for a path whose `symlink_metadata` was
+    /// already read.
+    fn from_metadata(
+        path: &Path,
+        root: &Path,
+        raw_metadata: std::fs::Metadata,
+        depth: usize,
+    ) -> std::io::Result<Option<Self>> {
+        let path = path.to_path_buf();
         let relative_path = path.strip_prefix(root)
             .unwrap_or(&path)
             .to_path_buf();
         let is_symlink = raw_metadata.is_symlink();
 
         // For symlinks, resolve to get the real file metadata
-        let (size, is_dir) = if is_symlink {
+        let metadata = if is_symlink {
             match std::fs::metadata(&path) {
                 Ok(resolved) => {
                     if resolved.is_dir() {
                         // Symlink to directory — skip it
                         return Ok(None);
                     }
-                    (resolved.len(), false)
+                    resolved
                 }
                 Err(_) => {
                     // Broken symlink — skip it
@@ -84,16 +109,19 @@ impl FileInfo {
                 }
             }
         } else {
-            (raw_metadata.len(), raw_metadata.is_dir())
+            raw_metadata
         };
 
         Ok(Some(Self {
             path,
             relative_path,
-            size,
-            is_dir,
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
             is_symlink,
-            depth: entry.depth(),
+            depth,
+            mtime: metadata.mtime(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
         }))
     }
 }
@@ -117,6 +145,15 @@ impl FileInfo {
 /// println!("Found {} files", files.len());
 /// ```
 pub fn walk_directory(root: &Path, options: WalkOptions) -> std::io::Result<Vec<FileInfo>> {
+    walk_directory_filtered(root, options, |_| true)
+}
+
+/// Walk a directory tree, skipping entries (and whole subtrees) for which
+/// `filter` returns false
+pub fn walk_directory_filtered<F>(root: &Path, options: WalkOptions, filter: F) -> std::io::Result<Vec<FileInfo>>
+where
+    F: FnMut(&DirEntry) -> bool,
+{
     let mut files = Vec::new();
 
     let mut walker = WalkDir::new(root)
@@ -126,7 +163,7 @@ pub fn walk_directory(root: &Path, options: WalkOptions) -> std::io::Result<Vec<
         walker = walker.max_depth(max_depth);
     }
 
-    for entry in walker {
+    for entry in walker.into_iter().filter_entry(filter) {
         let entry = entry?;
 
         // Skip if matches exclude pattern
@@ -147,6 +184,287 @@ pub fn walk_directory(root: &Path, options: WalkOptions) -> std::io::Result<Vec<
     Ok(files)
 }
 
+/// Like [`walk_directory_filtered`], but an entry below `root` that cannot be
+/// read (permission denied, removed while walking, ...) is passed to
+/// `on_error` and skipped instead of failing the whole walk. Only an
+/// unreadable `root` is an error.
+pub fn walk_directory_lenient<F, E>(
+    root: &Path,
+    options: WalkOptions,
+    filter: F,
+    mut on_error: E,
+) -> std::io::Result<Vec<FileInfo>>
+where
+    F: FnMut(&DirEntry) -> bool,
+    E: FnMut(&Path, std::io::Error),
+{
+    let mut files = Vec::new();
+
+    let mut walker = WalkDir::new(root)
+        .follow_links(options.follow_links);
+
+    if let Some(max_depth) = options.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+
+    for entry in walker.into_iter().filter_entry(filter) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.depth() == 0 => return Err(e.into()),
+            Err(e) => {
+                let path = e.path().unwrap_or(root).to_path_buf();
+                on_error(&path, e.into());
+                continue;
+            }
+        };
+
+        if should_exclude(&entry, &options.exclude_patterns) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        match FileInfo::from_entry(&entry, root) {
+            Ok(Some(file_info)) => files.push(file_info),
+            Ok(None) => {}
+            Err(e) => on_error(entry.path(), e),
+        }
+    }
+
+    Ok(files)
+}
+
+/// What [`walk_directory_parallel`] found
+#[derive(Debug)]
+pub enum WalkItem {
+    File(FileInfo),
+    /// An entry below the root that could not be read; it is skipped
+    Error(PathBuf, std::io::Error),
+}
+
+/// An entry of a directory listing, in [`walk_directory_parallel`]
+enum Listed {
+    /// A directory (or, when following links, a symlink to one) with the
+    /// device and inode it resolves to
+    Dir(PathBuf, (u64, u64)),
+    Item(WalkItem),
+}
+
+type Listing = std::io::Result<Vec<Listed>>;
+
+/// Directory listings requested ahead of the walk, per thread
+const PREFETCH_PER_THREAD: usize = 4;
+
+/// A directory whose entries are being walked
+struct Frame {
+    entries: Vec<Option<Listed>>,
+    depth: usize,
+    /// Device and inode of the directory, to tell a symlink loop
+    id: (u64, u64),
+    /// Index of the next entry to walk
+    next: usize,
+    /// Entries before this index were looked at for prefetching
+    prefetch_next: usize,
+    /// Listings requested for the subdirectories in `next..prefetch_next`, in order
+    prefetched: VecDeque<mpsc::Receiver<Listing>>,
+}
+
+impl Frame {
+    fn new(entries: Vec<Listed>, depth: usize, id: (u64, u64)) -> Self {
+        Self {
+            entries: entries.into_iter().map(Some).collect(),
+            depth,
+            id,
+            next: 0,
+            prefetch_next: 0,
+            prefetched: VecDeque::new(),
+        }
+    }
+}
+
+/// Walk a directory tree, passing every file to `visit` in manifest order
+/// (see [`backup_protocol::path::compare`]): entries are sorted by encoded
+/// name and each directory is walked where it sorts. Entries (and whole
+/// subtrees) for which `filter(path, is_symlink)` returns false are skipped;
+/// entries below `root` that cannot be read are passed to `visit` as
+/// [`WalkItem::Error`]. The walk stops early once `visit` returns false.
+///
+/// Directories are listed on `threads` threads, ahead of the walk, so
+/// `visit` runs on the calling thread only. With `options.follow_links`,
+/// symlinks to directories are walked like directories (a link back to one
+/// of its ancestors is a [`WalkItem::Error`]); otherwise they are skipped.
+/// Only an unreadable `root` is an error.
+pub fn walk_directory_parallel<F, V>(
+    root: &Path,
+    options: &WalkOptions,
+    threads: usize,
+    filter: F,
+    mut visit: V,
+) -> std::io::Result<()>
+where
+    F: Fn(&Path, bool) -> bool + Sync,
+    V: FnMut(WalkItem) -> bool,
+{
+    let root_is_symlink = std::fs::symlink_metadata(root)?.is_symlink();
+    let metadata = std::fs::metadata(root)?;
+    if !filter(root, root_is_symlink) {
+        return Ok(());
+    }
+    if !metadata.is_dir() {
+        if let Some(file) = FileInfo::from_metadata(root, root, metadata, 0)? {
+            visit(WalkItem::File(file));
+        }
+        return Ok(());
+    }
+
+    let list = |dir: &Path, depth: usize| -> Listing {
+        let mut listed = Vec::new();
+        if options.max_depth.is_some_and(|max| depth >= max) {
+            return Ok(Vec::new());
+        }
+        let descend = options.max_depth.is_none_or(|max| depth + 1 < max);
+        for entry in std::fs::read_dir(dir)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    listed.push((String::new(), Listed::Item(WalkItem::Error(dir.to_path_buf(), e))));
+                    continue;
+                }
+            };
+            let name = path_encoding::encode(entry.file_name().as_bytes());
+            let path = entry.path();
+            // A followed link to a directory is walked as the directory
+            let metadata = entry.metadata().map(|metadata| {
+                let target = (options.follow_links && metadata.is_symlink())
+                    .then(|| std::fs::metadata(&path).ok())
+                    .flatten()
+                    .filter(|target| target.is_dir());
+                (metadata, target)
+            });
+            let item = match metadata {
+                Ok((metadata, target)) if metadata.is_dir() || target.is_some() => {
+                    if !descend || !filter(&path, metadata.is_symlink()) {
+                        continue;
+                    }
+                    let dir = target.unwrap_or(metadata);
+                    Listed::Dir(path, (dir.dev(), dir.ino()))
+                }
+                Ok((metadata, _)) => {
+                    if !filter(&path, metadata.is_symlink())
+                        || excluded(entry.file_name().as_os_str(), &options.exclude_patterns)
+                    {
+                        continue;
+                    }
+                    match FileInfo::from_metadata(&path, root, metadata, depth + 1) {
+                        Ok(Some(file)) => Listed::Item(WalkItem::File(file)),
+                        Ok(None) => continue,
+                        Err(e) => Listed::Item(WalkItem::Error(path, e)),
+                    }
+                }
+                Err(e) => Listed::Item(WalkItem::Error(path, e)),
+            };
+            listed.push((name, item));
+        }
+        listed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(listed.into_iter().map(|(_, item)| item).collect())
+    };
+
+    // The root is listed here so that failing to read it fails the walk
+    let root_listing = list(root, 0)?;
+
+    let threads = threads.max(1);
+    let max_outstanding = threads * PREFETCH_PER_THREAD;
+    let (jobs, queue) = mpsc::channel::<(PathBuf, usize, mpsc::SyncSender<Listing>)>();
+    let queue = Mutex::new(queue);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok((dir, depth, reply)) = job else { break };
+                // The walk may have stopped and dropped the receiver
+                let _ = reply.send(list(&dir, depth));
+            });
+        }
+
+        let mut outstanding = 0;
+        let prefetch = |frame: &mut Frame, outstanding: &mut usize| {
+            frame.prefetch_next = frame.prefetch_next.max(frame.next);
+            while *outstanding < max_outstanding && frame.prefetch_next < frame.entries.len() {
+                if let Some(Listed::Dir(dir, _)) = &frame.entries[frame.prefetch_next] {
+                    let (reply, listing) = mpsc::sync_channel(1);
+                    if jobs.send((dir.clone(), frame.depth + 1, reply)).is_err() {
+                        break;
+                    }
+                    frame.prefetched.push_back(listing);
+                    *outstanding += 1;
+                }
+                frame.prefetch_next += 1;
+            }
+        };
+
+        let mut stack = vec![Frame::new(root_listing, 0, (metadata.dev(), metadata.ino()))];
+        prefetch(&mut stack[0], &mut outstanding);
+        while let Some(frame) = stack.last_mut() {
+            let index = frame.next;
+            let Some(slot) = frame.entries.get_mut(index) else {
+                stack.pop();
+                if let Some(parent) = stack.last_mut() {
+                    prefetch(parent, &mut outstanding);
+                }
+                continue;
+            };
+            frame.next += 1;
+            let (dir, id) = match slot.take() {
+                Some(Listed::Item(item)) => {
+                    if !visit(item) {
+                        break;
+                    }
+                    continue;
+                }
+                Some(Listed::Dir(dir, id)) => (dir, id),
+                None => continue,
+            };
+
+            let depth = frame.depth + 1;
+            let listing = if index < frame.prefetch_next {
+                let listing = frame.prefetched.pop_front().expect("prefetched listing");
+                outstanding -= 1;
+                listing.recv().unwrap_or_else(|_| Err(std::io::Error::other("directory listing thread stopped")))
+            } else {
+                frame.prefetch_next = index + 1;
+                list(&dir, depth)
+            };
+            // Only followed links can lead back to an ancestor
+            let listing = listing.and_then(|entries| {
+                if stack.iter().any(|ancestor| ancestor.id == id) {
+                    return Err(std::io::Error::other("symlink loop"));
+                }
+                Ok(entries)
+            });
+            match listing {
+                Ok(entries) => {
+                    let mut child = Frame::new(entries, depth, id);
+                    prefetch(&mut child, &mut outstanding);
+                    stack.push(child);
+                }
+                Err(e) => {
+                    if !visit(WalkItem::Error(dir, e)) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Let the threads finish what they were given and exit
+        drop(jobs);
+    });
+
+    Ok(())
+}
+
 /// Walk a directory tree with a callback for each file (for progress reporting)
 ///
 /// # Arguments
@@ -215,7 +533,11 @@ pub fn calculate_total_size(root: &Path, options: WalkOptions) -> std::io::Resul
 
 /// Check if a directory entry should be excluded based on patterns
 fn should_exclude(entry: &DirEntry, patterns: &[String]) -> bool {
-    let file_name = entry.file_name().to_string_lossy();
+    excluded(entry.file_name(), patterns)
+}
+
+fn excluded(file_name: &std::ffi::OsStr, patterns: &[String]) -> bool {
+    let file_name = file_name.to_string_lossy();
 
     for pattern in patterns {
         if file_name.contains(pattern) {
@@ -307,4 +629,149 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_filter_skips_subtrees() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+
+        fs::create_dir(temp_dir.path().join("private"))?;
+        fs::write(temp_dir.path().join("private/key"), b"secret")?;
+        fs::write(temp_dir.path().join("file.txt"), b"keep")?;
+
+        let files = walk_directory_filtered(temp_dir.path(), WalkOptions::default(), |entry| {
+            entry.file_name() != "private"
+        })?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].relative_path.to_str().unwrap(), "file.txt");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_walk_reports_and_skips_bad_entries() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+
+        fs::create_dir(temp_dir.path().join("dir"))?;
+        fs::write(temp_dir.path().join("dir/file.txt"), b"keep")?;
+        // Following this link loops back to an ancestor
+        std::os::unix::fs::symlink(temp_dir.path(), temp_dir.path().join("dir/loop"))?;
+
+        let options = WalkOptions { follow_links: true, ..WalkOptions::default() };
+        let mut errors = Vec::new();
+        let files = walk_directory_lenient(temp_dir.path(), options.clone(), |_| true, |path, e| {
+            errors.push((path.to_path_buf(), e.to_string()));
+        })?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, temp_dir.path().join("dir/loop"));
+
+        let missing = temp_dir.path().join("missing");
+        assert!(walk_directory_lenient(&missing, options, |_| true, |_, _| {}).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_walk_matches_sequential_walk() -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let temp_dir = TempDir::new()?;
+
+        for d in 0..5 {
+            let dir = temp_dir.path().join(format!("d{}/nested", d));
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join("file.txt"), b"content")?;
+            fs::write(dir.parent().unwrap().join(".DS_Store"), b"exclude")?;
+        }
+        fs::create_dir(temp_dir.path().join("private"))?;
+        fs::write(temp_dir.path().join("private/key"), b"secret")?;
+        std::os::unix::fs::symlink(temp_dir.path().join("d0"), temp_dir.path().join("dir-link"))?;
+        std::os::unix::fs::symlink(temp_dir.path().join("d0/nested/file.txt"), temp_dir.path().join("file-link"))?;
+        let locked = temp_dir.path().join("locked");
+        fs::create_dir(&locked)?;
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000))?;
+        // Root bypasses permissions, so the locked directory may be readable
+        let locked_readable = fs::read_dir(&locked).is_ok();
+
+        let filter = |path: &Path| !path.ends_with("private");
+        let mut expected: Vec<_> = walk_directory_lenient(
+            temp_dir.path(),
+            WalkOptions::default(),
+            |entry| filter(entry.path()),
+            |_, _| {},
+        )?
+        .into_iter()
+        .map(|f| (f.relative_path, f.size, f.is_symlink))
+        .collect();
+        expected.sort();
+
+        let mut found = Vec::new();
+        let mut errors = Vec::new();
+        walk_directory_parallel(temp_dir.path(), &WalkOptions::default(), 4, |path, _| filter(path), |item| {
+            match item {
+                WalkItem::File(f) => found.push((f.relative_path, f.size, f.is_symlink)),
+                WalkItem::Error(path, _) => errors.push(path),
+            }
+            true
+        })?;
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+
+        // Found in manifest order
+        let mut sorted = found.clone();
+        sorted.sort_by(|a, b| {
+            path_encoding::compare(&path_encoding::encode_path(&a.0), &path_encoding::encode_path(&b.0))
+        });
+        assert_eq!(found, sorted);
+        found.sort();
+        assert_eq!(found, expected);
+        assert_eq!(found.len(), 6);
+        assert_eq!(errors, if locked_readable { vec![] } else { vec![locked] });
+
+        // Stopping early, and a missing root
+        let mut visited = 0;
+        walk_directory_parallel(temp_dir.path(), &WalkOptions::default(), 4, |_, _| true, |_| {
+            visited += 1;
+            false
+        })?;
+        assert_eq!(visited, 1);
+        let missing = temp_dir.path().join("missing");
+        assert!(walk_directory_parallel(&missing, &WalkOptions::default(), 4, |_, _| true, |_| true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_walk_follows_links_when_asked() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir_all(temp_dir.path().join("data/sub"))?;
+        fs::write(temp_dir.path().join("data/sub/file.txt"), b"content")?;
+        std::os::unix::fs::symlink(temp_dir.path().join("data"), temp_dir.path().join("link"))?;
+        // A link back to an ancestor
+        std::os::unix::fs::symlink(temp_dir.path(), temp_dir.path().join("data/sub/loop"))?;
+
+        let walk = |follow_links: bool| -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+            let options = WalkOptions { follow_links, ..WalkOptions::default() };
+            let (mut found, mut errors) = (Vec::new(), Vec::new());
+            walk_directory_parallel(temp_dir.path(), &options, 2, |_, _| true, |item| {
+                match item {
+                    WalkItem::File(f) => found.push(f.relative_path),
+                    WalkItem::Error(path, _) => errors.push(path),
+                }
+                true
+            })?;
+            Ok((found, errors))
+        };
+
+        let (found, errors) = walk(false)?;
+        assert_eq!(found, vec![PathBuf::from("data/sub/file.txt")]);
+        assert!(errors.is_empty());
+
+        let (found, errors) = walk(true)?;
+        assert_eq!(found, vec![PathBuf::from("data/sub/file.txt"), PathBuf::from("link/sub/file.txt")]);
+        assert_eq!(
+            errors,
+            vec![temp_dir.path().join("data/sub/loop"), temp_dir.path().join("link/sub/loop")]
+        );
+
+        Ok(())
+    }
 }
@@ -5,9 +5,12 @@
 pub mod api;
 pub mod config;
 pub mod daemon;
+pub mod enroll;
 pub mod executor;
 pub mod fs;
+pub mod policy;
 pub mod sync;
+pub mod tls;
 pub mod transfer;
 pub mod update;
 pub mod utils;
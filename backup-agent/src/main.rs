@@ -3,8 +3,9 @@
 //! Rust-based backup agent with delta-sync capabilities.
 
 use anyhow::Result;
-use backup_agent::{api, config::Config, utils, daemon::shutdown::ShutdownCoordinator, ws};
-use clap::Parser;
+use backup_agent::{api, config::Config, enroll, policy, tls, transfer, update, utils, ws};
+use backup_agent::daemon::{pid::PidFile, privileges, shutdown::ShutdownCoordinator, signals};
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::net::SocketAddr;
 use tokio_util::sync::CancellationToken;
@@ -23,23 +24,99 @@ struct Args {
     /// Log level (trace, debug, info, warn, error)
     #[arg(short, long)]
     log_level: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Register this machine with a backup server using a one-time enrollment
+    /// token, write the config (to --config or /etc/backup-agent/config.toml)
+    /// and install the systemd service
+    Enroll {
+        /// Backup server URL, e.g. https://backup.example.com:3000
+        #[arg(long)]
+        server: String,
+
+        /// Enrollment token created in the backup server UI
+        #[arg(long)]
+        token: String,
+
+        /// Server certificate fingerprint to pin (shown with the token)
+        #[arg(long)]
+        fingerprint: Option<String>,
+
+        /// Only write the config; do not install or start the systemd service
+        #[arg(long)]
+        no_service: bool,
+    },
+
+    /// Roll back a self-update that is not confirmed in time (started by the
+    /// agent itself during an update)
+    #[command(hide = true)]
+    RollbackWatch {
+        /// Pending-update marker written by the updating agent
+        #[arg(long)]
+        marker: PathBuf,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+// The runtime is built by hand: the PID lock and the privilege drop must
+// happen before it starts any worker threads.
+fn main() -> Result<()> {
     let args = Args::parse();
+    let runtime = || tokio::runtime::Builder::new_multi_thread().enable_all().build();
+
+    match args.command {
+        Some(Command::Enroll { server, token, fingerprint, no_service }) => {
+            utils::logger::init(args.log_level.as_deref().unwrap_or("info"))?;
+            return runtime()?.block_on(enroll::enroll(&enroll::EnrollOptions {
+                server_url: server,
+                token,
+                fingerprint,
+                config_path: args.config.unwrap_or_else(|| PathBuf::from(enroll::DEFAULT_CONFIG_PATH)),
+                install_service: !no_service,
+            }));
+        }
+        Some(Command::RollbackWatch { marker }) => {
+            utils::logger::init(args.log_level.as_deref().unwrap_or("info"))?;
+            return runtime()?.block_on(update::watch_rollback(&marker));
+        }
+        None => {}
+    }
 
     // Load configuration
-    let config = if let Some(config_path) = args.config {
-        Config::from_file(&config_path)?
+    let config = if let Some(config_path) = &args.config {
+        Config::from_file(config_path)?
     } else {
         Config::default()
     };
 
     // Initialize logging
     let log_level = args.log_level.as_deref().unwrap_or(&config.log.level);
-    utils::logger::init(log_level)?;
+    utils::logger::init_with_config(&config.log, log_level)?;
+
+    // TLS settings (certificate pinning, client certificate) for server connections
+    tls::init(&config.server)?;
+    update::init(&config);
+    policy::init(&config)?;
+    transfer::compression::init(&config.sync)?;
+
+    // Refuse to start next to another instance, then give up root
+    let pid_file = PidFile::acquire(&config.daemon.pid_file)?;
+    let mut writable = vec![config.agent.data_dir.as_path()];
+    if config.log.output == "file" {
+        writable.extend(config.log.file.parent());
+    }
+    privileges::drop_privileges(&config.daemon.user, &config.daemon.group, &writable)?;
 
+    let result = runtime()?.block_on(run(args, config));
+    drop(pid_file);
+    result
+}
+
+async fn run(args: Args, config: Config) -> Result<()> {
     // Initialize start time for uptime tracking
     api::health::init_start_time();
 
@@ -51,13 +128,16 @@ async fn main() -> Result<()> {
 
     // Determine port
     let port = args.port.unwrap_or(config.agent.port);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let addr = SocketAddr::new(config.agent.listen, port);
 
     // Create shutdown coordinator
     let shutdown_coordinator = ShutdownCoordinator::new();
 
     // Create shared app state (shared between HTTP router and WS client)
-    let app_state = api::create_app_state();
+    let app_state = api::create_app_state(config.server.url.clone());
+
+    // Ship warnings and errors of running jobs to the server
+    utils::job_log::ship_to(app_state.ws_state.read().await.tx.clone());
 
     // Create API router with shared state
     let app = api::create_router_with_state(app_state.clone());
@@ -81,6 +161,17 @@ async fn main() -> Result<()> {
         client.run().await;
     });
 
+    // SIGHUP reloads the configuration, SIGUSR1 dumps job status
+    let signals_handle = tokio::spawn(signals::run(
+        app_state.clone(),
+        signals::ReloadSource {
+            config_path: args.config.clone(),
+            log_level_fixed: args.log_level.is_some(),
+            config: config.clone(),
+        },
+        ws_shutdown.clone(),
+    ));
+
     tracing::info!("Listening on http://{}", addr);
     tracing::info!("Health endpoint: http://{}/health", addr);
     tracing::info!("Version endpoint: http://{}/version", addr);
@@ -107,6 +198,7 @@ async fn main() -> Result<()> {
 
     // Wait for WS client to finish
     let _ = tokio::time::timeout(std::time::Duration::from_secs(3), ws_client_handle).await;
+    let _ = signals_handle.await;
 
     // Wait for server to finish (with timeout)
     match tokio::time::timeout(std::time::Duration::from_secs(5), server_handle).await {
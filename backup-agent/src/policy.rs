@@ -0,0 +1,411 @@
+//! Local path policy: which paths the server may browse and back up.
+//!
+//! The policy is read from `agent.policy_file` (or [`DEFAULT_POLICY_PATH`] if
+//! it exists), re-read on SIGHUP, and enforced by the agent whatever the
+//! server asks for:
+//!
+//! ```toml
+//! # Roots that may be browsed and backed up (default: everything)
+//! allow = ["/home", "/srv", "/etc"]
+//! # Never read, even inside an allowed root
+//! deny = ["/home/*/.cache"]
+//! # Keep the built-in deny rules for credentials and keys (default: true)
+//! default_deny = true
+//! ```
+//!
+//! Rules are absolute paths and cover everything below them; `*` and `?`
+//! match within a single path component. Paths are resolved (symlinks and
+//! `..`) before they are checked, so a link cannot lead out of an allowed root.
+//! Violations are logged on the `audit` target and sent to the server as
+//! `agent:policy:violation` events.
+
+use crate::config::Config;
+use crate::ws::{WsEvent, WsState};
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+pub const DEFAULT_POLICY_PATH: &str = "/etc/backup-agent/policy.toml";
+
+/// Credentials and keys that are never read unless `default_deny = false`.
+const DEFAULT_DENY: &[&str] = &[
+    "/etc/shadow",
+    "/etc/shadow-",
+    "/etc/gshadow",
+    "/etc/gshadow-",
+    "/etc/ssh/ssh_host_*_key",
+    "/etc/backup-agent",
+    "/root/.ssh",
+    "/home/*/.ssh",
+    "/home/*/.gnupg",
+];
+
+/// Why a path was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PolicyViolation {
+    #[error("{} is outside the roots allowed by the agent path policy", .0.display())]
+    OutsideAllowedRoots(PathBuf),
+
+    #[error("{} is denied by the agent path policy (rule {rule})", .path.display())]
+    Denied { path: PathBuf, rule: String },
+
+    #[error("{0} is not an absolute path")]
+    NotAbsolute(String),
+}
+
+impl PolicyViolation {
+    /// The refused path, as resolved.
+    pub fn path(&self) -> String {
+        match self {
+            PolicyViolation::OutsideAllowedRoots(path) | PolicyViolation::Denied { path, .. } => {
+                path.display().to_string()
+            }
+            PolicyViolation::NotAbsolute(path) => path.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default = "default_allow")]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default = "default_true")]
+    default_deny: bool,
+}
+
+fn default_allow() -> Vec<String> {
+    vec!["/".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// An absolute path pattern; the path and everything below it match.
+#[derive(Debug, Clone)]
+struct Rule {
+    text: String,
+    components: Vec<String>,
+}
+
+impl Rule {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let path = Path::new(text);
+        if !path.is_absolute() {
+            anyhow::bail!("Policy rule {} is not an absolute path", text);
+        }
+        Ok(Self {
+            text: text.to_string(),
+            components: components(&normalize(path)),
+        })
+    }
+
+    /// The path is the rule's path or lies below it.
+    fn covers(&self, path: &[String]) -> bool {
+        path.len() >= self.components.len()
+            && self.components.iter().zip(path).all(|(pattern, c)| wildcard_match(pattern, c))
+    }
+
+    /// The path is a directory above the rule's path.
+    fn is_ancestor(&self, path: &[String]) -> bool {
+        path.len() < self.components.len()
+            && path.iter().zip(&self.components).all(|(c, pattern)| wildcard_match(pattern, c))
+    }
+}
+
+/// Allowed roots and denied paths.
+#[derive(Debug, Clone)]
+pub struct PathPolicy {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl Default for PathPolicy {
+    /// Everything except the built-in deny rules.
+    fn default() -> Self {
+        Self::new(&default_allow(), &[], true).expect("built-in policy rules are valid")
+    }
+}
+
+impl PathPolicy {
+    pub fn new(allow: &[String], deny: &[String], default_deny: bool) -> anyhow::Result<Self> {
+        let defaults = if default_deny { DEFAULT_DENY } else { &[] };
+        Ok(Self {
+            allow: allow.iter().map(|r| Rule::parse(r)).collect::<anyhow::Result<_>>()?,
+            deny: defaults
+                .iter()
+                .copied()
+                .chain(deny.iter().map(String::as_str))
+                .map(Rule::parse)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read policy file {}: {}", path.display(), e))?;
+        let file: PolicyFile = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid policy file {}: {}", path.display(), e))?;
+        Self::new(&file.allow, &file.deny, file.default_deny)
+    }
+
+    /// Add a deny rule for a single path (e.g. the agent's own key).
+    pub fn deny_path(&mut self, path: &Path) {
+        if let Some(text) = path.to_str() {
+            if let Ok(rule) = Rule::parse(text) {
+                self.deny.push(rule);
+            }
+        }
+    }
+
+    /// Resolve `path` and check that it may be read. Returns the resolved path.
+    pub fn check(&self, path: &Path) -> Result<PathBuf, PolicyViolation> {
+        let resolved = resolve(path)?;
+        self.check_resolved(&resolved)?;
+        Ok(resolved)
+    }
+
+    /// Check a path that is already absolute and free of symlinks, without
+    /// touching the filesystem.
+    pub fn check_resolved(&self, path: &Path) -> Result<(), PolicyViolation> {
+        let comps = components(path);
+        if let Some(rule) = self.deny.iter().find(|r| r.covers(&comps)) {
+            return Err(PolicyViolation::Denied {
+                path: path.to_path_buf(),
+                rule: rule.text.clone(),
+            });
+        }
+        if !self.allow.iter().any(|r| r.covers(&comps)) {
+            return Err(PolicyViolation::OutsideAllowedRoots(path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    /// Resolve a directory and check that it may be listed: it is allowed, or
+    /// it leads to an allowed root (so the explorer can navigate there).
+    pub fn check_browse(&self, dir: &Path) -> Result<PathBuf, PolicyViolation> {
+        let resolved = resolve(dir)?;
+        match self.check_resolved(&resolved) {
+            Err(PolicyViolation::OutsideAllowedRoots(_)) if self.leads_to_allowed(&resolved) => Ok(resolved),
+            result => result.map(|_| resolved),
+        }
+    }
+
+    /// Whether an entry of a listing is shown.
+    pub fn is_visible(&self, path: &Path) -> bool {
+        match self.check_resolved(path) {
+            Ok(()) => true,
+            Err(PolicyViolation::OutsideAllowedRoots(_)) => self.leads_to_allowed(path),
+            Err(_) => false,
+        }
+    }
+
+    fn leads_to_allowed(&self, path: &Path) -> bool {
+        let comps = components(path);
+        self.allow.iter().any(|r| r.is_ancestor(&comps))
+    }
+}
+
+static POLICY: std::sync::RwLock<Option<Arc<PathPolicy>>> = std::sync::RwLock::new(None);
+
+/// Load the policy. Called at startup and again on SIGHUP; if loading fails
+/// the current policy stays in place. Jobs already scanning keep the policy
+/// they started with.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let default_path = Path::new(DEFAULT_POLICY_PATH);
+    let file = config
+        .agent
+        .policy_file
+        .as_deref()
+        .or_else(|| default_path.exists().then_some(default_path));
+
+    let mut policy = match file {
+        Some(path) => PathPolicy::from_file(path)?,
+        None => PathPolicy::default(),
+    };
+    if let Some(key) = &config.server.client_key {
+        policy.deny_path(key);
+    }
+
+    tracing::info!(
+        file = %file.map(|p| p.display().to_string()).unwrap_or_else(|| "built-in".into()),
+        allow = ?policy.allow.iter().map(|r| r.text.as_str()).collect::<Vec<_>>(),
+        deny_rules = policy.deny.len(),
+        "Path policy loaded"
+    );
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(policy));
+    Ok(())
+}
+
+/// The active policy (the built-in one if [`init`] was not called).
+pub fn current() -> Arc<PathPolicy> {
+    let mut policy = POLICY.write().unwrap_or_else(|e| e.into_inner());
+    policy.get_or_insert_with(|| Arc::new(PathPolicy::default())).clone()
+}
+
+/// Record a violation in the audit log and report it to the server.
+pub async fn report(ws_state: &RwLock<WsState>, action: &str, job_id: Option<&str>, violation: &PolicyViolation) {
+    tracing::warn!(target: "audit", action, job_id, path = %violation.path(), "{}", violation);
+    ws_state.read().await.broadcast(WsEvent::PolicyViolation {
+        action: action.to_string(),
+        path: violation.path(),
+        reason: violation.to_string(),
+        job_id: job_id.map(str::to_string),
+    });
+}
+
+fn resolve(path: &Path) -> Result<PathBuf, PolicyViolation> {
+    if !path.is_absolute() {
+        return Err(PolicyViolation::NotAbsolute(path.display().to_string()));
+    }
+    Ok(std::fs::canonicalize(path).unwrap_or_else(|_| normalize(path)))
+}
+
+/// Remove `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+fn components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Match one path component against a pattern with `*` and `?`.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> PathPolicy {
+        let strings = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        PathPolicy::new(&strings(allow), &strings(deny), true).unwrap()
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("ssh_host_*_key", "ssh_host_ed25519_key"));
+        assert!(!wildcard_match("ssh_host_*_key", "ssh_host_ed25519_key.pub"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("file?.txt", "file1.txt"));
+        assert!(!wildcard_match("file?.txt", "file10.txt"));
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let policy = policy(&["/home", "/etc"], &["/home/*/.cache"]);
+        assert!(policy.check_resolved(Path::new("/home/alice/docs/a.txt")).is_ok());
+        assert!(policy.check_resolved(Path::new("/etc/hosts")).is_ok());
+        assert!(matches!(
+            policy.check_resolved(Path::new("/var/log/syslog")),
+            Err(PolicyViolation::OutsideAllowedRoots(_))
+        ));
+        assert!(matches!(
+            policy.check_resolved(Path::new("/home/alice/.cache/x")),
+            Err(PolicyViolation::Denied { rule, .. }) if rule == "/home/*/.cache"
+        ));
+        // Built-in rules
+        assert!(policy.check_resolved(Path::new("/etc/shadow")).is_err());
+        assert!(policy.check_resolved(Path::new("/home/bob/.ssh/id_ed25519")).is_err());
+        assert!(policy.check_resolved(Path::new("/etc/ssh/ssh_host_rsa_key")).is_err());
+        assert!(policy.check_resolved(Path::new("/etc/ssh/ssh_host_rsa_key.pub")).is_ok());
+    }
+
+    #[test]
+    fn test_default_deny_can_be_disabled() {
+        let policy = PathPolicy::new(&default_allow(), &[], false).unwrap();
+        assert!(policy.check_resolved(Path::new("/etc/shadow")).is_ok());
+    }
+
+    #[test]
+    fn test_dot_dot_cannot_escape() {
+        let policy = policy(&["/srv"], &[]);
+        let err = policy.check(Path::new("/srv/../etc/passwd_does_not_exist")).unwrap_err();
+        assert_eq!(err, PolicyViolation::OutsideAllowedRoots(PathBuf::from("/etc/passwd_does_not_exist")));
+        assert!(matches!(policy.check(Path::new("srv/data")), Err(PolicyViolation::NotAbsolute(_))));
+    }
+
+    #[test]
+    fn test_symlink_out_of_allowed_root_is_refused() {
+        let allowed = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        let secret = outside.path().join("secret");
+        std::fs::write(&secret, b"x").unwrap();
+        let link = allowed.path().join("link");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+
+        let root = std::fs::canonicalize(allowed.path()).unwrap();
+        let policy = policy(&[root.to_str().unwrap()], &[]);
+        assert!(policy.check(&root).is_ok());
+        assert!(matches!(policy.check(&link), Err(PolicyViolation::OutsideAllowedRoots(_))));
+    }
+
+    #[test]
+    fn test_browse_leads_to_allowed_roots() {
+        let policy = policy(&["/srv/app", "/home"], &[]);
+        assert!(policy.check_browse(Path::new("/")).is_ok());
+        assert!(policy.is_visible(Path::new("/srv")));
+        assert!(policy.is_visible(Path::new("/home")));
+        assert!(!policy.is_visible(Path::new("/var")));
+        assert!(!policy.is_visible(Path::new("/srv/other")));
+        assert!(!policy.is_visible(Path::new("/home/alice/.ssh")));
+        assert!(policy.check_browse(Path::new("/var/nonexistent_dir")).is_err());
+    }
+
+    #[test]
+    fn test_policy_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, "allow = [\"/srv\"]\ndeny = [\"/srv/secrets\"]\n").unwrap();
+        let policy = PathPolicy::from_file(&path).unwrap();
+        assert!(policy.check_resolved(Path::new("/srv/www/index.html")).is_ok());
+        assert!(policy.check_resolved(Path::new("/srv/secrets/key")).is_err());
+        assert!(policy.check_resolved(Path::new("/etc/hosts")).is_err());
+
+        std::fs::write(&path, "allow = [\"srv\"]\n").unwrap();
+        assert!(PathPolicy::from_file(&path).is_err());
+        std::fs::write(&path, "alow = [\"/srv\"]\n").unwrap();
+        assert!(PathPolicy::from_file(&path).is_err());
+    }
+}
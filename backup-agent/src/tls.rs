@@ -0,0 +1,210 @@
+//! TLS settings for connections to the backup server.
+//!
+//! When `server.cert_fingerprint` is configured the agent trusts exactly that
+//! certificate (usually the server's self-generated one) instead of the public
+//! CA roots. The same client configuration is used for the WebSocket and for
+//! HTTP uploads, and carries the client certificate when mutual TLS is set up.
+
+use crate::config::ServerConfig;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, OnceLock};
+use tokio_tungstenite::Connector;
+
+static CLIENT_CONFIG: OnceLock<Option<Arc<ClientConfig>>> = OnceLock::new();
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Build the TLS client configuration from the `[server]` section. Must be
+/// called once at startup, before any connection to the server is made.
+pub fn init(server: &ServerConfig) -> anyhow::Result<()> {
+    let config = client_config(server)?.map(Arc::new);
+
+    if server.cert_fingerprint.is_some() && !server.url.starts_with("https://") {
+        tracing::warn!(url = %server.url, "cert_fingerprint is set but the server URL is not https://");
+    }
+
+    let mut builder = reqwest::Client::builder();
+    if let Some(config) = &config {
+        builder = builder.use_preconfigured_tls((**config).clone());
+    }
+    let _ = HTTP_CLIENT.set(builder.build()?);
+    let _ = CLIENT_CONFIG.set(config);
+    Ok(())
+}
+
+/// HTTP client for talking to the backup server.
+pub fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.get().cloned().unwrap_or_default()
+}
+
+/// Connector for the server WebSocket; `None` uses the default roots.
+pub fn ws_connector() -> Option<Connector> {
+    CLIENT_CONFIG.get().cloned().flatten().map(Connector::Rustls)
+}
+
+fn client_config(server: &ServerConfig) -> anyhow::Result<Option<ClientConfig>> {
+    if server.cert_fingerprint.is_none() && server.client_cert.is_none() {
+        return Ok(None);
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &server.cert_fingerprint {
+        Some(fingerprint) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: parse_fingerprint(fingerprint)?,
+                provider,
+            })),
+        None => {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots)
+        }
+    };
+
+    let config = match (&server.client_cert, &server.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert_path.display(), e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow::anyhow!("Invalid certificate in {}: {}", cert_path.display(), e))?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", key_path.display(), e))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("client_cert and client_key must be set together"),
+    };
+
+    Ok(Some(config))
+}
+
+/// Parse a SHA-256 fingerprint written as hex, with or without `:` separators
+/// and an optional `sha256:` prefix.
+pub fn parse_fingerprint(value: &str) -> anyhow::Result<[u8; 32]> {
+    let value = value.trim();
+    let value = value.strip_prefix("sha256:").unwrap_or(value);
+    let hex: String = value.chars().filter(|c| *c != ':').collect();
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid certificate fingerprint: not hex");
+    }
+    if hex.len() != 64 {
+        anyhow::bail!("Invalid certificate fingerprint: expected 32 bytes of hex");
+    }
+
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(out)
+}
+
+/// Accepts only the server certificate whose SHA-256 matches the pin. Names
+/// and validity are not checked: the pin identifies the certificate exactly.
+/// Handshake signatures are still verified against that certificate.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier_for(der: &[u8]) -> PinnedCertVerifier {
+        PinnedCertVerifier {
+            fingerprint: Sha256::digest(der).into(),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    #[test]
+    fn test_parse_fingerprint_formats() {
+        let plain = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let colons = "E3:B0:C4:42:98:FC:1C:14:9A:FB:F4:C8:99:6F:B9:24:27:AE:41:E4:64:9B:93:4C:A4:95:99:1B:78:52:B8:55";
+        let expected: [u8; 32] = Sha256::digest(b"").into();
+
+        assert_eq!(parse_fingerprint(plain).unwrap(), expected);
+        assert_eq!(parse_fingerprint(colons).unwrap(), expected);
+        assert_eq!(parse_fingerprint(&format!("sha256:{}", plain)).unwrap(), expected);
+        assert!(parse_fingerprint("e3b0").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        // 64 bytes, but a two-byte character straddles the first pair
+        assert!(parse_fingerprint(&format!("a{}a", "é".repeat(31))).is_err());
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        let pinned = CertificateDer::from(b"pinned certificate".to_vec());
+        let other = CertificateDer::from(b"some other certificate".to_vec());
+        let verifier = verifier_for(pinned.as_ref());
+        let name = ServerName::try_from("backup.local").unwrap();
+
+        assert!(verifier
+            .verify_server_cert(&pinned, &[], &name, &[], UnixTime::now())
+            .is_ok());
+        assert!(verifier
+            .verify_server_cert(&other, &[], &name, &[], UnixTime::now())
+            .is_err());
+    }
+}
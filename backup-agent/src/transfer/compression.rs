@@ -0,0 +1,157 @@
+//! Compression of upload bodies.
+//!
+//! Jobs bring their [`Compression`] in `backup:start`; `[sync] compression`
+//! and `compression_level` only apply when the server sends none. Files that
+//! would not shrink are sent as they are: known compressed formats by their
+//! extension, anything else when a sample of its first bytes looks random.
+
+use crate::config::SyncConfig;
+use async_compression::tokio::bufread::{GzipEncoder, Lz4Encoder, ZstdEncoder};
+use async_compression::Level;
+use backup_protocol::compression::{Algorithm, Compression};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::RwLock;
+use tokio::io::{AsyncBufRead, AsyncRead};
+
+/// Bytes read from the start of a file to estimate how well it compresses
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Samples shorter than this say too little; their files are compressed
+const MIN_SAMPLE_SIZE: usize = 4096;
+
+/// Samples with more entropy than this (bits per byte, 8 being random) are
+/// taken to be compressed or encrypted already
+const MAX_ENTROPY: f64 = 7.5;
+
+/// Extensions of formats that are compressed already
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avif", "bz2", "deb", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4", "m4a",
+    "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "png", "pptx", "rar", "rpm", "tbz2", "tgz",
+    "txz", "war", "webm", "webp", "whl", "xlsx", "xz", "zip", "zst",
+];
+
+static CONFIGURED: RwLock<Option<Compression>> = RwLock::new(None);
+
+/// Check and keep the `[sync]` settings, for servers that send none.
+pub fn init(sync: &SyncConfig) -> anyhow::Result<()> {
+    let algorithm = Algorithm::parse(&sync.compression).ok_or_else(|| {
+        anyhow::anyhow!("sync.compression must be none, zstd, lz4 or gzip, not {:?}", sync.compression)
+    })?;
+    // The level only matters to algorithms that have levels
+    let level = algorithm.levels().map(|_| sync.compression_level);
+    let compression = Compression::new(algorithm, level).map_err(|e| anyhow::anyhow!("sync.compression_level: {}", e))?;
+    *CONFIGURED.write().unwrap_or_else(|e| e.into_inner()) = Some(compression);
+    Ok(())
+}
+
+/// The `[sync]` settings (zstd at its default level if [`init`] was not called).
+pub fn configured() -> Compression {
+    CONFIGURED.read().unwrap_or_else(|e| e.into_inner()).unwrap_or_default()
+}
+
+/// Whether the file at `path` is of a compressed format, by its extension
+pub fn compressed_by_name(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| COMPRESSED_EXTENSIONS.iter().any(|known| extension.eq_ignore_ascii_case(known)))
+}
+
+/// Whether `sample`, the start of a file, looks compressed already
+pub fn looks_compressed(sample: &[u8]) -> bool {
+    sample.len() >= MIN_SAMPLE_SIZE && entropy(sample) > MAX_ENTROPY
+}
+
+/// Shannon entropy of `bytes`, in bits per byte
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+    let total = bytes.len() as f64;
+    counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// `reader` compressed with `compression`
+pub fn encode<R>(reader: R, compression: Compression) -> Pin<Box<dyn AsyncRead + Send>>
+where
+    R: AsyncBufRead + Send + 'static,
+{
+    let level = compression.level.map_or(Level::Default, Level::Precise);
+    match compression.algorithm {
+        Algorithm::None => Box::pin(reader),
+        Algorithm::Zstd => Box::pin(ZstdEncoder::with_quality(reader, level)),
+        Algorithm::Lz4 => Box::pin(Lz4Encoder::new(reader)),
+        Algorithm::Gzip => Box::pin(GzipEncoder::with_quality(reader, level)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_compressed_files_are_recognised() {
+        assert!(compressed_by_name(Path::new("/srv/photos/IMG_0001.JPG")));
+        assert!(compressed_by_name(Path::new("backup.tar.gz")));
+        assert!(!compressed_by_name(Path::new("/etc/app.conf")));
+        assert!(!compressed_by_name(Path::new("Makefile")));
+
+        let text = b"INFO request served in 12ms\n".repeat(400);
+        assert!(!looks_compressed(&text));
+        // A xorshift stream stands in for compressed data
+        let mut state = 0x2545f4914f6cdd1du64;
+        let random: Vec<u8> = (0..SAMPLE_SIZE).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        assert!(looks_compressed(&random));
+        assert!(!looks_compressed(&random[..100]), "short samples are not judged");
+    }
+
+    #[tokio::test]
+    async fn test_every_algorithm_round_trips() {
+        use async_compression::tokio::bufread::{GzipDecoder, Lz4Decoder, ZstdDecoder};
+        let data = b"the same line again and again\n".repeat(1000);
+        for algorithm in Algorithm::ALL {
+            let level = algorithm.levels().map(|levels| *levels.end());
+            let mut encoded = Vec::new();
+            let reader = std::io::Cursor::new(data.clone());
+            encode(reader, Compression { algorithm, level }).read_to_end(&mut encoded).await.unwrap();
+
+            let mut decoded = Vec::new();
+            match algorithm {
+                Algorithm::None => decoded = encoded.clone(),
+                Algorithm::Zstd => { ZstdDecoder::new(&encoded[..]).read_to_end(&mut decoded).await.unwrap(); }
+                Algorithm::Lz4 => { Lz4Decoder::new(&encoded[..]).read_to_end(&mut decoded).await.unwrap(); }
+                Algorithm::Gzip => { GzipDecoder::new(&encoded[..]).read_to_end(&mut decoded).await.unwrap(); }
+            }
+            assert_eq!(decoded, data, "{}", algorithm.as_str());
+            if algorithm != Algorithm::None {
+                assert!(encoded.len() < data.len() / 10, "{}", algorithm.as_str());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_settings_are_checked() {
+        let sync = |compression: &str, compression_level| SyncConfig {
+            chunk_size: 1024,
+            compression: compression.to_string(),
+            compression_level,
+        };
+        assert!(init(&sync("lz4", 3)).is_ok(), "levels are ignored where there are none");
+        assert!(init(&sync("brotli", 3)).is_err());
+        assert!(init(&sync("zstd", 40)).is_err());
+        assert_eq!(configured(), Compression { algorithm: Algorithm::Lz4, level: None });
+    }
+}
@@ -1,5 +1,6 @@
 //! Transfer engine for backup operations.
 
 pub mod chunked;
+pub mod compression;
 pub mod progress;
 pub mod progress_stream;
@@ -1,44 +1,133 @@
 //! Self-update mechanism for the backup agent.
 //!
-//! Downloads a new binary from the backup server and restarts via systemd.
+//! The server sends a release manifest (version, sha256, size) signed with its
+//! release key. The agent checks the signature against the public key in its
+//! config, downloads the binary next to the installed one, checks its hash,
+//! keeps the current binary as `backup-agent.prev` and restarts via systemd.
+//!
+//! A pending-update marker is written before the restart and removed once the
+//! new version has registered with the server. A watcher started with
+//! `systemd-run` (outside the service, so it survives the restart) restores
+//! the previous binary if the marker is still there when the timeout expires.
 
-use tracing::{info, error};
+use crate::config::Config;
+use backup_protocol::ReleaseManifest;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
-/// Download a new agent binary and restart the service.
-pub async fn self_update(download_url: &str, version: &str) {
-    info!("Starting self-update to version {}", version);
+pub const INSTALL_PATH: &str = "/usr/local/bin/backup-agent";
+const PREVIOUS_PATH: &str = "/usr/local/bin/backup-agent.prev";
+const STAGING_PATH: &str = "/usr/local/bin/backup-agent.new";
+const PENDING_FILE: &str = "update-pending.json";
 
-    let tmp_path = "/tmp/backup-agent-new";
-    let install_path = "/usr/local/bin/backup-agent";
+/// How long a new version has to reconnect and register before it is rolled back.
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
 
-    // Download new binary
-    match download_binary(download_url, tmp_path).await {
-        Ok(()) => {
-            info!("Downloaded new binary to {}", tmp_path);
-        }
-        Err(e) => {
-            error!("Failed to download update: {}", e);
-            return;
-        }
+/// Check a manifest's signature against a hex-encoded Ed25519 public key.
+pub fn verify_manifest(manifest: &ReleaseManifest, public_key: &str) -> anyhow::Result<()> {
+    let key = decode_hex(public_key).ok_or_else(|| anyhow::anyhow!("release_public_key is not hex"))?;
+    let signature = decode_hex(&manifest.signature).ok_or_else(|| anyhow::anyhow!("Manifest signature is not hex"))?;
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(manifest.signed_message().as_bytes(), &signature)
+        .map_err(|_| anyhow::anyhow!("Release manifest signature is invalid"))
+}
+
+/// Written before restarting into a new version.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingUpdate {
+    from_version: String,
+    to_version: String,
+    /// Unix time after which the update is rolled back.
+    deadline: i64,
+}
+
+struct UpdateSettings {
+    public_key: Option<String>,
+    pending_path: PathBuf,
+}
+
+static SETTINGS: OnceLock<UpdateSettings> = OnceLock::new();
+
+/// Remember the release key and where the pending-update marker lives.
+pub fn init(config: &Config) {
+    let _ = SETTINGS.set(UpdateSettings {
+        public_key: config.server.release_public_key.clone(),
+        pending_path: pending_path(&config.agent.data_dir),
+    });
+}
+
+pub fn pending_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(PENDING_FILE)
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    if !value.len().is_multiple_of(2) {
+        return None;
     }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
 
-    // Make executable
-    if let Err(e) = std::process::Command::new("chmod")
-        .args(["+x", tmp_path])
-        .status()
-    {
-        error!("Failed to chmod new binary: {}", e);
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Verify, install and restart into a new agent binary. Does nothing if the
+/// manifest is missing or does not verify.
+pub async fn self_update(download_url: &str, manifest: Option<ReleaseManifest>) {
+    let Some(settings) = SETTINGS.get() else {
+        error!("Self-update is not initialised");
+        return;
+    };
+    if crate::daemon::privileges::is_unprivileged() {
+        error!("Refusing update: the agent runs as daemon.user and cannot replace its binary; update it as root");
+        return;
+    }
+    let Some(manifest) = manifest else {
+        error!("Refusing update without a signed release manifest");
+        return;
+    };
+    let Some(public_key) = settings.public_key.as_deref() else {
+        error!("Refusing update: no release_public_key in [server] config (see publicKey in the server's /api/agent/release)");
+        return;
+    };
+    if let Err(e) = verify_manifest(&manifest, public_key) {
+        error!("Refusing update to {}: {}", manifest.version, e);
         return;
     }
 
-    // Replace current binary
-    if let Err(e) = std::fs::rename(tmp_path, install_path) {
-        // rename may fail across filesystems, try copy+remove
-        if let Err(e2) = std::fs::copy(tmp_path, install_path) {
-            error!("Failed to install new binary: {} (rename: {})", e2, e);
-            return;
-        }
-        let _ = std::fs::remove_file(tmp_path);
+    info!("Starting self-update to version {}", manifest.version);
+
+    if let Err(e) = download_binary(download_url, Path::new(STAGING_PATH), &manifest).await {
+        error!("Failed to download update: {}", e);
+        let _ = std::fs::remove_file(STAGING_PATH);
+        return;
+    }
+    info!("Downloaded and verified new binary ({} bytes, sha256 {})", manifest.size, manifest.sha256);
+
+    if let Err(e) = install(Path::new(STAGING_PATH), Path::new(INSTALL_PATH), Path::new(PREVIOUS_PATH)) {
+        error!("Failed to install new binary: {}", e);
+        let _ = std::fs::remove_file(STAGING_PATH);
+        return;
+    }
+
+    let pending = PendingUpdate {
+        from_version: env!("CARGO_PKG_VERSION").to_string(),
+        to_version: manifest.version.clone(),
+        deadline: chrono::Utc::now().timestamp() + CONFIRM_TIMEOUT.as_secs() as i64,
+    };
+    if let Err(e) = write_pending(&settings.pending_path, &pending) {
+        warn!("Failed to write pending-update marker, no automatic rollback: {}", e);
+    } else {
+        spawn_rollback_watch(&settings.pending_path);
     }
 
     info!("Installed new binary, restarting service...");
@@ -60,16 +149,190 @@ pub async fn self_update(download_url: &str, version: &str) {
     }
 }
 
-async fn download_binary(url: &str, dest: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+/// Download to `dest`, checking size and sha256 against the manifest.
+async fn download_binary(url: &str, dest: &Path, manifest: &ReleaseManifest) -> anyhow::Result<()> {
+    let client = crate::tls::http_client();
     let response = client.get(url).send().await?;
 
     if !response.status().is_success() {
-        return Err(format!("HTTP {}: {}", response.status(), url).into());
+        anyhow::bail!("HTTP {}: {}", response.status(), url);
     }
 
     let bytes = response.bytes().await?;
+    if bytes.len() as u64 != manifest.size {
+        anyhow::bail!("Size mismatch: expected {} bytes, got {}", manifest.size, bytes.len());
+    }
+    let sha256 = to_hex(&Sha256::digest(&bytes));
+    if !sha256.eq_ignore_ascii_case(&manifest.sha256) {
+        anyhow::bail!("Checksum mismatch: expected {}, got {}", manifest.sha256, sha256);
+    }
+
     std::fs::write(dest, &bytes)?;
+    Ok(())
+}
+
+/// Move `installed` aside to `previous` and `staged` into its place. Both
+/// renames stay within one directory, so each is atomic.
+fn install(staged: &Path, installed: &Path, previous: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o755))?;
 
+    std::fs::rename(installed, previous)?;
+    if let Err(e) = std::fs::rename(staged, installed) {
+        let _ = std::fs::rename(previous, installed);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn write_pending(path: &Path, pending: &PendingUpdate) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(pending)?)?;
     Ok(())
 }
+
+fn read_pending(path: &Path) -> Option<PendingUpdate> {
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+/// Run the watcher from the previous binary in its own transient unit; a
+/// child of this process would be killed with the service on restart.
+fn spawn_rollback_watch(pending_path: &Path) {
+    let result = std::process::Command::new("systemd-run")
+        .args(["--unit", "backup-agent-rollback", "--collect", PREVIOUS_PATH, "rollback-watch", "--marker"])
+        .arg(pending_path)
+        .status();
+    match result {
+        Ok(status) if status.success() => info!("Rollback watcher started"),
+        Ok(status) => warn!("systemd-run exited with {}, no automatic rollback", status),
+        Err(e) => warn!("Failed to start rollback watcher, no automatic rollback: {}", e),
+    }
+}
+
+/// Called once the agent has registered with the server: the running binary
+/// starts and talks to the server, so drop the marker and keep it. The version
+/// it reports is only logged, the manifest's being whatever the release was
+/// labelled with.
+pub fn confirm_pending_update() {
+    let Some(settings) = SETTINGS.get() else { return };
+    let Some(pending) = read_pending(&settings.pending_path) else { return };
+
+    if pending.to_version != env!("CARGO_PKG_VERSION") {
+        warn!(
+            "Update to {} registered as version {}",
+            pending.to_version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    match std::fs::remove_file(&settings.pending_path) {
+        Ok(()) => info!("Update from {} to {} confirmed", pending.from_version, pending.to_version),
+        Err(e) => warn!("Failed to remove pending-update marker: {}", e),
+    }
+}
+
+/// Entry point of `backup-agent rollback-watch`: wait until the update is
+/// confirmed or its deadline passes, and restore the previous binary in the
+/// latter case.
+pub async fn watch_rollback(marker: &Path) -> anyhow::Result<()> {
+    loop {
+        let Some(pending) = read_pending(marker) else {
+            info!("Update confirmed, rollback watcher exiting");
+            return Ok(());
+        };
+        if chrono::Utc::now().timestamp() >= pending.deadline {
+            warn!(
+                "Version {} did not register within {}s, rolling back to {}",
+                pending.to_version,
+                CONFIRM_TIMEOUT.as_secs(),
+                pending.from_version
+            );
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+
+    std::fs::rename(PREVIOUS_PATH, INSTALL_PATH)?;
+    std::fs::remove_file(marker)?;
+    let status = std::process::Command::new("systemctl")
+        .args(["restart", "backup-agent"])
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("systemctl restart exited with: {}", status);
+    }
+    info!("Rolled back to the previous agent binary");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use tempfile::TempDir;
+
+    fn signed(key: &Ed25519KeyPair, version: &str) -> ReleaseManifest {
+        let mut manifest = ReleaseManifest {
+            version: version.to_string(),
+            sha256: to_hex(&Sha256::digest(b"binary")),
+            size: 6,
+            signature: String::new(),
+        };
+        manifest.signature = to_hex(key.sign(manifest.signed_message().as_bytes()).as_ref());
+        manifest
+    }
+
+    #[test]
+    fn test_manifest_verification() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = to_hex(key.public_key().as_ref());
+
+        let manifest = signed(&key, "1.2.0");
+        assert!(verify_manifest(&manifest, &public_key).is_ok());
+
+        let mut tampered = manifest.clone();
+        tampered.sha256 = to_hex(&Sha256::digest(b"other binary"));
+        assert!(verify_manifest(&tampered, &public_key).is_err());
+
+        let other = Ed25519KeyPair::from_pkcs8(
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap().as_ref(),
+        )
+        .unwrap();
+        assert!(verify_manifest(&manifest, &to_hex(other.public_key().as_ref())).is_err());
+        assert!(verify_manifest(&manifest, "not hex").is_err());
+    }
+
+    #[test]
+    fn test_install_keeps_previous_binary() {
+        let dir = TempDir::new().unwrap();
+        let staged = dir.path().join("backup-agent.new");
+        let installed = dir.path().join("backup-agent");
+        let previous = dir.path().join("backup-agent.prev");
+        std::fs::write(&staged, b"new").unwrap();
+        std::fs::write(&installed, b"old").unwrap();
+
+        install(&staged, &installed, &previous).unwrap();
+
+        assert_eq!(std::fs::read(&installed).unwrap(), b"new");
+        assert_eq!(std::fs::read(&previous).unwrap(), b"old");
+        assert!(!staged.exists());
+    }
+
+    #[test]
+    fn test_pending_marker_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = pending_path(dir.path());
+        let pending = PendingUpdate {
+            from_version: "1.0.0".into(),
+            to_version: "1.1.0".into(),
+            deadline: 42,
+        };
+        write_pending(&path, &pending).unwrap();
+
+        let read = read_pending(&path).unwrap();
+        assert_eq!(read.to_version, "1.1.0");
+        assert_eq!(read.deadline, 42);
+    }
+}
@@ -0,0 +1,157 @@
+//! Shipping job warnings and errors to the server.
+//!
+//! Each backup runs inside a `backup` span carrying its `job_id`. Warnings and
+//! errors logged inside that span (including in tasks instrumented with it)
+//! are sent to the server as `agent:log` messages, which appends them to the
+//! run's log. Lines outside a job are only written locally.
+
+use crate::ws::WsEvent;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Lines shipped per run; the rest only go to the local log.
+pub const MAX_LINES_PER_RUN: usize = 1000;
+
+static SINK: OnceLock<broadcast::Sender<WsEvent>> = OnceLock::new();
+
+/// Start shipping job log lines through the agent's event channel (which the
+/// WebSocket client forwards to the server).
+pub fn ship_to(tx: broadcast::Sender<WsEvent>) {
+    let _ = SINK.set(tx);
+}
+
+/// The job a span belongs to, stored in the span's extensions.
+struct JobScope {
+    job_id: String,
+    shipped: AtomicUsize,
+}
+
+pub struct JobLogLayer;
+
+impl<S> Layer<S> for JobLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = JobIdVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(job_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(JobScope {
+                job_id,
+                shipped: AtomicUsize::new(0),
+            });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+        let Some(tx) = SINK.get() else { return };
+        let Some(scope) = ctx.event_scope(event) else { return };
+
+        for span in scope {
+            let extensions = span.extensions();
+            let Some(job) = extensions.get::<JobScope>() else { continue };
+
+            let shipped = job.shipped.fetch_add(1, Ordering::Relaxed);
+            let message = match shipped {
+                n if n < MAX_LINES_PER_RUN => format_event(event),
+                n if n == MAX_LINES_PER_RUN => format!(
+                    "More than {} warnings and errors; the rest are only in the agent's log",
+                    MAX_LINES_PER_RUN
+                ),
+                _ => return,
+            };
+            let _ = tx.send(WsEvent::LogMessage {
+                level: level.to_string(),
+                message,
+                job_id: Some(job.job_id.clone()),
+                timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            });
+            return;
+        }
+    }
+}
+
+fn format_event(event: &Event<'_>) -> String {
+    let mut visitor = MessageVisitor::default();
+    event.record(&mut visitor);
+    visitor.message + &visitor.fields
+}
+
+struct JobIdVisitor(Option<String>);
+
+impl Visit for JobIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "job_id" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "job_id" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{:?}", value);
+            }
+            "job_id" => {}
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_ships_job_warnings_only() {
+        let (tx, mut rx) = broadcast::channel(16);
+        ship_to(tx);
+        let subscriber = tracing_subscriber::registry().with(JobLogLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        tracing::warn!("outside any job");
+        async {
+            tracing::info!("scanning");
+            tracing::warn!(code = 13, "Failed to process file {}", "/srv/a");
+        }
+        .instrument(tracing::info_span!("backup", job_id = %"j1"))
+        .await;
+
+        let WsEvent::LogMessage { level, message, job_id, timestamp } = rx.try_recv().unwrap() else {
+            panic!("not a log message");
+        };
+        assert_eq!(level, "WARN");
+        assert_eq!(message, "Failed to process file /srv/a code=13");
+        assert_eq!(job_id.as_deref(), Some("j1"));
+        assert!(timestamp.is_some());
+        assert!(rx.try_recv().is_err());
+    }
+}
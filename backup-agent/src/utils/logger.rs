@@ -1,17 +1,72 @@
 //! Logging configuration using tracing.
 
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use crate::config::LogConfig;
+use crate::utils::job_log::JobLogLayer;
+use crate::utils::rotating_file::RotatingFile;
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::{reload, util::SubscriberInitExt, EnvFilter, Layer, Registry};
 
-/// Initialize logging with the specified level
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type OutputLayer = Box<dyn Layer<Filtered> + Send + Sync>;
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Initialize logging to stdout with the specified level
 pub fn init(level: &str) -> anyhow::Result<()> {
+    install(level, tracing_subscriber::fmt::layer().boxed())
+}
+
+/// Initialize logging to the output configured in `[log]`, with the
+/// specified level. Warnings and errors of running jobs are also shipped to
+/// the server (see [`crate::utils::job_log`]).
+pub fn init_with_config(config: &LogConfig, level: &str) -> anyhow::Result<()> {
+    let output: OutputLayer = match config.output.as_str() {
+        "stdout" => tracing_subscriber::fmt::layer().boxed(),
+        "file" => {
+            let file = RotatingFile::open(&config.file, config.max_size_mb * 1024 * 1024, config.max_files)
+                .map_err(|e| anyhow::anyhow!("Cannot open log file {}: {}", config.file.display(), e))?;
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .boxed()
+        }
+        "journald" => match tracing_journald::layer() {
+            Ok(layer) => layer.with_syslog_identifier("backup-agent".into()).boxed(),
+            Err(e) => {
+                // Not under systemd: log to stdout rather than nowhere
+                install(level, tracing_subscriber::fmt::layer().boxed())?;
+                tracing::warn!("journald is not available ({}), logging to stdout", e);
+                return Ok(());
+            }
+        },
+        other => anyhow::bail!("Unknown log output {:?} (expected stdout, file or journald)", other),
+    };
+    install(level, output)
+}
+
+fn install(level: &str, output: OutputLayer) -> anyhow::Result<()> {
     let filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new(level))
         .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
 
     tracing_subscriber::registry()
         .with(filter)
-        .with(tracing_subscriber::fmt::layer())
+        .with(output)
+        .with(JobLogLayer)
         .init();
 
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Change the log level of the running agent.
+pub fn set_level(level: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(level)?;
+    FILTER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Logging is not initialized"))?
+        .reload(filter)?;
     Ok(())
 }
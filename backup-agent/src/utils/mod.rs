@@ -1,6 +1,8 @@
 //! Utility modules for the backup agent.
 
 pub mod errors;
+pub mod job_log;
 pub mod logger;
+pub mod rotating_file;
 
 pub use errors::{AgentError, Result};
@@ -1,67 +1,23 @@
 //! Reverse WebSocket client — connects to the backup server.
 //!
 //! The agent initiates and maintains a persistent WebSocket connection to the
-//! backup server at `ws://{server_url}/ws/agent`. This is the primary
-//! communication channel for:
+//! backup server at `ws://{server_url}/ws/agent` (or `wss://`, pinned via
+//! [`crate::tls`]). This is the primary communication channel for:
 //! - Registration handshake (agent identity)
-//! - Receiving backup commands (start, cancel)
+//! - Receiving backup commands (start, cancel, status)
 //! - Receiving filesystem browse requests
 //! - Receiving update commands
 //! - Forwarding local WsEvent broadcasts to the server (progress, completion)
 
 use crate::api::AppState;
 use crate::api::filesystem;
-use crate::ws::WsEvent;
+use crate::ws::{protocol, WsEvent};
+use backup_protocol::{ReleaseManifest, ServerMessage, StartBackupPayload};
 use futures_util::{SinkExt, StreamExt};
-use serde::Deserialize;
 use std::path::PathBuf;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::Message};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, warn};
-
-/// Commands received from the backup server via WebSocket.
-#[derive(Debug, Clone, Deserialize)]
-#[serde(tag = "type", content = "payload")]
-pub enum ServerCommand {
-    #[serde(rename = "backup:start")]
-    StartBackup(StartBackupPayload),
-
-    #[serde(rename = "backup:cancel")]
-    CancelBackup { job_id: String },
-
-    #[serde(rename = "fs:browse")]
-    BrowseFilesystem {
-        path: String,
-        request_id: String,
-    },
-
-    #[serde(rename = "agent:update")]
-    UpdateAgent {
-        download_path: String,
-        version: String,
-    },
-
-    /// Registration acknowledgment from server
-    #[serde(rename = "agent:register:ok")]
-    RegisterOk { server_id: String },
-
-    #[serde(rename = "agent:register:error")]
-    RegisterError { error: String },
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct StartBackupPayload {
-    pub job_id: String,
-    pub paths: Vec<String>,
-    #[serde(default)]
-    pub server_url: Option<String>,
-    #[serde(default)]
-    pub token: Option<String>,
-    #[serde(default)]
-    pub incremental: bool,
-    #[serde(default)]
-    pub manifest_url: Option<String>,
-}
+use tracing::{error, info, info_span, warn, Instrument};
 
 /// Reverse WebSocket client that connects to the backup server.
 pub struct AgentWsClient {
@@ -133,7 +89,8 @@ impl AgentWsClient {
 
         info!("Connecting to server WebSocket: {}", url);
 
-        let (ws_stream, _) = connect_async(&url).await?;
+        let (ws_stream, _) =
+            connect_async_tls_with_config(&url, None, false, crate::tls::ws_connector()).await?;
         let (mut write, mut read) = ws_stream.split();
 
         info!("Connected to server WebSocket");
@@ -144,17 +101,9 @@ impl AgentWsClient {
             .and_then(|h| h.into_string().ok())
             .unwrap_or_else(|| "unknown".to_string());
 
-        let register_msg = serde_json::json!({
-            "type": "agent:register",
-            "payload": {
-                "hostname": hostname,
-                "version": env!("CARGO_PKG_VERSION"),
-                "server_id": self.server_id,
-                "agent_id": self.agent_id,
-            }
-        });
+        let register_msg = protocol::register_message(&hostname, self.server_id.as_deref(), &self.agent_id);
 
-        write.send(Message::Text(register_msg.to_string().into())).await?;
+        write.send(Message::Text(serde_json::to_string(&register_msg)?)).await?;
         info!("Registration handshake sent");
 
         // Subscribe to local broadcast channel to forward events to server
@@ -221,26 +170,34 @@ impl AgentWsClient {
 
 /// Handle a message received from the backup server.
 async fn handle_server_message(text: &str, app_state: &AppState, server_url: &str) {
-    let parsed: Result<ServerCommand, _> = serde_json::from_str(text);
+    let parsed: Result<ServerMessage, _> = serde_json::from_str(text);
 
     match parsed {
-        Ok(ServerCommand::StartBackup(payload)) => {
+        Ok(ServerMessage::StartBackup(payload)) => {
             handle_start_backup(payload, app_state, server_url).await;
         }
-        Ok(ServerCommand::CancelBackup { job_id }) => {
+        Ok(ServerMessage::CancelBackup { job_id }) => {
             handle_cancel_backup(&job_id, app_state).await;
         }
-        Ok(ServerCommand::BrowseFilesystem { path, request_id }) => {
+        Ok(ServerMessage::JobStatus { request_id }) => {
+            handle_job_status(&request_id, app_state).await;
+        }
+        Ok(ServerMessage::BrowseFilesystem { path, request_id }) => {
             handle_browse_filesystem(&path, &request_id, app_state).await;
         }
-        Ok(ServerCommand::UpdateAgent { download_path, version }) => {
+        Ok(ServerMessage::UpdateAgent { download_path, version, manifest }) => {
             let download_url = format!("{}{}", server_url.trim_end_matches('/'), download_path);
-            handle_update_agent(&download_url, &version).await;
+            handle_update_agent(&download_url, &version, manifest).await;
         }
-        Ok(ServerCommand::RegisterOk { server_id }) => {
-            info!("Registration confirmed for server_id: {}", server_id);
+        Ok(ServerMessage::RegisterOk { server_id, protocol_version }) => {
+            info!(
+                "Registration confirmed for server_id: {} (protocol {})",
+                server_id,
+                protocol_version.unwrap_or(1)
+            );
+            crate::update::confirm_pending_update();
         }
-        Ok(ServerCommand::RegisterError { error }) => {
+        Ok(ServerMessage::RegisterError { error }) => {
             error!("Registration failed: {}", error);
         }
         Err(e) => {
@@ -252,7 +209,12 @@ async fn handle_server_message(text: &str, app_state: &AppState, server_url: &st
 async fn handle_start_backup(payload: StartBackupPayload, app_state: &AppState, ws_server_url: &str) {
     info!("Received backup:start command for job: {}", payload.job_id);
 
-    let paths: Vec<PathBuf> = payload.paths.iter().map(PathBuf::from).collect();
+    // Labelled roots when the server sends them, otherwise the flat layout
+    let (paths, labels): (Vec<PathBuf>, Vec<String>) = if payload.roots.is_empty() {
+        (payload.paths.iter().map(PathBuf::from).collect(), Vec::new())
+    } else {
+        payload.roots.into_iter().map(|root| (PathBuf::from(root.path), root.label)).unzip()
+    };
     let destination = PathBuf::from(format!("/tmp/backup-{}", payload.job_id));
     let server_url = payload.server_url
         .filter(|s| !s.is_empty())
@@ -261,10 +223,17 @@ async fn handle_start_backup(payload: StartBackupPayload, app_state: &AppState,
     let job = crate::executor::BackupJob {
         job_id: payload.job_id.clone(),
         paths,
+        labels,
         destination,
         server_url,
         incremental: payload.incremental,
         manifest_url: payload.manifest_url,
+        binary_manifest: payload.binary_manifest,
+        carry_forward: payload.carry_forward,
+        detect_renames: payload.detect_renames,
+        compression: payload.compression,
+        max_concurrency: payload.max_concurrency.map(|max| max as usize),
+        max_file_errors: payload.max_file_errors,
     };
 
     let cancel_token = CancellationToken::new();
@@ -278,6 +247,8 @@ async fn handle_start_backup(payload: StartBackupPayload, app_state: &AppState,
     let job_id = payload.job_id.clone();
     let tracker = app_state.job_tracker.clone();
 
+    // Warnings and errors inside this span are shipped to the run's log
+    let span = info_span!("backup", job_id = %job_id);
     let handle = tokio::spawn(async move {
         match executor.execute(job).await {
             Ok(result) => {
@@ -292,7 +263,7 @@ async fn handle_start_backup(payload: StartBackupPayload, app_state: &AppState,
                 tracker.complete(&job_id).await;
             }
         }
-    });
+    }.instrument(span));
 
     app_state
         .job_tracker
@@ -310,13 +281,26 @@ async fn handle_cancel_backup(job_id: &str, app_state: &AppState) {
     }
 }
 
+async fn handle_job_status(request_id: &str, app_state: &AppState) {
+    let running_jobs = app_state.job_tracker.job_ids().await;
+    info!("Received backup:status, {} job(s) running", running_jobs.len());
+
+    let ws_state = app_state.ws_state.read().await;
+    ws_state.broadcast(WsEvent::JobStatusResponse {
+        request_id: request_id.to_string(),
+        running_jobs,
+    });
+}
+
 async fn handle_browse_filesystem(path: &str, request_id: &str, app_state: &AppState) {
     info!("Received fs:browse for path: {}", path);
 
-    let result = filesystem::browse_path(path);
-
-    let (entries, error) = match result {
+    let (entries, error) = match filesystem::browse_path(path) {
         Ok(entries) => (entries, None),
+        Err(filesystem::BrowseError::Policy(violation)) => {
+            crate::policy::report(&app_state.ws_state, "browse", None, &violation).await;
+            (vec![], Some(violation.to_string()))
+        }
         Err(e) => (vec![], Some(e.to_string())),
     };
 
@@ -329,26 +313,11 @@ async fn handle_browse_filesystem(path: &str, request_id: &str, app_state: &AppS
     });
 }
 
-async fn handle_update_agent(download_url: &str, version: &str) {
+async fn handle_update_agent(
+    download_url: &str,
+    version: &str,
+    manifest: Option<ReleaseManifest>,
+) {
     info!("Received agent:update command: version={}, url={}", version, download_url);
-    crate::update::self_update(download_url, version).await;
-}
-
-/// Convert a WsEvent (serde externally-tagged enum) to the `{"type": ..., "payload": ...}`
-/// format expected by the backup server's agent registry.
-fn event_to_server_json(event: &WsEvent) -> Result<String, serde_json::Error> {
-    // Serialize to Value first — externally tagged produces {"event_name": {fields...}}
-    let value = serde_json::to_value(event)?;
-    if let serde_json::Value::Object(map) = value {
-        // The single key is the event type, value is the payload
-        if let Some((event_type, payload)) = map.into_iter().next() {
-            let msg = serde_json::json!({
-                "type": event_type,
-                "payload": payload,
-            });
-            return serde_json::to_string(&msg);
-        }
-    }
-    // Fallback: serialize as-is
-    serde_json::to_string(event)
+    crate::update::self_update(download_url, manifest).await;
 }
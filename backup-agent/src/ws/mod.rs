@@ -6,13 +6,14 @@
 
 pub mod client;
 pub mod handler;
+pub mod protocol;
 
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
     response::IntoResponse,
 };
 use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
 use tracing::{debug, error, info, warn};
@@ -20,105 +21,12 @@ use tracing::{debug, error, info, warn};
 /// Maximum number of queued messages per subscriber
 const BROADCAST_CAPACITY: usize = 1000;
 
-/// WebSocket event types sent from agent to server
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", content = "payload")]
-pub enum WsEvent {
-    /// Backup job progress update
-    #[serde(rename = "backup:progress")]
-    BackupProgress(BackupProgressPayload),
-
-    /// Backup job started
-    #[serde(rename = "backup:started")]
-    BackupStarted { job_id: String },
-
-    /// Backup job completed successfully
-    #[serde(rename = "backup:completed")]
-    BackupCompleted {
-        job_id: String,
-        total_bytes: u64,
-        total_files: usize,
-        #[serde(default)]
-        transferred_bytes: u64,
-        #[serde(default)]
-        transferred_files: usize,
-        #[serde(default)]
-        unchanged_files: usize,
-        #[serde(default)]
-        unchanged_bytes: u64,
-        #[serde(default)]
-        deleted_files: usize,
-        #[serde(default)]
-        backup_type: String,
-    },
-
-    /// Backup job failed
-    #[serde(rename = "backup:failed")]
-    BackupFailed { job_id: String, error: String },
-
-    /// Agent status update
-    #[serde(rename = "agent:status")]
-    AgentStatus(AgentStatusPayload),
-
-    /// Log message
-    #[serde(rename = "agent:log")]
-    LogMessage { level: String, message: String },
-
-    /// Filesystem browse response (sent to server via reverse WS)
-    #[serde(rename = "fs:browse:response")]
-    FsBrowseResponse {
-        request_id: String,
-        entries: Vec<crate::api::filesystem::FsEntry>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        error: Option<String>,
-    },
-}
-
-/// Progress information for a backup job
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BackupProgressPayload {
-    pub job_id: String,
-    pub percent: f64,
-    pub transferred_bytes: u64,
-    pub total_bytes: u64,
-    pub bytes_per_second: u64,
-    pub eta_seconds: u64,
-    pub current_file: Option<String>,
-    pub files_processed: usize,
-    pub total_files: usize,
-    pub speed: String,
-    // Per-file progress (legacy single file)
-    pub current_file_bytes: u64,
-    pub current_file_total: u64,
-    pub current_file_percent: f64,
-    // Active parallel transfers
-    #[serde(default)]
-    pub active_files: Vec<ActiveFileProgress>,
-    // Incremental backup stats
-    #[serde(default)]
-    pub skipped_files: usize,
-    #[serde(default)]
-    pub skipped_bytes: u64,
-    #[serde(default)]
-    pub backup_type: String,
-}
-
-/// Progress for a single active file transfer
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ActiveFileProgress {
-    pub path: String,
-    pub transferred_bytes: u64,
-    pub total_bytes: u64,
-    pub percent: f64,
-}
-
-/// Agent status information
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AgentStatusPayload {
-    pub status: String, // "idle", "running", "paused"
-    pub active_jobs: usize,
-    pub uptime_secs: u64,
-}
+/// Events the agent broadcasts to local subscribers and forwards to the server.
+pub use backup_protocol::AgentMessage as WsEvent;
+pub use backup_protocol::{
+    ActiveFileProgress, AgentStatusPayload, BackupCompletedPayload, BackupProgressPayload, FileErrorPayload,
+    ScanProgressPayload,
+};
 
 /// WebSocket command types received from server
 #[derive(Debug, Clone, Deserialize)]
@@ -275,6 +183,7 @@ mod tests {
             skipped_files: 0,
             skipped_bytes: 0,
             backup_type: "full".to_string(),
+            concurrency: 4,
         });
 
         let json = serde_json::to_string(&event).unwrap();
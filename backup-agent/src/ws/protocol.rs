@@ -0,0 +1,52 @@
+//! Protocol version and capabilities announced in `agent:register`.
+//!
+//! The messages themselves live in the `backup-protocol` crate shared with the
+//! server; this module only decides what this build announces.
+
+use backup_protocol::{AgentMessage, RegisterPayload};
+pub use backup_protocol::{
+    CAP_BINARY_MANIFEST, CAP_CARRY_FORWARD, CAP_COMPRESSION_GZIP, CAP_COMPRESSION_LZ4, CAP_COMPRESSION_ZSTD, CAP_DELTA,
+    CAP_HOOKS, CAP_INCREMENTAL, CAP_RENAMES, CAP_RESTORE, CAP_ROOTS, PROTOCOL_VERSION,
+};
+
+/// Features this build supports.
+pub fn capabilities() -> Vec<&'static str> {
+    vec![
+        CAP_INCREMENTAL,
+        CAP_COMPRESSION_ZSTD,
+        CAP_COMPRESSION_LZ4,
+        CAP_COMPRESSION_GZIP,
+        CAP_ROOTS,
+        CAP_BINARY_MANIFEST,
+        CAP_CARRY_FORWARD,
+        CAP_RENAMES,
+    ]
+}
+
+/// The `agent:register` handshake.
+pub fn register_message(hostname: &str, server_id: Option<&str>, agent_id: &str) -> AgentMessage {
+    AgentMessage::Register(RegisterPayload {
+        hostname: hostname.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        server_id: server_id.map(str::to_string),
+        agent_id: agent_id.to_string(),
+        protocol_version: Some(PROTOCOL_VERSION),
+        capabilities: capabilities().into_iter().map(str::to_string).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_message_announces_protocol() {
+        let AgentMessage::Register(payload) = register_message("web-01", Some("srv-1"), "agent-1") else {
+            panic!("not a register message");
+        };
+        assert_eq!(payload.protocol_version, Some(PROTOCOL_VERSION));
+        assert_eq!(payload.server_id.as_deref(), Some("srv-1"));
+        assert!(payload.capabilities.iter().any(|c| c == CAP_INCREMENTAL));
+        assert!(!payload.capabilities.iter().any(|c| c == CAP_RESTORE));
+    }
+}
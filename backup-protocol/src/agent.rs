@@ -0,0 +1,414 @@
+//! Messages sent by the agent.
+
+use serde::{Deserialize, Serialize};
+
+/// Everything an agent sends over the WebSocket. The agent also broadcasts
+/// these to clients of its local `/ws` endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum AgentMessage {
+    /// Handshake identifying the agent; the server answers with
+    /// [`ServerMessage::RegisterOk`](crate::ServerMessage::RegisterOk) or `RegisterError`.
+    #[serde(rename = "agent:register")]
+    Register(RegisterPayload),
+
+    /// Backup job progress update
+    #[serde(rename = "backup:progress")]
+    BackupProgress(BackupProgressPayload),
+
+    /// Files scanned so far; sent while the scan runs alongside the uploads
+    #[serde(rename = "backup:scan:progress")]
+    BackupScanProgress(ScanProgressPayload),
+
+    /// Every root has been scanned; the totals are final
+    #[serde(rename = "backup:scan:completed")]
+    BackupScanCompleted(ScanProgressPayload),
+
+    /// Backup job started
+    #[serde(rename = "backup:started")]
+    BackupStarted { job_id: String },
+
+    /// Backup job completed successfully
+    #[serde(rename = "backup:completed")]
+    BackupCompleted(BackupCompletedPayload),
+
+    /// A file could not be backed up; the run goes on without it
+    #[serde(rename = "backup:file:error")]
+    BackupFileError(FileErrorPayload),
+
+    /// Backup job failed
+    #[serde(rename = "backup:failed")]
+    BackupFailed { job_id: String, error: String },
+
+    /// Agent status update
+    #[serde(rename = "agent:status")]
+    AgentStatus(AgentStatusPayload),
+
+    /// Log message. Warnings and errors logged while running a job carry its
+    /// ID and are appended to the run's log on the server.
+    #[serde(rename = "agent:log")]
+    LogMessage {
+        level: String,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        job_id: Option<String>,
+        /// RFC 3339 time the agent logged the line
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<String>,
+    },
+
+    /// Jobs currently executing on this agent (reply to `backup:status`)
+    #[serde(rename = "backup:status:response")]
+    JobStatusResponse {
+        request_id: String,
+        running_jobs: Vec<String>,
+    },
+
+    /// The agent refused a path because of its local path policy
+    #[serde(rename = "agent:policy:violation")]
+    PolicyViolation {
+        /// `browse` or `backup`
+        action: String,
+        path: String,
+        reason: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        job_id: Option<String>,
+    },
+
+    /// Directory listing (reply to `fs:browse`)
+    #[serde(rename = "fs:browse:response")]
+    FsBrowseResponse {
+        request_id: String,
+        entries: Vec<FsEntry>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl AgentMessage {
+    /// The `type` of the message on the wire.
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            AgentMessage::Register(_) => "agent:register",
+            AgentMessage::BackupProgress(_) => "backup:progress",
+            AgentMessage::BackupScanProgress(_) => "backup:scan:progress",
+            AgentMessage::BackupScanCompleted(_) => "backup:scan:completed",
+            AgentMessage::BackupStarted { .. } => "backup:started",
+            AgentMessage::BackupCompleted(_) => "backup:completed",
+            AgentMessage::BackupFileError(_) => "backup:file:error",
+            AgentMessage::BackupFailed { .. } => "backup:failed",
+            AgentMessage::AgentStatus(_) => "agent:status",
+            AgentMessage::LogMessage { .. } => "agent:log",
+            AgentMessage::JobStatusResponse { .. } => "backup:status:response",
+            AgentMessage::PolicyViolation { .. } => "agent:policy:violation",
+            AgentMessage::FsBrowseResponse { .. } => "fs:browse:response",
+        }
+    }
+
+    /// The request a response answers, for replies to server requests.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            AgentMessage::JobStatusResponse { request_id, .. }
+            | AgentMessage::FsBrowseResponse { request_id, .. } => Some(request_id),
+            _ => None,
+        }
+    }
+
+    /// The backup job a lifecycle event or job log line concerns.
+    pub fn job_id(&self) -> Option<&str> {
+        match self {
+            AgentMessage::BackupProgress(p) => Some(&p.job_id),
+            AgentMessage::BackupScanProgress(p) | AgentMessage::BackupScanCompleted(p) => Some(&p.job_id),
+            AgentMessage::BackupStarted { job_id } | AgentMessage::BackupFailed { job_id, .. } => Some(job_id),
+            AgentMessage::BackupCompleted(p) => Some(&p.job_id),
+            AgentMessage::BackupFileError(p) => Some(&p.job_id),
+            AgentMessage::LogMessage { job_id, .. } => job_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of `agent:register`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegisterPayload {
+    pub hostname: String,
+    /// Agent release version
+    pub version: String,
+    pub server_id: Option<String>,
+    #[serde(default)]
+    pub agent_id: String,
+    /// Absent from agents that predate protocol versioning
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// Progress information for a backup job
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupProgressPayload {
+    pub job_id: String,
+    pub percent: f64,
+    pub transferred_bytes: u64,
+    pub total_bytes: u64,
+    pub bytes_per_second: u64,
+    pub eta_seconds: u64,
+    pub current_file: Option<String>,
+    pub files_processed: usize,
+    pub total_files: usize,
+    pub speed: String,
+    // Per-file progress (legacy single file)
+    pub current_file_bytes: u64,
+    pub current_file_total: u64,
+    pub current_file_percent: f64,
+    // Active parallel transfers
+    #[serde(default)]
+    pub active_files: Vec<ActiveFileProgress>,
+    // Incremental backup stats
+    #[serde(default)]
+    pub skipped_files: usize,
+    #[serde(default)]
+    pub skipped_bytes: u64,
+    #[serde(default)]
+    pub backup_type: String,
+    /// Uploads the agent currently allows at once, tuned from their
+    /// throughput and latency (0 from older agents, and once uploads are over)
+    #[serde(default)]
+    pub concurrency: usize,
+}
+
+/// How far the scan of a backup job's roots has got. Changed files are
+/// uploaded as they are found, so `changed_*` grow with the upload totals.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanProgressPayload {
+    pub job_id: String,
+    pub scanned_files: usize,
+    pub scanned_bytes: u64,
+    /// New or modified files, queued for upload
+    pub changed_files: usize,
+    pub changed_bytes: u64,
+    /// Files matching the previous version, linked on the server
+    pub unchanged_files: usize,
+    pub unchanged_bytes: u64,
+    /// The file scanned last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_path: Option<String>,
+}
+
+/// Progress for a single active file transfer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActiveFileProgress {
+    pub path: String,
+    pub transferred_bytes: u64,
+    pub total_bytes: u64,
+    pub percent: f64,
+}
+
+/// Final statistics of a backup run
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupCompletedPayload {
+    pub job_id: String,
+    pub total_bytes: u64,
+    pub total_files: usize,
+    #[serde(default)]
+    pub transferred_bytes: u64,
+    #[serde(default)]
+    pub transferred_files: usize,
+    #[serde(default)]
+    pub unchanged_files: usize,
+    #[serde(default)]
+    pub unchanged_bytes: u64,
+    #[serde(default)]
+    pub deleted_files: usize,
+    /// `full` or `incremental`
+    #[serde(default)]
+    pub backup_type: String,
+    /// Files that could not be backed up (each reported as `backup:file:error`)
+    #[serde(default)]
+    pub failed_files: usize,
+}
+
+/// A file the agent could not back up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileErrorPayload {
+    pub job_id: String,
+    pub path: String,
+    /// What failed: `scan`, `stat`, `open`, `read` or `upload`
+    pub operation: String,
+    /// OS error number, when the failure came from the OS
+    #[serde(default)]
+    pub errno: Option<i32>,
+    pub message: String,
+}
+
+/// Agent status information
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentStatusPayload {
+    pub status: String, // "idle", "running", "paused"
+    pub active_jobs: usize,
+    pub uptime_secs: u64,
+}
+
+/// A directory entry returned by `fs:browse`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsEntry {
+    /// File name to show ([`crate::path::display`])
+    pub name: String,
+    /// Absolute path, encoded like [`crate::path`]; pass it back to `fs:browse`
+    pub path: String,
+    /// `path` to show, and to back up as a job path
+    #[serde(default)]
+    pub display_path: String,
+    /// `directory`, `file` or `symlink`
+    #[serde(rename = "type")]
+    pub entry_type: String,
+    pub size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip(message: &AgentMessage) -> serde_json::Value {
+        let value = serde_json::to_value(message).unwrap();
+        let parsed: AgentMessage = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(&parsed, message);
+        assert_eq!(value["type"], message.message_type());
+        value
+    }
+
+    #[test]
+    fn test_agent_messages_round_trip() {
+        let messages = vec![
+            AgentMessage::Register(RegisterPayload {
+                hostname: "web-01".into(),
+                version: "1.0.0".into(),
+                server_id: Some("srv-1".into()),
+                agent_id: "agent-1".into(),
+                protocol_version: Some(crate::PROTOCOL_VERSION),
+                capabilities: vec![crate::CAP_INCREMENTAL.into()],
+            }),
+            AgentMessage::BackupProgress(BackupProgressPayload {
+                job_id: "j1".into(),
+                percent: 50.0,
+                transferred_bytes: 10,
+                total_bytes: 20,
+                bytes_per_second: 5,
+                eta_seconds: 2,
+                current_file: Some("/etc/hosts".into()),
+                files_processed: 1,
+                total_files: 2,
+                speed: "5 B/s".into(),
+                current_file_bytes: 1,
+                current_file_total: 2,
+                current_file_percent: 50.0,
+                active_files: vec![ActiveFileProgress {
+                    path: "/etc/hosts".into(),
+                    transferred_bytes: 1,
+                    total_bytes: 2,
+                    percent: 50.0,
+                }],
+                skipped_files: 0,
+                skipped_bytes: 0,
+                backup_type: "full".into(),
+                concurrency: 8,
+            }),
+            AgentMessage::BackupScanProgress(ScanProgressPayload {
+                job_id: "j1".into(),
+                scanned_files: 3,
+                scanned_bytes: 30,
+                changed_files: 1,
+                changed_bytes: 10,
+                unchanged_files: 2,
+                unchanged_bytes: 20,
+                current_path: Some("/etc/hosts".into()),
+            }),
+            AgentMessage::BackupScanCompleted(ScanProgressPayload {
+                job_id: "j1".into(),
+                scanned_files: 3,
+                ..Default::default()
+            }),
+            AgentMessage::BackupStarted { job_id: "j1".into() },
+            AgentMessage::BackupCompleted(BackupCompletedPayload {
+                job_id: "j1".into(),
+                total_bytes: 20,
+                total_files: 2,
+                backup_type: "incremental".into(),
+                ..Default::default()
+            }),
+            AgentMessage::BackupFileError(FileErrorPayload {
+                job_id: "j1".into(),
+                path: "/srv/gone.txt".into(),
+                operation: "open".into(),
+                errno: Some(2),
+                message: "No such file or directory (os error 2)".into(),
+            }),
+            AgentMessage::BackupFailed { job_id: "j1".into(), error: "boom".into() },
+            AgentMessage::AgentStatus(AgentStatusPayload { status: "idle".into(), active_jobs: 0, uptime_secs: 3 }),
+            AgentMessage::LogMessage { level: "info".into(), message: "hi".into(), job_id: None, timestamp: None },
+            AgentMessage::LogMessage {
+                level: "WARN".into(),
+                message: "Failed to process file /a: permission denied".into(),
+                job_id: Some("j1".into()),
+                timestamp: Some("2026-01-01T00:00:00Z".into()),
+            },
+            AgentMessage::JobStatusResponse { request_id: "r1".into(), running_jobs: vec!["j1".into()] },
+            AgentMessage::PolicyViolation {
+                action: "backup".into(),
+                path: "/etc/shadow".into(),
+                reason: "denied".into(),
+                job_id: Some("j1".into()),
+            },
+            AgentMessage::FsBrowseResponse {
+                request_id: "r2".into(),
+                entries: vec![FsEntry { name: "etc".into(), path: "/etc".into(), display_path: "/etc".into(), entry_type: "directory".into(), size: 0 }],
+                error: None,
+            },
+        ];
+        for message in &messages {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let value = round_trip(&AgentMessage::BackupFailed { job_id: "j1".into(), error: "boom".into() });
+        assert_eq!(value, json!({ "type": "backup:failed", "payload": { "job_id": "j1", "error": "boom" } }));
+
+        let value = round_trip(&AgentMessage::FsBrowseResponse {
+            request_id: "r1".into(),
+            entries: vec![FsEntry { name: "a".into(), path: "/a".into(), display_path: "/a".into(), entry_type: "file".into(), size: 1 }],
+            error: None,
+        });
+        assert_eq!(value["payload"]["entries"][0]["type"], "file");
+        assert!(value["payload"].get("error").is_none());
+    }
+
+    #[test]
+    fn test_legacy_register_parses() {
+        let message: AgentMessage = serde_json::from_value(json!({
+            "type": "agent:register",
+            "payload": { "hostname": "web-01", "version": "0.9.0", "server_id": "srv-1", "agent_id": "a" },
+        }))
+        .unwrap();
+        let AgentMessage::Register(payload) = message else { panic!("not a register message") };
+        assert_eq!(payload.protocol_version, None);
+        assert!(payload.capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let completed = AgentMessage::BackupCompleted(BackupCompletedPayload { job_id: "j1".into(), ..Default::default() });
+        assert_eq!(completed.job_id(), Some("j1"));
+        assert_eq!(completed.request_id(), None);
+
+        let response = AgentMessage::JobStatusResponse { request_id: "r1".into(), running_jobs: vec![] };
+        assert_eq!(response.request_id(), Some("r1"));
+        assert_eq!(response.job_id(), None);
+
+        let legacy_log: AgentMessage =
+            serde_json::from_value(json!({ "type": "agent:log", "payload": { "level": "info", "message": "hi" } })).unwrap();
+        assert_eq!(legacy_log.job_id(), None);
+    }
+}
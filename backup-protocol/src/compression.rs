@@ -0,0 +1,123 @@
+//! Compression of file uploads.
+//!
+//! Each job has a [`Compression`], sent in `backup:start` to agents announcing
+//! the algorithm's capability. The agent compresses upload bodies with it,
+//! except files that would not shrink, and names the algorithm in
+//! `content-encoding`; the server decodes whichever encoding an upload names.
+
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    None,
+    #[default]
+    Zstd,
+    /// Fast, for hosts where CPU rather than bandwidth is scarce
+    Lz4,
+    Gzip,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 4] = [Self::None, Self::Zstd, Self::Lz4, Self::Gzip];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|algorithm| algorithm.as_str() == value)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The `content-encoding` of bodies compressed with it; None for `none`
+    pub fn content_encoding(self) -> Option<&'static str> {
+        (self != Self::None).then(|| self.as_str())
+    }
+
+    /// The algorithm of a `content-encoding` header; `identity` is `none`
+    pub fn from_content_encoding(value: &str) -> Option<Self> {
+        match value.trim() {
+            "identity" => Some(Self::None),
+            "none" => None,
+            value => Self::parse(&value.to_ascii_lowercase()),
+        }
+    }
+
+    /// What agents announce to take it. Every agent can send files as they are.
+    pub fn capability(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Zstd => Some(crate::CAP_COMPRESSION_ZSTD),
+            Self::Lz4 => Some(crate::CAP_COMPRESSION_LZ4),
+            Self::Gzip => Some(crate::CAP_COMPRESSION_GZIP),
+        }
+    }
+
+    /// Levels it takes; None if it has no levels
+    pub fn levels(self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Self::Zstd => Some(1..=22),
+            Self::Gzip => Some(1..=9),
+            Self::None | Self::Lz4 => None,
+        }
+    }
+}
+
+/// Compression settings of a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    /// The algorithm's default level if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    /// Settings whose level, if any, is one the algorithm takes
+    pub fn new(algorithm: Algorithm, level: Option<i32>) -> Result<Self, String> {
+        match (level, algorithm.levels()) {
+            (Some(level), Some(levels)) if !levels.contains(&level) => Err(format!(
+                "{} compression levels are {} to {}",
+                algorithm.as_str(),
+                levels.start(),
+                levels.end()
+            )),
+            (Some(_), None) => Err(format!("{} compression has no levels", algorithm.as_str())),
+            _ => Ok(Self { algorithm, level }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_are_checked_per_algorithm() {
+        assert!(Compression::new(Algorithm::Zstd, Some(19)).is_ok());
+        assert!(Compression::new(Algorithm::Zstd, Some(23)).is_err());
+        assert!(Compression::new(Algorithm::Gzip, Some(10)).is_err());
+        assert!(Compression::new(Algorithm::Lz4, Some(1)).is_err());
+        assert_eq!(Compression::new(Algorithm::Lz4, None), Ok(Compression { algorithm: Algorithm::Lz4, level: None }));
+    }
+
+    #[test]
+    fn test_content_encodings() {
+        for algorithm in Algorithm::ALL {
+            let parsed = algorithm.content_encoding().map_or(Some(Algorithm::None), Algorithm::from_content_encoding);
+            assert_eq!(parsed, Some(algorithm));
+        }
+        assert_eq!(Algorithm::from_content_encoding("GZIP"), Some(Algorithm::Gzip));
+        assert_eq!(Algorithm::from_content_encoding("br"), None);
+        assert_eq!(
+            serde_json::to_value(Compression { algorithm: Algorithm::Lz4, level: None }).unwrap(),
+            serde_json::json!({ "algorithm": "lz4" })
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Wire protocol between the backup agent and the backup server.
+//!
+//! Every WebSocket message is a JSON object `{"type": "...", "payload": {...}}`.
+//! [`AgentMessage`] covers what agents send, [`ServerMessage`] what the server
+//! sends; [`rest`] holds the bodies and headers of the HTTP endpoints agents
+//! call, [`path`] the encoding file paths travel in, [`manifest`] the format
+//! of version manifests and [`compression`] how uploads are compressed. Both
+//! binaries depend on this crate, so a change to a message that one side does
+//! not follow fails to compile instead of failing at runtime.
+//!
+//! [`PROTOCOL_VERSION`] changes whenever a message changes incompatibly.
+//! Optional features are announced as capabilities in `agent:register`.
+
+pub mod agent;
+pub mod compression;
+pub mod manifest;
+pub mod path;
+pub mod rest;
+pub mod server;
+
+pub use agent::{
+    ActiveFileProgress, AgentMessage, AgentStatusPayload, BackupCompletedPayload, BackupProgressPayload,
+    FileErrorPayload, FsEntry, RegisterPayload, ScanProgressPayload,
+};
+pub use rest::{ChangesRequest, EnrollRequest, EnrollResponse, ReleaseManifest, Rename, RenamesRequest, RenamesResponse};
+pub use server::{BackupRoot, ServerMessage, StartBackupPayload};
+
+/// Protocol spoken by this build. Agents that predate versioning send no
+/// version and count as protocol 1.
+///
+/// - 2: `agent:register` carries the version and capabilities.
+/// - 3: paths travel percent-encoded ([`path`]); `agent:backup:start` carries
+///   labelled roots, the manifest format, compression and the concurrency
+///   cap; agents report per-file errors, scan progress and, with
+///   [`CAP_CARRY_FORWARD`], changes instead of unchanged files.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+pub const CAP_INCREMENTAL: &str = "incremental";
+pub const CAP_DELTA: &str = "delta";
+pub const CAP_RESTORE: &str = "restore";
+pub const CAP_HOOKS: &str = "hooks";
+pub const CAP_COMPRESSION_ZSTD: &str = "compression:zstd";
+pub const CAP_COMPRESSION_LZ4: &str = "compression:lz4";
+pub const CAP_COMPRESSION_GZIP: &str = "compression:gzip";
+pub const CAP_ROOTS: &str = "roots";
+pub const CAP_BINARY_MANIFEST: &str = "manifest:binary";
+pub const CAP_CARRY_FORWARD: &str = "carry-forward";
+pub const CAP_RENAMES: &str = "renames";
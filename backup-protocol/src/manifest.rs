@@ -0,0 +1,471 @@
+//! Manifest of a backup version: every file with its size and mtime, which
+//! incremental runs diff against.
+//!
+//! [`FILE_NAME`] is binary and sorted by [`path::compare`], so a scan that
+//! walks the tree in the same order can be merged against it entry by entry
+//! without holding either side in memory. Its layout is the magic `BKMF`, a
+//! format version byte and a zstd stream of:
+//!
+//! - the [`ManifestHeader`] as length-prefixed JSON;
+//! - per file, tag `1`, the number of leading bytes its path shares with the
+//!   previous path, the rest of the path (length-prefixed), the size, the
+//!   mtime (zigzag) and, from format 2, the inode (`0` if unknown) followed
+//!   by the device if it is known;
+//! - tag `0` and the number of files and bytes, so a truncated manifest is
+//!   told apart from a complete one.
+//!
+//! Integers are LEB128 varints. Format 1 has no inodes; it is still read,
+//! and written for agents that do not ask for format 2 with
+//! [`CONTENT_TYPE_V2`]. Versions written before the binary format carry a
+//! [`JSON_FILE_NAME`] ([`JsonManifest`]) instead.
+
+use crate::path;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, Read, Write};
+
+/// Binary manifest in a version directory.
+pub const FILE_NAME: &str = ".backup-manifest.bin";
+/// Manifest of versions written before [`FILE_NAME`].
+pub const JSON_FILE_NAME: &str = ".backup-manifest.json";
+/// Media type of [`FILE_NAME`]; agents send it in `Accept` to get it.
+pub const CONTENT_TYPE: &str = "application/vnd.backup-manifest";
+/// `Accept` of agents that read format 2, with [`FileId`]s.
+pub const CONTENT_TYPE_V2: &str = "application/vnd.backup-manifest; version=2";
+
+const MAGIC: &[u8; 4] = b"BKMF";
+const FORMAT_VERSION: u8 = 2;
+/// Format without [`FileId`]s
+const FORMAT_VERSION_V1: u8 = 1;
+/// [`JsonManifest::version`] of manifests whose paths are encoded
+const JSON_FORMAT_VERSION: u32 = 2;
+const TAG_END: u8 = 0;
+const TAG_FILE: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
+/// Longest header and path a reader accepts, so a corrupt length cannot
+/// make it allocate gigabytes
+const MAX_HEADER_LEN: u64 = 1 << 20;
+const MAX_PATH_LEN: usize = 1 << 16;
+
+/// What a manifest says about the version as a whole.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestHeader {
+    pub job_id: String,
+    /// Source path of each label the version's files are stored under; empty
+    /// for versions stored before roots were labelled
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roots: BTreeMap<String, String>,
+}
+
+/// Metadata for a single file in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub mtime: i64,
+    /// The source file's identity, which tells a moved file from a new one;
+    /// None where it is not known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<FileId>,
+}
+
+/// Device and inode of a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+/// Whether `prefix`, the start of a manifest file, is a binary manifest.
+pub fn is_binary(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+/// Whether `prefix`, the start of a binary manifest, has [`FileId`]s
+/// (format 2 or later).
+pub fn has_file_ids(prefix: &[u8]) -> bool {
+    is_binary(prefix) && prefix.get(MAGIC.len()).is_some_and(|&format| format >= FORMAT_VERSION)
+}
+
+/// Whether an `Accept` header asks for format 2.
+pub fn accepts_v2(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        let mut parts = media.split(';').map(str::trim);
+        parts.next() == Some(CONTENT_TYPE) && parts.any(|param| param.replace(' ', "") == "version=2")
+    })
+}
+
+/// Writes a binary manifest; paths must be added in [`path::compare`] order.
+pub struct ManifestWriter<W: Write> {
+    out: zstd::Encoder<'static, W>,
+    /// Whether file ids are written (format 2)
+    ids: bool,
+    last: String,
+    total_files: u64,
+    total_bytes: u64,
+}
+
+impl<W: Write> ManifestWriter<W> {
+    pub fn new(out: W, header: &ManifestHeader) -> io::Result<Self> {
+        Self::start(out, header, FORMAT_VERSION)
+    }
+
+    /// Write format 1, leaving file ids out, for agents that cannot read
+    /// format 2.
+    pub fn v1(out: W, header: &ManifestHeader) -> io::Result<Self> {
+        Self::start(out, header, FORMAT_VERSION_V1)
+    }
+
+    fn start(mut out: W, header: &ManifestHeader, format: u8) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[format])?;
+        let mut out = zstd::Encoder::new(out, ZSTD_LEVEL)?;
+        let header = serde_json::to_vec(header)?;
+        write_varint(&mut out, header.len() as u64)?;
+        out.write_all(&header)?;
+        Ok(Self { out, ids: format >= FORMAT_VERSION, last: String::new(), total_files: 0, total_bytes: 0 })
+    }
+
+    pub fn add(&mut self, path: &str, entry: ManifestEntry) -> io::Result<()> {
+        if self.total_files > 0 && path::compare(&self.last, path) != Ordering::Less {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("manifest paths out of order: {} after {}", path, self.last),
+            ));
+        }
+        let shared = self.last.bytes().zip(path.bytes()).take_while(|(a, b)| a == b).count();
+        let rest = &path.as_bytes()[shared..];
+
+        self.out.write_all(&[TAG_FILE])?;
+        write_varint(&mut self.out, shared as u64)?;
+        write_varint(&mut self.out, rest.len() as u64)?;
+        self.out.write_all(rest)?;
+        write_varint(&mut self.out, entry.size)?;
+        write_varint(&mut self.out, ((entry.mtime << 1) ^ (entry.mtime >> 63)) as u64)?;
+        if self.ids {
+            match entry.id.filter(|id| id.ino != 0) {
+                Some(id) => {
+                    write_varint(&mut self.out, id.ino)?;
+                    write_varint(&mut self.out, id.dev)?;
+                }
+                None => write_varint(&mut self.out, 0)?,
+            }
+        }
+
+        self.last.clear();
+        self.last.push_str(path);
+        self.total_files += 1;
+        self.total_bytes = self.total_bytes.wrapping_add(entry.size);
+        Ok(())
+    }
+
+    /// Files added so far
+    pub fn total_files(&self) -> u64 {
+        self.total_files
+    }
+
+    /// Bytes of the files added so far
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Write the end of the manifest and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[TAG_END])?;
+        write_varint(&mut self.out, self.total_files)?;
+        write_varint(&mut self.out, self.total_bytes)?;
+        self.out.finish()
+    }
+}
+
+/// Reads a binary manifest as an iterator of `(path, entry)` in
+/// [`path::compare`] order. A manifest that ends early or whose totals do not
+/// match its entries yields an error as its last item.
+pub struct ManifestReader<R: Read> {
+    input: zstd::Decoder<'static, BufReader<R>>,
+    header: ManifestHeader,
+    ids: bool,
+    last: Vec<u8>,
+    total_files: u64,
+    total_bytes: u64,
+    done: bool,
+}
+
+impl<R: Read> ManifestReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut start = [0u8; 5];
+        input.read_exact(&mut start)?;
+        if !is_binary(&start) {
+            return Err(invalid("not a binary manifest"));
+        }
+        if !(FORMAT_VERSION_V1..=FORMAT_VERSION).contains(&start[4]) {
+            return Err(invalid(format!("unsupported manifest format version {}", start[4])));
+        }
+        let mut input = zstd::Decoder::new(input)?;
+        let len = read_varint(&mut input)?;
+        if len > MAX_HEADER_LEN {
+            return Err(invalid("manifest header too long"));
+        }
+        let mut header = vec![0u8; len as usize];
+        input.read_exact(&mut header)?;
+        let header = serde_json::from_slice(&header)?;
+        let ids = start[4] >= FORMAT_VERSION;
+        Ok(Self { input, header, ids, last: Vec::new(), total_files: 0, total_bytes: 0, done: false })
+    }
+
+    pub fn header(&self) -> &ManifestHeader {
+        &self.header
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<(String, ManifestEntry)>> {
+        let mut tag = [0u8];
+        self.input.read_exact(&mut tag)?;
+        match tag[0] {
+            TAG_FILE => {
+                let shared = read_varint(&mut self.input)? as usize;
+                let len = read_varint(&mut self.input)? as usize;
+                if shared > self.last.len() || shared + len > MAX_PATH_LEN {
+                    return Err(invalid("invalid manifest path length"));
+                }
+                self.last.truncate(shared);
+                let start = self.last.len();
+                self.last.resize(start + len, 0);
+                self.input.read_exact(&mut self.last[start..])?;
+                let size = read_varint(&mut self.input)?;
+                let mtime = read_varint(&mut self.input)?;
+                let mtime = ((mtime >> 1) as i64) ^ -((mtime & 1) as i64);
+                let ino = if self.ids { read_varint(&mut self.input)? } else { 0 };
+                let id = match ino {
+                    0 => None,
+                    ino => Some(FileId { ino, dev: read_varint(&mut self.input)? }),
+                };
+                let path = String::from_utf8(self.last.clone()).map_err(invalid)?;
+                self.total_files += 1;
+                self.total_bytes = self.total_bytes.wrapping_add(size);
+                Ok(Some((path, ManifestEntry { size, mtime, id })))
+            }
+            TAG_END => {
+                let files = read_varint(&mut self.input)?;
+                let bytes = read_varint(&mut self.input)?;
+                if (files, bytes) != (self.total_files, self.total_bytes) {
+                    return Err(invalid("manifest totals do not match its entries"));
+                }
+                Ok(None)
+            }
+            tag => Err(invalid(format!("unknown manifest record {}", tag))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ManifestReader<R> {
+    type Item = io::Result<(String, ManifestEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+/// `.backup-manifest.json`, the manifest of versions written before the
+/// binary format. Still read, and written for servers that predate it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JsonManifest {
+    /// `1` for manifests written before paths were encoded, whose keys are
+    /// the raw names
+    pub version: u32,
+    pub job_id: String,
+    pub files: HashMap<String, ManifestEntry>,
+    pub total_files: usize,
+    pub total_bytes: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roots: BTreeMap<String, String>,
+}
+
+impl JsonManifest {
+    pub fn new(header: ManifestHeader, files: impl IntoIterator<Item = (String, ManifestEntry)>) -> Self {
+        let files: HashMap<_, _> = files.into_iter().collect();
+        Self {
+            version: JSON_FORMAT_VERSION,
+            job_id: header.job_id,
+            total_files: files.len(),
+            total_bytes: files.values().map(|e| e.size).sum(),
+            files,
+            roots: header.roots,
+        }
+    }
+
+    /// The header and files in binary manifest order. The raw names of
+    /// manifests written before paths were encoded (`café` rather than
+    /// `caf%C3%A9`) are encoded, so unchanged files still match; a `%` in
+    /// them is a literal one.
+    pub fn into_sorted(self) -> (ManifestHeader, Vec<(String, ManifestEntry)>) {
+        let legacy = self.version < JSON_FORMAT_VERSION;
+        let mut files: Vec<_> = self
+            .files
+            .into_iter()
+            .map(|(p, e)| if legacy { (path::encode(p.as_bytes()), e) } else { (p, e) })
+            .collect();
+        files.sort_by(|(a, _), (b, _)| path::compare(a, b));
+        (ManifestHeader { job_id: self.job_id, roots: self.roots }, files)
+    }
+}
+
+fn invalid(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn write_varint(out: &mut impl Write, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    out.write_all(&buf[..len])
+}
+
+fn read_varint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64, mtime: i64) -> ManifestEntry {
+        ManifestEntry { size, mtime, id: None }
+    }
+
+    fn write(header: &ManifestHeader, files: &[(&str, ManifestEntry)]) -> Vec<u8> {
+        let mut writer = ManifestWriter::new(Vec::new(), header).unwrap();
+        for (path, e) in files {
+            writer.add(path, *e).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let header = ManifestHeader {
+            job_id: "j1".into(),
+            roots: BTreeMap::from([("etc".to_string(), "/etc".to_string())]),
+        };
+        let files = [
+            ("etc/a", entry(1, 1_700_000_000)),
+            ("etc/a/b", entry(0, -5)),
+            ("etc/a-c", entry(u64::MAX / 2, i64::MIN)),
+            ("etc/caf%C3%A9", entry(7, i64::MAX)),
+        ];
+        let bytes = write(&header, &files);
+        assert!(is_binary(&bytes));
+
+        let reader = ManifestReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.header(), &header);
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        let expected: Vec<_> = files.iter().map(|(p, e)| (p.to_string(), *e)).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_writer_requires_manifest_order() {
+        let mut writer = ManifestWriter::new(Vec::new(), &ManifestHeader::default()).unwrap();
+        writer.add("a/b", entry(1, 1)).unwrap();
+        // '/' sorts before every other byte, so all of `a/` comes before `a-b`
+        writer.add("a-b", entry(1, 1)).unwrap();
+        let e = writer.add("a/c", entry(1, 1)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(writer.add("a-b", entry(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_file_ids_only_in_format_2() {
+        let moved = ManifestEntry { id: Some(FileId { dev: 2049, ino: 1 << 40 }), ..entry(3, 4) };
+        let files = [("a", moved), ("b", entry(1, 2))];
+        let read = |bytes: Vec<u8>| -> Vec<_> {
+            ManifestReader::new(bytes.as_slice()).unwrap().map(|e| e.unwrap().1).collect()
+        };
+
+        assert_eq!(read(write(&ManifestHeader::default(), &files)), vec![moved, entry(1, 2)]);
+
+        let mut writer = ManifestWriter::v1(Vec::new(), &ManifestHeader::default()).unwrap();
+        for (path, e) in files {
+            writer.add(path, e).unwrap();
+        }
+        let v1 = writer.finish().unwrap();
+        assert!(is_binary(&v1) && !has_file_ids(&v1));
+        assert_eq!(read(v1), vec![entry(3, 4), entry(1, 2)]);
+
+        assert!(has_file_ids(&write(&ManifestHeader::default(), &files)));
+
+        assert!(accepts_v2("application/json, application/vnd.backup-manifest; version=2"));
+        assert!(!accepts_v2(CONTENT_TYPE));
+        assert!(!accepts_v2("application/json; version=2"));
+    }
+
+    #[test]
+    fn test_truncated_manifest_ends_with_an_error() {
+        // Every entry made it, but not the end record
+        let mut writer = ManifestWriter::new(Vec::new(), &ManifestHeader::default()).unwrap();
+        for i in 0..1000 {
+            writer.add(&format!("f{:04}", i), entry(i, i as i64)).unwrap();
+        }
+        let bytes = writer.out.finish().unwrap();
+        let read: Vec<_> = ManifestReader::new(bytes.as_slice()).unwrap().collect();
+        assert_eq!(read.len(), 1001);
+        assert_eq!(read.last().unwrap().as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Cut mid-stream
+        let bytes = write(&ManifestHeader::default(), &[("a", entry(1, 1))]);
+        let read: Vec<_> = ManifestReader::new(&bytes[..bytes.len() - 4]).unwrap().collect();
+        assert!(read.last().unwrap().is_err());
+
+        assert!(ManifestReader::new(&b"{\"version\":1}"[..]).is_err());
+    }
+
+    #[test]
+    fn test_json_manifest_is_sorted_and_normalized() {
+        let json = r#"{"version":1,"job_id":"j1","files":{"b":{"size":1,"mtime":2},"café":{"size":3,"mtime":4},"a-c":{"size":5,"mtime":6},"a/z":{"size":7,"mtime":8}},"total_files":4,"total_bytes":16}"#;
+        let manifest: JsonManifest = serde_json::from_str(json).unwrap();
+        let (header, files) = manifest.into_sorted();
+        assert_eq!(header.job_id, "j1");
+        let paths: Vec<_> = files.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, vec!["a/z", "a-c", "b", "caf%C3%A9"]);
+    }
+
+    #[test]
+    fn test_json_manifest_keeps_literal_percent_of_legacy_names() {
+        let json = r#"{"version":1,"job_id":"j1","files":{"100%41.txt":{"size":1,"mtime":2}},"total_files":1,"total_bytes":1}"#;
+        let manifest: JsonManifest = serde_json::from_str(json).unwrap();
+        let (_, files) = manifest.into_sorted();
+        assert_eq!(files[0].0, "100%2541.txt");
+        assert_eq!(path::decode(&files[0].0), b"100%41.txt");
+
+        // Manifests written since are encoded already
+        let entry = ManifestEntry { size: 1, mtime: 2, id: None };
+        let manifest = JsonManifest::new(ManifestHeader::default(), [("100%2541.txt".to_string(), entry)]);
+        let json: JsonManifest = serde_json::from_slice(&serde_json::to_vec(&manifest).unwrap()).unwrap();
+        assert_eq!(json.into_sorted().1[0].0, "100%2541.txt");
+    }
+}
@@ -0,0 +1,133 @@
+//! Byte-exact encoding of file paths.
+//!
+//! Unix file names are arbitrary bytes (except `/` and NUL), while manifest
+//! keys, JSON bodies and HTTP headers are text. Paths travel in an encoded
+//! form: printable ASCII other than `%` is kept as is, every other byte
+//! (controls, space, `%`, bytes of non-ASCII names) is written as `%XX`.
+//! Ordinary names therefore look the same encoded and decoded, and any byte
+//! string survives the round trip.
+//!
+//! Decoding is lenient so that paths written before this encoding existed
+//! still resolve: a `%` not followed by two hex digits is kept literally and
+//! non-ASCII characters stand for their UTF-8 bytes.
+
+/// Encode raw path bytes.
+pub fn encode(bytes: &[u8]) -> String {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push('%');
+            encoded.push(HEX[(b >> 4) as usize] as char);
+            encoded.push(HEX[(b & 0xf) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Decode an encoded path back to its bytes.
+pub fn decode(encoded: &str) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let (Some(hi), Some(lo)) = (bytes.get(i + 1).and_then(|&b| hex(b)), bytes.get(i + 2).and_then(|&b| hex(b))) {
+                decoded.push((hi << 4) | lo);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+/// Bring a path from an older manifest or client to its canonical encoding.
+pub fn normalize(encoded: &str) -> String {
+    encode(&decode(encoded))
+}
+
+/// Human-readable form of an encoded path (invalid UTF-8 is replaced).
+pub fn display(encoded: &str) -> String {
+    String::from_utf8_lossy(&decode(encoded)).into_owned()
+}
+
+/// Order of encoded paths in manifests: component by component, which is the
+/// order a depth-first walk meets them when it visits each directory's
+/// entries sorted by encoded name.
+pub fn compare(a: &str, b: &str) -> std::cmp::Ordering {
+    // Encoded paths have no NUL, so mapping '/' to it sorts a directory's
+    // contents right after the directory
+    fn key(s: &str) -> impl Iterator<Item = u8> + '_ {
+        s.bytes().map(|b| if b == b'/' { 0 } else { b })
+    }
+    key(a).cmp(key(b))
+}
+
+#[cfg(unix)]
+pub fn encode_path(path: &std::path::Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    encode(path.as_os_str().as_bytes())
+}
+
+#[cfg(unix)]
+pub fn decode_path(encoded: &str) -> std::path::PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(decode(encoded)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordinary_paths_are_unchanged() {
+        assert_eq!(encode(b"docs/report-2024_v2.pdf"), "docs/report-2024_v2.pdf");
+        assert_eq!(decode("docs/report-2024_v2.pdf"), b"docs/report-2024_v2.pdf");
+    }
+
+    #[test]
+    fn test_round_trips_arbitrary_bytes() {
+        let all: Vec<u8> = (1..=255).collect();
+        let encoded = encode(&all);
+        assert!(encoded.bytes().all(|b| b.is_ascii_graphic()));
+        assert_eq!(decode(&encoded), all);
+
+        for name in [&b"new\nline"[..], b" leading space", b"50%off", b"caf\xc3\xa9", b"bad\xffutf8", b"tab\tand\r"] {
+            assert_eq!(decode(&encode(name)), name, "{:?}", encode(name));
+        }
+        assert_eq!(encode(b"a b%\n\xff"), "a%20b%25%0A%FF");
+    }
+
+    #[test]
+    fn test_decodes_legacy_paths() {
+        assert_eq!(decode("café/50%off"), "café/50%off".as_bytes());
+        assert_eq!(decode("trailing%"), b"trailing%");
+        assert_eq!(normalize("café"), "caf%C3%A9");
+        assert_eq!(display("bad%FFutf8"), "bad\u{FFFD}utf8");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compare_orders_like_a_depth_first_walk() {
+        let mut paths = vec!["a-c", "a/b/c", "ab", "a", "a/b", "a/b-c", "%25", "B"];
+        paths.sort_by(|a, b| compare(a, b));
+        assert_eq!(paths, vec!["%25", "B", "a", "a/b", "a/b/c", "a/b-c", "a-c", "ab"]);
+    }
+
+    #[test]
+    fn test_path_helpers() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = decode_path("dir/bad%FF");
+        assert_eq!(path.as_os_str().as_bytes(), b"dir/bad\xff");
+        assert_eq!(encode_path(&path), "dir/bad%FF");
+    }
+}
@@ -0,0 +1,135 @@
+//! Bodies and headers of the HTTP endpoints agents call.
+
+use serde::{Deserialize, Serialize};
+
+/// Backup job an upload belongs to.
+pub const HEADER_JOB_ID: &str = "x-job-id";
+/// Path of the uploaded file relative to the backup root, encoded with
+/// [`crate::path::encode`].
+pub const HEADER_RELATIVE_PATH: &str = "x-relative-path";
+/// Uncompressed size of the uploaded file.
+pub const HEADER_TOTAL_SIZE: &str = "x-total-size";
+
+/// Body of `POST /api/agent/enroll`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnrollRequest {
+    pub token: String,
+    pub hostname: String,
+}
+
+/// Response of `POST /api/agent/enroll`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrollResponse {
+    pub server_id: String,
+    pub server_name: String,
+    /// Fingerprint of the server certificate, when it serves HTTPS
+    pub cert_fingerprint: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Hex-encoded Ed25519 key release manifests are signed with
+    pub release_public_key: Option<String>,
+}
+
+/// Body of `POST /api/files/changes`: paths, encoded like
+/// [`HEADER_RELATIVE_PATH`], that differ from the previous version in an
+/// incremental run. Sent in batches; every file of the previous version not
+/// listed is carried forward into the new one once `done` is set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangesRequest {
+    pub job_id: String,
+    /// New or modified files, uploaded separately
+    #[serde(default)]
+    pub changed: Vec<String>,
+    /// Files of the previous version that are gone
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    /// Set on the last batch, once the scan is complete
+    #[serde(default)]
+    pub done: bool,
+    /// Files the agent found unchanged, set with `done`. Servers count the
+    /// files they carry forward from the manifest instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unchanged_files: Option<u64>,
+}
+
+/// Body of `POST /api/files/renames`: new files of an incremental run that
+/// are files of the previous version moved or renamed. The server links or
+/// clones them from the previous version instead of them being uploaded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RenamesRequest {
+    pub job_id: String,
+    pub renames: Vec<Rename>,
+}
+
+/// A file found at `to` that was at `from` in the previous version; paths are
+/// encoded like [`HEADER_RELATIVE_PATH`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+    /// Hex SHA-256 of the file, when it was matched by content rather than
+    /// by inode; the server only links `from` if its content has this hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Response to [`RenamesRequest`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RenamesResponse {
+    pub linked: u64,
+    /// `to` of the renames not linked, which the agent uploads instead
+    #[serde(default)]
+    pub rejected: Vec<String>,
+}
+
+/// Signed description of an agent release, as sent with `agent:update`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseManifest {
+    pub version: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Hex-encoded Ed25519 signature over [`ReleaseManifest::signed_message`].
+    pub signature: String,
+}
+
+impl ReleaseManifest {
+    /// The bytes the signature covers.
+    pub fn signed_message(&self) -> String {
+        format!(
+            "backup-agent-release\nversion={}\nsha256={}\nsize={}\n",
+            self.version, self.sha256, self.size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_enroll_response_is_camel_case() {
+        let response = EnrollResponse {
+            server_id: "srv-1".into(),
+            server_name: "web-01".into(),
+            cert_fingerprint: None,
+            client_cert: None,
+            client_key: None,
+            release_public_key: Some("ab".into()),
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["serverId"], "srv-1");
+        assert_eq!(value["releasePublicKey"], "ab");
+        assert_eq!(serde_json::from_value::<EnrollResponse>(value).unwrap(), response);
+
+        let request: EnrollRequest = serde_json::from_value(json!({ "token": "t", "hostname": "h" })).unwrap();
+        assert_eq!(request, EnrollRequest { token: "t".into(), hostname: "h".into() });
+    }
+
+    #[test]
+    fn test_signed_message_covers_every_field() {
+        let manifest = ReleaseManifest { version: "1.2.3".into(), sha256: "abcd".into(), size: 42, signature: String::new() };
+        assert_eq!(manifest.signed_message(), "backup-agent-release\nversion=1.2.3\nsha256=abcd\nsize=42\n");
+    }
+}
@@ -0,0 +1,172 @@
+//! Messages sent by the server.
+
+use crate::rest::ReleaseManifest;
+use serde::{Deserialize, Serialize};
+
+/// Everything the server sends to an agent over the WebSocket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum ServerMessage {
+    #[serde(rename = "backup:start")]
+    StartBackup(StartBackupPayload),
+
+    #[serde(rename = "backup:cancel")]
+    CancelBackup { job_id: String },
+
+    /// Ask which jobs are currently executing (used by the server to reattach after a restart)
+    #[serde(rename = "backup:status")]
+    JobStatus { request_id: String },
+
+    #[serde(rename = "fs:browse")]
+    BrowseFilesystem { path: String, request_id: String },
+
+    #[serde(rename = "agent:update")]
+    UpdateAgent {
+        download_path: String,
+        version: String,
+        /// Signed release manifest; agents refuse updates without one
+        #[serde(default)]
+        manifest: Option<ReleaseManifest>,
+    },
+
+    /// Registration acknowledgment
+    #[serde(rename = "agent:register:ok")]
+    RegisterOk {
+        server_id: String,
+        /// Protocol the server will use with this agent (absent from older servers)
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+
+    #[serde(rename = "agent:register:error")]
+    RegisterError { error: String },
+}
+
+/// Payload of `backup:start`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StartBackupPayload {
+    pub job_id: String,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub server_url: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub incremental: bool,
+    #[serde(default)]
+    pub manifest_url: Option<String>,
+    /// Fail the run once more files than this could not be backed up
+    /// (no limit if unset)
+    #[serde(default)]
+    pub max_file_errors: Option<u64>,
+    /// `paths` with the directory each one is stored under in the version.
+    /// Sent only to agents announcing [`crate::CAP_ROOTS`]; without it files
+    /// are stored relative to their own root, as older servers expect.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<BackupRoot>,
+    /// Upload the manifest as [`crate::manifest::FILE_NAME`] rather than
+    /// JSON. Sent only to agents announcing [`crate::CAP_BINARY_MANIFEST`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub binary_manifest: bool,
+    /// Report only changed and deleted paths to `POST /api/files/changes`;
+    /// the server links every other file from the previous version itself.
+    /// Sent only to agents announcing [`crate::CAP_CARRY_FORWARD`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub carry_forward: bool,
+    /// Send files moved since the previous version as renames
+    /// (`POST /api/files/renames`) rather than uploading them. Sent only to
+    /// agents announcing [`crate::CAP_RENAMES`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detect_renames: bool,
+    /// How to compress uploads. Sent only to agents announcing the
+    /// algorithm's capability ([`crate::compression::Algorithm::capability`]);
+    /// without it the agent uses its own `[sync]` settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<crate::compression::Compression>,
+    /// Most files the agent uploads at once; within it the agent tunes
+    /// concurrency from throughput and latency (its own maximum if unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<u32>,
+}
+
+/// A source root of a backup and the label namespacing its files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupRoot {
+    pub path: String,
+    /// Single path component; the root's files are stored as `<label>/<relative path>`
+    pub label: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_server_messages_round_trip() {
+        let messages = vec![
+            ServerMessage::StartBackup(StartBackupPayload {
+                job_id: "j1".into(),
+                paths: vec!["/etc".into()],
+                server_url: Some("http://backup:3000".into()),
+                token: Some("t".into()),
+                incremental: true,
+                manifest_url: Some("http://backup:3000/manifest".into()),
+                max_file_errors: Some(100),
+                roots: vec![BackupRoot { path: "/etc".into(), label: "etc".into() }],
+                binary_manifest: true,
+                carry_forward: true,
+                detect_renames: true,
+                compression: Some(crate::compression::Compression {
+                    algorithm: crate::compression::Algorithm::Gzip,
+                    level: Some(9),
+                }),
+                max_concurrency: Some(16),
+            }),
+            ServerMessage::CancelBackup { job_id: "j1".into() },
+            ServerMessage::JobStatus { request_id: "r1".into() },
+            ServerMessage::BrowseFilesystem { path: "/".into(), request_id: "r2".into() },
+            ServerMessage::UpdateAgent {
+                download_path: "/api/agent/download".into(),
+                version: "1.1.0".into(),
+                manifest: Some(ReleaseManifest {
+                    version: "1.1.0".into(),
+                    sha256: "ab".into(),
+                    size: 1,
+                    signature: "cd".into(),
+                }),
+            },
+            ServerMessage::RegisterOk { server_id: "srv-1".into(), protocol_version: Some(crate::PROTOCOL_VERSION) },
+            ServerMessage::RegisterError { error: "unknown server".into() },
+        ];
+        for message in &messages {
+            let value = serde_json::to_value(message).unwrap();
+            let parsed: ServerMessage = serde_json::from_value(value).unwrap();
+            assert_eq!(&parsed, message);
+        }
+    }
+
+    #[test]
+    fn test_older_payloads_parse() {
+        let message: ServerMessage = serde_json::from_value(json!({
+            "type": "backup:start",
+            "payload": { "job_id": "j1", "paths": ["/etc"] },
+        }))
+        .unwrap();
+        assert_eq!(
+            message,
+            ServerMessage::StartBackup(StartBackupPayload {
+                job_id: "j1".into(),
+                paths: vec!["/etc".into()],
+                ..Default::default()
+            })
+        );
+
+        let message: ServerMessage = serde_json::from_value(json!({
+            "type": "agent:register:ok",
+            "payload": { "server_id": "srv-1" },
+        }))
+        .unwrap();
+        assert_eq!(message, ServerMessage::RegisterOk { server_id: "srv-1".into(), protocol_version: None });
+    }
+}
@@ -12,6 +12,32 @@ pub struct AppConfig {
     pub max_concurrent_global: usize,
     pub max_concurrent_per_server: usize,
     pub backup_server_ip: Option<String>,
+    pub recovery_grace_secs: u64,
+    pub remove_interrupted_versions: bool,
+    pub stall_timeout_secs: u64,
+    pub tls_enabled: bool,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub tls_client_auth: ClientAuthMode,
+}
+
+/// Whether agents must present a client certificate issued by this server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    Off,
+    /// Certificates are checked when presented; agents without one are still accepted.
+    Optional,
+    Required,
+}
+
+impl ClientAuthMode {
+    fn parse(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "optional" => ClientAuthMode::Optional,
+            "required" => ClientAuthMode::Required,
+            _ => ClientAuthMode::Off,
+        }
+    }
 }
 
 impl AppConfig {
@@ -43,6 +69,25 @@ impl AppConfig {
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(4),
             backup_server_ip: std::env::var("BACKUP_SERVER_IP").ok(),
+            recovery_grace_secs: std::env::var("RECOVERY_GRACE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(120),
+            remove_interrupted_versions: std::env::var("REMOVE_INTERRUPTED_VERSIONS")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            stall_timeout_secs: std::env::var("STALL_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(900),
+            tls_enabled: std::env::var("TLS_ENABLED")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            tls_cert_path: std::env::var("TLS_CERT_PATH").ok().map(PathBuf::from),
+            tls_key_path: std::env::var("TLS_KEY_PATH").ok().map(PathBuf::from),
+            tls_client_auth: std::env::var("TLS_CLIENT_AUTH")
+                .map(|v| ClientAuthMode::parse(&v))
+                .unwrap_or(ClientAuthMode::Off),
         }
     }
 }
@@ -26,7 +26,7 @@ CREATE TABLE IF NOT EXISTS backup_jobs (
   remote_paths TEXT NOT NULL DEFAULT '[]',
   local_path TEXT NOT NULL,
   cron_schedule TEXT,
-  status TEXT NOT NULL DEFAULT 'idle' CHECK(status IN ('idle','running','completed','failed','cancelled')),
+  status TEXT NOT NULL DEFAULT 'idle' CHECK(status IN ('idle','queued','running','completed','completed_with_warnings','failed','cancelled')),
   rsync_options TEXT NOT NULL DEFAULT '',
   max_parallel INTEGER NOT NULL DEFAULT 4,
   enabled INTEGER NOT NULL DEFAULT 1,
@@ -46,7 +46,7 @@ CREATE TABLE IF NOT EXISTS backup_logs (
   job_id TEXT NOT NULL REFERENCES backup_jobs(id) ON DELETE CASCADE,
   started_at TEXT NOT NULL DEFAULT (datetime('now')),
   finished_at TEXT,
-  status TEXT NOT NULL DEFAULT 'running' CHECK(status IN ('running','completed','failed','cancelled')),
+  status TEXT NOT NULL DEFAULT 'running' CHECK(status IN ('running','completed','completed_with_warnings','failed','cancelled')),
   bytes_transferred INTEGER NOT NULL DEFAULT 0,
   files_transferred INTEGER NOT NULL DEFAULT 0,
   output TEXT NOT NULL DEFAULT '',
@@ -68,6 +68,91 @@ CREATE TABLE IF NOT EXISTS backup_versions (
   completed_at TEXT
 );
 
+CREATE TABLE IF NOT EXISTS backup_retries (
+  id TEXT PRIMARY KEY,
+  job_id TEXT NOT NULL REFERENCES backup_jobs(id) ON DELETE CASCADE,
+  retry_of TEXT NOT NULL,
+  attempt INTEGER NOT NULL,
+  run_at TEXT NOT NULL,
+  last_error TEXT,
+  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE TABLE IF NOT EXISTS backup_queue (
+  id TEXT PRIMARY KEY,
+  job_id TEXT NOT NULL UNIQUE REFERENCES backup_jobs(id) ON DELETE CASCADE,
+  server_id TEXT NOT NULL,
+  trigger_kind TEXT NOT NULL CHECK(trigger_kind IN ('manual','scheduled','retry','recovery')),
+  priority INTEGER NOT NULL,
+  sort_order INTEGER NOT NULL,
+  attempt INTEGER NOT NULL DEFAULT 1,
+  retry_of TEXT,
+  enqueued_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE TABLE IF NOT EXISTS enrollment_tokens (
+  id TEXT PRIMARY KEY,
+  token_hash TEXT NOT NULL UNIQUE,
+  name TEXT,
+  expires_at TEXT NOT NULL,
+  used_at TEXT,
+  server_id TEXT,
+  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE TABLE IF NOT EXISTS agent_rollouts (
+  id TEXT PRIMARY KEY,
+  target_version TEXT NOT NULL,
+  target_sha256 TEXT NOT NULL,
+  selector TEXT NOT NULL DEFAULT '{}',
+  canary_percent INTEGER,
+  wave_size INTEGER NOT NULL,
+  max_failures INTEGER NOT NULL DEFAULT 0,
+  test_backup INTEGER NOT NULL DEFAULT 1,
+  status TEXT NOT NULL DEFAULT 'running' CHECK(status IN ('running','completed','halted')),
+  halt_reason TEXT,
+  current_wave INTEGER NOT NULL DEFAULT 0,
+  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+  finished_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS agent_rollout_targets (
+  id TEXT PRIMARY KEY,
+  rollout_id TEXT NOT NULL REFERENCES agent_rollouts(id) ON DELETE CASCADE,
+  server_id TEXT NOT NULL REFERENCES source_servers(id) ON DELETE CASCADE,
+  wave INTEGER NOT NULL,
+  status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending','updating','verifying','succeeded','unverified','failed','skipped')),
+  from_version TEXT,
+  test_job_id TEXT,
+  error TEXT,
+  started_at TEXT,
+  finished_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS agent_audit_events (
+  id TEXT PRIMARY KEY,
+  server_id TEXT NOT NULL REFERENCES source_servers(id) ON DELETE CASCADE,
+  action TEXT NOT NULL,
+  path TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  job_id TEXT,
+  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE TABLE IF NOT EXISTS backup_file_errors (
+  id TEXT PRIMARY KEY,
+  log_id TEXT NOT NULL REFERENCES backup_logs(id) ON DELETE CASCADE,
+  job_id TEXT NOT NULL,
+  path TEXT NOT NULL,
+  operation TEXT NOT NULL,
+  errno INTEGER,
+  message TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_backup_file_errors_log_id ON backup_file_errors(log_id);
+CREATE INDEX IF NOT EXISTS idx_agent_audit_events_server_id ON agent_audit_events(server_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_agent_rollout_targets_rollout_id ON agent_rollout_targets(rollout_id);
 CREATE INDEX IF NOT EXISTS idx_backup_versions_job_id ON backup_versions(job_id);
 CREATE INDEX IF NOT EXISTS idx_backup_versions_timestamp ON backup_versions(version_timestamp DESC);
 "#;
@@ -116,9 +201,25 @@ pub fn migrate(pool: &DbPool, data_dir: &Path, keys_dir: &Path) -> anyhow::Resul
     if !has_column("source_servers", "agent_version") {
         conn.execute_batch("ALTER TABLE source_servers ADD COLUMN agent_version TEXT")?;
     }
+    if !has_column("source_servers", "agent_cert_fingerprint") {
+        conn.execute_batch("ALTER TABLE source_servers ADD COLUMN agent_cert_fingerprint TEXT")?;
+    }
     if !has_column("source_servers", "agent_last_seen") {
         conn.execute_batch("ALTER TABLE source_servers ADD COLUMN agent_last_seen TEXT")?;
     }
+    if !has_column("source_servers", "agent_protocol_version") {
+        conn.execute_batch("ALTER TABLE source_servers ADD COLUMN agent_protocol_version INTEGER")?;
+    }
+    if !has_column("source_servers", "agent_capabilities") {
+        conn.execute_batch(
+            "ALTER TABLE source_servers ADD COLUMN agent_capabilities TEXT NOT NULL DEFAULT '[]'",
+        )?;
+    }
+    if !has_column("source_servers", "tags") {
+        conn.execute_batch(
+            "ALTER TABLE source_servers ADD COLUMN tags TEXT NOT NULL DEFAULT '[]'",
+        )?;
+    }
 
     // backup_jobs migrations
     if !has_column("backup_jobs", "max_versions") {
@@ -127,6 +228,74 @@ pub fn migrate(pool: &DbPool, data_dir: &Path, keys_dir: &Path) -> anyhow::Resul
         )?;
     }
 
+    // backup_jobs migrations (retry policy)
+    if !has_column("backup_jobs", "retry_max_attempts") {
+        conn.execute_batch(
+            "ALTER TABLE backup_jobs ADD COLUMN retry_max_attempts INTEGER NOT NULL DEFAULT 3",
+        )?;
+    }
+    if !has_column("backup_jobs", "retry_backoff_secs") {
+        conn.execute_batch(
+            "ALTER TABLE backup_jobs ADD COLUMN retry_backoff_secs INTEGER NOT NULL DEFAULT 300",
+        )?;
+    }
+
+    // backup_jobs migrations (file error threshold; NULL means no limit)
+    if !has_column("backup_jobs", "max_file_errors") {
+        conn.execute_batch("ALTER TABLE backup_jobs ADD COLUMN max_file_errors INTEGER")?;
+    }
+
+    // backup_jobs migrations (upload compression; NULL means zstd at its default level)
+    if !has_column("backup_jobs", "compression") {
+        conn.execute_batch("ALTER TABLE backup_jobs ADD COLUMN compression TEXT")?;
+    }
+    if !has_column("backup_jobs", "compression_level") {
+        conn.execute_batch("ALTER TABLE backup_jobs ADD COLUMN compression_level INTEGER")?;
+    }
+
+    // backup_jobs migrations (per-root labels; roots without one get a derived label)
+    if !has_column("backup_jobs", "root_labels") {
+        conn.execute_batch("ALTER TABLE backup_jobs ADD COLUMN root_labels TEXT NOT NULL DEFAULT '{}'")?;
+    }
+
+    // backup_jobs: allow the 'queued' and 'completed_with_warnings' statuses
+    if widen_status_check(&conn, "backup_jobs", "'idle','running'", "'idle','queued','running'")? {
+        tracing::info!("[DB] Rebuilt backup_jobs to allow the queued status");
+    }
+    if widen_status_check(&conn, "backup_jobs", "'completed',", "'completed','completed_with_warnings',")? {
+        tracing::info!("[DB] Rebuilt backup_jobs to allow the completed_with_warnings status");
+    }
+
+    // backup_queue: allow runs recovered after a restart to wait for capacity
+    if widen_status_check(&conn, "backup_queue", "'retry')", "'retry','recovery')")? {
+        tracing::info!("[DB] Rebuilt backup_queue to allow the recovery trigger");
+    }
+
+    // agent_rollout_targets: allow targets updated without a test backup
+    if widen_status_check(&conn, "agent_rollout_targets", "'succeeded',", "'succeeded','unverified',")? {
+        tracing::info!("[DB] Rebuilt agent_rollout_targets to allow the unverified status");
+    }
+
+    // backup_logs migrations (retry attempts)
+    if !has_column("backup_logs", "attempt") {
+        conn.execute_batch(
+            "ALTER TABLE backup_logs ADD COLUMN attempt INTEGER NOT NULL DEFAULT 1",
+        )?;
+    }
+    if !has_column("backup_logs", "retry_of") {
+        conn.execute_batch("ALTER TABLE backup_logs ADD COLUMN retry_of TEXT")?;
+    }
+
+    // backup_logs migrations (per-file errors)
+    if !has_column("backup_logs", "file_errors") {
+        conn.execute_batch(
+            "ALTER TABLE backup_logs ADD COLUMN file_errors INTEGER NOT NULL DEFAULT 0",
+        )?;
+    }
+    if widen_status_check(&conn, "backup_logs", "'completed',", "'completed','completed_with_warnings',")? {
+        tracing::info!("[DB] Rebuilt backup_logs to allow the completed_with_warnings status");
+    }
+
     // backup_versions migrations (incremental backup support)
     if !has_column("backup_versions", "backup_type") {
         conn.execute_batch(
@@ -149,6 +318,53 @@ pub fn migrate(pool: &DbPool, data_dir: &Path, keys_dir: &Path) -> anyhow::Resul
         )?;
     }
 
+    // backup_versions migrations (source roots; NULL for versions stored
+    // relative to their own root, before roots were labelled)
+    if !has_column("backup_versions", "roots") {
+        conn.execute_batch("ALTER TABLE backup_versions ADD COLUMN roots TEXT")?;
+    }
+
+    // backup_versions migrations (reflink or hardlink carry-forward; NULL when
+    // nothing was carried forward)
+    if !has_column("backup_versions", "link_mode") {
+        conn.execute_batch("ALTER TABLE backup_versions ADD COLUMN link_mode TEXT")?;
+    }
+
     tracing::info!("[DB] Migration completed successfully");
     Ok(())
 }
+
+/// Replace `from` with `to` in a CHECK constraint of `table`, unless
+/// the definition already contains `to`. Returns whether the table was rebuilt.
+///
+/// SQLite cannot alter a CHECK constraint, so the table is rebuilt from its
+/// current definition with foreign keys off (otherwise dropping it would
+/// cascade to the tables referencing it).
+fn widen_status_check(conn: &rusqlite::Connection, table: &str, from: &str, to: &str) -> anyhow::Result<bool> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get(0),
+    )?;
+    if sql.contains(to) {
+        return Ok(false);
+    }
+    let new_sql = sql
+        .replacen(&format!("CREATE TABLE {}", table), &format!("CREATE TABLE {}_new", table), 1)
+        .replacen(from, to, 1);
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let rebuilt = conn.execute_batch(&format!(
+        "BEGIN;
+         {new_sql};
+         INSERT INTO {table}_new SELECT * FROM {table};
+         DROP TABLE {table};
+         ALTER TABLE {table}_new RENAME TO {table};
+         COMMIT;"
+    ));
+    if rebuilt.is_err() {
+        let _ = conn.execute_batch("ROLLBACK;");
+    }
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    rebuilt?;
+    Ok(true)
+}
@@ -1,2 +1,12 @@
 pub mod connection;
 pub mod migrate;
+
+/// A migrated database in a directory of its own, for tests
+#[cfg(test)]
+pub fn test_pool() -> connection::DbPool {
+    let dir = std::env::temp_dir().join(format!("backup-db-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = connection::create_pool(dir.join("backup.db").to_str().unwrap());
+    migrate::migrate(&pool, &dir, &dir.join("keys")).unwrap();
+    pool
+}
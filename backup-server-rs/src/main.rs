@@ -14,8 +14,11 @@ use crate::db::migrate::migrate;
 use crate::services::backup_migration::migrate_existing_backups;
 use crate::services::backup_scheduler::BackupScheduler;
 use crate::services::db_backup::backup_database;
+use crate::services::job_queue::start_queue_dispatcher;
 use crate::services::path_migration::migrate_server_folder_names;
+use crate::services::run_recovery::start_run_recovery;
 use crate::services::server_ping::start_ping_service;
+use crate::services::tls::{PeerInfo, TlsListener};
 use crate::state::AppState;
 use std::sync::Arc;
 use tokio::signal;
@@ -58,14 +61,41 @@ async fn main() -> anyhow::Result<()> {
 
     // Backfill manifests for completed versions that predate incremental backup support
     services::agent_orchestrator::backfill_manifests(&pool);
+    // Store versions of single-root jobs under their root label
+    if let Err(e) = services::backup_roots::migrate_root_layout(&pool) {
+        tracing::warn!("Root layout migration failed: {}", e);
+    }
+
+    // Load or generate the TLS certificate
+    let tls = if config.tls_enabled {
+        Some(Arc::new(services::tls::load_or_generate(&config)?))
+    } else {
+        None
+    };
+
+    // Key for signing agent release manifests
+    let release = Arc::new(services::agent_release::ReleaseSigner::load_or_generate(&config.keys_dir)?);
 
     // Build application state
-    let state = Arc::new(AppState::new(pool, config.clone()));
+    let state = Arc::new(AppState::new(pool, config.clone(), tls, release));
+
+    // Reconcile runs left in "running" by a previous process
+    if let Err(e) = start_run_recovery(state.clone()).await {
+        tracing::warn!("Run recovery failed: {}", e);
+    }
+
+    // Halt agent rollouts whose runner died with the previous process
+    if let Err(e) = services::agent_rollout::recover_rollouts(&state).await {
+        tracing::warn!("Rollout recovery failed: {}", e);
+    }
 
     // Start ping service
     let cancel = CancellationToken::new();
     start_ping_service(state.clone(), cancel.clone());
 
+    // Start queued runs as capacity allows
+    start_queue_dispatcher(state.clone(), cancel.clone());
+
     // Initialize cron scheduler
     let scheduler = match BackupScheduler::new(state.clone()).await {
         Ok(s) => {
@@ -84,17 +114,27 @@ async fn main() -> anyhow::Result<()> {
     };
 
     // Build router
-    let app = routes::create_router(state.clone());
+    let app = routes::create_router(state.clone()).into_make_service_with_connect_info::<PeerInfo>();
 
-    // Start HTTP server
+    // Start HTTP(S) server
     let addr = format!("0.0.0.0:{}", config.port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    tracing::info!("Listening on {}", addr);
 
     // Graceful shutdown
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(cancel.clone()))
-        .await?;
+    match &state.tls {
+        Some(tls) => {
+            tracing::info!("Listening on {} (HTTPS)", addr);
+            axum::serve(TlsListener::new(listener, tls.server_config())?, app)
+                .with_graceful_shutdown(shutdown_signal(cancel.clone()))
+                .await?;
+        }
+        None => {
+            tracing::info!("Listening on {}", addr);
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal(cancel.clone()))
+                .await?;
+        }
+    }
 
     // Cleanup
     tracing::info!("Shutting down...");
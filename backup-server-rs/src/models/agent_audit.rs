@@ -0,0 +1,64 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Something an agent refused to do because of its local path policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: String,
+    pub server_id: String,
+    /// `browse` or `backup`.
+    pub action: String,
+    pub path: String,
+    pub reason: String,
+    pub job_id: Option<String>,
+    pub created_at: String,
+}
+
+pub struct CreateAuditEventData<'a> {
+    pub server_id: &'a str,
+    pub action: &'a str,
+    pub path: &'a str,
+    pub reason: &'a str,
+    pub job_id: Option<&'a str>,
+}
+
+fn row_to_event(row: &Row) -> rusqlite::Result<AuditEvent> {
+    Ok(AuditEvent {
+        id: row.get("id")?,
+        server_id: row.get("server_id")?,
+        action: row.get("action")?,
+        path: row.get("path")?,
+        reason: row.get("reason")?,
+        job_id: row.get("job_id")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+/// Newest first.
+pub fn find_by_server(conn: &Connection, server_id: &str, limit: i64) -> anyhow::Result<Vec<AuditEvent>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM agent_audit_events WHERE server_id = ? ORDER BY created_at DESC LIMIT ?",
+    )?;
+    let rows = stmt.query_map(params![server_id, limit], row_to_event)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn create(conn: &Connection, data: &CreateAuditEventData) -> anyhow::Result<AuditEvent> {
+    let id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO agent_audit_events (id, server_id, action, path, reason, job_id, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, data.server_id, data.action, data.path, data.reason, data.job_id, now],
+    )?;
+    Ok(AuditEvent {
+        id,
+        server_id: data.server_id.to_string(),
+        action: data.action.to_string(),
+        path: data.path.to_string(),
+        reason: data.reason.to_string(),
+        job_id: data.job_id.map(str::to_string),
+        created_at: now,
+    })
+}
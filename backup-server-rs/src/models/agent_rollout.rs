@@ -0,0 +1,181 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A campaign updating a set of agents to one release, wave by wave.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollout {
+    pub id: String,
+    pub target_version: String,
+    /// Hash of the release binary the campaign was started with.
+    pub target_sha256: String,
+    pub selector: String, // JSON object stored as text
+    pub canary_percent: Option<i64>,
+    pub wave_size: i64,
+    /// Failed targets tolerated before the campaign halts.
+    pub max_failures: i64,
+    pub test_backup: i64,
+    /// `running`, `completed` or `halted`.
+    pub status: String,
+    pub halt_reason: Option<String>,
+    pub current_wave: i64,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// One server in a rollout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutTarget {
+    pub id: String,
+    pub rollout_id: String,
+    pub server_id: String,
+    pub wave: i64,
+    /// `pending`, `updating`, `verifying`, `succeeded`, `unverified` (updated,
+    /// but no job was idle for the test backup), `failed` or `skipped`.
+    pub status: String,
+    pub from_version: Option<String>,
+    /// Job run as the health check's test backup, if any.
+    pub test_job_id: Option<String>,
+    pub error: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+pub struct CreateRolloutData {
+    pub target_version: String,
+    pub target_sha256: String,
+    pub selector: String,
+    pub canary_percent: Option<i64>,
+    pub wave_size: i64,
+    pub max_failures: i64,
+    pub test_backup: bool,
+    /// `(server_id, wave, status)` for every selected server.
+    pub targets: Vec<(String, i64, &'static str)>,
+}
+
+fn row_to_rollout(row: &Row) -> rusqlite::Result<Rollout> {
+    Ok(Rollout {
+        id: row.get("id")?,
+        target_version: row.get("target_version")?,
+        target_sha256: row.get("target_sha256")?,
+        selector: row.get("selector")?,
+        canary_percent: row.get("canary_percent")?,
+        wave_size: row.get("wave_size")?,
+        max_failures: row.get("max_failures")?,
+        test_backup: row.get("test_backup")?,
+        status: row.get("status")?,
+        halt_reason: row.get("halt_reason")?,
+        current_wave: row.get("current_wave")?,
+        created_at: row.get("created_at")?,
+        finished_at: row.get("finished_at")?,
+    })
+}
+
+fn row_to_target(row: &Row) -> rusqlite::Result<RolloutTarget> {
+    Ok(RolloutTarget {
+        id: row.get("id")?,
+        rollout_id: row.get("rollout_id")?,
+        server_id: row.get("server_id")?,
+        wave: row.get("wave")?,
+        status: row.get("status")?,
+        from_version: row.get("from_version")?,
+        test_job_id: row.get("test_job_id")?,
+        error: row.get("error")?,
+        started_at: row.get("started_at")?,
+        finished_at: row.get("finished_at")?,
+    })
+}
+
+pub fn find_all(conn: &Connection) -> anyhow::Result<Vec<Rollout>> {
+    let mut stmt = conn.prepare("SELECT * FROM agent_rollouts ORDER BY created_at DESC")?;
+    let rows = stmt.query_map([], row_to_rollout)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn find_by_id(conn: &Connection, id: &str) -> anyhow::Result<Option<Rollout>> {
+    let mut stmt = conn.prepare("SELECT * FROM agent_rollouts WHERE id = ?")?;
+    let mut rows = stmt.query_map(params![id], row_to_rollout)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+pub fn find_by_status(conn: &Connection, status: &str) -> anyhow::Result<Vec<Rollout>> {
+    let mut stmt = conn.prepare("SELECT * FROM agent_rollouts WHERE status = ? ORDER BY created_at")?;
+    let rows = stmt.query_map(params![status], row_to_rollout)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+/// Targets in wave order.
+pub fn find_targets(conn: &Connection, rollout_id: &str) -> anyhow::Result<Vec<RolloutTarget>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.* FROM agent_rollout_targets t
+         LEFT JOIN source_servers s ON s.id = t.server_id
+         WHERE t.rollout_id = ? ORDER BY t.wave, s.name",
+    )?;
+    let rows = stmt.query_map(params![rollout_id], row_to_target)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn create(conn: &Connection, data: &CreateRolloutData) -> anyhow::Result<Rollout> {
+    let id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO agent_rollouts (id, target_version, target_sha256, selector, canary_percent, wave_size, max_failures, test_backup, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            id,
+            data.target_version,
+            data.target_sha256,
+            data.selector,
+            data.canary_percent,
+            data.wave_size,
+            data.max_failures,
+            data.test_backup as i64,
+            now
+        ],
+    )?;
+    for (server_id, wave, status) in &data.targets {
+        tx.execute(
+            "INSERT INTO agent_rollout_targets (id, rollout_id, server_id, wave, status)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![Uuid::new_v4().to_string(), id, server_id, wave, status],
+        )?;
+    }
+    tx.commit()?;
+    find_by_id(conn, &id)?
+        .ok_or_else(|| anyhow::anyhow!("Failed to retrieve created rollout"))
+}
+
+pub fn update_fields(conn: &Connection, id: &str, fields: &[(&str, &dyn rusqlite::types::ToSql)]) -> anyhow::Result<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let sets: Vec<String> = fields.iter().map(|(k, _)| format!("{} = ?", k)).collect();
+    let sql = format!("UPDATE agent_rollouts SET {} WHERE id = ?", sets.join(", "));
+    let mut params: Vec<&dyn rusqlite::types::ToSql> = fields.iter().map(|(_, v)| *v).collect();
+    params.push(&id);
+    conn.execute(&sql, params.as_slice())?;
+    Ok(())
+}
+
+pub fn update_target(conn: &Connection, id: &str, fields: &[(&str, &dyn rusqlite::types::ToSql)]) -> anyhow::Result<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let sets: Vec<String> = fields.iter().map(|(k, _)| format!("{} = ?", k)).collect();
+    let sql = format!("UPDATE agent_rollout_targets SET {} WHERE id = ?", sets.join(", "));
+    let mut params: Vec<&dyn rusqlite::types::ToSql> = fields.iter().map(|(_, v)| *v).collect();
+    params.push(&id);
+    conn.execute(&sql, params.as_slice())?;
+    Ok(())
+}
+
+/// Mark the rollout halted, unless it already finished.
+pub fn halt(conn: &Connection, id: &str, reason: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute(
+        "UPDATE agent_rollouts SET status = 'halted', halt_reason = ?, finished_at = ?
+         WHERE id = ? AND status = 'running'",
+        params![reason, chrono::Utc::now().to_rfc3339(), id],
+    )?;
+    Ok(changes > 0)
+}
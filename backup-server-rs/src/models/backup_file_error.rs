@@ -0,0 +1,73 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A file the agent could not back up during a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileError {
+    pub id: String,
+    pub log_id: String,
+    pub job_id: String,
+    pub path: String,
+    /// `scan`, `open`, `read` or `upload`.
+    pub operation: String,
+    pub errno: Option<i64>,
+    pub message: String,
+    pub created_at: String,
+}
+
+pub struct CreateFileErrorData<'a> {
+    pub log_id: &'a str,
+    pub job_id: &'a str,
+    pub path: &'a str,
+    pub operation: &'a str,
+    pub errno: Option<i64>,
+    pub message: &'a str,
+}
+
+fn row_to_error(row: &Row) -> rusqlite::Result<FileError> {
+    Ok(FileError {
+        id: row.get("id")?,
+        log_id: row.get("log_id")?,
+        job_id: row.get("job_id")?,
+        path: row.get("path")?,
+        operation: row.get("operation")?,
+        errno: row.get("errno")?,
+        message: row.get("message")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+/// In the order they were reported.
+pub fn find_by_log(conn: &Connection, log_id: &str, limit: i64, offset: i64) -> anyhow::Result<Vec<FileError>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM backup_file_errors WHERE log_id = ? ORDER BY created_at, rowid LIMIT ? OFFSET ?",
+    )?;
+    let rows = stmt.query_map(params![log_id, limit, offset], row_to_error)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+/// Store the error and count it on the run's log.
+pub fn create(conn: &Connection, data: &CreateFileErrorData) -> anyhow::Result<FileError> {
+    let id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO backup_file_errors (id, log_id, job_id, path, operation, errno, message, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![id, data.log_id, data.job_id, data.path, data.operation, data.errno, data.message, now],
+    )?;
+    conn.execute(
+        "UPDATE backup_logs SET file_errors = file_errors + 1 WHERE id = ?",
+        params![data.log_id],
+    )?;
+    Ok(FileError {
+        id,
+        log_id: data.log_id.to_string(),
+        job_id: data.job_id.to_string(),
+        path: data.path.to_string(),
+        operation: data.operation.to_string(),
+        errno: data.errno,
+        message: data.message.to_string(),
+        created_at: now,
+    })
+}
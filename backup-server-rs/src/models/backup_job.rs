@@ -1,5 +1,7 @@
-use rusqlite::{params, Connection, Row};
-use serde::{Deserialize, Serialize};
+use backup_protocol::compression::{Algorithm, Compression};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 // ── BackupJob ──
@@ -10,24 +12,60 @@ pub struct BackupJob {
     pub server_id: String,
     pub name: String,
     pub remote_paths: String, // JSON array stored as text
+    /// Labels chosen for some of `remote_paths` (JSON object path → label);
+    /// the others get a label derived from their path.
+    pub root_labels: String,
     pub local_path: String,
     pub cron_schedule: Option<String>,
     pub status: String,
     pub rsync_options: String,
+    /// Most files the agent uploads at once; it tunes concurrency below this.
     pub max_parallel: i64,
     pub enabled: i64,
     pub max_versions: i64,
+    /// Retries allowed after a scheduled run fails with a transient error (0 disables).
+    pub retry_max_attempts: i64,
+    /// Delay before the first retry; doubled for each further attempt.
+    pub retry_backoff_secs: i64,
+    /// Fail a run once more files than this could not be backed up (no limit if unset).
+    pub max_file_errors: Option<i64>,
+    /// Upload compression: `none`, `zstd` (the default if unset), `lz4` or `gzip`.
+    pub compression: Option<String>,
+    /// Level of `compression`; the algorithm's default if unset.
+    pub compression_level: Option<i64>,
     pub last_run_at: Option<String>,
     pub created_at: String,
     pub updated_at: String,
 }
 
+impl BackupJob {
+    /// How the job's uploads are compressed
+    pub fn compression(&self) -> Result<Compression, String> {
+        compression(self.compression.as_deref(), self.compression_level)
+    }
+}
+
+/// Compression settings from a job's `compression` and `compression_level`
+pub fn compression(algorithm: Option<&str>, level: Option<i64>) -> Result<Compression, String> {
+    let algorithm = match algorithm {
+        Some(value) => Algorithm::parse(value)
+            .ok_or_else(|| format!("Unknown compression {:?}: expected none, zstd, lz4 or gzip", value))?,
+        None => Algorithm::default(),
+    };
+    let level = level
+        .map(|level| i32::try_from(level).map_err(|_| format!("Invalid compression level {}", level)))
+        .transpose()?;
+    Compression::new(algorithm, level)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateBackupJobRequest {
     pub server_id: String,
     pub name: String,
     pub remote_paths: Vec<String>,
     #[serde(default)]
+    pub root_labels: HashMap<String, String>,
+    #[serde(default)]
     pub local_path: String,
     pub cron_schedule: Option<String>,
     #[serde(default)]
@@ -38,22 +76,78 @@ pub struct CreateBackupJobRequest {
     pub enabled: i64,
     #[serde(default = "default_max_versions")]
     pub max_versions: i64,
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: i64,
+    #[serde(default = "default_retry_backoff_secs")]
+    pub retry_backoff_secs: i64,
+    #[serde(default)]
+    pub max_file_errors: Option<i64>,
+    #[serde(default)]
+    pub compression: Option<String>,
+    #[serde(default)]
+    pub compression_level: Option<i64>,
+}
+
+impl CreateBackupJobRequest {
+    /// A job of `server_id` backing up `remote_paths`, with the API's defaults, for tests
+    #[cfg(test)]
+    pub fn for_test(server_id: &str, name: &str, remote_paths: &[&str]) -> Self {
+        Self {
+            server_id: server_id.to_string(),
+            name: name.to_string(),
+            remote_paths: remote_paths.iter().map(|p| p.to_string()).collect(),
+            root_labels: HashMap::new(),
+            local_path: String::new(),
+            cron_schedule: None,
+            rsync_options: String::new(),
+            max_parallel: default_max_parallel(),
+            enabled: default_enabled(),
+            max_versions: default_max_versions(),
+            retry_max_attempts: default_retry_max_attempts(),
+            retry_backoff_secs: default_retry_backoff_secs(),
+            max_file_errors: None,
+            compression: None,
+            compression_level: None,
+        }
+    }
 }
 
 fn default_max_parallel() -> i64 { 4 }
 fn default_enabled() -> i64 { 1 }
 fn default_max_versions() -> i64 { 7 }
+fn default_retry_max_attempts() -> i64 { 3 }
+fn default_retry_backoff_secs() -> i64 { 300 }
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateBackupJobRequest {
     pub name: Option<String>,
     pub remote_paths: Option<Vec<String>>,
+    pub root_labels: Option<HashMap<String, String>>,
     pub local_path: Option<String>,
+    #[serde(default, deserialize_with = "double_option")]
     pub cron_schedule: Option<Option<String>>,
     pub rsync_options: Option<String>,
     pub max_parallel: Option<i64>,
     pub enabled: Option<i64>,
     pub max_versions: Option<i64>,
+    pub retry_max_attempts: Option<i64>,
+    pub retry_backoff_secs: Option<i64>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub max_file_errors: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub compression: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub compression_level: Option<Option<i64>>,
+}
+
+/// A field left out stays `None`, an explicit `null` is `Some(None)` and
+/// clears the setting.
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 fn row_to_job(row: &Row) -> rusqlite::Result<BackupJob> {
@@ -62,6 +156,7 @@ fn row_to_job(row: &Row) -> rusqlite::Result<BackupJob> {
         server_id: row.get("server_id")?,
         name: row.get("name")?,
         remote_paths: row.get("remote_paths")?,
+        root_labels: row.get("root_labels")?,
         local_path: row.get("local_path")?,
         cron_schedule: row.get("cron_schedule")?,
         status: row.get("status")?,
@@ -69,6 +164,11 @@ fn row_to_job(row: &Row) -> rusqlite::Result<BackupJob> {
         max_parallel: row.get("max_parallel")?,
         enabled: row.get("enabled")?,
         max_versions: row.get("max_versions")?,
+        retry_max_attempts: row.get("retry_max_attempts")?,
+        retry_backoff_secs: row.get("retry_backoff_secs")?,
+        max_file_errors: row.get("max_file_errors")?,
+        compression: row.get("compression")?,
+        compression_level: row.get("compression_level")?,
         last_run_at: row.get("last_run_at")?,
         created_at: row.get("created_at")?,
         updated_at: row.get("updated_at")?,
@@ -99,20 +199,27 @@ pub fn create(conn: &Connection, data: &CreateBackupJobRequest) -> anyhow::Resul
     let id = Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     let remote_paths_json = serde_json::to_string(&data.remote_paths)?;
+    let root_labels_json = serde_json::to_string(&data.root_labels)?;
     conn.execute(
-        "INSERT INTO backup_jobs (id, server_id, name, remote_paths, local_path, cron_schedule, rsync_options, max_parallel, enabled, max_versions, created_at, updated_at)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        "INSERT INTO backup_jobs (id, server_id, name, remote_paths, root_labels, local_path, cron_schedule, rsync_options, max_parallel, enabled, max_versions, retry_max_attempts, retry_backoff_secs, max_file_errors, compression, compression_level, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
         params![
             id,
             data.server_id,
             data.name,
             remote_paths_json,
+            root_labels_json,
             data.local_path,
             data.cron_schedule,
             data.rsync_options,
             data.max_parallel,
             data.enabled,
             data.max_versions,
+            data.retry_max_attempts,
+            data.retry_backoff_secs,
+            data.max_file_errors,
+            data.compression,
+            data.compression_level,
             now,
             now,
         ],
@@ -138,6 +245,10 @@ pub fn update(conn: &Connection, id: &str, data: &UpdateBackupJobRequest) -> any
         sets.push("remote_paths = ?");
         values.push(Box::new(serde_json::to_string(remote_paths).unwrap()));
     }
+    if let Some(ref root_labels) = data.root_labels {
+        sets.push("root_labels = ?");
+        values.push(Box::new(serde_json::to_string(root_labels).unwrap()));
+    }
     if let Some(ref local_path) = data.local_path {
         sets.push("local_path = ?");
         values.push(Box::new(local_path.clone()));
@@ -162,6 +273,26 @@ pub fn update(conn: &Connection, id: &str, data: &UpdateBackupJobRequest) -> any
         sets.push("max_versions = ?");
         values.push(Box::new(max_versions));
     }
+    if let Some(retry_max_attempts) = data.retry_max_attempts {
+        sets.push("retry_max_attempts = ?");
+        values.push(Box::new(retry_max_attempts));
+    }
+    if let Some(retry_backoff_secs) = data.retry_backoff_secs {
+        sets.push("retry_backoff_secs = ?");
+        values.push(Box::new(retry_backoff_secs));
+    }
+    if let Some(max_file_errors) = data.max_file_errors {
+        sets.push("max_file_errors = ?");
+        values.push(Box::new(max_file_errors));
+    }
+    if let Some(ref compression) = data.compression {
+        sets.push("compression = ?");
+        values.push(Box::new(compression.clone()));
+    }
+    if let Some(compression_level) = data.compression_level {
+        sets.push("compression_level = ?");
+        values.push(Box::new(compression_level));
+    }
 
     if sets.is_empty() {
         return find_by_id(conn, id);
@@ -176,6 +307,14 @@ pub fn update(conn: &Connection, id: &str, data: &UpdateBackupJobRequest) -> any
     find_by_id(conn, id)
 }
 
+pub fn update_root_labels(conn: &Connection, id: &str, labels: &HashMap<String, String>) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE backup_jobs SET root_labels = ? WHERE id = ?",
+        params![serde_json::to_string(labels)?, id],
+    )?;
+    Ok(())
+}
+
 pub fn update_status(conn: &Connection, id: &str, status: &str) -> anyhow::Result<()> {
     conn.execute(
         "UPDATE backup_jobs SET status = ?, updated_at = datetime('now') WHERE id = ?",
@@ -202,6 +341,12 @@ pub struct BackupLog {
     pub files_transferred: i64,
     pub output: String,
     pub error: Option<String>,
+    /// 1 for the original run, 2 for its first retry, and so on.
+    pub attempt: i64,
+    /// Log ID of the original run this attempt retries.
+    pub retry_of: Option<String>,
+    /// Files that could not be backed up (listed in `backup_file_errors`).
+    pub file_errors: i64,
 }
 
 fn row_to_log(row: &Row) -> rusqlite::Result<BackupLog> {
@@ -215,6 +360,9 @@ fn row_to_log(row: &Row) -> rusqlite::Result<BackupLog> {
         files_transferred: row.get("files_transferred")?,
         output: row.get("output")?,
         error: row.get("error")?,
+        attempt: row.get("attempt")?,
+        retry_of: row.get("retry_of")?,
+        file_errors: row.get("file_errors")?,
     })
 }
 
@@ -226,12 +374,18 @@ pub fn find_logs_by_job_id(conn: &Connection, job_id: &str, limit: i64) -> anyho
     Ok(rows.filter_map(|r| r.ok()).collect())
 }
 
-pub fn create_log(conn: &Connection, job_id: &str) -> anyhow::Result<BackupLog> {
+pub fn find_log_by_id(conn: &Connection, id: &str) -> anyhow::Result<Option<BackupLog>> {
+    let mut stmt = conn.prepare("SELECT * FROM backup_logs WHERE id = ?")?;
+    let mut rows = stmt.query_map(params![id], row_to_log)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+pub fn create_log(conn: &Connection, job_id: &str, attempt: i64, retry_of: Option<&str>) -> anyhow::Result<BackupLog> {
     let id = Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     conn.execute(
-        "INSERT INTO backup_logs (id, job_id, started_at) VALUES (?1, ?2, ?3)",
-        params![id, job_id, now],
+        "INSERT INTO backup_logs (id, job_id, started_at, attempt, retry_of) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id, job_id, now, attempt, retry_of],
     )?;
     let mut stmt = conn.prepare("SELECT * FROM backup_logs WHERE id = ?")?;
     let mut rows = stmt.query_map(params![id], |row| row_to_log(row))?;
@@ -240,6 +394,31 @@ pub fn create_log(conn: &Connection, job_id: &str) -> anyhow::Result<BackupLog>
         .map_err(Into::into)
 }
 
+/// Largest `output` a run keeps; agent log lines past it are dropped.
+pub const MAX_LOG_OUTPUT: i64 = 1024 * 1024;
+
+/// The log of the job's latest run, which agent reports for the job belong to.
+pub fn latest_log_id(conn: &Connection, job_id: &str) -> anyhow::Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT id FROM backup_logs WHERE job_id = ? ORDER BY started_at DESC LIMIT 1",
+            params![job_id],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Append a line to the output of the job's latest run. Returns the run's log
+/// ID, or `None` if the job has no run or its output is full.
+pub fn append_log_output(conn: &Connection, job_id: &str, line: &str) -> anyhow::Result<Option<String>> {
+    let Some(log_id) = latest_log_id(conn, job_id)? else { return Ok(None) };
+    let changes = conn.execute(
+        "UPDATE backup_logs SET output = output || ?1 || char(10) WHERE id = ?2 AND length(output) < ?3",
+        params![line, log_id, MAX_LOG_OUTPUT],
+    )?;
+    Ok((changes > 0).then_some(log_id))
+}
+
 pub fn update_log(conn: &Connection, id: &str, fields: &[(&str, &dyn rusqlite::types::ToSql)]) -> anyhow::Result<()> {
     if fields.is_empty() {
         return Ok(());
@@ -0,0 +1,148 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A run waiting for capacity. Entries are served by `priority` (highest
+/// first), then `sort_order`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub job_id: String,
+    pub server_id: String,
+    /// `manual`, `scheduled` or `retry`.
+    pub trigger_kind: String,
+    pub priority: i64,
+    pub sort_order: i64,
+    pub attempt: i64,
+    pub retry_of: Option<String>,
+    pub enqueued_at: String,
+}
+
+fn row_to_entry(row: &Row) -> rusqlite::Result<QueueEntry> {
+    Ok(QueueEntry {
+        id: row.get("id")?,
+        job_id: row.get("job_id")?,
+        server_id: row.get("server_id")?,
+        trigger_kind: row.get("trigger_kind")?,
+        priority: row.get("priority")?,
+        sort_order: row.get("sort_order")?,
+        attempt: row.get("attempt")?,
+        retry_of: row.get("retry_of")?,
+        enqueued_at: row.get("enqueued_at")?,
+    })
+}
+
+/// All entries in service order.
+pub fn find_all(conn: &Connection) -> anyhow::Result<Vec<QueueEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM backup_queue ORDER BY priority DESC, sort_order ASC, enqueued_at ASC",
+    )?;
+    let rows = stmt.query_map([], row_to_entry)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn find_by_id(conn: &Connection, id: &str) -> anyhow::Result<Option<QueueEntry>> {
+    let mut stmt = conn.prepare("SELECT * FROM backup_queue WHERE id = ?")?;
+    let mut rows = stmt.query_map(params![id], row_to_entry)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+pub fn find_by_job_id(conn: &Connection, job_id: &str) -> anyhow::Result<Option<QueueEntry>> {
+    let mut stmt = conn.prepare("SELECT * FROM backup_queue WHERE job_id = ?")?;
+    let mut rows = stmt.query_map(params![job_id], row_to_entry)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+pub struct CreateQueueEntryData {
+    pub job_id: String,
+    pub server_id: String,
+    pub trigger_kind: String,
+    pub priority: i64,
+    pub attempt: i64,
+    pub retry_of: Option<String>,
+}
+
+/// Append an entry behind everything already queued at its priority.
+pub fn create(conn: &Connection, data: &CreateQueueEntryData) -> anyhow::Result<QueueEntry> {
+    let id = Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO backup_queue (id, job_id, server_id, trigger_kind, priority, sort_order, attempt, retry_of, enqueued_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, (SELECT COALESCE(MAX(sort_order), 0) + 1 FROM backup_queue), ?6, ?7, ?8)",
+        params![id, data.job_id, data.server_id, data.trigger_kind, data.priority, data.attempt, data.retry_of, now],
+    )?;
+    find_by_id(conn, &id)?
+        .ok_or_else(|| anyhow::anyhow!("Failed to retrieve created queue entry"))
+}
+
+pub fn update_priority(conn: &Connection, id: &str, priority: i64) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE backup_queue SET priority = ? WHERE id = ?",
+        params![priority, id],
+    )?;
+    Ok(())
+}
+
+/// Hand a queued entry over to the trigger of a later, higher-priority run:
+/// its kind, priority, attempt and retried run change together.
+pub fn update_trigger(
+    conn: &Connection,
+    id: &str,
+    trigger_kind: &str,
+    priority: i64,
+    attempt: i64,
+    retry_of: Option<&str>,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE backup_queue SET trigger_kind = ?, priority = ?, attempt = ?, retry_of = ? WHERE id = ?",
+        params![trigger_kind, priority, attempt, retry_of, id],
+    )?;
+    Ok(())
+}
+
+/// Move an entry to `position` (0-based) in service order. The entry takes the
+/// priority of the entry it lands in front of (or behind, at the end) so the
+/// new order is stable under the priority-first sort.
+pub fn move_to(conn: &Connection, id: &str, position: usize) -> anyhow::Result<bool> {
+    let mut entries = find_all(conn)?;
+    let Some(index) = entries.iter().position(|e| e.id == id) else {
+        return Ok(false);
+    };
+    let mut entry = entries.remove(index);
+    let position = position.min(entries.len());
+    if let Some(neighbour) = entries.get(position).or_else(|| entries.last()) {
+        entry.priority = neighbour.priority;
+    }
+    entries.insert(position, entry);
+
+    let tx = conn.unchecked_transaction()?;
+    for (order, e) in entries.iter().enumerate() {
+        tx.execute(
+            "UPDATE backup_queue SET priority = ?, sort_order = ? WHERE id = ?",
+            params![e.priority, order as i64, e.id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn delete(conn: &Connection, id: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute("DELETE FROM backup_queue WHERE id = ?", params![id])?;
+    Ok(changes > 0)
+}
+
+pub fn delete_by_job_id(conn: &Connection, job_id: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute("DELETE FROM backup_queue WHERE job_id = ?", params![job_id])?;
+    Ok(changes > 0)
+}
+
+/// Reset jobs marked `queued` that no longer have a queue entry (e.g. the server
+/// stopped between dequeuing a run and starting it).
+pub fn release_orphaned_jobs(conn: &Connection) -> anyhow::Result<usize> {
+    let changes = conn.execute(
+        "UPDATE backup_jobs SET status = 'idle', updated_at = datetime('now')
+         WHERE status = 'queued' AND id NOT IN (SELECT job_id FROM backup_queue)",
+        [],
+    )?;
+    Ok(changes)
+}
@@ -0,0 +1,69 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A retry of a failed scheduled run, waiting for `run_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRetry {
+    pub id: String,
+    pub job_id: String,
+    /// Log ID of the original scheduled run.
+    pub retry_of: String,
+    /// Attempt number the retry will run as (2 for the first retry).
+    pub attempt: i64,
+    pub run_at: String,
+    pub last_error: Option<String>,
+    pub created_at: String,
+}
+
+fn row_to_retry(row: &Row) -> rusqlite::Result<BackupRetry> {
+    Ok(BackupRetry {
+        id: row.get("id")?,
+        job_id: row.get("job_id")?,
+        retry_of: row.get("retry_of")?,
+        attempt: row.get("attempt")?,
+        run_at: row.get("run_at")?,
+        last_error: row.get("last_error")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+pub struct CreateRetryData {
+    pub job_id: String,
+    pub retry_of: String,
+    pub attempt: i64,
+    pub run_at: String,
+    pub last_error: Option<String>,
+}
+
+pub fn create(conn: &Connection, data: &CreateRetryData) -> anyhow::Result<BackupRetry> {
+    let id = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO backup_retries (id, job_id, retry_of, attempt, run_at, last_error) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![id, data.job_id, data.retry_of, data.attempt, data.run_at, data.last_error],
+    )?;
+    let mut stmt = conn.prepare("SELECT * FROM backup_retries WHERE id = ?")?;
+    let mut rows = stmt.query_map(params![id], row_to_retry)?;
+    rows.next()
+        .ok_or_else(|| anyhow::anyhow!("Failed to retrieve created retry"))?
+        .map_err(Into::into)
+}
+
+/// Retries whose `run_at` is at or before `now` (RFC 3339), oldest first.
+pub fn find_due(conn: &Connection, now: &str) -> anyhow::Result<Vec<BackupRetry>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM backup_retries WHERE run_at <= ? ORDER BY run_at ASC",
+    )?;
+    let rows = stmt.query_map(params![now], row_to_retry)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn delete(conn: &Connection, id: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute("DELETE FROM backup_retries WHERE id = ?", params![id])?;
+    Ok(changes > 0)
+}
+
+pub fn delete_by_job_id(conn: &Connection, job_id: &str) -> anyhow::Result<i64> {
+    let changes = conn.execute("DELETE FROM backup_retries WHERE job_id = ?", params![job_id])?;
+    Ok(changes as i64)
+}
@@ -20,6 +20,12 @@ pub struct BackupVersion {
     pub files_unchanged: i64,
     pub bytes_unchanged: i64,
     pub files_deleted: i64,
+    /// Source roots (JSON array of `{path, label}`), each stored under its
+    /// label. NULL for versions storing files relative to their own root.
+    pub roots: Option<String>,
+    /// How unchanged files were carried forward: `reflink`, `hardlink` or
+    /// `mixed`. NULL when none were.
+    pub link_mode: Option<String>,
 }
 
 fn row_to_version(row: &Row) -> rusqlite::Result<BackupVersion> {
@@ -40,6 +46,8 @@ fn row_to_version(row: &Row) -> rusqlite::Result<BackupVersion> {
         files_unchanged: row.get("files_unchanged").unwrap_or(0),
         bytes_unchanged: row.get("bytes_unchanged").unwrap_or(0),
         files_deleted: row.get("files_deleted").unwrap_or(0),
+        roots: row.get("roots").unwrap_or(None),
+        link_mode: row.get("link_mode").unwrap_or(None),
     })
 }
 
@@ -63,6 +71,14 @@ pub fn find_by_job_id(conn: &Connection, job_id: &str) -> anyhow::Result<Vec<Bac
     Ok(rows.filter_map(|r| r.ok()).collect())
 }
 
+pub fn find_by_status(conn: &Connection, status: &str) -> anyhow::Result<Vec<BackupVersion>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM backup_versions WHERE status = ? ORDER BY version_timestamp ASC",
+    )?;
+    let rows = stmt.query_map(params![status], row_to_version)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
 pub fn find_latest_completed(conn: &Connection, job_id: &str) -> anyhow::Result<Option<BackupVersion>> {
     let mut stmt = conn.prepare(
         "SELECT * FROM backup_versions WHERE job_id = ? AND status = 'completed' ORDER BY version_timestamp DESC LIMIT 1",
@@ -76,15 +92,16 @@ pub struct CreateVersionData {
     pub log_id: String,
     pub version_timestamp: String,
     pub local_path: String,
+    pub roots: Option<String>,
 }
 
 pub fn create(conn: &Connection, data: &CreateVersionData) -> anyhow::Result<BackupVersion> {
     let id = Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
     conn.execute(
-        "INSERT INTO backup_versions (id, job_id, log_id, version_timestamp, local_path, created_at)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![id, data.job_id, data.log_id, data.version_timestamp, data.local_path, now],
+        "INSERT INTO backup_versions (id, job_id, log_id, version_timestamp, local_path, roots, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, data.job_id, data.log_id, data.version_timestamp, data.local_path, data.roots, now],
     )?;
     find_by_id(conn, &id)?
         .ok_or_else(|| anyhow::anyhow!("Failed to retrieve created version"))
@@ -97,6 +114,7 @@ pub struct CompletionData {
     pub files_unchanged: i64,
     pub bytes_unchanged: i64,
     pub files_deleted: i64,
+    pub link_mode: Option<String>,
 }
 
 pub fn update_completion(conn: &Connection, id: &str, bytes_transferred: i64, files_transferred: i64) -> anyhow::Result<()> {
@@ -111,8 +129,8 @@ pub fn update_completion(conn: &Connection, id: &str, bytes_transferred: i64, fi
 pub fn update_completion_incremental(conn: &Connection, id: &str, data: &CompletionData) -> anyhow::Result<()> {
     let now = chrono::Utc::now().to_rfc3339();
     conn.execute(
-        "UPDATE backup_versions SET status = 'completed', bytes_transferred = ?, files_transferred = ?, backup_type = ?, files_unchanged = ?, bytes_unchanged = ?, files_deleted = ?, completed_at = ? WHERE id = ?",
-        params![data.bytes_transferred, data.files_transferred, data.backup_type, data.files_unchanged, data.bytes_unchanged, data.files_deleted, now, id],
+        "UPDATE backup_versions SET status = 'completed', bytes_transferred = ?, files_transferred = ?, backup_type = ?, files_unchanged = ?, bytes_unchanged = ?, files_deleted = ?, link_mode = ?, completed_at = ? WHERE id = ?",
+        params![data.bytes_transferred, data.files_transferred, data.backup_type, data.files_unchanged, data.bytes_unchanged, data.files_deleted, data.link_mode, now, id],
     )?;
     Ok(())
 }
@@ -0,0 +1,83 @@
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// A single-use token an agent exchanges for its `server_id` and credentials.
+/// Only a hash of the token is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentToken {
+    pub id: String,
+    /// Name for the server created on enrollment (defaults to the agent's hostname).
+    pub name: Option<String>,
+    pub expires_at: String,
+    pub used_at: Option<String>,
+    pub server_id: Option<String>,
+    pub created_at: String,
+}
+
+fn row_to_token(row: &Row) -> rusqlite::Result<EnrollmentToken> {
+    Ok(EnrollmentToken {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        expires_at: row.get("expires_at")?,
+        used_at: row.get("used_at")?,
+        server_id: row.get("server_id")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Tokens that are neither used nor expired, newest first.
+pub fn find_pending(conn: &Connection, now: &str) -> anyhow::Result<Vec<EnrollmentToken>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM enrollment_tokens WHERE used_at IS NULL AND expires_at > ? ORDER BY created_at DESC",
+    )?;
+    let rows = stmt.query_map(params![now], row_to_token)?;
+    Ok(rows.filter_map(|r| r.ok()).collect())
+}
+
+pub fn find_by_id(conn: &Connection, id: &str) -> anyhow::Result<Option<EnrollmentToken>> {
+    let mut stmt = conn.prepare("SELECT * FROM enrollment_tokens WHERE id = ?")?;
+    let mut rows = stmt.query_map(params![id], row_to_token)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+pub fn find_by_token(conn: &Connection, token: &str) -> anyhow::Result<Option<EnrollmentToken>> {
+    let mut stmt = conn.prepare("SELECT * FROM enrollment_tokens WHERE token_hash = ?")?;
+    let mut rows = stmt.query_map(params![hash_token(token)], row_to_token)?;
+    Ok(rows.next().and_then(|r| r.ok()))
+}
+
+/// Store a new token. The plaintext `token` is not kept.
+pub fn create(conn: &Connection, token: &str, name: Option<&str>, expires_at: &str) -> anyhow::Result<EnrollmentToken> {
+    let id = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO enrollment_tokens (id, token_hash, name, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![id, hash_token(token), name, expires_at],
+    )?;
+    find_by_id(conn, &id)?
+        .ok_or_else(|| anyhow::anyhow!("Failed to retrieve created enrollment token"))
+}
+
+/// Mark the token used by `server_id`. Returns false if it was already used or
+/// has expired, so two agents racing with the same token cannot both enroll.
+pub fn consume(conn: &Connection, id: &str, server_id: &str, now: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute(
+        "UPDATE enrollment_tokens SET used_at = ?1, server_id = ?2
+         WHERE id = ?3 AND used_at IS NULL AND expires_at > ?1",
+        params![now, server_id, id],
+    )?;
+    Ok(changes > 0)
+}
+
+pub fn delete(conn: &Connection, id: &str) -> anyhow::Result<bool> {
+    let changes = conn.execute("DELETE FROM enrollment_tokens WHERE id = ?", params![id])?;
+    Ok(changes > 0)
+}
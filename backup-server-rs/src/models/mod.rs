@@ -1,4 +1,10 @@
 pub mod server;
 pub mod backup_job;
 pub mod backup_version;
+pub mod backup_retry;
+pub mod backup_queue;
+pub mod enrollment_token;
 pub mod settings;
+pub mod agent_rollout;
+pub mod agent_audit;
+pub mod backup_file_error;
@@ -1,3 +1,4 @@
+use crate::ws::protocol::AgentProtocol;
 use rusqlite::{params, Connection, Row};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,6 +18,12 @@ pub struct Server {
     pub agent_status: String,
     pub agent_version: Option<String>,
     pub agent_last_seen: Option<String>,
+    /// Fingerprint of the client certificate issued to this server's agent.
+    pub agent_cert_fingerprint: Option<String>,
+    /// Protocol version the agent announced; `None` for agents that predate versioning.
+    pub agent_protocol_version: Option<i64>,
+    pub agent_capabilities: String, // JSON array stored as text
+    pub tags: String, // JSON array stored as text
     pub last_seen_at: Option<String>,
     pub created_at: String,
     pub updated_at: String,
@@ -42,6 +49,7 @@ pub struct UpdateServerRequest {
     pub hostname: Option<String>,
     pub port: Option<i64>,
     pub ssh_user: Option<String>,
+    pub tags: Option<Vec<String>>,
 }
 
 fn row_to_server(row: &Row) -> rusqlite::Result<Server> {
@@ -59,6 +67,10 @@ fn row_to_server(row: &Row) -> rusqlite::Result<Server> {
         agent_status: row.get("agent_status")?,
         agent_version: row.get("agent_version")?,
         agent_last_seen: row.get("agent_last_seen")?,
+        agent_cert_fingerprint: row.get("agent_cert_fingerprint")?,
+        agent_protocol_version: row.get("agent_protocol_version")?,
+        agent_capabilities: row.get("agent_capabilities")?,
+        tags: row.get("tags")?,
         last_seen_at: row.get("last_seen_at")?,
         created_at: row.get("created_at")?,
         updated_at: row.get("updated_at")?,
@@ -77,6 +89,19 @@ pub fn find_by_id(conn: &Connection, id: &str) -> anyhow::Result<Option<Server>>
     Ok(rows.next().and_then(|r| r.ok()))
 }
 
+/// A server named `name` to hang jobs on, for tests
+#[cfg(test)]
+pub fn create_for_test(conn: &Connection, name: &str) -> Server {
+    create(conn, &CreateServerRequest {
+        name: name.to_string(),
+        hostname: format!("{}.local", name),
+        port: default_port(),
+        ssh_user: default_ssh_user(),
+        password: None,
+    })
+    .unwrap()
+}
+
 pub fn create(conn: &Connection, data: &CreateServerRequest) -> anyhow::Result<Server> {
     let id = Uuid::new_v4().to_string();
     let now = chrono::Utc::now().to_rfc3339();
@@ -114,6 +139,10 @@ pub fn update(conn: &Connection, id: &str, data: &UpdateServerRequest) -> anyhow
         sets.push("ssh_user = ?");
         values.push(Box::new(ssh_user.clone()));
     }
+    if let Some(ref tags) = data.tags {
+        sets.push("tags = ?");
+        values.push(Box::new(serde_json::to_string(tags)?));
+    }
 
     sets.push("updated_at = datetime('now')");
     values.push(Box::new(id.to_string()));
@@ -142,6 +171,18 @@ pub fn update_fields(conn: &Connection, id: &str, fields: &[(&str, &dyn rusqlite
     Ok(())
 }
 
+impl Server {
+    pub fn agent_protocol(&self) -> AgentProtocol {
+        AgentProtocol::from_stored(self.agent_protocol_version, &self.agent_capabilities)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        serde_json::from_str::<Vec<String>>(&self.tags)
+            .map(|tags| tags.iter().any(|t| t == tag))
+            .unwrap_or(false)
+    }
+}
+
 pub fn delete(conn: &Connection, id: &str) -> anyhow::Result<bool> {
     let changes = conn.execute("DELETE FROM source_servers WHERE id = ?", params![id])?;
     Ok(changes > 0)
@@ -1,20 +1,33 @@
+use crate::config::ClientAuthMode;
 use crate::error::AppError;
-use crate::models::server;
+use crate::models::{enrollment_token, server};
 use crate::services::agent_deployer;
+use crate::services::agent_release::{self, ReleaseManifest};
 use crate::state::AppState;
 use axum::body::Body;
 use axum::extract::{Path, State};
-use axum::http::header;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
+use backup_protocol::{EnrollRequest, EnrollResponse};
+use chrono::SecondsFormat;
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio_util::io::ReaderStream;
 
+/// Enrollment tokens expire after this unless the request asks otherwise.
+const DEFAULT_ENROLLMENT_TTL_SECS: i64 = 15 * 60;
+const MAX_ENROLLMENT_TTL_SECS: i64 = 24 * 60 * 60;
+
 pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
     Router::new()
         .route("/deploy", post(deploy_agent))
+        .route("/enrollment-tokens", get(list_enrollment_tokens).post(create_enrollment_token))
+        .route("/enrollment-tokens/{id}", delete(revoke_enrollment_token))
+        .route("/enroll", post(enroll_agent))
         .route("/binary", get(get_binary))
+        .route("/release", get(get_release))
         .route("/update/{server_id}", post(update_agent))
         .route("/status/{server_id}", get(get_status))
 }
@@ -22,7 +35,7 @@ pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
 async fn deploy_agent(
     State(state): State<Arc<AppState>>,
     Json(body): Json<server::CreateServerRequest>,
-) -> Result<(axum::http::StatusCode, Json<server::Server>), AppError> {
+) -> Result<(StatusCode, Json<server::Server>), AppError> {
     if body.name.is_empty() || body.hostname.is_empty() {
         return Err(AppError::BadRequest("name and hostname are required".into()));
     }
@@ -51,6 +64,35 @@ async fn deploy_agent(
     .map_err(|e| anyhow::anyhow!(e))??;
 
     let server_id = srv.id.clone();
+
+    // Pin the server certificate and, with mutual TLS, issue the agent its own
+    let tls = match &state.tls {
+        Some(identity) => {
+            let client_cert = if state.config.tls_client_auth == ClientAuthMode::Off {
+                None
+            } else {
+                let cert = identity.issue_agent_certificate(&server_id)?;
+                let db = state.db.clone();
+                let sid = server_id.clone();
+                let fingerprint = cert.fingerprint.clone();
+                tokio::task::spawn_blocking(move || {
+                    let conn = db.get()?;
+                    server::update_fields(&conn, &sid, &[
+                        ("agent_cert_fingerprint", &fingerprint as &dyn rusqlite::types::ToSql),
+                    ])
+                })
+                .await
+                .map_err(|e| anyhow::anyhow!(e))??;
+                Some(cert)
+            };
+            Some(agent_deployer::AgentTlsOptions {
+                server_fingerprint: identity.fingerprint.clone(),
+                client_cert,
+            })
+        }
+        None => None,
+    };
+
     let opts = agent_deployer::DeployOptions {
         hostname: body.hostname.clone(),
         port: body.port as u16,
@@ -59,6 +101,8 @@ async fn deploy_agent(
         server_id: srv.id.clone(),
         server_port: state.config.port,
         backup_server_ip: state.config.backup_server_ip.clone(),
+        tls,
+        release_public_key: state.release.public_key.clone(),
     };
 
     match agent_deployer::deploy_agent(opts, state.agents.clone()).await {
@@ -74,7 +118,7 @@ async fn deploy_agent(
             .ok_or_else(|| AppError::Internal(anyhow::anyhow!("Server disappeared")))?;
 
             state.ui.broadcast("server:updated", serde_json::json!({ "server": updated }));
-            Ok((axum::http::StatusCode::CREATED, Json(updated)))
+            Ok((StatusCode::CREATED, Json(updated)))
         }
         Err(e) => {
             let error_msg = e.to_string();
@@ -94,6 +138,173 @@ async fn deploy_agent(
     }
 }
 
+fn now_secs() -> String {
+    chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[derive(Deserialize)]
+struct CreateEnrollmentTokenRequest {
+    name: Option<String>,
+    ttl_secs: Option<i64>,
+}
+
+/// Mint a single-use enrollment token. The plaintext token is only returned
+/// here, together with the command to run on the new machine.
+async fn create_enrollment_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateEnrollmentTokenRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    let ttl = body.ttl_secs.unwrap_or(DEFAULT_ENROLLMENT_TTL_SECS);
+    if !(1..=MAX_ENROLLMENT_TTL_SECS).contains(&ttl) {
+        return Err(AppError::BadRequest(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_ENROLLMENT_TTL_SECS
+        )));
+    }
+
+    let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let expires_at = (chrono::Utc::now() + chrono::Duration::seconds(ttl))
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let db = state.db.clone();
+    let name = body.name.filter(|n| !n.trim().is_empty());
+    let (plain, expires) = (token.clone(), expires_at.clone());
+    let created = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        enrollment_token::create(&conn, &plain, name.as_deref(), &expires)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+
+    // Agents reach the server the same way the browser did
+    let scheme = if state.tls.is_some() { "https" } else { "http" };
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| {
+            let ip = state.config.backup_server_ip.as_deref().unwrap_or("127.0.0.1");
+            format!("{}:{}", ip, state.config.port)
+        });
+    let mut command = format!("backup-agent enroll --server {}://{} --token {}", scheme, host, token);
+    if let Some(tls) = &state.tls {
+        command.push_str(&format!(" --fingerprint {}", tls.fingerprint));
+    }
+
+    tracing::info!(token_id = %created.id, expires_at = %expires_at, "Enrollment token created");
+
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": created.id,
+        "token": token,
+        "name": created.name,
+        "expiresAt": expires_at,
+        "command": command,
+    }))))
+}
+
+async fn list_enrollment_tokens(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<enrollment_token::EnrollmentToken>>, AppError> {
+    let db = state.db.clone();
+    let tokens = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        enrollment_token::find_pending(&conn, &now_secs())
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    Ok(Json(tokens))
+}
+
+async fn revoke_enrollment_token(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let db = state.db.clone();
+    let deleted = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        enrollment_token::delete(&conn, &id)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound("Enrollment token not found".into()))
+    }
+}
+
+/// Called by `backup-agent enroll`: exchange a token for a new server record
+/// and the agent's credentials.
+async fn enroll_agent(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<EnrollRequest>,
+) -> Result<(StatusCode, Json<EnrollResponse>), AppError> {
+    if body.hostname.trim().is_empty() {
+        return Err(AppError::BadRequest("hostname is required".into()));
+    }
+
+    let db = state.db.clone();
+    let hostname = body.hostname.trim().to_string();
+    let srv = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let now = now_secs();
+        let token = match enrollment_token::find_by_token(&conn, &body.token)? {
+            Some(t) if t.used_at.is_none() && t.expires_at > now => t,
+            _ => return Ok(None),
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let srv = server::create(&tx, &server::CreateServerRequest {
+            name: token.name.clone().unwrap_or_else(|| hostname.clone()),
+            hostname,
+            port: 22,
+            ssh_user: "root".into(),
+            password: None,
+        })?;
+        if !enrollment_token::consume(&tx, &token.id, &srv.id, &now)? {
+            return Ok(None);
+        }
+        tx.commit()?;
+        Ok::<_, anyhow::Error>(Some(srv))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::Forbidden("Invalid or expired enrollment token".into()))?;
+
+    // With mutual TLS the agent also gets its client certificate
+    let client_cert = match &state.tls {
+        Some(identity) if state.config.tls_client_auth != ClientAuthMode::Off => {
+            let cert = identity.issue_agent_certificate(&srv.id)?;
+            let db = state.db.clone();
+            let sid = srv.id.clone();
+            let fingerprint = cert.fingerprint.clone();
+            tokio::task::spawn_blocking(move || {
+                let conn = db.get()?;
+                server::update_fields(&conn, &sid, &[
+                    ("agent_cert_fingerprint", &fingerprint as &dyn rusqlite::types::ToSql),
+                ])
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))??;
+            Some(cert)
+        }
+        _ => None,
+    };
+
+    tracing::info!(server_id = %srv.id, hostname = %srv.hostname, "Agent enrolled");
+    state.ui.broadcast("server:updated", serde_json::json!({ "server": srv }));
+
+    Ok((StatusCode::CREATED, Json(EnrollResponse {
+        server_id: srv.id,
+        server_name: srv.name,
+        cert_fingerprint: state.tls.as_ref().map(|t| t.fingerprint.clone()),
+        release_public_key: Some(state.release.public_key.clone()),
+        client_cert: client_cert.as_ref().map(|c| c.cert_pem.clone()),
+        client_key: client_cert.as_ref().map(|c| c.key_pem.clone()),
+    })))
+}
+
 async fn get_binary() -> Result<impl IntoResponse, AppError> {
     let binary_path = agent_deployer::get_agent_binary_path();
 
@@ -122,6 +333,25 @@ async fn get_binary() -> Result<impl IntoResponse, AppError> {
     ))
 }
 
+/// Sign the agent binary currently on disk.
+pub(crate) async fn release_manifest(state: &AppState) -> Result<ReleaseManifest, AppError> {
+    agent_release::current_manifest(state).await?.ok_or_else(|| {
+        AppError::NotFound(
+            "Agent binary not found. Build with: cd backup-agent && cargo build --release".into(),
+        )
+    })
+}
+
+/// Signed manifest for the binary served by `/binary`.
+async fn get_release(State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>, AppError> {
+    let manifest = release_manifest(&state).await?;
+    Ok(Json(serde_json::json!({
+        "manifest": manifest,
+        "downloadPath": "/api/agent/binary",
+        "publicKey": state.release.public_key,
+    })))
+}
+
 async fn update_agent(
     State(state): State<Arc<AppState>>,
     Path(server_id): Path<String>,
@@ -143,32 +373,11 @@ async fn update_agent(
         return Err(AppError::Conflict("Agent is not connected".into()));
     }
 
-    let sent = state.agents.send_to_agent(
-        &server_id,
-        serde_json::json!({
-            "type": "agent:update",
-            "payload": {
-                "download_path": "/api/agent/binary",
-                "version": "latest",
-            },
-        }),
-    );
-
-    if !sent {
+    let manifest = release_manifest(&state).await?;
+    if !agent_release::send_update(&state, &server_id, &manifest).await {
         return Err(AppError::Internal(anyhow::anyhow!("Failed to send update command")));
     }
 
-    // Mark as updating
-    let db = state.db.clone();
-    let sid = server_id.clone();
-    let _ = tokio::task::spawn_blocking(move || {
-        let conn = db.get()?;
-        server::update_fields(&conn, &sid, &[
-            ("agent_status", &"updating" as &dyn rusqlite::types::ToSql),
-        ])
-    })
-    .await;
-
     Ok(Json(serde_json::json!({ "status": "update_initiated" })))
 }
 
@@ -3,6 +3,8 @@ use crate::models::server;
 use crate::state::AppState;
 use axum::extract::{Path, Query, State};
 use axum::Json;
+use backup_protocol::path as path_encoding;
+use backup_protocol::{AgentMessage, FsEntry, ServerMessage};
 use serde::Deserialize;
 use std::sync::Arc;
 
@@ -15,7 +17,7 @@ pub async fn explore(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
     Query(query): Query<ExploreQuery>,
-) -> Result<Json<serde_json::Value>, AppError> {
+) -> Result<Json<Vec<FsEntry>>, AppError> {
     let remote_path = query.path.unwrap_or_else(|| "/".into());
 
     // Verify server exists
@@ -36,30 +38,28 @@ pub async fn explore(
         return Err(AppError::ServiceUnavailable("Agent is not connected".into()));
     }
 
-    let message = serde_json::json!({
-        "type": "fs:browse",
-        "payload": { "path": remote_path },
-    });
-
-    let result = state
+    let path = remote_path.clone();
+    let response = state
         .agents
-        .request_from_agent(&id, message, 30_000)
-        .await
-        .map_err(|e| {
-            let msg = e.to_string();
-            if msg.contains("Permission denied") || msg.contains("EACCES") {
-                AppError::BadRequest(format!("Permission denied: {}", remote_path))
-            } else if msg.contains("No such file") || msg.contains("not found") {
-                AppError::NotFound(format!("Path not found: {}", remote_path))
+        .request_from_agent(&id, |request_id| ServerMessage::BrowseFilesystem { path, request_id }, 30_000)
+        .await?;
+
+    match response {
+        AgentMessage::FsBrowseResponse { error: Some(error), .. } => {
+            if error.contains("agent path policy") {
+                Err(AppError::Forbidden(error))
+            } else if error.contains("Permission denied") || error.contains("EACCES") {
+                Err(AppError::BadRequest(format!("Permission denied: {}", path_encoding::display(&remote_path))))
+            } else if error.contains("No such file") || error.contains("not found") {
+                Err(AppError::NotFound(format!("Path not found: {}", path_encoding::display(&remote_path))))
             } else {
-                AppError::Internal(e)
+                Err(AppError::Internal(anyhow::anyhow!(error)))
             }
-        })?;
-
-    if let Some(error) = result.get("error") {
-        return Err(AppError::Internal(anyhow::anyhow!("{}", error)));
+        }
+        AgentMessage::FsBrowseResponse { entries, .. } => Ok(Json(entries)),
+        other => Err(AppError::Internal(anyhow::anyhow!(
+            "Unexpected response to fs:browse: {}",
+            other.message_type()
+        ))),
     }
-
-    let entries = result.get("entries").cloned().unwrap_or(serde_json::json!([]));
-    Ok(Json(entries))
 }
@@ -1,113 +1,167 @@
+use crate::config::ClientAuthMode;
 use crate::error::AppError;
-use crate::models::backup_version;
+use crate::models::{backup_job, backup_version, server};
+use crate::services::tls::{self, PeerInfo};
+use crate::services::version_links::LinkTally;
 use crate::state::AppState;
-use axum::extract::{Path as AxumPath, Request, State};
-use axum::http::HeaderMap;
+use crate::utils::safe_dir::{SafeDir, SafePathError};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path as AxumPath, Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Json;
 use axum::Router;
+use async_compression::tokio::bufread::{GzipDecoder, Lz4Decoder, ZstdDecoder};
 use futures_util::StreamExt;
+use backup_protocol::compression::Algorithm;
+use backup_protocol::manifest;
+use backup_protocol::{ChangesRequest, RenamesRequest, RenamesResponse};
+use backup_protocol::path as path_encoding;
+use backup_protocol::rest::{HEADER_JOB_ID, HEADER_RELATIVE_PATH, HEADER_TOTAL_SIZE};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio_util::io::{ReaderStream, StreamReader};
 
-pub fn router(_state: Arc<AppState>) -> Router<Arc<AppState>> {
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
     Router::new()
         .route("/upload", post(upload_file))
         .route("/manifest/{job_id}", get(get_manifest))
         .route("/hardlink", post(create_hardlinks))
+        .route("/changes", post(record_changes))
+        .route("/renames", post(link_renames))
+        .route_layer(middleware::from_fn_with_state(state, require_agent_certificate))
+}
+
+/// These endpoints are only called by agents; with `TLS_CLIENT_AUTH=required`
+/// the connection must carry a client certificate issued by this server.
+/// Which server's it is is checked against the job of each request by
+/// [`authorize_job`].
+async fn require_agent_certificate(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if state.config.tls_client_auth == ClientAuthMode::Required && peer.client_cert_fingerprint.is_none() {
+        tracing::warn!(peer = %peer.addr, path = %request.uri().path(), "Rejected agent request without client certificate");
+        return Err(AppError::Forbidden("Client certificate required".into()));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Reject requests on a job from an agent other than its server's: the client
+/// certificate must be the one issued for the server the job backs up.
+async fn authorize_job(state: &AppState, peer: &PeerInfo, job_id: &str) -> Result<(), AppError> {
+    if state.config.tls_client_auth == ClientAuthMode::Off {
+        return Ok(());
+    }
+    let db = state.db.clone();
+    let jid = job_id.to_string();
+    let srv = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let Some(job) = backup_job::find_by_id(&conn, &jid)? else {
+            return Ok(None);
+        };
+        server::find_by_id(&conn, &job.server_id)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::NotFound(format!("Job {} not found", job_id)))?;
+
+    tls::check_job_certificate(
+        state.config.tls_client_auth,
+        srv.agent_cert_fingerprint.as_deref(),
+        peer.client_cert_fingerprint.as_deref(),
+    )
+    .map_err(|error| {
+        tracing::warn!(job_id = %job_id, server_id = %srv.id, peer = %peer.addr, error, "Rejected agent request for another server's job");
+        AppError::Forbidden(error.to_string())
+    })
 }
 
 async fn upload_file(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
     headers: HeaderMap,
     request: Request,
 ) -> Result<Json<serde_json::Value>, AppError> {
     let job_id = headers
-        .get("x-job-id")
+        .get(HEADER_JOB_ID)
         .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| AppError::BadRequest("Missing x-job-id header".into()))?
+        .ok_or_else(|| AppError::BadRequest(format!("Missing {} header", HEADER_JOB_ID)))?
         .to_string();
 
     let relative_path = headers
-        .get("x-relative-path")
+        .get(HEADER_RELATIVE_PATH)
         .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| AppError::BadRequest("Missing x-relative-path header".into()))?
+        .ok_or_else(|| AppError::BadRequest(format!("Missing {} header", HEADER_RELATIVE_PATH)))?
         .to_string();
 
     let total_size: u64 = headers
-        .get("x-total-size")
+        .get(HEADER_TOTAL_SIZE)
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse().ok())
-        .ok_or_else(|| AppError::BadRequest("Missing or invalid x-total-size header".into()))?;
+        .ok_or_else(|| AppError::BadRequest(format!("Missing or invalid {} header", HEADER_TOTAL_SIZE)))?;
 
-    let content_encoding = headers
-        .get("content-encoding")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
+    let encoding = match headers.get(header::CONTENT_ENCODING).map(|v| v.to_str()) {
+        Some(Ok(value)) => Algorithm::from_content_encoding(value)
+            .ok_or_else(|| AppError::BadRequest(format!("Unsupported content-encoding {:?}", value)))?,
+        Some(Err(_)) => return Err(AppError::BadRequest("Invalid content-encoding header".into())),
+        None => Algorithm::None,
+    };
 
     tracing::debug!(job_id = %job_id, relative_path = %relative_path, total_size, "Receiving file upload");
 
+    authorize_job(&state, &peer, &job_id).await?;
+    ensure_job_running(&state, &job_id).await?;
+
     // Determine destination path
     let db = state.db.clone();
     let jid = job_id.clone();
-    let backups_dir = state.config.backups_dir.clone();
     let base_dir = tokio::task::spawn_blocking(move || {
         let conn = db.get()?;
         let versions = backup_version::find_by_job_id(&conn, &jid)?;
         let running = versions.into_iter().find(|v| v.status == "running");
-        Ok::<_, anyhow::Error>(match running {
-            Some(v) => PathBuf::from(v.local_path),
-            None => backups_dir.join(&jid),
-        })
+        Ok::<_, anyhow::Error>(running.map(|v| PathBuf::from(v.local_path)))
     })
     .await
-    .map_err(|e| anyhow::anyhow!(e))??;
-
-    let dest_path = base_dir.join(&relative_path);
-    if let Some(parent) = dest_path.parent() {
-        tokio::fs::create_dir_all(parent).await
-            .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to create directory: {}", e)))?;
-    }
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::Conflict("No running version found".into()))?;
 
-    // Stream body to file
-    let body = request.into_body();
-    let body_stream = body.into_data_stream();
-
-    if content_encoding.as_deref() == Some("zstd") {
-        // Collect body, decompress, then write
-        let mut compressed = Vec::new();
-        let mut stream = body_stream;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| AppError::Internal(anyhow::anyhow!("Read error: {}", e)))?;
-            compressed.extend_from_slice(&chunk);
-        }
-        let decompressed = tokio::task::spawn_blocking(move || {
-            zstd::decode_all(compressed.as_slice())
-        })
+    // The path comes from the agent: keep it inside the version directory
+    let rel = path_encoding::decode_path(&relative_path);
+    let file = tokio::task::spawn_blocking(move || SafeDir::open(&base_dir)?.create_file(&rel))
         .await
         .map_err(|e| anyhow::anyhow!(e))?
-        .map_err(|e| AppError::Internal(anyhow::anyhow!("Zstd decompression failed: {}", e)))?;
-
-        tokio::fs::write(&dest_path, &decompressed).await
-            .map_err(|e| AppError::Internal(anyhow::anyhow!("Write error: {}", e)))?;
-    } else {
-        // Stream directly to file
-        let mut file = tokio::fs::File::create(&dest_path).await
-            .map_err(|e| AppError::Internal(anyhow::anyhow!("Create file error: {}", e)))?;
-        let mut stream = body_stream;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| AppError::Internal(anyhow::anyhow!("Read error: {}", e)))?;
-            file.write_all(&chunk).await
-                .map_err(|e| AppError::Internal(anyhow::anyhow!("Write error: {}", e)))?;
-        }
-        file.flush().await
-            .map_err(|e| AppError::Internal(anyhow::anyhow!("Flush error: {}", e)))?;
-    }
+        .map_err(|e| {
+            tracing::warn!(job_id = %job_id, relative_path = %relative_path, "Rejected upload: {}", e);
+            AppError::from(e)
+        })?;
+    let mut file = tokio::fs::File::from_std(file);
+
+    // Stream the body to the file, decoding it on the way
+    let body = request.into_body().into_data_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+    let body = StreamReader::new(body);
+    let mut body: Pin<Box<dyn AsyncRead + Send>> = match encoding {
+        Algorithm::None => Box::pin(body),
+        Algorithm::Zstd => Box::pin(ZstdDecoder::new(body)),
+        Algorithm::Lz4 => Box::pin(Lz4Decoder::new(body)),
+        Algorithm::Gzip => Box::pin(GzipDecoder::new(body)),
+    };
+    tokio::io::copy(&mut body, &mut file).await
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("Receiving {} upload failed: {}", encoding.as_str(), e)))?;
+    file.flush().await
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("Flush error: {}", e)))?;
 
     // Verify file size
-    let metadata = tokio::fs::metadata(&dest_path).await
+    let metadata = file.metadata().await
         .map_err(|e| AppError::Internal(anyhow::anyhow!("Stat error: {}", e)))?;
 
     if metadata.len() != total_size {
@@ -134,12 +188,28 @@ async fn upload_file(
     })))
 }
 
-/// Returns the manifest JSON from the latest completed version for a given job.
+/// Reject writes for jobs the orchestrator is not currently following. After a
+/// server restart this keeps agents from writing into versions that run
+/// recovery has not (yet) reattached or has already marked as failed.
+async fn ensure_job_running(state: &AppState, job_id: &str) -> Result<(), AppError> {
+    if state.running_jobs.lock().await.contains(job_id) {
+        Ok(())
+    } else {
+        Err(AppError::Conflict(format!("Job {} is not running", job_id)))
+    }
+}
+
+/// Returns the manifest of the latest completed version for a given job.
 /// The agent fetches this to determine which files have changed for incremental backups.
+/// Agents that accept [`manifest::CONTENT_TYPE`] get the binary manifest
+/// streamed, others JSON; a version holding only the other format is converted.
 async fn get_manifest(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
     AxumPath(job_id): AxumPath<String>,
-) -> Result<Json<serde_json::Value>, AppError> {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    authorize_job(&state, &peer, &job_id).await?;
     let db = state.db.clone();
     let jid = job_id.clone();
 
@@ -151,20 +221,86 @@ async fn get_manifest(
     .map_err(|e| anyhow::anyhow!(e))??;
 
     let prev = prev.ok_or_else(|| AppError::NotFound("No completed version found".into()))?;
-    let manifest_path = PathBuf::from(&prev.local_path).join(".backup-manifest.json");
+    let accept = headers.get(header::ACCEPT).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let binary = accept.contains(manifest::CONTENT_TYPE);
+    let v2 = manifest::accepts_v2(accept);
+
+    // Whether the stored manifest is binary, and if so whether it has file ids
+    let (file, stored_ids) = tokio::task::spawn_blocking(move || {
+        let dir = SafeDir::open(&prev.local_path)?;
+        match dir.open_file(manifest::FILE_NAME) {
+            Ok(mut file) => {
+                let mut prefix = [0u8; 5];
+                file.read_exact(&mut prefix)?;
+                file.rewind()?;
+                Ok::<_, anyhow::Error>((file, Some(manifest::has_file_ids(&prefix))))
+            }
+            Err(e) if e.is_not_found() => Ok((dir.open_file(manifest::JSON_FILE_NAME)?, None)),
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))?
+    .map_err(|_| AppError::NotFound("Manifest not found for latest version".into()))?;
+
+    let content_type = if binary { manifest::CONTENT_TYPE } else { "application/json" };
+    let body = match (binary, stored_ids) {
+        (true, Some(ids)) if v2 || !ids => Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file))),
+        (false, None) => Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file))),
+        (_, stored_ids) => {
+            let converted = tokio::task::spawn_blocking(move || match (binary, stored_ids) {
+                (true, Some(_)) => binary_to_v1(file),
+                (true, None) => json_to_binary(file, v2),
+                (false, _) => binary_to_json(file),
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?
+            .map_err(|e| AppError::Internal(anyhow::anyhow!("Invalid manifest: {}", e)))?;
+            Body::from(converted)
+        }
+    };
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
 
-    let content = tokio::fs::read_to_string(&manifest_path).await
-        .map_err(|_| AppError::NotFound("Manifest not found for latest version".into()))?;
+/// A version's JSON manifest as a binary one, for agents that take it.
+fn json_to_binary(file: std::fs::File, v2: bool) -> anyhow::Result<Vec<u8>> {
+    let json: manifest::JsonManifest = serde_json::from_reader(std::io::BufReader::new(file))?;
+    let (header, files) = json.into_sorted();
+    let mut writer = if v2 {
+        manifest::ManifestWriter::new(Vec::new(), &header)?
+    } else {
+        manifest::ManifestWriter::v1(Vec::new(), &header)?
+    };
+    for (path, entry) in files {
+        writer.add(&path, entry)?;
+    }
+    Ok(writer.finish()?)
+}
 
-    let manifest: serde_json::Value = serde_json::from_str(&content)
-        .map_err(|e| AppError::Internal(anyhow::anyhow!("Invalid manifest JSON: {}", e)))?;
+/// A binary manifest with file ids in format 1, for agents that predate them.
+fn binary_to_v1(file: std::fs::File) -> anyhow::Result<Vec<u8>> {
+    let reader = manifest::ManifestReader::new(file)?;
+    let mut writer = manifest::ManifestWriter::v1(Vec::new(), &reader.header().clone())?;
+    for entry in reader {
+        let (path, entry) = entry?;
+        writer.add(&path, entry)?;
+    }
+    Ok(writer.finish()?)
+}
 
-    Ok(Json(manifest))
+/// A version's binary manifest as JSON, for agents from before the binary format.
+fn binary_to_json(file: std::fs::File) -> anyhow::Result<Vec<u8>> {
+    let reader = manifest::ManifestReader::new(file)?;
+    let header = reader.header().clone();
+    let files = reader.collect::<std::io::Result<Vec<_>>>()?;
+    Ok(serde_json::to_vec(&manifest::JsonManifest::new(header, files))?)
 }
 
 #[derive(Deserialize)]
 struct HardlinkRequest {
     job_id: String,
+    /// Relative paths, encoded like `x-relative-path`.
     files: Vec<String>,
 }
 
@@ -173,54 +309,28 @@ struct HardlinkRequest {
 /// keeping each version as a complete, browsable snapshot.
 async fn create_hardlinks(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
     Json(body): Json<HardlinkRequest>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    let db = state.db.clone();
-    let jid = body.job_id.clone();
-
-    // Find current running version and previous completed version
-    let (current_path, previous_path) = tokio::task::spawn_blocking(move || {
-        let conn = db.get()?;
-        let versions = backup_version::find_by_job_id(&conn, &jid)?;
-        let running = versions.iter()
-            .find(|v| v.status == "running")
-            .map(|v| v.local_path.clone());
-        let completed = versions.iter()
-            .find(|v| v.status == "completed")
-            .map(|v| v.local_path.clone());
-        Ok::<_, anyhow::Error>((running, completed))
-    })
-    .await
-    .map_err(|e| anyhow::anyhow!(e))??;
-
-    let current = PathBuf::from(
-        current_path.ok_or_else(|| AppError::BadRequest("No running version found".into()))?
-    );
-    let previous = PathBuf::from(
-        previous_path.ok_or_else(|| AppError::BadRequest("No previous completed version".into()))?
-    );
+    authorize_job(&state, &peer, &body.job_id).await?;
+    ensure_job_running(&state, &body.job_id).await?;
+    let (current, previous) = run_versions(&state, &body.job_id).await?;
 
     let files = body.files;
+    let mode = state.version_links.mode(&body.job_id);
     let (linked, failed) = tokio::task::spawn_blocking(move || {
-        let mut linked = 0u64;
+        let current = SafeDir::open(&current)?;
+        let previous = SafeDir::open(&previous)?;
+        let mut linked = LinkTally::default();
         let mut failed = 0u64;
 
         for rel_path in &files {
-            let src = previous.join(rel_path);
-            let dst = current.join(rel_path);
-
-            if !src.exists() {
-                tracing::warn!(path = %rel_path, "Hardlink source does not exist");
-                failed += 1;
-                continue;
-            }
-
-            if let Some(parent) = dst.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-
-            match std::fs::hard_link(&src, &dst) {
-                Ok(_) => linked += 1,
+            match current.link_from(&previous, path_encoding::decode_path(rel_path), mode) {
+                Ok(used) => linked.add(used),
+                Err(e) if e.is_not_found() => {
+                    tracing::warn!(path = %rel_path, "Hardlink source does not exist");
+                    failed += 1;
+                }
                 Err(e) => {
                     tracing::warn!(path = %rel_path, error = %e, "Hardlink failed");
                     failed += 1;
@@ -228,20 +338,121 @@ async fn create_hardlinks(
             }
         }
 
-        (linked, failed)
+        Ok::<_, SafePathError>((linked, failed))
     })
     .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+    .map_err(|e| anyhow::anyhow!(e))??;
+    state.version_links.record(&body.job_id, linked);
 
     tracing::info!(
         job_id = %body.job_id,
-        linked,
+        linked = linked.total(),
         failed,
         "Hardlink creation completed"
     );
 
     Ok(Json(serde_json::json!({
-        "linked": linked,
+        "linked": linked.total(),
         "failed": failed,
     })))
 }
+
+/// Links files the agent found moved since the previous version to their new
+/// paths. Renames matched by content are only linked if the previous file
+/// still hashes the same; rejected ones are uploaded by the agent.
+async fn link_renames(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
+    Json(body): Json<RenamesRequest>,
+) -> Result<Json<RenamesResponse>, AppError> {
+    authorize_job(&state, &peer, &body.job_id).await?;
+    ensure_job_running(&state, &body.job_id).await?;
+    let (current, previous) = run_versions(&state, &body.job_id).await?;
+
+    let renames = body.renames;
+    let mode = state.version_links.mode(&body.job_id);
+    let (linked, rejected) = tokio::task::spawn_blocking(move || {
+        let current = SafeDir::open(&current)?;
+        let previous = SafeDir::open(&previous)?;
+        let mut linked = LinkTally::default();
+        let mut rejected = Vec::new();
+
+        for rename in renames {
+            let from = path_encoding::decode_path(&rename.from);
+            let same_content = match &rename.sha256 {
+                Some(expected) => previous.open_file(&from).and_then(|file| {
+                    sha256_hex(file).map_err(|e| SafePathError::Io { path: rename.from.clone(), source: e })
+                }).is_ok_and(|actual| actual.eq_ignore_ascii_case(expected)),
+                None => true,
+            };
+            if !same_content {
+                rejected.push(rename.to);
+                continue;
+            }
+            match current.link_moved(&previous, &from, path_encoding::decode_path(&rename.to), mode) {
+                Ok(used) => linked.add(used),
+                Err(e) => {
+                    tracing::warn!(from = %rename.from, to = %rename.to, error = %e, "Linking moved file failed");
+                    rejected.push(rename.to);
+                }
+            }
+        }
+
+        Ok::<_, SafePathError>((linked, rejected))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    state.version_links.record(&body.job_id, linked);
+
+    tracing::info!(job_id = %body.job_id, linked = linked.total(), rejected = rejected.len(), "Moved files linked");
+    Ok(Json(RenamesResponse { linked: linked.total(), rejected }))
+}
+
+/// Hex SHA-256 of a file's content
+fn sha256_hex(mut file: std::fs::File) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Paths of the job's running version and of the previous completed one.
+async fn run_versions(state: &AppState, job_id: &str) -> Result<(String, String), AppError> {
+    let db = state.db.clone();
+    let jid = job_id.to_string();
+
+    let (current_path, previous_path) = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let versions = backup_version::find_by_job_id(&conn, &jid)?;
+        let running = versions.iter()
+            .find(|v| v.status == "running")
+            .map(|v| v.local_path.clone());
+        let completed = versions.iter()
+            .find(|v| v.status == "completed")
+            .map(|v| v.local_path.clone());
+        Ok::<_, anyhow::Error>((running, completed))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+
+    let current = current_path.ok_or_else(|| AppError::BadRequest("No running version found".into()))?;
+    let previous = previous_path.ok_or_else(|| AppError::BadRequest("No previous completed version".into()))?;
+    Ok((current, previous))
+}
+
+/// Records changed and deleted paths of an incremental run whose unchanged
+/// files the server carries forward (see [`crate::services::carry_forward`]).
+async fn record_changes(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
+    Json(body): Json<ChangesRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    authorize_job(&state, &peer, &body.job_id).await?;
+    ensure_job_running(&state, &body.job_id).await?;
+
+    let recorded = body.changed.len() + body.deleted.len();
+    state.carry_forward.record(&state.ui, body).map_err(AppError::Conflict)?;
+
+    Ok(Json(serde_json::json!({
+        "recorded": recorded,
+    })))
+}
@@ -1,10 +1,14 @@
 use crate::error::AppError;
-use crate::models::backup_job;
+use crate::models::{backup_file_error, backup_job};
+use crate::services::agent_orchestrator::RunTrigger;
+use crate::services::backup_roots;
+use crate::services::job_queue;
 use crate::state::AppState;
 use axum::extract::{Path, Query, State};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
@@ -14,6 +18,8 @@ pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
         .route("/{id}/run", post(run_job))
         .route("/{id}/cancel", post(cancel_job))
         .route("/{id}/logs", get(get_job_logs))
+        .route("/{id}/logs/{log_id}", get(get_job_log))
+        .route("/{id}/logs/{log_id}/errors", get(get_log_file_errors))
 }
 
 async fn list_jobs(State(state): State<Arc<AppState>>) -> Result<Json<Vec<backup_job::BackupJob>>, AppError> {
@@ -46,7 +52,7 @@ async fn get_job(
 
 async fn create_job(
     State(state): State<Arc<AppState>>,
-    Json(body): Json<backup_job::CreateBackupJobRequest>,
+    Json(mut body): Json<backup_job::CreateBackupJobRequest>,
 ) -> Result<(axum::http::StatusCode, Json<backup_job::BackupJob>), AppError> {
     if body.name.is_empty() {
         return Err(AppError::BadRequest("name is required".into()));
@@ -54,6 +60,15 @@ async fn create_job(
     if body.remote_paths.is_empty() {
         return Err(AppError::BadRequest("remote_paths must not be empty".into()));
     }
+    if body.max_file_errors.is_some_and(|max| max < 0) {
+        return Err(AppError::BadRequest("max_file_errors must not be negative".into()));
+    }
+    if body.max_parallel < 1 {
+        return Err(AppError::BadRequest("max_parallel must be at least 1".into()));
+    }
+    backup_roots::check_labels(&body.remote_paths, &body.root_labels).map_err(AppError::BadRequest)?;
+    body.root_labels = backup_roots::pin_labels(&body.remote_paths, &body.root_labels);
+    backup_job::compression(body.compression.as_deref(), body.compression_level).map_err(AppError::BadRequest)?;
 
     let db = state.db.clone();
     let ui = state.ui.clone();
@@ -74,13 +89,48 @@ async fn create_job(
 async fn update_job(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-    Json(body): Json<backup_job::UpdateBackupJobRequest>,
+    Json(mut body): Json<backup_job::UpdateBackupJobRequest>,
 ) -> Result<Json<backup_job::BackupJob>, AppError> {
+    if body.max_file_errors.flatten().is_some_and(|max| max < 0) {
+        return Err(AppError::BadRequest("max_file_errors must not be negative".into()));
+    }
+    if body.max_parallel.is_some_and(|max| max < 1) {
+        return Err(AppError::BadRequest("max_parallel must be at least 1".into()));
+    }
     let db = state.db.clone();
     let id2 = id.clone();
     let job = tokio::task::spawn_blocking(move || {
-        let conn = db.get()?;
-        backup_job::update(&conn, &id2, &body)
+        let conn = db.get().map_err(anyhow::Error::from)?;
+        // Labels are checked against the paths the job will have
+        if body.remote_paths.is_some() || body.root_labels.is_some() {
+            if let Some(existing) = backup_job::find_by_id(&conn, &id2)? {
+                let paths: Vec<String> = match &body.remote_paths {
+                    Some(paths) => paths.clone(),
+                    None => serde_json::from_str(&existing.remote_paths).unwrap_or_default(),
+                };
+                let labels = body.root_labels.take().unwrap_or_else(|| {
+                    // Labels of removed paths go with them
+                    let mut labels: HashMap<String, String> =
+                        serde_json::from_str(&existing.root_labels).unwrap_or_default();
+                    labels.retain(|path, _| paths.contains(path));
+                    labels
+                });
+                backup_roots::check_labels(&paths, &labels).map_err(AppError::BadRequest)?;
+                body.root_labels = Some(backup_roots::pin_labels(&paths, &labels));
+            }
+        }
+        // A new algorithm starts at its default level unless one is given
+        if body.compression.is_some() && body.compression_level.is_none() {
+            body.compression_level = Some(None);
+        }
+        if body.compression.is_some() || body.compression_level.is_some() {
+            if let Some(existing) = backup_job::find_by_id(&conn, &id2)? {
+                let algorithm = body.compression.clone().unwrap_or(existing.compression);
+                let level = body.compression_level.unwrap_or(existing.compression_level);
+                backup_job::compression(algorithm.as_deref(), level).map_err(AppError::BadRequest)?;
+            }
+        }
+        Ok::<_, AppError>(backup_job::update(&conn, &id2, &body)?)
     })
     .await
     .map_err(|e| anyhow::anyhow!(e))??;
@@ -119,17 +169,27 @@ async fn run_job(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    let state2 = state.clone();
+    let db = state.db.clone();
     let id2 = id.clone();
+    let exists = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        Ok::<_, anyhow::Error>(backup_job::find_by_id(&conn, &id2)?.is_some())
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    if !exists {
+        return Err(AppError::NotFound("Job not found".into()));
+    }
 
-    // Spawn the backup as a background task
-    tokio::spawn(async move {
-        if let Err(e) = crate::services::agent_orchestrator::run_backup_job(state2, id2).await {
-            tracing::error!("Backup job failed: {:#}", e);
-        }
-    });
-
-    Ok(Json(serde_json::json!({ "started": true })))
+    // Manual runs go through the queue at the highest priority
+    match job_queue::enqueue(&state, &id, RunTrigger::Manual).await? {
+        Some((entry, position)) => Ok(Json(serde_json::json!({
+            "queued": true,
+            "queueId": entry.id,
+            "position": position,
+        }))),
+        None => Err(AppError::Conflict("Job is already running".into())),
+    }
 }
 
 async fn cancel_job(
@@ -162,3 +222,112 @@ async fn get_job_logs(
     .map_err(|e| anyhow::anyhow!(e))??;
     Ok(Json(logs))
 }
+
+async fn get_job_log(
+    State(state): State<Arc<AppState>>,
+    Path((id, log_id)): Path<(String, String)>,
+) -> Result<Json<backup_job::BackupLog>, AppError> {
+    let db = state.db.clone();
+    let log = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_job::find_log_by_id(&conn, &log_id)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .filter(|log| log.job_id == id)
+    .ok_or_else(|| AppError::NotFound("Log not found".into()))?;
+    Ok(Json(log))
+}
+
+#[derive(Deserialize)]
+pub struct FileErrorsQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Files a run could not back up.
+async fn get_log_file_errors(
+    State(state): State<Arc<AppState>>,
+    Path((id, log_id)): Path<(String, String)>,
+    Query(query): Query<FileErrorsQuery>,
+) -> Result<Json<Vec<backup_file_error::FileError>>, AppError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let db = state.db.clone();
+    let errors = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        match backup_job::find_log_by_id(&conn, &log_id)? {
+            Some(log) if log.job_id == id => backup_file_error::find_by_log(&conn, &log_id, limit, offset).map(Some),
+            _ => Ok(None),
+        }
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::NotFound("Log not found".into()))?;
+    Ok(Json(errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::backup_job::CreateBackupJobRequest;
+    use crate::models::server;
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
+    use tower::ServiceExt;
+
+    async fn put(state: &Arc<AppState>, id: &str, body: serde_json::Value) -> backup_job::BackupJob {
+        let response = router(Arc::clone(state))
+            .with_state(Arc::clone(state))
+            .oneshot(
+                Request::put(format!("/{}", id))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_max_file_errors_is_set_then_cleared() {
+        let state = crate::state::test_state();
+        let job = {
+            let conn = state.db.get().unwrap();
+            let srv = server::create_for_test(&conn, "web");
+            backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "etc", &["/etc"])).unwrap()
+        };
+        assert_eq!(job.max_file_errors, None);
+
+        let job = put(&state, &job.id, serde_json::json!({ "max_file_errors": 10 })).await;
+        assert_eq!(job.max_file_errors, Some(10));
+        // Leaving it out keeps it
+        let job = put(&state, &job.id, serde_json::json!({ "name": "etc-daily" })).await;
+        assert_eq!(job.max_file_errors, Some(10));
+        let job = put(&state, &job.id, serde_json::json!({ "max_file_errors": null })).await;
+        assert_eq!(job.max_file_errors, None);
+    }
+
+    #[tokio::test]
+    async fn test_derived_labels_survive_reordering_paths() {
+        let state = crate::state::test_state();
+        let job = {
+            let conn = state.db.get().unwrap();
+            let srv = server::create_for_test(&conn, "web");
+            // A job saved before derived labels were stored
+            let request = CreateBackupJobRequest::for_test(&srv.id, "app", &["/etc/app", "/etc_app"]);
+            backup_job::create(&conn, &request).unwrap()
+        };
+        assert_eq!(job.root_labels, "{}");
+
+        backup_roots::migrate_root_layout(&state.db).unwrap();
+        let job = put(&state, &job.id, serde_json::json!({ "remote_paths": ["/etc_app", "/etc/app", "/srv"] })).await;
+        let labels: Vec<_> = backup_roots::job_roots(&job).into_iter().map(|root| root.label).collect();
+        assert_eq!(labels, vec!["etc_app-2", "etc_app", "srv"]);
+        let stored: HashMap<String, String> = serde_json::from_str(&job.root_labels).unwrap();
+        assert_eq!(stored.len(), 3);
+    }
+}
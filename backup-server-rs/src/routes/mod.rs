@@ -5,6 +5,9 @@ pub mod storage;
 pub mod files;
 pub mod agent;
 pub mod explorer;
+pub mod webdav;
+pub mod queue;
+pub mod rollouts;
 
 use crate::state::AppState;
 use axum::Router;
@@ -22,6 +25,9 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         .nest("/api/storage", storage::router(state.clone()))
         .nest("/api/files", files::router(state.clone()))
         .nest("/api/agent", agent::router(state.clone()))
+        .nest("/api/queue", queue::router(state.clone()))
+        .nest("/api/rollouts", rollouts::router(state.clone()))
+        .merge(webdav::router(state.clone()))
         .route("/ws", axum::routing::get(crate::ws::ui::ws_handler))
         .route("/ws/agent", axum::routing::get(crate::ws::agent_registry::ws_handler))
         .fallback_service(
@@ -0,0 +1,84 @@
+use crate::error::AppError;
+use crate::models::backup_queue::{self, QueueEntry};
+use crate::services::job_queue;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub fn router(_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(list_queue))
+        .route("/{id}", put(update_entry))
+}
+
+#[derive(Serialize)]
+struct QueueItem {
+    #[serde(flatten)]
+    entry: QueueEntry,
+    /// 1-based position in the order runs will be started.
+    position: usize,
+}
+
+fn with_positions(state: &AppState, entries: Vec<QueueEntry>) -> Vec<QueueItem> {
+    state.queue.service_order(entries)
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| QueueItem { entry, position: i + 1 })
+        .collect()
+}
+
+async fn list_queue(State(state): State<Arc<AppState>>) -> Result<Json<Vec<QueueItem>>, AppError> {
+    let db = state.db.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_queue::find_all(&conn)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    Ok(Json(with_positions(&state, entries)))
+}
+
+#[derive(Deserialize)]
+struct UpdateEntryRequest {
+    priority: Option<i64>,
+    /// 1-based target position in queue order; applied after `priority`.
+    /// Servers still take turns among runs of the same priority, so the run
+    /// can start later than that.
+    position: Option<usize>,
+}
+
+/// Change an entry's priority and/or move it to another position in the queue.
+async fn update_entry(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(body): Json<UpdateEntryRequest>,
+) -> Result<Json<Vec<QueueItem>>, AppError> {
+    if body.position == Some(0) {
+        return Err(AppError::BadRequest("position is 1-based".into()));
+    }
+
+    let db = state.db.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        if backup_queue::find_by_id(&conn, &id)?.is_none() {
+            return Ok(None);
+        }
+        if let Some(priority) = body.priority {
+            backup_queue::update_priority(&conn, &id, priority)?;
+        }
+        if let Some(position) = body.position {
+            backup_queue::move_to(&conn, &id, position - 1)?;
+        }
+        Ok::<_, anyhow::Error>(Some(backup_queue::find_all(&conn)?))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::NotFound("Queue entry not found".into()))?;
+
+    job_queue::broadcast_queue(&state, &entries);
+    state.queue.wake();
+    Ok(Json(with_positions(&state, entries)))
+}
@@ -0,0 +1,148 @@
+use crate::error::AppError;
+use crate::models::{agent_rollout, server};
+use crate::services::agent_rollout::{self as rollout_service, NewRollout, RolloutStatus};
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub fn router(_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(list_rollouts).post(create_rollout))
+        .route("/{id}", get(get_rollout))
+        .route("/{id}/halt", post(halt_rollout))
+}
+
+#[derive(Deserialize)]
+struct CreateRolloutRequest {
+    /// Must match the agent binary the server serves; defaults to it.
+    target_version: Option<String>,
+    /// Only servers with this tag.
+    tag: Option<String>,
+    /// Only these servers.
+    server_ids: Option<Vec<String>>,
+    /// Size of a first canary wave, in percent of the selected servers.
+    canary_percent: Option<i64>,
+    #[serde(default = "default_wave_size")]
+    wave_size: usize,
+    #[serde(default)]
+    max_failures: i64,
+    #[serde(default = "default_test_backup")]
+    test_backup: bool,
+}
+
+fn default_wave_size() -> usize { 5 }
+fn default_test_backup() -> bool { true }
+
+async fn list_rollouts(State(state): State<Arc<AppState>>) -> Result<Json<Vec<agent_rollout::Rollout>>, AppError> {
+    let db = state.db.clone();
+    let rollouts = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        agent_rollout::find_all(&conn)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    Ok(Json(rollouts))
+}
+
+async fn get_rollout(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<RolloutStatus>, AppError> {
+    let db = state.db.clone();
+    let status = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        rollout_service::load_status(&conn, &id)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    status
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("Rollout not found".into()))
+}
+
+/// Start a campaign updating the selected agents to the release binary.
+async fn create_rollout(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<CreateRolloutRequest>,
+) -> Result<(StatusCode, Json<RolloutStatus>), AppError> {
+    if let Some(percent) = body.canary_percent {
+        if !(1..=100).contains(&percent) {
+            return Err(AppError::BadRequest("canary_percent must be between 1 and 100".into()));
+        }
+    }
+    if body.wave_size == 0 {
+        return Err(AppError::BadRequest("wave_size must be at least 1".into()));
+    }
+    if body.max_failures < 0 {
+        return Err(AppError::BadRequest("max_failures cannot be negative".into()));
+    }
+
+    let manifest = crate::routes::agent::release_manifest(&state).await?;
+    if let Some(version) = body.target_version.as_deref() {
+        if version != manifest.version {
+            return Err(AppError::Conflict(format!(
+                "The agent binary on the server is version {}, not {}",
+                manifest.version, version
+            )));
+        }
+    }
+
+    let db = state.db.clone();
+    let (tag, server_ids) = (body.tag.clone(), body.server_ids.clone());
+    let (running, mut servers) = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let running = !agent_rollout::find_by_status(&conn, "running")?.is_empty();
+        let servers: Vec<server::Server> = server::find_all(&conn)?
+            .into_iter()
+            .filter(|s| tag.as_deref().is_none_or(|tag| s.has_tag(tag)))
+            .filter(|s| server_ids.as_ref().is_none_or(|ids| ids.contains(&s.id)))
+            .collect();
+        Ok::<_, anyhow::Error>((running, servers))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+
+    if running {
+        return Err(AppError::Conflict("Another rollout is already running".into()));
+    }
+    if servers.is_empty() {
+        return Err(AppError::BadRequest("No servers match the selection".into()));
+    }
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let status = rollout_service::start(state.clone(), NewRollout {
+        manifest,
+        selector: serde_json::json!({ "tag": body.tag, "serverIds": body.server_ids }),
+        servers: servers.into_iter().map(|s| (s.id, s.agent_version)).collect(),
+        canary_percent: body.canary_percent,
+        wave_size: body.wave_size,
+        max_failures: body.max_failures,
+        test_backup: body.test_backup,
+    })
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+async fn halt_rollout(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<RolloutStatus>, AppError> {
+    if !rollout_service::halt(&state, &id, "Halted by user").await? {
+        return Err(AppError::Conflict("Rollout is not running".into()));
+    }
+
+    let db = state.db.clone();
+    let status = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        rollout_service::load_status(&conn, &id)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+    .ok_or_else(|| AppError::NotFound("Rollout not found".into()))?;
+    Ok(Json(status))
+}
@@ -1,5 +1,5 @@
 use crate::error::AppError;
-use crate::models::server;
+use crate::models::{agent_audit, server};
 use crate::state::AppState;
 use axum::extract::{Path, Query, State};
 use axum::routing::get;
@@ -13,6 +13,12 @@ pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
         .route("/ping-status", get(get_ping_status))
         .route("/{id}", get(get_server).put(update_server).delete(delete_server))
         .route("/{id}/explore", get(crate::routes::explorer::explore))
+        .route("/{id}/audit", get(get_audit_events))
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<i64>,
 }
 
 async fn list_servers(State(state): State<Arc<AppState>>) -> Result<Json<Vec<server::Server>>, AppError> {
@@ -132,3 +138,25 @@ async fn get_ping_status(
     .map_err(|e| anyhow::anyhow!(e))??;
     Ok(Json(statuses))
 }
+
+/// Paths the server's agent refused to browse or back up, newest first.
+async fn get_audit_events(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<agent_audit::AuditEvent>>, AppError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let db = state.db.clone();
+    let events = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        if server::find_by_id(&conn, &id)?.is_none() {
+            return Ok(None);
+        }
+        agent_audit::find_by_server(&conn, &id, limit).map(Some)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??;
+    events
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("Server not found".into()))
+}
@@ -1,10 +1,15 @@
 use crate::error::AppError;
 use crate::models::{backup_job, backup_version, server, settings};
+use crate::services::version_links::{self, LinkSetting};
 use crate::state::AppState;
+use crate::utils::safe_dir::{supports_reflink, EntryKind, SafeDir};
+use backup_protocol::path as path_encoding;
+use backup_protocol::BackupRoot;
 use axum::extract::{Query, State};
 use axum::routing::{get, put};
 use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -21,36 +26,69 @@ pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
 
 async fn get_settings(State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>, AppError> {
     let db = state.db.clone();
-    let backup_root = tokio::task::spawn_blocking(move || {
+    let (backup_root, link_mode, reflink_supported) = tokio::task::spawn_blocking(move || {
         let conn = db.get()?;
-        Ok::<_, anyhow::Error>(settings::get(&conn, "backup_root")?)
+        let backup_root = settings::get(&conn, "backup_root")?;
+        let link_mode = LinkSetting::load(&conn)?;
+        let reflink_supported = backup_root.as_deref().is_some_and(|root| supports_reflink(Path::new(root)));
+        Ok::<_, anyhow::Error>((backup_root, link_mode, reflink_supported))
     })
     .await
     .map_err(|e| anyhow::anyhow!(e))??;
-    Ok(Json(serde_json::json!({ "backup_root": backup_root })))
+    Ok(Json(serde_json::json!({
+        "backup_root": backup_root,
+        "link_mode": link_mode.as_str(),
+        "reflink_supported": reflink_supported,
+    })))
 }
 
+/// Either setting can be changed alone.
 #[derive(Deserialize)]
 struct UpdateSettingsBody {
-    backup_root: String,
+    backup_root: Option<String>,
+    /// `auto`, `reflink` or `hardlink` (see [`crate::services::version_links`])
+    link_mode: Option<String>,
 }
 
 async fn update_settings(
     State(state): State<Arc<AppState>>,
     Json(body): Json<UpdateSettingsBody>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    if body.backup_root.is_empty() {
+    let link_mode = body.link_mode.as_deref()
+        .map(|mode| LinkSetting::parse(mode).ok_or_else(|| AppError::BadRequest(format!("Unknown link_mode: {}", mode))))
+        .transpose()?;
+    let Some(new_root) = body.backup_root else {
+        let link_mode = link_mode.ok_or_else(|| AppError::BadRequest("backup_root or link_mode is required".into()))?;
+        let db = state.db.clone();
+        let backup_root = tokio::task::spawn_blocking(move || {
+            let conn = db.get().map_err(anyhow::Error::from)?;
+            let backup_root = settings::get(&conn, "backup_root")?;
+            if let Some(root) = &backup_root {
+                check_link_mode(link_mode, Path::new(root))?;
+            }
+            settings::set(&conn, version_links::SETTING, link_mode.as_str())?;
+            Ok::<_, AppError>(backup_root)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))??;
+        return Ok(Json(serde_json::json!({ "backup_root": backup_root, "link_mode": link_mode.as_str() })));
+    };
+
+    if new_root.is_empty() {
         return Err(AppError::BadRequest("backup_root is required".into()));
     }
 
-    let path = PathBuf::from(&body.backup_root);
+    let path = PathBuf::from(&new_root);
     if !path.is_dir() {
         return Err(AppError::BadRequest("Path does not exist or is not a directory".into()));
     }
+    if let Some(link_mode) = link_mode {
+        check_link_mode(link_mode, &path)?;
+    }
 
     let db = state.db.clone();
-    let new_root = body.backup_root.clone();
-    tokio::task::spawn_blocking(move || {
+    let saved_root = new_root.clone();
+    let link_mode = tokio::task::spawn_blocking(move || {
         let conn = db.get()?;
 
         let old_root = settings::get(&conn, "backup_root")?;
@@ -75,12 +113,18 @@ async fn update_settings(
                         backup_job::update(&conn, &job.id, &backup_job::UpdateBackupJobRequest {
                             name: None,
                             remote_paths: None,
+                            root_labels: None,
                             local_path: Some(new_path),
                             cron_schedule: None,
                             rsync_options: None,
                             max_parallel: None,
                             enabled: None,
                             max_versions: None,
+                            retry_max_attempts: None,
+                            retry_backoff_secs: None,
+                            max_file_errors: None,
+                            compression: None,
+                            compression_level: None,
                         })?;
                     }
                 }
@@ -88,12 +132,24 @@ async fn update_settings(
         }
 
         settings::set(&conn, "backup_root", &new_root)?;
-        Ok::<_, anyhow::Error>(())
+        let link_mode = match link_mode {
+            Some(mode) => {
+                settings::set(&conn, version_links::SETTING, mode.as_str())?;
+                mode
+            }
+            None => LinkSetting::load(&conn)?,
+        };
+        Ok::<_, anyhow::Error>(link_mode)
     })
     .await
     .map_err(|e| anyhow::anyhow!(e))??;
 
-    Ok(Json(serde_json::json!({ "backup_root": body.backup_root })))
+    Ok(Json(serde_json::json!({ "backup_root": saved_root, "link_mode": link_mode.as_str() })))
+}
+
+/// Refuse `reflink` on storage that cannot clone, where every run would fail.
+fn check_link_mode(mode: LinkSetting, root: &Path) -> Result<(), AppError> {
+    mode.resolve(root).map(|_| ()).map_err(AppError::BadRequest)
 }
 
 // ── Browse ──
@@ -103,10 +159,15 @@ struct BrowseQuery {
     path: Option<String>,
 }
 
+/// `path` is encoded with `backup_protocol::path` and is what `path` query
+/// parameters take back, so names that are not UTF-8 can be browsed too;
+/// `name` and `displayPath` are for showing.
 #[derive(Serialize)]
 struct LocalEntry {
     name: String,
     path: String,
+    #[serde(rename = "displayPath")]
+    display_path: String,
     #[serde(rename = "type")]
     entry_type: String,
     size: u64,
@@ -115,76 +176,56 @@ struct LocalEntry {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "backupMeta")]
     backup_meta: Option<serde_json::Value>,
-}
-
-fn assert_within_root(root: &str, sub_path: &str) -> Result<PathBuf, AppError> {
-    let relative = sub_path.trim_start_matches('/');
-    let resolved = PathBuf::from(root).join(relative).canonicalize()
-        .map_err(|_| AppError::BadRequest("Path does not exist".into()))?;
-    let root_canonical = PathBuf::from(root).canonicalize()
-        .map_err(|_| AppError::BadRequest("Backup root does not exist".into()))?;
-    if !resolved.starts_with(&root_canonical) {
-        return Err(AppError::BadRequest("Access denied".into()));
-    }
-    Ok(resolved)
+    /// Where the entry was backed up from, inside versions that record their roots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "sourcePath")]
+    source_path: Option<String>,
 }
 
 fn explore_local(root: &str, sub_path: &str) -> Result<Vec<LocalEntry>, AppError> {
-    let resolved = assert_within_root(root, sub_path)?;
+    let dir = SafeDir::open(root).map_err(|_| AppError::BadRequest("Backup root does not exist".into()))?;
+    let relative = path_encoding::decode_path(sub_path.trim_start_matches('/'));
+    let parent = path_encoding::normalize(sub_path.trim_end_matches('/'));
 
     let mut entries = Vec::new();
-    let read_dir = std::fs::read_dir(&resolved)
-        .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to read directory: {}", e)))?;
-
-    for entry in read_dir.flatten() {
-        let name = entry.file_name().to_string_lossy().to_string();
-        if name == ".backup-meta.json" {
+    for entry in dir.read_dir(&relative)? {
+        if entry.name == ".backup-meta.json" {
             continue;
         }
 
-        let ft = entry.file_type().unwrap_or_else(|_| std::fs::metadata(entry.path()).unwrap().file_type());
-        let entry_type = if ft.is_dir() {
-            "directory"
-        } else if ft.is_file() {
-            "file"
-        } else if ft.is_symlink() {
-            "symlink"
-        } else {
-            "other"
+        let entry_type = match entry.kind {
+            EntryKind::Directory => "directory",
+            EntryKind::File => "file",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Other => "other",
         };
 
-        let (size, modified_at) = match std::fs::metadata(entry.path()) {
-            Ok(meta) => {
-                let modified = meta.modified()
-                    .ok()
-                    .map(|t| {
-                        let dt: chrono::DateTime<chrono::Utc> = t.into();
-                        dt.to_rfc3339()
-                    })
-                    .unwrap_or_default();
-                (meta.len(), modified)
-            }
-            Err(_) => (0, String::new()),
-        };
+        let modified_at = entry.modified
+            .map(|t| {
+                let dt: chrono::DateTime<chrono::Utc> = t.into();
+                dt.to_rfc3339()
+            })
+            .unwrap_or_default();
 
-        let rel_path = format!("{}/{}", sub_path.trim_end_matches('/'), name);
+        let rel_path = format!("{}/{}", parent, path_encoding::encode(entry.name.as_bytes()));
 
-        let backup_meta = if entry_type == "directory" {
-            let meta_path = entry.path().join(".backup-meta.json");
-            std::fs::read_to_string(meta_path)
+        let backup_meta = if entry.kind == EntryKind::Directory {
+            dir.open_file(relative.join(&entry.name).join(".backup-meta.json"))
                 .ok()
-                .and_then(|s| serde_json::from_str(&s).ok())
+                .and_then(|file| serde_json::from_reader(file).ok())
         } else {
             None
         };
 
         entries.push(LocalEntry {
-            name,
+            name: entry.name.to_string_lossy().into_owned(),
+            display_path: path_encoding::display(&rel_path),
             path: rel_path,
             entry_type: entry_type.into(),
-            size,
+            size: entry.size,
             modified_at,
             backup_meta,
+            source_path: None,
         });
     }
 
@@ -294,10 +335,26 @@ async fn browse_version(
     let version = version.ok_or_else(|| AppError::NotFound("Version not found".into()))?;
     let local_path = version.local_path;
 
-    let entries = tokio::task::spawn_blocking(move || explore_local(&local_path, &sub_path))
+    let mut entries = tokio::task::spawn_blocking(move || explore_local(&local_path, &sub_path))
         .await
         .map_err(|e| anyhow::anyhow!(e))??;
 
+    // Each root is stored under its label: map entries back to the source
+    let roots: Vec<BackupRoot> = version.roots
+        .and_then(|roots| serde_json::from_str(&roots).ok())
+        .unwrap_or_default();
+    for entry in &mut entries {
+        let path = entry.path.trim_start_matches('/');
+        let (label, rest) = path.split_once('/').unwrap_or((path, ""));
+        if let Some(root) = roots.iter().find(|r| r.label == label) {
+            entry.source_path = Some(if rest.is_empty() {
+                root.path.clone()
+            } else {
+                format!("{}/{}", root.path.trim_end_matches('/'), path_encoding::display(rest))
+            });
+        }
+    }
+
     Ok(Json(entries))
 }
 
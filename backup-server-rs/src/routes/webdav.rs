@@ -0,0 +1,527 @@
+//! Read-only WebDAV view of the backup catalog.
+//!
+//! Exposes `/dav/{server}/{job}/{version}/...` so any WebDAV client (davfs2,
+//! Finder, Explorer, rclone) can browse and copy files out of completed
+//! versions. The first three levels are virtual collections built from the
+//! database; everything below a version is served from its `local_path`.
+//!
+//! Supported methods: OPTIONS, PROPFIND (Depth 0/1), GET and HEAD with single
+//! byte-range requests. Anything that would modify the catalog is rejected
+//! with 405. The routes are mounted on the same router as `/api`, so they sit
+//! behind exactly the same layers.
+//!
+//! Path segments are raw bytes, percent-encoded in hrefs and decoded from
+//! request paths, so file names that are not UTF-8 list and download as they
+//! are.
+
+use crate::error::AppError;
+use crate::models::{backup_job, backup_version, server};
+use crate::state::AppState;
+use crate::utils::safe_dir::{EntryKind, SafeDir, SafeEntry};
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+const DAV_PREFIX: &str = "/dav";
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD";
+
+/// Characters left as-is in hrefs (RFC 3986 unreserved set).
+const HREF_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Files that live in version directories but are not part of the backup.
+const HIDDEN_FILES: &[&str] = &[".backup-manifest.bin", ".backup-manifest.json", ".backup-meta.json"];
+
+pub fn router(_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    // Merged rather than nested: clients request both `/dav` and `/dav/`.
+    Router::new()
+        .route(DAV_PREFIX, any(handle_root))
+        .route("/dav/", any(handle_root))
+        .route("/dav/{*path}", any(handle_path))
+}
+
+/// A single entry in a PROPFIND response.
+struct DavEntry {
+    /// Path segments relative to `/dav`.
+    segments: Vec<Vec<u8>>,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+/// What a request path points at.
+enum Resolved {
+    /// Catalog level (root, server, job): the collection itself plus its children.
+    Virtual { this: DavEntry, children: Vec<DavEntry> },
+    /// A path inside a version directory, relative to it.
+    Fs { segments: Vec<Vec<u8>>, dir: SafeDir, path: PathBuf },
+}
+
+async fn handle_root(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    dispatch(state, method, headers, String::new()).await
+}
+
+/// The path is taken still percent-encoded from the URI: the `Path`
+/// extractor would insist on UTF-8.
+async fn handle_path(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, AppError> {
+    let path = uri.path().strip_prefix(DAV_PREFIX).unwrap_or_default().to_string();
+    dispatch(state, method, headers, path).await
+}
+
+async fn dispatch(
+    state: Arc<AppState>,
+    method: Method,
+    headers: HeaderMap,
+    path: String,
+) -> Result<Response, AppError> {
+    match method.as_str() {
+        "OPTIONS" => Ok(options_response()),
+        "PROPFIND" => {
+            let segments = split_segments(&path)?;
+            let depth_one = !matches!(
+                headers.get("depth").and_then(|v| v.to_str().ok()),
+                Some("0")
+            );
+            let resolved = resolve(&state, segments).await?;
+            propfind(resolved, depth_one).await
+        }
+        "GET" | "HEAD" => {
+            let segments = split_segments(&path)?;
+            match resolve(&state, segments).await? {
+                Resolved::Fs { dir, path, .. } => {
+                    serve_file(dir, path, &headers, method == Method::HEAD).await
+                }
+                Resolved::Virtual { .. } => Ok(method_not_allowed()),
+            }
+        }
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+fn options_response() -> Response {
+    (
+        StatusCode::OK,
+        [
+            ("dav", "1"),
+            ("allow", ALLOWED_METHODS),
+            ("ms-author-via", "DAV"),
+        ],
+    )
+        .into_response()
+}
+
+fn method_not_allowed() -> Response {
+    (StatusCode::METHOD_NOT_ALLOWED, [("allow", ALLOWED_METHODS)]).into_response()
+}
+
+/// Split a percent-encoded request path into decoded segments, rejecting
+/// anything that could step outside the catalog.
+fn split_segments(path: &str) -> Result<Vec<Vec<u8>>, AppError> {
+    let mut segments = Vec::new();
+    for seg in path.split('/') {
+        let seg: Vec<u8> = percent_decode_str(seg).collect();
+        match seg.as_slice() {
+            b"" | b"." => continue,
+            b".." => return Err(AppError::BadRequest("Invalid path".into())),
+            s if s.contains(&0) || s.contains(&b'/') => return Err(AppError::BadRequest("Invalid path".into())),
+            _ => segments.push(seg),
+        }
+    }
+    Ok(segments)
+}
+
+/// Names are used as path segments, so slashes must not leak through.
+fn dav_name(name: &str) -> Vec<u8> {
+    name.replace('/', "_").into_bytes()
+}
+
+fn is_hidden(name: &[u8]) -> bool {
+    HIDDEN_FILES.iter().any(|hidden| hidden.as_bytes() == name)
+}
+
+fn parse_timestamp(ts: Option<&str>) -> Option<DateTime<Utc>> {
+    ts.and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+async fn resolve(state: &AppState, segments: Vec<Vec<u8>>) -> Result<Resolved, AppError> {
+    let db = state.db.clone();
+    tokio::task::spawn_blocking(move || resolve_blocking(&db, segments))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?
+}
+
+fn resolve_blocking(
+    db: &crate::db::connection::DbPool,
+    segments: Vec<Vec<u8>>,
+) -> Result<Resolved, AppError> {
+    let conn = db.get().map_err(|e| anyhow::anyhow!(e))?;
+    let not_found = || AppError::NotFound("Not found".into());
+
+    let dir = |segments: Vec<Vec<u8>>, modified: Option<DateTime<Utc>>| DavEntry {
+        segments,
+        is_dir: true,
+        size: 0,
+        modified,
+    };
+
+    // Root: one collection per server
+    let servers = server::find_all(&conn)?;
+    let Some(server_name) = segments.first() else {
+        let children = servers
+            .iter()
+            .map(|s| dir(vec![dav_name(&s.name)], parse_timestamp(Some(&s.updated_at))))
+            .collect();
+        return Ok(Resolved::Virtual { this: dir(vec![], None), children });
+    };
+
+    let srv = servers
+        .into_iter()
+        .find(|s| &dav_name(&s.name) == server_name)
+        .ok_or_else(not_found)?;
+
+    // Server: one collection per job
+    let jobs = backup_job::find_by_server_id(&conn, &srv.id)?;
+    let Some(job_name) = segments.get(1) else {
+        let children = jobs
+            .iter()
+            .map(|j| {
+                dir(
+                    vec![server_name.clone(), dav_name(&j.name)],
+                    parse_timestamp(j.last_run_at.as_deref()),
+                )
+            })
+            .collect();
+        return Ok(Resolved::Virtual {
+            this: dir(segments, parse_timestamp(Some(&srv.updated_at))),
+            children,
+        });
+    };
+
+    let job = jobs
+        .into_iter()
+        .find(|j| &dav_name(&j.name) == job_name)
+        .ok_or_else(not_found)?;
+
+    // Job: one collection per completed version (running/failed ones are partial)
+    let versions: Vec<_> = backup_version::find_by_job_id(&conn, &job.id)?
+        .into_iter()
+        .filter(|v| v.status == "completed")
+        .collect();
+    let Some(version_name) = segments.get(2) else {
+        let children = versions
+            .iter()
+            .map(|v| {
+                dir(
+                    vec![server_name.clone(), job_name.clone(), dav_name(&v.version_timestamp)],
+                    parse_timestamp(v.completed_at.as_deref()),
+                )
+            })
+            .collect();
+        return Ok(Resolved::Virtual {
+            this: dir(segments, parse_timestamp(job.last_run_at.as_deref())),
+            children,
+        });
+    };
+
+    let version = versions
+        .into_iter()
+        .find(|v| &dav_name(&v.version_timestamp) == version_name)
+        .ok_or_else(not_found)?;
+
+    // Inside a version: everything is resolved below the version directory,
+    // which never follows symlinks out of it.
+    if segments.len() == 4 && is_hidden(&segments[3]) {
+        return Err(not_found());
+    }
+    let dir = SafeDir::open(&version.local_path).map_err(|_| not_found())?;
+    let path = PathBuf::from(OsString::from_vec(segments[3..].join(&b'/')));
+    Ok(Resolved::Fs { segments, dir, path })
+}
+
+/// Only files and directories are served; symlinks and special files are not.
+fn fs_entry(segments: Vec<Vec<u8>>, entry: &SafeEntry) -> Option<DavEntry> {
+    let is_dir = match entry.kind {
+        EntryKind::Directory => true,
+        EntryKind::File => false,
+        EntryKind::Symlink | EntryKind::Other => return None,
+    };
+    Some(DavEntry {
+        segments,
+        is_dir,
+        size: if is_dir { 0 } else { entry.size },
+        modified: entry.modified.map(DateTime::<Utc>::from),
+    })
+}
+
+async fn propfind(resolved: Resolved, depth_one: bool) -> Result<Response, AppError> {
+    let entries = match resolved {
+        Resolved::Virtual { this, children } => {
+            let mut entries = vec![this];
+            if depth_one {
+                entries.extend(children);
+            }
+            entries
+        }
+        Resolved::Fs { segments, dir, path } => {
+            tokio::task::spawn_blocking(move || -> Result<Vec<DavEntry>, AppError> {
+                let not_found = || AppError::NotFound("Not found".into());
+                let this = dir.metadata(&path).map_err(|_| not_found())?;
+                let this = fs_entry(segments.clone(), &this).ok_or_else(not_found)?;
+                let is_dir = this.is_dir;
+                let mut entries = vec![this];
+
+                if depth_one && is_dir {
+                    for entry in dir.read_dir(&path)? {
+                        let name = entry.name.clone().into_vec();
+                        if segments.len() == 3 && is_hidden(&name) {
+                            continue;
+                        }
+                        let mut child_segments = segments.clone();
+                        child_segments.push(name);
+                        entries.extend(fs_entry(child_segments, &entry));
+                    }
+                }
+                Ok(entries)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))??
+        }
+    };
+
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for entry in &entries {
+        body.push_str(&entry_xml(entry));
+    }
+    body.push_str("</D:multistatus>\n");
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+fn href(segments: &[Vec<u8>], is_dir: bool) -> String {
+    let mut href = String::from(DAV_PREFIX);
+    for seg in segments {
+        href.push('/');
+        href.push_str(&percent_encode(seg, HREF_ENCODE_SET).to_string());
+    }
+    if is_dir {
+        href.push('/');
+    }
+    href
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn http_date(dt: &DateTime<Utc>) -> String {
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn entry_xml(entry: &DavEntry) -> String {
+    let display_name = entry.segments.last().map(|seg| String::from_utf8_lossy(seg)).unwrap_or_default();
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname>",
+        xml_escape(&display_name)
+    );
+    if entry.is_dir {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        props.push_str("<D:resourcetype/>");
+        props.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", entry.size));
+        props.push_str("<D:getcontenttype>application/octet-stream</D:getcontenttype>");
+    }
+    if let Some(modified) = &entry.modified {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            http_date(modified)
+        ));
+    }
+
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        xml_escape(&href(&entry.segments, entry.is_dir)),
+        props
+    )
+}
+
+/// Parse a single `bytes=` range against a file of `len` bytes.
+///
+/// Returns `Ok(None)` when the header should be ignored (absent, multi-range
+/// or not a byte range) and `Err(())` when the range cannot be satisfied.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1).ok_or(())?)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, len.checked_sub(1).ok_or(())?),
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if start >= len || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+async fn serve_file(dir: SafeDir, path: PathBuf, headers: &HeaderMap, head_only: bool) -> Result<Response, AppError> {
+    let entry = tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || dir.metadata(&path).map(|entry| (dir, entry))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))?;
+    let (dir, meta) = entry.map_err(|_| AppError::NotFound("Not found".into()))?;
+    match meta.kind {
+        EntryKind::File => {}
+        EntryKind::Directory => return Ok(method_not_allowed()),
+        EntryKind::Symlink | EntryKind::Other => return Err(AppError::NotFound("Not found".into())),
+    }
+
+    let len = meta.size;
+    let modified = meta.modified.map(DateTime::<Utc>::from);
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => match parse_range(value, len) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+                )
+                    .into_response());
+            }
+        },
+        None => None,
+    };
+
+    let (status, start, count) = match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        None => (StatusCode::OK, 0, len),
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(count));
+    if let Some(modified) = &modified {
+        if let Ok(v) = HeaderValue::from_str(&http_date(modified)) {
+            response_headers.insert(header::LAST_MODIFIED, v);
+        }
+        let etag = format!("\"{:x}-{:x}\"", modified.timestamp(), len);
+        if let Ok(v) = HeaderValue::from_str(&etag) {
+            response_headers.insert(header::ETAG, v);
+        }
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", start, start + count - 1, len);
+        if let Ok(v) = HeaderValue::from_str(&content_range) {
+            response_headers.insert(header::CONTENT_RANGE, v);
+        }
+    }
+
+    if head_only {
+        return Ok((status, response_headers).into_response());
+    }
+
+    let file = tokio::task::spawn_blocking(move || dir.open_file(&path))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))??;
+    let mut file = tokio::fs::File::from_std(file);
+    if start > 0 {
+        file.seek(std::io::SeekFrom::Start(start))
+            .await
+            .map_err(|e| AppError::Internal(anyhow::anyhow!("Seek error: {}", e)))?;
+    }
+    let body = Body::from_stream(ReaderStream::new(file.take(count)));
+
+    Ok((status, response_headers, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=5-1", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    }
+
+    #[test]
+    fn test_split_segments_rejects_traversal() {
+        assert_eq!(split_segments("a/b/./c/").unwrap(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert!(split_segments("a/../b").is_err());
+        assert!(split_segments("a/%2E%2E/b").is_err());
+        assert!(split_segments("a%2F..%2Fb").is_err());
+    }
+
+    #[test]
+    fn test_href_encoding() {
+        let segments = vec![b"web 01".to_vec(), b"a&b".to_vec()];
+        assert_eq!(href(&segments, true), "/dav/web%2001/a%26b/");
+        assert_eq!(href(&[], true), "/dav/");
+    }
+
+    #[test]
+    fn test_names_that_are_not_utf8_round_trip() {
+        let segments = vec![b"web".to_vec(), b"caf\xe9 50%".to_vec()];
+        let href = href(&segments, false);
+        assert_eq!(href, "/dav/web/caf%E9%2050%25");
+        assert_eq!(split_segments(href.strip_prefix(DAV_PREFIX).unwrap()).unwrap(), segments);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::config::AppConfig;
+use crate::services::tls::IssuedCertificate;
 use crate::ws::agent_registry::AgentRegistry;
 use std::io::Read;
 use std::path::PathBuf;
@@ -7,6 +8,8 @@ use std::sync::Arc;
 const REMOTE_BINARY_PATH: &str = "/usr/local/bin/backup-agent";
 const REMOTE_CONFIG_DIR: &str = "/etc/backup-agent";
 const REMOTE_CONFIG_PATH: &str = "/etc/backup-agent/config.toml";
+const REMOTE_CLIENT_CERT_PATH: &str = "/etc/backup-agent/client.pem";
+const REMOTE_CLIENT_KEY_PATH: &str = "/etc/backup-agent/client-key.pem";
 const SYSTEMD_SERVICE_PATH: &str = "/etc/systemd/system/backup-agent.service";
 
 pub struct DeployOptions {
@@ -17,6 +20,17 @@ pub struct DeployOptions {
     pub server_id: String,
     pub server_port: u16,
     pub backup_server_ip: Option<String>,
+    pub tls: Option<AgentTlsOptions>,
+    /// Key agents verify signed self-updates with.
+    pub release_public_key: String,
+}
+
+/// TLS settings written into the agent config when the server listens with TLS.
+pub struct AgentTlsOptions {
+    /// Server certificate fingerprint the agent pins.
+    pub server_fingerprint: String,
+    /// Client certificate for mutual TLS, if enabled.
+    pub client_cert: Option<IssuedCertificate>,
 }
 
 pub fn get_agent_binary_path() -> PathBuf {
@@ -84,13 +98,25 @@ fn deploy_via_ssh(opts: &DeployOptions, binary_path: &PathBuf) -> anyhow::Result
 
     // 2. Detect server IP
     let detected_ip = detect_source_ip(&sess, &opts.password, &opts.backup_server_ip)?;
-    let server_url = format!("http://{}:{}", detected_ip, opts.server_port);
+    let scheme = if opts.tls.is_some() { "https" } else { "http" };
+    let server_url = format!("{}://{}:{}", scheme, detected_ip, opts.server_port);
     tracing::info!(hostname = %opts.hostname, server_url = %server_url, "Detected backup server URL");
 
-    // 3. Write config
+    // 3. Write config (and the client certificate, for mutual TLS)
     tracing::info!(hostname = %opts.hostname, "Writing agent config...");
-    let config_content = generate_config(&opts.hostname, &server_url, &opts.server_id);
+    let config_content = generate_config(
+        &opts.hostname,
+        &server_url,
+        &opts.server_id,
+        &opts.release_public_key,
+        opts.tls.as_ref(),
+    );
     exec_ssh(&sess, &opts.password, &format!("sudo mkdir -p {}", REMOTE_CONFIG_DIR))?;
+    if let Some(cert) = opts.tls.as_ref().and_then(|tls| tls.client_cert.as_ref()) {
+        write_remote_file(&sess, &opts.password, REMOTE_CLIENT_CERT_PATH, &cert.cert_pem)?;
+        write_remote_file(&sess, &opts.password, REMOTE_CLIENT_KEY_PATH, &cert.key_pem)?;
+        exec_ssh(&sess, &opts.password, &format!("sudo chmod 600 {}", REMOTE_CLIENT_KEY_PATH))?;
+    }
     write_remote_file(&sess, &opts.password, REMOTE_CONFIG_PATH, &config_content)?;
 
     // 4. Create systemd service
@@ -181,7 +207,22 @@ async fn wait_for_agent_connection(server_id: &str, agents: Arc<AgentRegistry>,
     tracing::warn!(server_id, "Agent did not connect within timeout, it may connect later");
 }
 
-fn generate_config(hostname: &str, server_url: &str, server_id: &str) -> String {
+fn generate_config(
+    hostname: &str,
+    server_url: &str,
+    server_id: &str,
+    release_public_key: &str,
+    tls: Option<&AgentTlsOptions>,
+) -> String {
+    let mut tls_lines = String::new();
+    if let Some(tls) = tls {
+        tls_lines.push_str(&format!("cert_fingerprint = \"{}\"\n", tls.server_fingerprint));
+        if tls.client_cert.is_some() {
+            tls_lines.push_str(&format!("client_cert = \"{REMOTE_CLIENT_CERT_PATH}\"\n"));
+            tls_lines.push_str(&format!("client_key = \"{REMOTE_CLIENT_KEY_PATH}\"\n"));
+        }
+    }
+
     format!(
         r#"[agent]
 id = "{hostname}"
@@ -192,7 +233,8 @@ data_dir = "/var/lib/backup-agent"
 url = "{server_url}"
 token = ""
 server_id = "{server_id}"
-
+release_public_key = "{release_public_key}"
+{tls_lines}
 [sync]
 chunk_size = 1048576
 compression = "zstd"
@@ -1,21 +1,72 @@
 use crate::db::connection::DbPool;
 use crate::models::{backup_job, backup_version, server};
+use crate::services::backup_roots;
+use crate::services::version_links::LinkSetting;
 use crate::state::AppState;
-use std::collections::HashMap;
-use std::path::Path;
+use crate::ws::job_events::{CompletionStats, JobEvent};
+use crate::ws::protocol::{CAP_BINARY_MANIFEST, CAP_CARRY_FORWARD, CAP_INCREMENTAL, CAP_RENAMES, CAP_ROOTS};
+use backup_protocol::manifest;
+use backup_protocol::{ServerMessage, StartBackupPayload};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// What started a run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunTrigger {
+    /// Started from the API.
+    Manual,
+    /// Started by the job's cron schedule.
+    Scheduled,
+    /// Retry of a failed scheduled run whose log is `retry_of`.
+    Retry { attempt: i64, retry_of: String },
+}
+
+impl RunTrigger {
+    pub fn attempt(&self) -> i64 {
+        match self {
+            RunTrigger::Retry { attempt, .. } => *attempt,
+            _ => 1,
+        }
+    }
+}
+
+/// A failure likely to clear up on its own, such as the agent being briefly
+/// offline or a stalled transfer. Only these make a scheduled run eligible for retry.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct TransientError(pub String);
+
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TransientError>().is_some()
+}
 
-pub async fn run_backup_job(state: Arc<AppState>, job_id: String) -> anyhow::Result<()> {
+/// A failed run and the log it was recorded in, which retries of it link to
+/// (None if it failed before it had one).
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct RunFailed {
+    pub log_id: Option<String>,
+    pub error: anyhow::Error,
+}
+
+impl From<anyhow::Error> for RunFailed {
+    fn from(error: anyhow::Error) -> Self {
+        Self { log_id: None, error }
+    }
+}
+
+pub async fn run_backup_job(state: Arc<AppState>, job_id: String, trigger: RunTrigger) -> Result<(), RunFailed> {
     // Check if already running
     {
         let mut running = state.running_jobs.lock().await;
         if running.contains(&job_id) {
-            anyhow::bail!("Job already running");
+            return Err(anyhow::anyhow!("Job already running").into());
         }
         running.insert(job_id.clone());
     }
 
-    let result = run_backup_inner(state.clone(), &job_id).await;
+    let result = run_backup_inner(state.clone(), &job_id, &trigger).await;
 
     // Always remove from running set
     {
@@ -26,7 +77,7 @@ pub async fn run_backup_job(state: Arc<AppState>, job_id: String) -> anyhow::Res
     result
 }
 
-async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<()> {
+async fn run_backup_inner(state: Arc<AppState>, job_id: &str, trigger: &RunTrigger) -> Result<(), RunFailed> {
     let db = state.db.clone();
     let jid = job_id.to_string();
 
@@ -43,16 +94,64 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
             Ok::<_, anyhow::Error>((job, srv))
         }
     })
-    .await??;
+    .await
+    .map_err(anyhow::Error::from)??;
+
+    // Create log entry up front so that runs failing before they start are recorded too
+    let db3 = db.clone();
+    let jid3 = jid.clone();
+    let attempt = trigger.attempt();
+    let retry_of = match trigger {
+        RunTrigger::Retry { retry_of, .. } => Some(retry_of.clone()),
+        _ => None,
+    };
+    let log = tokio::task::spawn_blocking(move || {
+        let conn = db3.get()?;
+        backup_job::create_log(&conn, &jid3, attempt, retry_of.as_deref())
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    let log_id = log.id.clone();
+
+    if let Err(error) = execute_run(state.clone(), job, srv, log).await {
+        record_failure(&db, &jid, &log_id, &error.to_string()).await;
+        return Err(RunFailed { log_id: Some(log_id), error });
+    }
+    Ok(())
+}
+
+async fn execute_run(
+    state: Arc<AppState>,
+    job: backup_job::BackupJob,
+    srv: server::Server,
+    log: backup_job::BackupLog,
+) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let jid = job.id.clone();
 
     if !state.agents.is_connected(&srv.id) {
-        anyhow::bail!("Agent is not connected");
+        return Err(TransientError("Agent is not connected".into()).into());
+    }
+
+    // Only ask the agent for what it said it supports
+    let protocol = srv.agent_protocol();
+    if let Err(reason) = protocol.check_backup_support() {
+        anyhow::bail!(reason);
     }
 
-    let remote_paths: Vec<String> = serde_json::from_str(&job.remote_paths).unwrap_or_default();
-    if remote_paths.is_empty() {
+    let roots = backup_roots::job_roots(&job);
+    if roots.is_empty() {
         anyhow::bail!("No remote paths configured");
     }
+    let remote_paths: Vec<String> = roots.iter().map(|r| r.path.clone()).collect();
+
+    // Older agents store every root's files directly in the version, as
+    // before roots were labelled: files at the same relative path in two
+    // roots overwrite each other
+    let labelled = protocol.has(CAP_ROOTS);
+    if !labelled && roots.len() > 1 {
+        tracing::warn!(job_id = %jid, "Agent does not label roots; storing the job's paths in the flat layout");
+    }
 
     // Update status to running
     let db2 = db.clone();
@@ -63,15 +162,6 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
     })
     .await??;
 
-    // Create log entry
-    let db3 = db.clone();
-    let jid3 = jid.clone();
-    let log = tokio::task::spawn_blocking(move || {
-        let conn = db3.get()?;
-        backup_job::create_log(&conn, &jid3)
-    })
-    .await??;
-
     let start_time = std::time::Instant::now();
 
     state.ui.broadcast("backup:started", serde_json::json!({
@@ -98,11 +188,27 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
     let version_path = versions_dir.join(&version_timestamp);
     tokio::fs::create_dir_all(&version_path).await?;
 
+    // Unchanged files are cloned where the storage supports it
+    let db_links = db.clone();
+    let probe_dir = versions_dir.clone();
+    let link_mode = tokio::task::spawn_blocking(move || {
+        let conn = db_links.get()?;
+        Ok::<_, anyhow::Error>(LinkSetting::load(&conn)?.resolve(&probe_dir))
+    })
+    .await??;
+    let link_mode = match link_mode {
+        Ok(mode) => mode,
+        Err(reason) => {
+            let _ = tokio::fs::remove_dir(&version_path).await;
+            anyhow::bail!(reason);
+        }
+    };
+
     // Write backup metadata
     let meta_path = std::path::PathBuf::from(&job.local_path).join(".backup-meta.json");
     let meta = serde_json::json!({
         "server": { "name": srv.name, "hostname": srv.hostname, "port": srv.port },
-        "job": { "id": job.id, "name": job.name, "remotePaths": remote_paths },
+        "job": { "id": job.id, "name": job.name, "remotePaths": remote_paths, "roots": roots },
         "agent": { "enabled": true },
         "createdAt": job.created_at,
         "lastRunAt": chrono::Utc::now().to_rfc3339(),
@@ -115,6 +221,7 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
     let jid4 = jid.clone();
     let log_id = log.id.clone();
     let vt = version_timestamp.clone();
+    let version_roots = labelled.then(|| serde_json::to_string(&roots)).transpose()?;
     let version = tokio::task::spawn_blocking(move || {
         let conn = db4.get()?;
         backup_version::create(&conn, &backup_version::CreateVersionData {
@@ -122,199 +229,135 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
             log_id,
             version_timestamp: vt,
             local_path: vp,
+            roots: version_roots,
         })
     })
     .await??;
 
-    // Acquire semaphores
-    let _global_permit = state.global_semaphore.acquire().await?;
-    let server_sem = state.get_server_semaphore(&srv.id).await;
-    let _server_permit = server_sem.acquire().await?;
-
-    // Determine if incremental backup is possible (automatic: manifest exists → incremental)
-    let incremental = {
+    // Determine if incremental backup is possible (automatic: manifest exists → incremental).
+    // The previous version must use the same layout for its paths to match.
+    let previous = if protocol.has(CAP_INCREMENTAL) {
         let db_inc = db.clone();
         let jid_inc = jid.clone();
         tokio::task::spawn_blocking(move || {
             let conn = db_inc.get()?;
-            if let Some(prev) = backup_version::find_latest_completed(&conn, &jid_inc)? {
-                let manifest_path = std::path::PathBuf::from(&prev.local_path)
-                    .join(".backup-manifest.json");
-                Ok::<_, anyhow::Error>(manifest_path.exists())
-            } else {
-                Ok(false)
-            }
+            let prev = backup_version::find_latest_completed(&conn, &jid_inc)?
+                .filter(|prev| prev.roots.is_some() == labelled)
+                .map(|prev| PathBuf::from(prev.local_path))
+                .filter(|path| has_manifest(path));
+            Ok::<_, anyhow::Error>(prev)
         })
         .await
-        .unwrap_or(Ok(false))
-        .unwrap_or(false)
+        .unwrap_or(Ok(None))
+        .unwrap_or(None)
+    } else {
+        None
+    };
+    let incremental = previous.is_some();
+    // Unchanged files are then linked by the server, not listed by the agent
+    let carry_forward = incremental && protocol.has(CAP_CARRY_FORWARD);
+
+    // Agents without the job's algorithm compress with their own settings
+    let compression = match job.compression() {
+        Ok(compression) if compression.algorithm.capability().is_none_or(|cap| protocol.has(cap)) => Some(compression),
+        Ok(compression) => {
+            tracing::warn!(job_id = %jid, algorithm = compression.algorithm.as_str(), "Agent does not support the job's compression");
+            None
+        }
+        Err(e) => {
+            tracing::warn!(job_id = %jid, "Ignoring the job's compression: {}", e);
+            None
+        }
     };
 
     let backup_type = if incremental { "incremental" } else { "full" };
     tracing::info!(job_id = %jid, ?remote_paths, backup_type, "Starting agent backup via WebSocket");
 
     // Send backup start command
-    let mut payload = serde_json::json!({
-        "job_id": jid,
-        "paths": remote_paths,
-        "incremental": incremental,
-    });
-    if incremental {
-        payload.as_object_mut().unwrap().insert(
-            "manifest_url".to_string(),
-            serde_json::json!(format!("/api/files/manifest/{}", jid)),
-        );
+    let payload = StartBackupPayload {
+        job_id: jid.clone(),
+        paths: remote_paths,
+        incremental,
+        manifest_url: incremental.then(|| format!("/api/files/manifest/{}", jid)),
+        binary_manifest: protocol.has(CAP_BINARY_MANIFEST),
+        carry_forward,
+        detect_renames: incremental && protocol.has(CAP_RENAMES),
+        compression,
+        max_concurrency: Some(job.max_parallel.clamp(1, u32::MAX as i64) as u32),
+        max_file_errors: job.max_file_errors.map(|max| max.max(0) as u64),
+        roots: if labelled { roots } else { Vec::new() },
+        ..Default::default()
+    };
+
+    // Subscribe before starting so no early event is missed
+    let events = state.job_events.subscribe(&jid, &srv.id);
+    state.version_links.start(&jid, link_mode);
+    if let Some(previous) = previous.filter(|_| carry_forward) {
+        state.carry_forward.expect(&jid, version_path.clone(), previous, link_mode);
     }
 
-    let sent = state.agents.send_to_agent(&srv.id, serde_json::json!({
-        "type": "backup:start",
-        "payload": payload,
-    }));
+    let sent = state.agents.send_to_agent(&srv.id, &ServerMessage::StartBackup(payload));
 
     if !sent {
+        state.job_events.close(&jid);
+        state.carry_forward.discard(&jid);
+        state.version_links.finish(&jid);
         fail_backup(&state, &db, &jid, &log.id, &version.id).await;
-        anyhow::bail!("Failed to send backup command to agent");
+        return Err(TransientError("Failed to send backup command to agent".into()).into());
     }
 
-    // Wait for completion via polling (agent messages are forwarded through the WS handler)
-    // We use a channel-based approach: listen for completion/failure events
-    #[derive(Debug)]
-    struct BackupCompletionStats {
-        total_bytes: i64,
-        total_files: i64,
-        transferred_bytes: i64,
-        transferred_files: i64,
-        unchanged_files: i64,
-        unchanged_bytes: i64,
-        deleted_files: i64,
-        backup_type: String,
-    }
-    let (done_tx, done_rx) = tokio::sync::oneshot::channel::<Result<BackupCompletionStats, String>>();
-    let done_tx = Arc::new(tokio::sync::Mutex::new(Some(done_tx)));
-
-    // Spawn a timeout + polling task
-    let state2 = state.clone();
-    let jid5 = jid.clone();
-    let sid = srv.id.clone();
-    let db5 = db.clone();
-    let done_tx2 = done_tx.clone();
-    let monitor = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-        let timeout = tokio::time::sleep(std::time::Duration::from_secs(3600));
-        tokio::pin!(timeout);
-
-        loop {
-            tokio::select! {
-                _ = &mut timeout => {
-                    if let Some(tx) = done_tx2.lock().await.take() {
-                        let _ = tx.send(Err("Backup timed out after 1 hour".into()));
-                    }
-                    break;
-                }
-                _ = interval.tick() => {
-                    // Check if job was cancelled
-                    let db = db5.clone();
-                    let jid = jid5.clone();
-                    if let Ok(Ok(Some(j))) = tokio::task::spawn_blocking(move || {
-                        let conn = db.get()?;
-                        backup_job::find_by_id(&conn, &jid)
-                    }).await {
-                        if j.status == "cancelled" {
-                            state2.agents.send_to_agent(&sid, serde_json::json!({
-                                "type": "backup:cancel",
-                                "payload": { "job_id": jid5 },
-                            }));
-                            if let Some(tx) = done_tx2.lock().await.take() {
-                                let _ = tx.send(Err("Job cancelled by user".into()));
-                            }
-                            break;
-                        }
-                    }
-
-                    // Check agent connection
-                    if !state2.agents.is_connected(&sid) {
-                        if let Some(tx) = done_tx2.lock().await.take() {
-                            let _ = tx.send(Err("Agent disconnected during backup".into()));
-                        }
-                        break;
-                    }
-                }
-            }
-        }
-    });
+    await_run(state.clone(), ActiveRun {
+        job,
+        server_id: srv.id,
+        log_id: log.id,
+        version_id: version.id,
+        version_path,
+        started: start_time,
+        events,
+    })
+    .await
+}
 
-    // Listen for backup:completed and backup:failed from the UI broadcast
-    let mut rx = state.ui.subscribe();
-    let jid6 = jid.clone();
-    let done_tx3 = done_tx.clone();
-    let event_listener = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&msg) {
-                let msg_type = parsed.get("type").and_then(|t| t.as_str()).unwrap_or("");
-                let payload = parsed.get("payload").cloned().unwrap_or_default();
-                let msg_job_id = payload.get("jobId").or(payload.get("job_id"))
-                    .and_then(|v| v.as_str()).unwrap_or("");
-
-                if msg_job_id != jid6 {
-                    continue;
-                }
+/// A run the agent has been told to start: everything needed to follow it to the end.
+/// Built by `run_backup_inner` for fresh runs and by run recovery for reattached ones.
+pub struct ActiveRun {
+    pub job: backup_job::BackupJob,
+    pub server_id: String,
+    pub log_id: String,
+    pub version_id: String,
+    pub version_path: PathBuf,
+    pub started: std::time::Instant,
+    /// Subscription to the job's events, taken before the agent could emit any.
+    pub events: broadcast::Receiver<JobEvent>,
+}
 
-                match msg_type {
-                    "backup:completed" => {
-                        let total_bytes = payload.get("totalBytes").or(payload.get("total_bytes"))
-                            .and_then(|v| v.as_i64()).unwrap_or(0);
-                        let total_files = payload.get("totalFiles").or(payload.get("total_files"))
-                            .and_then(|v| v.as_i64()).unwrap_or(0);
-                        let transferred_bytes = payload.get("transferredBytes").or(payload.get("transferred_bytes"))
-                            .and_then(|v| v.as_i64()).unwrap_or(total_bytes);
-                        let transferred_files = payload.get("transferredFiles").or(payload.get("transferred_files"))
-                            .and_then(|v| v.as_i64()).unwrap_or(total_files);
-                        let unchanged_files = payload.get("unchangedFiles").or(payload.get("unchanged_files"))
-                            .and_then(|v| v.as_i64()).unwrap_or(0);
-                        let unchanged_bytes = payload.get("unchangedBytes").or(payload.get("unchanged_bytes"))
-                            .and_then(|v| v.as_i64()).unwrap_or(0);
-                        let deleted_files = payload.get("deletedFiles").or(payload.get("deleted_files"))
-                            .and_then(|v| v.as_i64()).unwrap_or(0);
-                        let backup_type = payload.get("backupType").or(payload.get("backup_type"))
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("full")
-                            .to_string();
-                        if let Some(tx) = done_tx3.lock().await.take() {
-                            let _ = tx.send(Ok(BackupCompletionStats {
-                                total_bytes,
-                                total_files,
-                                transferred_bytes,
-                                transferred_files,
-                                unchanged_files,
-                                unchanged_bytes,
-                                deleted_files,
-                                backup_type,
-                            }));
-                        }
-                        break;
-                    }
-                    "backup:failed" => {
-                        let error = payload.get("error")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("Backup failed on agent")
-                            .to_string();
-                        if let Some(tx) = done_tx3.lock().await.take() {
-                            let _ = tx.send(Err(error));
-                        }
-                        break;
-                    }
-                    _ => {}
-                }
+/// Wait for the agent to finish `run`, then record the outcome in the database
+/// and notify the UI.
+pub async fn await_run(state: Arc<AppState>, run: ActiveRun) -> anyhow::Result<()> {
+    let ActiveRun { job, server_id, log_id, version_id, version_path, started: start_time, mut events } = run;
+    let db = state.db.clone();
+    let jid = job.id.clone();
+
+    let result = wait_for_outcome(&state, &jid, &server_id, &mut events).await;
+    state.job_events.close(&jid);
+
+    // Unchanged files the server carries forward must be in place first
+    let result = match result {
+        Ok(mut stats) => match state.carry_forward.finish(&jid).await {
+            Some(Ok(links)) => {
+                stats.failed_files += links.failed as i64;
+                state.version_links.record(&jid, links.linked);
+                Ok(stats)
             }
+            Some(Err(e)) => Err(e.context("Failed to carry unchanged files forward")),
+            None => Ok(stats),
+        },
+        Err(e) => {
+            state.carry_forward.discard(&jid);
+            Err(e)
         }
-    });
-
-    // Wait for result
-    let result = done_rx.await.map_err(|_| anyhow::anyhow!("Monitor channel closed"))?;
-
-    // Cleanup monitor tasks
-    monitor.abort();
-    event_listener.abort();
+    };
+    let link_mode = state.version_links.finish(&jid).mode();
 
     match result {
         Ok(stats) => {
@@ -327,12 +370,15 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
             let unchanged_files = stats.unchanged_files;
             let unchanged_bytes = stats.unchanged_bytes;
             let deleted_files = stats.deleted_files;
+            let failed_files = stats.failed_files;
+            // Files were skipped but the version is still usable
+            let status = if failed_files > 0 { "completed_with_warnings" } else { "completed" };
 
             // Update job status
             let db_c = db.clone();
             let jid_c = jid.clone();
-            let log_id = log.id.clone();
-            let vid = version.id.clone();
+            let log_id_c = log_id.clone();
+            let vid = version_id.clone();
             let completion_data = backup_version::CompletionData {
                 bytes_transferred: stats.transferred_bytes,
                 files_transferred: stats.transferred_files,
@@ -340,12 +386,13 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
                 files_unchanged: stats.unchanged_files,
                 bytes_unchanged: stats.unchanged_bytes,
                 files_deleted: stats.deleted_files,
+                link_mode: link_mode.map(String::from),
             };
             tokio::task::spawn_blocking(move || {
                 let conn = db_c.get()?;
-                backup_job::update_status(&conn, &jid_c, "completed")?;
-                backup_job::update_log(&conn, &log_id, &[
-                    ("status", &"completed" as &dyn rusqlite::types::ToSql),
+                backup_job::update_status(&conn, &jid_c, status)?;
+                backup_job::update_log(&conn, &log_id_c, &[
+                    ("status", &status as &dyn rusqlite::types::ToSql),
                     ("files_transferred", &stats.transferred_files as &dyn rusqlite::types::ToSql),
                     ("bytes_transferred", &stats.transferred_bytes as &dyn rusqlite::types::ToSql),
                     ("finished_at", &chrono::Utc::now().to_rfc3339() as &dyn rusqlite::types::ToSql),
@@ -364,8 +411,7 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
             let manifest_vp = version_path.clone();
             let manifest_jid = jid.clone();
             let _ = tokio::task::spawn_blocking(move || {
-                let manifest_path = manifest_vp.join(".backup-manifest.json");
-                if !manifest_path.exists() {
+                if !has_manifest(&manifest_vp) {
                     tracing::info!(job_id = %manifest_jid, "No agent manifest found, generating server-side manifest (fallback)");
                     if let Err(e) = generate_manifest(&manifest_vp, &manifest_jid) {
                         tracing::warn!(job_id = %manifest_jid, "Failed to generate manifest: {}", e);
@@ -386,6 +432,9 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
                 "unchangedFiles": unchanged_files,
                 "unchangedBytes": unchanged_bytes,
                 "deletedFiles": deleted_files,
+                "failedFiles": failed_files,
+                "linkMode": link_mode,
+                "status": status,
             }));
 
             state.ui.broadcast("backup:progress", serde_json::json!({
@@ -407,23 +456,95 @@ async fn run_backup_inner(state: Arc<AppState>, job_id: &str) -> anyhow::Result<
                 total_bytes,
                 total_files,
                 duration_secs,
+                link_mode,
                 "Backup job completed"
             );
 
             Ok(())
         }
-        Err(error_msg) => {
-            fail_backup(&state, &db, &jid, &log.id, &version.id).await;
+        Err(e) => {
+            fail_backup(&state, &db, &jid, &log_id, &version_id).await;
             state.ui.broadcast("backup:failed", serde_json::json!({
                 "jobId": jid,
-                "error": error_msg,
+                "error": e.to_string(),
             }));
-            tracing::error!(job_id = %jid, error = %error_msg, "Backup job failed");
-            anyhow::bail!("{}", error_msg)
+            tracing::error!(job_id = %jid, error = %e, "Backup job failed");
+            Err(e)
         }
     }
 }
 
+/// Follow the job's events until the agent reports an outcome. A dropped agent
+/// connection does not end the run: the agent keeps working and resumes reporting
+/// once it reconnects. The run fails on an agent error, a cancellation, or when no
+/// progress arrives for `stall_timeout_secs`.
+async fn wait_for_outcome(
+    state: &AppState,
+    job_id: &str,
+    server_id: &str,
+    events: &mut broadcast::Receiver<JobEvent>,
+) -> anyhow::Result<CompletionStats> {
+    let stall_timeout = std::time::Duration::from_secs(state.config.stall_timeout_secs);
+    let mut deadline = tokio::time::Instant::now() + stall_timeout;
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = tokio::time::sleep_until(deadline) => {
+                state.agents.send_to_agent(server_id, &ServerMessage::CancelBackup { job_id: job_id.to_string() });
+                return Err(TransientError(format!(
+                    "Backup stalled: no progress from agent for {}s",
+                    stall_timeout.as_secs()
+                )).into());
+            }
+        };
+
+        match event {
+            Ok(JobEvent::Started) | Ok(JobEvent::Progress) | Err(RecvError::Lagged(_)) => {
+                deadline = tokio::time::Instant::now() + stall_timeout;
+            }
+            Ok(JobEvent::Completed(stats)) => return Ok(stats),
+            Ok(JobEvent::Failed(error)) => anyhow::bail!(error),
+            Ok(JobEvent::Cancelled) => anyhow::bail!("Job cancelled by user"),
+            Ok(JobEvent::AgentDisconnected) => {
+                tracing::warn!(job_id, server_id, "Agent disconnected mid-run, waiting for it to reconnect");
+            }
+            Ok(JobEvent::AgentReconnected) => match state.agents.running_jobs(server_id).await {
+                Ok(jobs) if jobs.iter().any(|j| j == job_id) => {
+                    tracing::info!(job_id, server_id, "Agent reconnected, resuming run");
+                    deadline = tokio::time::Instant::now() + stall_timeout;
+                }
+                Ok(_) => {
+                    return Err(TransientError("Agent reconnected but is no longer running the job".into()).into());
+                }
+                Err(e) => {
+                    tracing::warn!(job_id, server_id, error = %e, "Could not confirm job status after reconnect");
+                }
+            },
+            Err(RecvError::Closed) => anyhow::bail!("Job event channel closed"),
+        }
+    }
+}
+
+/// Mark the run's log and the job as failed, keeping the error on the log.
+async fn record_failure(db: &DbPool, job_id: &str, log_id: &str, error: &str) {
+    let db = db.clone();
+    let jid = job_id.to_string();
+    let lid = log_id.to_string();
+    let error = error.to_string();
+    let _ = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_job::update_status(&conn, &jid, "failed")?;
+        backup_job::update_log(&conn, &lid, &[
+            ("status", &"failed" as &dyn rusqlite::types::ToSql),
+            ("error", &error as &dyn rusqlite::types::ToSql),
+            ("finished_at", &chrono::Utc::now().to_rfc3339() as &dyn rusqlite::types::ToSql),
+        ])?;
+        Ok::<_, anyhow::Error>(())
+    })
+    .await;
+}
+
 async fn fail_backup(state: &AppState, db: &DbPool, job_id: &str, log_id: &str, version_id: &str) {
     let db = db.clone();
     let jid = job_id.to_string();
@@ -445,6 +566,19 @@ async fn fail_backup(state: &AppState, db: &DbPool, job_id: &str, log_id: &str,
 pub async fn cancel_backup_job(state: Arc<AppState>, job_id: &str) -> anyhow::Result<()> {
     tracing::info!(job_id, "Cancelling backup job");
 
+    // A run that has not started yet only needs to leave the queue
+    if crate::services::job_queue::remove(&state, job_id).await? {
+        let db = state.db.clone();
+        let jid = job_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            backup_job::update_status(&conn, &jid, "cancelled")
+        })
+        .await??;
+        state.ui.broadcast("backup:cancelled", serde_json::json!({ "jobId": job_id }));
+        return Ok(());
+    }
+
     let db = state.db.clone();
     let jid = job_id.to_string();
     let (job, srv) = tokio::task::spawn_blocking(move || {
@@ -458,10 +592,7 @@ pub async fn cancel_backup_job(state: Arc<AppState>, job_id: &str) -> anyhow::Re
     .await??;
 
     if state.agents.is_connected(&srv.id) {
-        state.agents.send_to_agent(&srv.id, serde_json::json!({
-            "type": "backup:cancel",
-            "payload": { "job_id": job_id },
-        }));
+        state.agents.send_to_agent(&srv.id, &ServerMessage::CancelBackup { job_id: job_id.to_string() });
         tracing::info!(job_id, "Sent cancel command to agent");
     }
 
@@ -474,6 +605,7 @@ pub async fn cancel_backup_job(state: Arc<AppState>, job_id: &str) -> anyhow::Re
     .await??;
 
     state.ui.broadcast("backup:cancelled", serde_json::json!({ "jobId": job_id }));
+    state.job_events.publish(&srv.id, job_id, JobEvent::Cancelled);
 
     {
         let mut running = state.running_jobs.lock().await;
@@ -483,68 +615,54 @@ pub async fn cancel_backup_job(state: Arc<AppState>, job_id: &str) -> anyhow::Re
     Ok(())
 }
 
-/// Generate a `.backup-manifest.json` file in the version directory.
+/// Whether a version directory has a manifest, binary or JSON.
+fn has_manifest(version_path: &Path) -> bool {
+    version_path.join(manifest::FILE_NAME).exists() || version_path.join(manifest::JSON_FILE_NAME).exists()
+}
+
+/// Generate a binary manifest file in the version directory.
 /// The manifest records every file with its relative path, size, and mtime
-/// so future incremental backups can diff against it.
+/// so future incremental backups can diff against it. Directories are walked
+/// in manifest order, so the files are written as they are found.
 fn generate_manifest(version_path: &Path, job_id: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
     use std::os::unix::fs::MetadataExt;
 
-    let mut files: HashMap<String, serde_json::Value> = HashMap::new();
-    let mut total_files: usize = 0;
-    let mut total_bytes: u64 = 0;
-
-    fn walk_recursive(
-        dir: &Path,
-        root: &Path,
-        files: &mut HashMap<String, serde_json::Value>,
-        total_files: &mut usize,
-        total_bytes: &mut u64,
-    ) -> std::io::Result<()> {
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
+    type Writer = manifest::ManifestWriter<std::io::BufWriter<std::fs::File>>;
+
+    fn walk_recursive(dir: &Path, root: &Path, writer: &mut Writer) -> std::io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_cached_key(|entry| backup_protocol::path::encode(entry.file_name().as_bytes()));
+        for entry in entries {
             let path = entry.path();
             let file_type = entry.file_type()?;
 
             if file_type.is_dir() {
-                walk_recursive(&path, root, files, total_files, total_bytes)?;
+                walk_recursive(&path, root, writer)?;
             } else if file_type.is_file() {
-                let metadata = std::fs::metadata(&path)?;
-                let relative = path.strip_prefix(root)
-                    .unwrap_or(&path)
-                    .to_string_lossy()
-                    .to_string();
-
-                // Skip the manifest file itself
-                if relative == ".backup-manifest.json" {
+                let relative = backup_protocol::path::encode_path(path.strip_prefix(root).unwrap_or(&path));
+
+                // Skip the manifest files themselves
+                if dir == root && entry.file_name().to_str().is_some_and(|name| name.starts_with(".backup-manifest.")) {
                     continue;
                 }
 
-                let size = metadata.len();
-                let mtime = metadata.mtime();
-
-                files.insert(relative, serde_json::json!({
-                    "size": size,
-                    "mtime": mtime,
-                }));
-                *total_files += 1;
-                *total_bytes += size;
+                let metadata = entry.metadata()?;
+                writer.add(&relative, manifest::ManifestEntry { size: metadata.len(), mtime: metadata.mtime(), id: None })?;
             }
         }
         Ok(())
     }
 
-    walk_recursive(version_path, version_path, &mut files, &mut total_files, &mut total_bytes)?;
-
-    let manifest = serde_json::json!({
-        "version": 1,
-        "job_id": job_id,
-        "files": files,
-        "total_files": total_files,
-        "total_bytes": total_bytes,
-    });
-
-    let manifest_path = version_path.join(".backup-manifest.json");
-    std::fs::write(&manifest_path, serde_json::to_string(&manifest)?)?;
+    let manifest_path = version_path.join(manifest::FILE_NAME);
+    let tmp = version_path.join(format!("{}.tmp", manifest::FILE_NAME));
+    let header = manifest::ManifestHeader { job_id: job_id.to_string(), ..Default::default() };
+    let mut writer = manifest::ManifestWriter::new(std::io::BufWriter::new(std::fs::File::create(&tmp)?), &header)?;
+    walk_recursive(version_path, version_path, &mut writer)?;
+    let (total_files, total_bytes) = (writer.total_files(), writer.total_bytes());
+    writer.finish()?.flush()?;
+    std::fs::rename(&tmp, &manifest_path)?;
 
     tracing::info!(
         job_id = %job_id,
@@ -556,7 +674,7 @@ fn generate_manifest(version_path: &Path, job_id: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Backfill `.backup-manifest.json` for completed versions that don't have one.
+/// Backfill a manifest for completed versions that don't have one.
 /// This is a startup migration for versions completed before manifest generation was added.
 pub fn backfill_manifests(db: &DbPool) {
     let conn = match db.get() {
@@ -586,9 +704,7 @@ pub fn backfill_manifests(db: &DbPool) {
             if v.status != "completed" {
                 continue;
             }
-            let manifest_path = std::path::PathBuf::from(&v.local_path)
-                .join(".backup-manifest.json");
-            if manifest_path.exists() {
+            if has_manifest(Path::new(&v.local_path)) {
                 continue;
             }
             // Version directory must exist
@@ -0,0 +1,198 @@
+use crate::services::agent_deployer;
+use crate::state::AppState;
+use backup_protocol::ServerMessage;
+pub use backup_protocol::ReleaseManifest;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const SIGNING_KEY_FILE: &str = "release-signing.pk8";
+
+/// Ed25519 key the server signs release manifests with.
+pub struct ReleaseSigner {
+    key: Ed25519KeyPair,
+    /// Hex-encoded public key, written into agent configs.
+    pub public_key: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ReleaseSigner {
+    /// Load the signing key from `keys_dir`, generating it on first start.
+    pub fn load_or_generate(keys_dir: &Path) -> anyhow::Result<Self> {
+        let path = keys_dir.join(SIGNING_KEY_FILE);
+        let pkcs8 = if path.exists() {
+            std::fs::read(&path)?
+        } else {
+            let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("Failed to generate release signing key"))?;
+            std::fs::write(&path, doc.as_ref())?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            tracing::info!(path = %path.display(), "Generated release signing key");
+            doc.as_ref().to_vec()
+        };
+
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| anyhow::anyhow!("Invalid release signing key {}: {}", path.display(), e))?;
+        let public_key = to_hex(key.public_key().as_ref());
+        Ok(Self { key, public_key })
+    }
+
+    /// Agents check the signature against the public key in their config,
+    /// then the download against `sha256` and `size`.
+    pub fn sign(&self, version: &str, sha256: &str, size: u64) -> ReleaseManifest {
+        let mut manifest = ReleaseManifest {
+            version: version.to_string(),
+            sha256: sha256.to_string(),
+            size,
+            signature: String::new(),
+        };
+        manifest.signature = to_hex(self.key.sign(manifest.signed_message().as_bytes()).as_ref());
+        manifest
+    }
+
+    /// Hash and sign the agent binary at `path`.
+    pub fn sign_binary(&self, path: &Path) -> anyhow::Result<ReleaseManifest> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut size = 0u64;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+
+        let version = release_version(path)?;
+        Ok(self.sign(&version, &to_hex(&hasher.finalize()), size))
+    }
+}
+
+/// Sign the agent binary currently on disk, or `None` if it has not been built.
+pub async fn current_manifest(state: &AppState) -> anyhow::Result<Option<ReleaseManifest>> {
+    let binary_path = agent_deployer::get_agent_binary_path();
+    if !binary_path.exists() {
+        return Ok(None);
+    }
+
+    let release = state.release.clone();
+    let manifest = tokio::task::spawn_blocking(move || release.sign_binary(&binary_path)).await??;
+    Ok(Some(manifest))
+}
+
+/// Send `agent:update` for `manifest` and mark the agent as updating. Returns
+/// whether the command reached the agent.
+pub async fn send_update(state: &AppState, server_id: &str, manifest: &ReleaseManifest) -> bool {
+    tracing::info!(server_id, version = %manifest.version, sha256 = %manifest.sha256, "Sending agent update");
+
+    let sent = state.agents.send_to_agent(
+        server_id,
+        &ServerMessage::UpdateAgent {
+            download_path: "/api/agent/binary".into(),
+            version: manifest.version.clone(),
+            manifest: Some(manifest.clone()),
+        },
+    );
+    if !sent {
+        return false;
+    }
+
+    let db = state.db.clone();
+    let sid = server_id.to_string();
+    let _ = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        crate::models::server::update_fields(&conn, &sid, &[
+            ("agent_status", &"updating" as &dyn rusqlite::types::ToSql),
+        ])
+    })
+    .await;
+    true
+}
+
+/// File next to the agent binary holding its version, e.g.
+/// `target/release/backup-agent.version`. The binary is never run on the
+/// server to ask it.
+pub fn version_path(binary_path: &Path) -> PathBuf {
+    binary_path.with_extension("version")
+}
+
+/// Version of the agent binary at `path`, from its [`version_path`] file.
+fn release_version(path: &Path) -> anyhow::Result<String> {
+    let version_path = version_path(path);
+    let version = std::fs::read_to_string(&version_path).map_err(|e| {
+        anyhow::anyhow!(
+            "Agent release has no version: write it to {} ({})",
+            version_path.display(),
+            e
+        )
+    })?;
+    let version = version.trim();
+    let valid = !version.is_empty()
+        && version.len() <= 64
+        && version.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'));
+    if !valid {
+        anyhow::bail!("Invalid agent release version {:?} in {}", version, version_path.display());
+    }
+    Ok(version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn manifest_signature_verifies_with_public_key() {
+        let dir = std::env::temp_dir().join(format!("release-key-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let signer = ReleaseSigner::load_or_generate(&dir).unwrap();
+        let reloaded = ReleaseSigner::load_or_generate(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(signer.public_key, reloaded.public_key);
+
+        let manifest = signer.sign("1.2.3", "abcd", 42);
+        let public_key: Vec<u8> = (0..signer.public_key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signer.public_key[i..i + 2], 16).unwrap())
+            .collect();
+        let signature: Vec<u8> = (0..manifest.signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&manifest.signature[i..i + 2], 16).unwrap())
+            .collect();
+
+        let key = UnparsedPublicKey::new(&ED25519, public_key);
+        assert!(key.verify(manifest.signed_message().as_bytes(), &signature).is_ok());
+        let tampered = ReleaseManifest { version: "1.2.4".into(), ..manifest.clone() };
+        assert!(key.verify(tampered.signed_message().as_bytes(), &signature).is_err());
+    }
+
+    #[test]
+    fn release_version_comes_from_metadata_file() {
+        let dir = std::env::temp_dir().join(format!("release-version-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("backup-agent");
+        std::fs::write(&binary, b"binary").unwrap();
+
+        let missing = release_version(&binary);
+        std::fs::write(version_path(&binary), "1.2.3\n").unwrap();
+        let written = release_version(&binary);
+        std::fs::write(version_path(&binary), "1.2.3; rm -rf /").unwrap();
+        let invalid = release_version(&binary);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(missing.is_err());
+        assert_eq!(written.unwrap(), "1.2.3");
+        assert!(invalid.is_err());
+    }
+}
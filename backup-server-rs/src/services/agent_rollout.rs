@@ -0,0 +1,471 @@
+use crate::models::agent_rollout::{self, CreateRolloutData, Rollout, RolloutTarget};
+use crate::models::backup_job;
+use crate::services::agent_orchestrator::{self, RunTrigger};
+use crate::services::agent_release::{self, ReleaseManifest};
+use crate::services::job_queue;
+use crate::state::AppState;
+use dashmap::DashMap;
+use rusqlite::types::Value;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How long an updated agent has to come back with the new version. Longer
+/// than the agent's own confirm timeout, so a rolled-back agent shows up with
+/// its old version first.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(180);
+const RECONNECT_POLL: Duration = Duration::from_secs(2);
+
+const INTERRUPTED_ERROR: &str = "Interrupted: the server restarted during the rollout";
+const NO_TEST_JOB_ERROR: &str = "No idle backup job for a test backup";
+
+/// Rollouts currently being driven by this process.
+pub struct RolloutManager {
+    active: DashMap<String, CancellationToken>,
+}
+
+impl RolloutManager {
+    pub fn new() -> Self {
+        Self {
+            active: DashMap::new(),
+        }
+    }
+}
+
+/// A rollout with its targets, as served by the status API and broadcast to the UI.
+#[derive(Debug, Serialize)]
+pub struct RolloutStatus {
+    #[serde(flatten)]
+    pub rollout: Rollout,
+    pub targets: Vec<RolloutTarget>,
+}
+
+pub fn load_status(conn: &rusqlite::Connection, id: &str) -> anyhow::Result<Option<RolloutStatus>> {
+    let Some(rollout) = agent_rollout::find_by_id(conn, id)? else {
+        return Ok(None);
+    };
+    let targets = agent_rollout::find_targets(conn, id)?;
+    Ok(Some(RolloutStatus { rollout, targets }))
+}
+
+/// Split `servers` into waves: first a canary wave of `canary_percent` of them
+/// (rounded up, at least one) when given, then waves of `wave_size`.
+pub fn plan_waves<T: Clone>(servers: &[T], canary_percent: Option<i64>, wave_size: usize) -> Vec<Vec<T>> {
+    let mut waves = Vec::new();
+    let mut rest = servers;
+    if let Some(percent) = canary_percent {
+        let canary = (servers.len() * percent as usize).div_ceil(100).clamp(1, servers.len().max(1));
+        let (head, tail) = rest.split_at(canary.min(rest.len()));
+        if !head.is_empty() {
+            waves.push(head.to_vec());
+        }
+        rest = tail;
+    }
+    waves.extend(rest.chunks(wave_size.max(1)).map(<[T]>::to_vec));
+    waves
+}
+
+pub struct NewRollout {
+    pub manifest: ReleaseManifest,
+    pub selector: serde_json::Value,
+    /// Selected servers with the agent version they currently run, in rollout order.
+    pub servers: Vec<(String, Option<String>)>,
+    pub canary_percent: Option<i64>,
+    pub wave_size: usize,
+    pub max_failures: i64,
+    pub test_backup: bool,
+}
+
+/// Record a rollout and start driving it. Servers already on the target
+/// version are skipped; the rest are numbered into waves from 1.
+pub async fn start(state: Arc<AppState>, new: NewRollout) -> anyhow::Result<RolloutStatus> {
+    let (current, outdated): (Vec<_>, Vec<_>) = new
+        .servers
+        .into_iter()
+        .partition(|(_, version)| version.as_deref() == Some(new.manifest.version.as_str()));
+
+    let mut targets: Vec<(String, i64, &'static str)> =
+        current.into_iter().map(|(id, _)| (id, 0, "skipped")).collect();
+    let ids: Vec<String> = outdated.into_iter().map(|(id, _)| id).collect();
+    for (i, wave) in plan_waves(&ids, new.canary_percent, new.wave_size).into_iter().enumerate() {
+        targets.extend(wave.into_iter().map(|id| (id, i as i64 + 1, "pending")));
+    }
+
+    let data = CreateRolloutData {
+        target_version: new.manifest.version.clone(),
+        target_sha256: new.manifest.sha256.clone(),
+        selector: new.selector.to_string(),
+        canary_percent: new.canary_percent,
+        wave_size: new.wave_size as i64,
+        max_failures: new.max_failures,
+        test_backup: new.test_backup,
+        targets,
+    };
+    let db = state.db.clone();
+    let status = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let rollout = agent_rollout::create(&conn, &data)?;
+        load_status(&conn, &rollout.id)?.ok_or_else(|| anyhow::anyhow!("Rollout disappeared"))
+    })
+    .await??;
+
+    tracing::info!(
+        rollout_id = %status.rollout.id,
+        version = %status.rollout.target_version,
+        targets = status.targets.len(),
+        "Agent rollout started"
+    );
+    broadcast(&state, &status);
+
+    let cancel = CancellationToken::new();
+    state.rollouts.active.insert(status.rollout.id.clone(), cancel.clone());
+    tokio::spawn(run(state.clone(), status.rollout.id.clone(), new.manifest, cancel));
+    Ok(status)
+}
+
+/// Stop a running rollout before its next wave. Targets already being updated
+/// finish their health check. Returns whether the rollout was running.
+pub async fn halt(state: &AppState, id: &str, reason: &str) -> anyhow::Result<bool> {
+    let db = state.db.clone();
+    let (rid, why) = (id.to_string(), reason.to_string());
+    let halted = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        agent_rollout::halt(&conn, &rid, &why)
+    })
+    .await??;
+
+    if let Some(cancel) = state.rollouts.active.get(id) {
+        cancel.cancel();
+    }
+    if halted {
+        tracing::warn!(rollout_id = id, reason, "Agent rollout halted");
+        publish(state, id).await;
+    }
+    Ok(halted)
+}
+
+/// Halt rollouts left `running` by a previous process. Their runner is gone and
+/// an agent may be halfway through an update, so an operator has to look.
+pub async fn recover_rollouts(state: &AppState) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let halted = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let mut halted = Vec::new();
+        for rollout in agent_rollout::find_by_status(&conn, "running")? {
+            for target in agent_rollout::find_targets(&conn, &rollout.id)? {
+                if target.status == "updating" || target.status == "verifying" {
+                    agent_rollout::update_target(&conn, &target.id, &[
+                        ("status", &"failed" as &dyn rusqlite::types::ToSql),
+                        ("error", &INTERRUPTED_ERROR as &dyn rusqlite::types::ToSql),
+                        ("finished_at", &chrono::Utc::now().to_rfc3339() as &dyn rusqlite::types::ToSql),
+                    ])?;
+                }
+            }
+            agent_rollout::halt(&conn, &rollout.id, INTERRUPTED_ERROR)?;
+            halted.push(rollout.id);
+        }
+        Ok::<_, anyhow::Error>(halted)
+    })
+    .await??;
+
+    for id in halted {
+        tracing::warn!(rollout_id = %id, "Halted rollout interrupted by a restart");
+    }
+    Ok(())
+}
+
+fn broadcast(state: &AppState, status: &RolloutStatus) {
+    state.ui.broadcast("rollout:updated", serde_json::json!({ "rollout": status }));
+}
+
+/// Reload the rollout and tell the UI.
+async fn publish(state: &AppState, id: &str) {
+    let db = state.db.clone();
+    let rid = id.to_string();
+    let status = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        load_status(&conn, &rid)
+    })
+    .await;
+    if let Ok(Ok(Some(status))) = status {
+        broadcast(state, &status);
+    }
+}
+
+async fn run(state: Arc<AppState>, id: String, manifest: ReleaseManifest, cancel: CancellationToken) {
+    if let Err(e) = drive(&state, &id, &manifest, &cancel).await {
+        tracing::error!(rollout_id = %id, error = %e, "Agent rollout failed");
+        let _ = halt(&state, &id, &format!("Rollout error: {}", e)).await;
+    }
+    state.rollouts.active.remove(&id);
+}
+
+async fn drive(
+    state: &Arc<AppState>,
+    id: &str,
+    manifest: &ReleaseManifest,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let rid = id.to_string();
+    let status = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        load_status(&conn, &rid)
+    })
+    .await??
+    .ok_or_else(|| anyhow::anyhow!("Rollout not found"))?;
+    let rollout = status.rollout;
+    let last_wave = status.targets.iter().map(|t| t.wave).max().unwrap_or(0);
+
+    for wave in 1..=last_wave {
+        if cancel.is_cancelled() {
+            return Ok(());
+        }
+
+        // Every wave must get the binary the campaign was started with
+        match agent_release::current_manifest(state).await {
+            Ok(Some(current)) if current.sha256 == manifest.sha256 => {}
+            _ => {
+                halt(state, id, "The release binary changed or disappeared during the rollout").await?;
+                return Ok(());
+            }
+        }
+
+        let db = state.db.clone();
+        let rid = id.to_string();
+        let targets = tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            agent_rollout::update_fields(&conn, &rid, &[("current_wave", &wave as &dyn rusqlite::types::ToSql)])?;
+            agent_rollout::find_targets(&conn, &rid)
+        })
+        .await??;
+        tracing::info!(rollout_id = id, wave, last_wave, "Starting rollout wave");
+        publish(state, id).await;
+
+        let updates = targets
+            .into_iter()
+            .filter(|t| t.wave == wave && t.status == "pending")
+            .map(|t| update_target(state, &rollout, t, manifest));
+        futures_util::future::join_all(updates).await;
+
+        // Health gate: too many failures stop the campaign before the next wave.
+        // A target whose test backup could not run counts as one.
+        let db = state.db.clone();
+        let rid = id.to_string();
+        let failed = tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            let targets = agent_rollout::find_targets(&conn, &rid)?;
+            Ok::<_, anyhow::Error>(counts_against_gate(&targets))
+        })
+        .await??;
+        publish(state, id).await;
+
+        if failed > rollout.max_failures {
+            let reason = format!(
+                "{} target(s) failed or went unverified in wave {} (at most {} allowed)",
+                failed, wave, rollout.max_failures
+            );
+            halt(state, id, &reason).await?;
+            return Ok(());
+        }
+    }
+
+    let db = state.db.clone();
+    let rid = id.to_string();
+    let completed = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let changes = conn.execute(
+            "UPDATE agent_rollouts SET status = 'completed', finished_at = ? WHERE id = ? AND status = 'running'",
+            rusqlite::params![chrono::Utc::now().to_rfc3339(), rid],
+        )?;
+        Ok::<_, anyhow::Error>(changes > 0)
+    })
+    .await??;
+    if completed {
+        tracing::info!(rollout_id = id, version = %rollout.target_version, "Agent rollout completed");
+        publish(state, id).await;
+    }
+    Ok(())
+}
+
+/// Update one agent and run its health check. The outcome is recorded on the target.
+async fn update_target(state: &Arc<AppState>, rollout: &Rollout, target: RolloutTarget, manifest: &ReleaseManifest) {
+    let from_version = state.agents.agent_version(&target.server_id);
+    set_target(state, &target.id, vec![
+        ("status", "updating".to_string().into()),
+        ("from_version", from_version.into()),
+        ("started_at", chrono::Utc::now().to_rfc3339().into()),
+    ])
+    .await;
+    publish(state, &rollout.id).await;
+
+    let result = check_target(state, rollout, &target, manifest).await;
+    let now = chrono::Utc::now().to_rfc3339();
+    match &result {
+        Ok(Checked::Succeeded(test_job_id)) => {
+            tracing::info!(rollout_id = %rollout.id, server_id = %target.server_id, "Rollout target updated");
+            set_target(state, &target.id, vec![
+                ("status", "succeeded".to_string().into()),
+                ("test_job_id", test_job_id.clone().into()),
+                ("finished_at", now.into()),
+            ])
+            .await;
+        }
+        Ok(Checked::Unverified) => {
+            tracing::warn!(rollout_id = %rollout.id, server_id = %target.server_id, "Rollout target updated but not verified");
+            set_target(state, &target.id, vec![
+                ("status", "unverified".to_string().into()),
+                ("error", NO_TEST_JOB_ERROR.to_string().into()),
+                ("finished_at", now.into()),
+            ])
+            .await;
+        }
+        Err(e) => {
+            tracing::warn!(rollout_id = %rollout.id, server_id = %target.server_id, error = %e, "Rollout target failed");
+            set_target(state, &target.id, vec![
+                ("status", "failed".to_string().into()),
+                ("error", e.to_string().into()),
+                ("finished_at", now.into()),
+            ])
+            .await;
+        }
+    }
+}
+
+/// How a target came through its health check.
+enum Checked {
+    /// Updated, and backed up the job given as a test, if the rollout asked
+    /// for a test backup.
+    Succeeded(Option<String>),
+    /// Updated, but the server had no idle job to run the test backup with.
+    Unverified,
+}
+
+/// Targets that count against the rollout's `max_failures`.
+fn counts_against_gate(targets: &[RolloutTarget]) -> i64 {
+    targets
+        .iter()
+        .filter(|t| t.status == "failed" || t.status == "unverified")
+        .count() as i64
+}
+
+/// Send the update, wait for the agent to register with the new version, then
+/// run a test backup.
+async fn check_target(
+    state: &Arc<AppState>,
+    rollout: &Rollout,
+    target: &RolloutTarget,
+    manifest: &ReleaseManifest,
+) -> anyhow::Result<Checked> {
+    if !state.agents.is_connected(&target.server_id) {
+        anyhow::bail!("Agent is not connected");
+    }
+    if !agent_release::send_update(state, &target.server_id, manifest).await {
+        anyhow::bail!("Failed to send update command");
+    }
+
+    let deadline = tokio::time::Instant::now() + RECONNECT_TIMEOUT;
+    while state.agents.agent_version(&target.server_id).as_deref() != Some(manifest.version.as_str()) {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!(
+                "Agent did not reconnect with version {} within {}s",
+                manifest.version,
+                RECONNECT_TIMEOUT.as_secs()
+            );
+        }
+        tokio::time::sleep(RECONNECT_POLL).await;
+    }
+
+    if rollout.test_backup == 0 {
+        return Ok(Checked::Succeeded(None));
+    }
+    set_target(state, &target.id, vec![("status", "verifying".to_string().into())]).await;
+    publish(state, &rollout.id).await;
+
+    // Any enabled job that is neither queued nor running will do
+    let db = state.db.clone();
+    let sid = target.server_id.clone();
+    let jobs = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_job::find_by_server_id(&conn, &sid)
+    })
+    .await??;
+    let running = state.running_jobs.lock().await.clone();
+    let Some(job) = jobs
+        .into_iter()
+        .find(|j| j.enabled != 0 && j.status != "queued" && j.status != "running" && !running.contains(&j.id))
+    else {
+        return Ok(Checked::Unverified);
+    };
+
+    // A manual run through the queue, followed here for its outcome
+    let permits = job_queue::wait_for_turn(state, &job.id, RunTrigger::Manual)
+        .await
+        .map_err(|e| anyhow::anyhow!("Test backup of job '{}' could not start: {}", job.name, e))?;
+    let result = agent_orchestrator::run_backup_job(state.clone(), job.id.clone(), RunTrigger::Manual).await;
+    drop(permits);
+    state.queue.wake();
+
+    result.map_err(|e| anyhow::anyhow!("Test backup of job '{}' failed: {}", job.name, e))?;
+    Ok(Checked::Succeeded(Some(job.id)))
+}
+
+async fn set_target(state: &AppState, id: &str, fields: Vec<(&'static str, Value)>) {
+    let db = state.db.clone();
+    let tid = id.to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let fields: Vec<(&str, &dyn rusqlite::types::ToSql)> = fields
+            .iter()
+            .map(|(k, v)| (*k, v as &dyn rusqlite::types::ToSql))
+            .collect();
+        agent_rollout::update_target(&conn, &tid, &fields)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|r| r);
+    if let Err(e) = result {
+        tracing::error!(target_id = id, error = %e, "Failed to update rollout target");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_waves_with_canary() {
+        let servers: Vec<u32> = (1..=10).collect();
+        let waves = plan_waves(&servers, Some(10), 4);
+        assert_eq!(waves, vec![vec![1], vec![2, 3, 4, 5], vec![6, 7, 8, 9], vec![10]]);
+
+        // The canary rounds up and never exceeds the fleet
+        assert_eq!(plan_waves(&servers, Some(25), 10), vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8, 9, 10]]);
+        assert_eq!(plan_waves(&[1, 2], Some(100), 1), vec![vec![1, 2]]);
+    }
+
+    #[test]
+    fn plan_waves_without_canary() {
+        assert_eq!(plan_waves(&[1, 2, 3], None, 2), vec![vec![1, 2], vec![3]]);
+        assert!(plan_waves::<u32>(&[], Some(50), 2).is_empty());
+    }
+
+    #[test]
+    fn unverified_targets_count_against_the_gate() {
+        let target = |status: &str| RolloutTarget {
+            id: status.into(),
+            rollout_id: "r1".into(),
+            server_id: "s1".into(),
+            wave: 1,
+            status: status.into(),
+            from_version: None,
+            test_job_id: None,
+            error: None,
+            started_at: None,
+            finished_at: None,
+        };
+        let targets: Vec<_> = ["succeeded", "failed", "unverified", "skipped", "pending"].map(target).into();
+        assert_eq!(counts_against_gate(&targets), 2);
+    }
+}
@@ -71,6 +71,7 @@ fn migrate_job(conn: &rusqlite::Connection, job: &backup_job::BackupJob) -> anyh
             log_id: String::new(),
             version_timestamp: timestamp.clone(),
             local_path: version_path.to_string_lossy().to_string(),
+            roots: None,
         },
     )?;
 
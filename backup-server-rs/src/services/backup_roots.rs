@@ -0,0 +1,352 @@
+//! Source roots of a job and where each one is stored in a version.
+//!
+//! Files are named relative to the root they were found under, so with roots
+//! `/etc/app` and `/opt/app` both `config.yml` files would land on the same
+//! path. Each root is therefore stored in its own top-level directory of the
+//! version, named by a label: the one set for it in the job's `root_labels`,
+//! or one derived from its path (`/etc/app` → `etc_app`). Derived labels
+//! depend on the order of the paths, so they are stored in `root_labels`
+//! along with the set ones ([`pin_labels`]) and keep naming the same
+//! directory when paths are reordered, added or removed.
+//!
+//! Versions written before roots were labelled hold every root's files
+//! directly in the version directory. [`migrate_root_layout`] moves those of
+//! single-root jobs under the root's label; the roots of a multi-root version
+//! cannot be told apart after the fact, so those keep their layout.
+
+use crate::db::connection::DbPool;
+use crate::models::{backup_job, backup_version};
+use backup_protocol::manifest;
+use backup_protocol::BackupRoot;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = manifest::JSON_FILE_NAME;
+const MANIFEST_TMP: &str = ".backup-manifest.json.tmp";
+const BINARY_MANIFEST_TMP: &str = ".backup-manifest.bin.tmp";
+const MAX_LABEL_LEN: usize = 100;
+
+/// The job's roots with the label each is stored under.
+pub fn job_roots(job: &backup_job::BackupJob) -> Vec<BackupRoot> {
+    let paths: Vec<String> = serde_json::from_str(&job.remote_paths).unwrap_or_default();
+    let labels: HashMap<String, String> = serde_json::from_str(&job.root_labels).unwrap_or_default();
+    resolve(&paths, &labels)
+}
+
+/// Label every path: its own label if set, otherwise a derived one made
+/// unique with a `-2`, `-3`... suffix.
+fn resolve(paths: &[String], labels: &HashMap<String, String>) -> Vec<BackupRoot> {
+    let mut taken: HashSet<String> = paths.iter().filter_map(|p| labels.get(p).cloned()).collect();
+    paths
+        .iter()
+        .map(|path| {
+            let label = match labels.get(path) {
+                Some(label) => label.clone(),
+                None => {
+                    let base = default_label(path);
+                    let mut label = base.clone();
+                    let mut n = 2;
+                    while !taken.insert(label.clone()) {
+                        label = format!("{}-{}", base, n);
+                        n += 1;
+                    }
+                    label
+                }
+            };
+            BackupRoot { path: path.clone(), label }
+        })
+        .collect()
+}
+
+/// Every path's label, derived ones included, for the job's `root_labels`.
+pub fn pin_labels(paths: &[String], labels: &HashMap<String, String>) -> HashMap<String, String> {
+    resolve(paths, labels).into_iter().map(|root| (root.path, root.label)).collect()
+}
+
+/// Label for a root without one: its path components joined with `_`
+/// (`/etc/app` → `etc_app`, `/` → `root`).
+pub fn default_label(path: &str) -> String {
+    let label = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.chars().map(|ch| if is_label_char(ch) { ch } else { '_' }).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("_");
+    let label: String = label.trim_start_matches('.').chars().take(MAX_LABEL_LEN).collect();
+    if label.is_empty() { "root".into() } else { label }
+}
+
+fn is_label_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.')
+}
+
+/// Check the labels given in a job request against its paths.
+pub fn check_labels(paths: &[String], labels: &HashMap<String, String>) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for (path, label) in labels {
+        if !paths.contains(path) {
+            return Err(format!("root_labels: {} is not one of remote_paths", path));
+        }
+        if label.is_empty()
+            || label.len() > MAX_LABEL_LEN
+            || label.starts_with('.')
+            || !label.chars().all(is_label_char)
+        {
+            return Err(format!(
+                "Root label {:?} must be 1-{} letters, digits, '-', '_' or '.' and not start with '.'",
+                label, MAX_LABEL_LEN
+            ));
+        }
+        if !seen.insert(label) {
+            return Err(format!("Root label {:?} is used for more than one path", label));
+        }
+    }
+    Ok(())
+}
+
+/// Store the labels of jobs created before they were pinned, then move
+/// versions of single-root jobs stored before roots were labelled under the
+/// root's label, and record their root.
+pub fn migrate_root_layout(pool: &DbPool) -> anyhow::Result<()> {
+    let conn = pool.get()?;
+    let mut migrated = 0;
+
+    for job in backup_job::find_all(&conn)? {
+        let roots = job_roots(&job);
+        let labels: HashMap<String, String> = serde_json::from_str(&job.root_labels).unwrap_or_default();
+        if roots.iter().any(|root| !labels.contains_key(&root.path)) {
+            let pinned = roots.iter().map(|root| (root.path.clone(), root.label.clone())).collect();
+            backup_job::update_root_labels(&conn, &job.id, &pinned)?;
+        }
+
+        let versions: Vec<_> = backup_version::find_by_job_id(&conn, &job.id)?
+            .into_iter()
+            .filter(|v| v.roots.is_none() && v.status != "running")
+            .collect();
+        if versions.is_empty() {
+            continue;
+        }
+
+        let [root] = roots.as_slice() else {
+            tracing::info!(
+                job_id = %job.id,
+                versions = versions.len(),
+                "[ROOT_MIGRATION] Job has several roots; versions stored before roots were labelled keep their layout"
+            );
+            continue;
+        };
+
+        for version in versions {
+            match migrate_version(Path::new(&version.local_path), root) {
+                Ok(true) => {
+                    let roots = serde_json::to_string(std::slice::from_ref(root))?;
+                    backup_version::update_fields(&conn, &version.id, &[("roots", &roots)])?;
+                    migrated += 1;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!(
+                        job_id = %job.id,
+                        version = %version.version_timestamp,
+                        "[ROOT_MIGRATION] Failed to move version under its root label: {}", e
+                    );
+                }
+            }
+        }
+    }
+
+    if migrated > 0 {
+        tracing::info!("[ROOT_MIGRATION] Moved {} versions under their root label", migrated);
+    }
+    Ok(())
+}
+
+/// Move the contents of a flat version directory under `root.label` and
+/// prefix its manifest paths to match. Returns false for versions without a
+/// manifest, which are left alone.
+///
+/// Each step can be redone if the server stops half-way: entries are first
+/// moved into a staging directory, then the manifest is rewritten (recording
+/// `roots`, which marks the move as done), then the staging directory takes
+/// the label's name.
+fn migrate_version(dir: &Path, root: &BackupRoot) -> anyhow::Result<bool> {
+    let binary = dir.join(manifest::FILE_NAME).exists();
+    let manifest_path = dir.join(if binary { manifest::FILE_NAME } else { MANIFEST });
+    let Ok(file) = std::fs::File::open(&manifest_path) else {
+        return Ok(false);
+    };
+    let done = if binary {
+        !manifest::ManifestReader::new(file)?.header().roots.is_empty()
+    } else {
+        let manifest: serde_json::Value = serde_json::from_reader(BufReader::new(file))?;
+        manifest.get("roots").is_some()
+    };
+    // Labels never start with '.', so this cannot be a root's directory
+    let staging = dir.join(format!(".{}.migrating", root.label));
+
+    if !done {
+        if !staging.exists() {
+            std::fs::create_dir(&staging)?;
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if [MANIFEST, MANIFEST_TMP, manifest::FILE_NAME, BINARY_MANIFEST_TMP].iter().any(|m| name == *m)
+                || entry.path() == staging
+            {
+                continue;
+            }
+            std::fs::rename(entry.path(), staging.join(&name))?;
+        }
+
+        let tmp = if binary {
+            relabel_binary_manifest(dir, root)?
+        } else {
+            relabel_json_manifest(dir, root)?
+        };
+        std::fs::rename(&tmp, &manifest_path)?;
+    }
+
+    if staging.exists() {
+        std::fs::rename(&staging, dir.join(&root.label))?;
+    }
+    Ok(true)
+}
+
+/// Write the JSON manifest with its paths under `root.label` next to it;
+/// returns where.
+fn relabel_json_manifest(dir: &Path, root: &BackupRoot) -> anyhow::Result<PathBuf> {
+    let mut manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.join(MANIFEST))?)?;
+    if let Some(files) = manifest.get_mut("files").and_then(|f| f.as_object_mut()) {
+        *files = std::mem::take(files)
+            .into_iter()
+            .map(|(path, entry)| (format!("{}/{}", root.label, path), entry))
+            .collect();
+    }
+    manifest["roots"] = serde_json::json!({ &root.label: &root.path });
+    let tmp = dir.join(MANIFEST_TMP);
+    std::fs::write(&tmp, serde_json::to_string(&manifest)?)?;
+    Ok(tmp)
+}
+
+/// Like [`relabel_json_manifest`] for a binary manifest, which stays sorted
+/// as every path gets the same prefix.
+fn relabel_binary_manifest(dir: &Path, root: &BackupRoot) -> anyhow::Result<PathBuf> {
+    let reader = manifest::ManifestReader::new(std::fs::File::open(dir.join(manifest::FILE_NAME))?)?;
+    let header = manifest::ManifestHeader {
+        job_id: reader.header().job_id.clone(),
+        roots: [(root.label.clone(), root.path.clone())].into(),
+    };
+    let tmp = dir.join(BINARY_MANIFEST_TMP);
+    let mut writer = manifest::ManifestWriter::new(BufWriter::new(std::fs::File::create(&tmp)?), &header)?;
+    for entry in reader {
+        let (path, entry) = entry?;
+        writer.add(&format!("{}/{}", root.label, path), entry)?;
+    }
+    writer.finish()?.flush()?;
+    Ok(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(p, l)| (p.to_string(), l.to_string())).collect()
+    }
+
+    #[test]
+    fn test_roots_get_distinct_labels() {
+        let paths: Vec<String> = ["/etc/app", "/opt/app", "/", "/srv/my data", "/etc/app/"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let roots = resolve(&paths, &labels(&[("/opt/app", "etc_app")]));
+        let labels: Vec<_> = roots.iter().map(|r| r.label.as_str()).collect();
+        assert_eq!(labels, vec!["etc_app-2", "etc_app", "root", "srv_my_data", "etc_app-3"]);
+
+        assert_eq!(default_label("/home/.hidden"), "home_.hidden");
+        assert_eq!(default_label("/.config"), "config");
+    }
+
+    #[test]
+    fn test_check_labels() {
+        let paths = vec!["/etc/app".to_string(), "/opt/app".to_string()];
+        assert!(check_labels(&paths, &labels(&[("/etc/app", "etc"), ("/opt/app", "opt")])).is_ok());
+        assert!(check_labels(&paths, &labels(&[("/var", "var")])).is_err());
+        assert!(check_labels(&paths, &labels(&[("/etc/app", "a"), ("/opt/app", "a")])).is_err());
+        for bad in ["", "..", ".hidden", "a/b", "caf\u{e9}", "a b"] {
+            assert!(check_labels(&paths, &labels(&[("/etc/app", bad)])).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_migrate_version_moves_files_under_label() {
+        let dir = std::env::temp_dir().join(format!("root-migration-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("config.yml"), "a").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        // A source directory that happens to have the label's name
+        std::fs::create_dir(dir.join("etc_app")).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"{"version":1,"job_id":"j1","files":{"config.yml":{"size":1,"mtime":0},"sub/b.txt":{"size":1,"mtime":0}},"total_files":2,"total_bytes":2}"#,
+        )
+        .unwrap();
+        let root = BackupRoot { path: "/etc/app".into(), label: "etc_app".into() };
+
+        // Interrupted after moving one entry
+        let staging = dir.join(".etc_app.migrating");
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::rename(dir.join("sub"), staging.join("sub")).unwrap();
+
+        assert!(migrate_version(&dir, &root).unwrap());
+        assert!(migrate_version(&dir, &root).unwrap());
+
+        let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, vec![MANIFEST, "etc_app"]);
+        assert_eq!(std::fs::read_to_string(dir.join("etc_app/config.yml")).unwrap(), "a");
+        assert_eq!(std::fs::read_to_string(dir.join("etc_app/sub/b.txt")).unwrap(), "b");
+        assert!(dir.join("etc_app/etc_app").is_dir());
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(MANIFEST)).unwrap()).unwrap();
+        let mut keys: Vec<_> = manifest["files"].as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["etc_app/config.yml", "etc_app/sub/b.txt"]);
+        assert_eq!(manifest["roots"]["etc_app"], "/etc/app");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_version_relabels_binary_manifest() {
+        let dir = std::env::temp_dir().join(format!("root-migration-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        let entry = manifest::ManifestEntry { size: 1, mtime: 0, id: None };
+        let mut writer = manifest::ManifestWriter::new(Vec::new(), &manifest::ManifestHeader {
+            job_id: "j1".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        writer.add("a", entry).unwrap();
+        writer.add("sub/b.txt", entry).unwrap();
+        std::fs::write(dir.join(manifest::FILE_NAME), writer.finish().unwrap()).unwrap();
+        let root = BackupRoot { path: "/etc/app".into(), label: "etc_app".into() };
+
+        assert!(migrate_version(&dir, &root).unwrap());
+        assert!(migrate_version(&dir, &root).unwrap());
+
+        assert_eq!(std::fs::read_to_string(dir.join("etc_app/sub/b.txt")).unwrap(), "b");
+        let reader = manifest::ManifestReader::new(std::fs::File::open(dir.join(manifest::FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(reader.header().job_id, "j1");
+        assert_eq!(reader.header().roots.get("etc_app").map(String::as_str), Some("/etc/app"));
+        let paths: Vec<_> = reader.map(|e| e.unwrap().0).collect();
+        assert_eq!(paths, vec!["etc_app/a", "etc_app/sub/b.txt"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,17 @@
-use crate::models::backup_job;
-use crate::services::agent_orchestrator;
+use crate::models::{backup_job, backup_retry};
+use crate::services::agent_orchestrator::{self, RunFailed, RunTrigger};
+use crate::services::job_queue;
 use crate::state::AppState;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio_cron_scheduler::{Job, JobScheduler};
 
+/// How often persisted retries are checked for being due.
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Upper bound for the exponential retry backoff.
+const MAX_RETRY_DELAY_SECS: i64 = 6 * 3600;
+
 pub struct BackupScheduler {
     scheduler: Mutex<JobScheduler>,
     state: Arc<AppState>,
@@ -43,17 +50,11 @@ impl BackupScheduler {
                     return;
                 }
 
-                {
-                    let running = state.running_jobs.lock().await;
-                    if running.contains(&jid) {
-                        tracing::warn!(job_id = %jid, "Skipping scheduled run: job already running");
-                        return;
-                    }
-                }
-
-                tracing::info!(job_id = %jid, name = %job_data.name, "Starting scheduled backup");
-                if let Err(e) = agent_orchestrator::run_backup_job(state, jid.clone()).await {
-                    tracing::error!(job_id = %jid, error = %e, "Scheduled backup failed");
+                tracing::info!(job_id = %jid, name = %job_data.name, "Queueing scheduled backup");
+                match job_queue::enqueue(&state, &jid, RunTrigger::Scheduled).await {
+                    Ok(Some(_)) => {}
+                    Ok(None) => tracing::warn!(job_id = %jid, "Skipping scheduled run: job already running"),
+                    Err(e) => tracing::error!(job_id = %jid, error = %e, "Failed to queue scheduled backup"),
                 }
             })
         })?;
@@ -85,6 +86,23 @@ impl BackupScheduler {
         }
 
         tracing::info!(count, "Cron schedules initialized");
+
+        self.schedule_retry_dispatch().await?;
+        Ok(())
+    }
+
+    /// Periodically queue retries that have come due. Retries live in the database,
+    /// so those pending when the server stopped are picked up after a restart.
+    async fn schedule_retry_dispatch(&self) -> anyhow::Result<()> {
+        let state = self.state.clone();
+        let job = Job::new_repeated_async(RETRY_POLL_INTERVAL, move |_uuid, _lock| {
+            let state = state.clone();
+            Box::pin(async move {
+                dispatch_due_retries(state).await;
+            })
+        })?;
+
+        self.scheduler.lock().await.add(job).await?;
         Ok(())
     }
 
@@ -98,3 +116,203 @@ impl BackupScheduler {
         Ok(())
     }
 }
+
+/// Run a backup taken off the queue and, for scheduled runs and their retries,
+/// enqueue a retry if it fails with a transient error.
+pub async fn run_and_retry(state: Arc<AppState>, job: backup_job::BackupJob, trigger: RunTrigger) {
+    let jid = job.id.clone();
+    match agent_orchestrator::run_backup_job(state.clone(), jid.clone(), trigger.clone()).await {
+        Ok(()) => {
+            // A successful run makes any retry still pending for the job redundant
+            let db = state.db.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                let conn = db.get()?;
+                backup_retry::delete_by_job_id(&conn, &jid)
+            })
+            .await;
+        }
+        Err(failed) => {
+            tracing::error!(job_id = %jid, attempt = trigger.attempt(), error = %failed, "Backup run failed");
+            if let Err(e) = enqueue_retry(&state, &job, &trigger, &failed).await {
+                tracing::error!(job_id = %jid, error = %e, "Failed to enqueue backup retry");
+            }
+        }
+    }
+}
+
+async fn enqueue_retry(
+    state: &AppState,
+    job: &backup_job::BackupJob,
+    trigger: &RunTrigger,
+    failed: &RunFailed,
+) -> anyhow::Result<()> {
+    let error = &failed.error;
+    if matches!(trigger, RunTrigger::Manual) || !agent_orchestrator::is_transient(error) {
+        return Ok(());
+    }
+
+    let attempt = trigger.attempt() + 1;
+    let retry_number = attempt - 1;
+    if retry_number > job.retry_max_attempts {
+        tracing::warn!(job_id = %job.id, attempts = trigger.attempt(), "Retry limit reached, waiting for next scheduled run");
+        return Ok(());
+    }
+
+    let delay_secs = retry_delay_secs(job.retry_backoff_secs, retry_number);
+    let run_at = (chrono::Utc::now() + chrono::Duration::seconds(delay_secs)).to_rfc3339();
+
+    let db = state.db.clone();
+    let jid = job.id.clone();
+    // Retries link to the original run; a first retry to the run that failed
+    let retry_of = match trigger {
+        RunTrigger::Retry { retry_of, .. } => retry_of.clone(),
+        _ => failed.log_id.clone().ok_or_else(|| anyhow::anyhow!("The failed run has no log"))?,
+    };
+    let run_at2 = run_at.clone();
+    let last_error = error.to_string();
+    tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_retry::create(&conn, &backup_retry::CreateRetryData {
+            job_id: jid,
+            retry_of,
+            attempt,
+            run_at: run_at2,
+            last_error: Some(last_error),
+        })
+    })
+    .await??;
+
+    state.ui.broadcast("backup:retry", serde_json::json!({
+        "jobId": job.id,
+        "attempt": attempt,
+        "runAt": run_at,
+        "error": error.to_string(),
+    }));
+
+    tracing::info!(job_id = %job.id, attempt, delay_secs, "Scheduled retry of failed backup");
+    Ok(())
+}
+
+async fn dispatch_due_retries(state: Arc<AppState>) {
+    let db = state.db.clone();
+    let due = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let now = chrono::Utc::now().to_rfc3339();
+        let mut due = Vec::new();
+        for retry in backup_retry::find_due(&conn, &now)? {
+            match backup_job::find_by_id(&conn, &retry.job_id)? {
+                Some(job) => due.push((retry, job)),
+                None => {
+                    backup_retry::delete(&conn, &retry.id)?;
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(due)
+    })
+    .await;
+
+    let due = match due.map_err(anyhow::Error::from).and_then(|r| r) {
+        Ok(due) => due,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to load due backup retries");
+            return;
+        }
+    };
+
+    for (retry, job) in due {
+        if job.enabled == 0 {
+            tracing::info!(job_id = %job.id, "Dropping retry: job is disabled");
+            drop_retry(&state, retry.id).await;
+            continue;
+        }
+
+        let trigger = RunTrigger::Retry {
+            attempt: retry.attempt,
+            retry_of: retry.retry_of,
+        };
+        // The retry is deleted in the transaction that queues its run, so a
+        // failure leaves it to be tried again on the next tick
+        let retry_id = retry.id.clone();
+        let claim = move |conn: &rusqlite::Connection| backup_retry::delete(conn, &retry_id).map(drop);
+        match job_queue::enqueue_with(&state, &job.id, trigger, claim).await {
+            Ok(Some(_)) => tracing::info!(job_id = %job.id, attempt = retry.attempt, "Queued backup retry"),
+            Ok(None) => {
+                tracing::info!(job_id = %job.id, "Dropping retry: job already running");
+                drop_retry(&state, retry.id).await;
+            }
+            Err(e) => tracing::error!(job_id = %job.id, error = %e, "Failed to queue backup retry"),
+        }
+    }
+}
+
+async fn drop_retry(state: &AppState, retry_id: String) {
+    let db = state.db.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_retry::delete(&conn, &retry_id)
+    })
+    .await;
+    if let Err(e) = result.map_err(anyhow::Error::from).and_then(|r| r) {
+        tracing::error!(error = %e, "Failed to drop backup retry");
+    }
+}
+
+/// Delay before retry number `retry_number` (1-based): `backoff_secs` doubled for
+/// each earlier retry, capped at `MAX_RETRY_DELAY_SECS`.
+fn retry_delay_secs(backoff_secs: i64, retry_number: i64) -> i64 {
+    let doublings = (retry_number - 1).clamp(0, 62) as u32;
+    backoff_secs
+        .max(0)
+        .saturating_mul(1i64 << doublings)
+        .min(MAX_RETRY_DELAY_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::backup_job::CreateBackupJobRequest;
+    use crate::models::{backup_queue, server};
+
+    #[test]
+    fn test_retry_delay_doubles_and_caps() {
+        assert_eq!(retry_delay_secs(300, 1), 300);
+        assert_eq!(retry_delay_secs(300, 2), 600);
+        assert_eq!(retry_delay_secs(300, 3), 1200);
+        assert_eq!(retry_delay_secs(300, 40), MAX_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay_secs(-5, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_due_retry_is_claimed_with_its_queue_entry() {
+        let state = crate::state::test_state();
+        let (job, retry) = {
+            let conn = state.db.get().unwrap();
+            let srv = server::create_for_test(&conn, "web");
+            let job = backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "etc", &["/etc"])).unwrap();
+            let retry = backup_retry::create(&conn, &backup_retry::CreateRetryData {
+                job_id: job.id.clone(),
+                retry_of: "log-1".into(),
+                attempt: 2,
+                run_at: chrono::Utc::now().to_rfc3339(),
+                last_error: None,
+            })
+            .unwrap();
+            (job, retry)
+        };
+
+        // A queue that cannot take the run keeps the retry for the next tick
+        state.db.get().unwrap().execute("ALTER TABLE backup_queue RENAME TO backup_queue_away", []).unwrap();
+        dispatch_due_retries(state.clone()).await;
+        let conn = state.db.get().unwrap();
+        assert_eq!(backup_retry::find_due(&conn, &chrono::Utc::now().to_rfc3339()).unwrap().len(), 1);
+        conn.execute("ALTER TABLE backup_queue_away RENAME TO backup_queue", []).unwrap();
+        drop(conn);
+
+        dispatch_due_retries(state.clone()).await;
+        let conn = state.db.get().unwrap();
+        assert!(backup_retry::find_due(&conn, &chrono::Utc::now().to_rfc3339()).unwrap().is_empty());
+        let entry = backup_queue::find_by_job_id(&conn, &job.id).unwrap().unwrap();
+        assert_eq!((entry.trigger_kind.as_str(), entry.attempt), ("retry", 2));
+        assert_eq!(entry.retry_of, Some(retry.retry_of));
+    }
+}
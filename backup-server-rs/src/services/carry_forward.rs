@@ -0,0 +1,244 @@
+//! Server-side carry-forward of unchanged files in incremental runs.
+//!
+//! Agents announcing [`backup_protocol::CAP_CARRY_FORWARD`] do not list the
+//! files that did not change: they report only changed and deleted paths to
+//! `POST /api/files/changes`. Once the last batch is in, a background task
+//! links every other file of the previous version's manifest into the new
+//! version, broadcasting `backup:link:progress`. The run completes once it is
+//! done.
+
+use crate::services::version_links::LinkTally;
+use crate::utils::safe_dir::{LinkMode, SafeDir};
+use crate::ws::ui::UiBroadcaster;
+use backup_protocol::manifest;
+use backup_protocol::path as path_encoding;
+use backup_protocol::ChangesRequest;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Link failures logged one by one per run; the rest are only counted
+const MAX_LOGGED_FAILURES: u64 = 20;
+
+/// Files linked from the previous version
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    pub linked: LinkTally,
+    pub failed: u64,
+}
+
+/// Runs whose unchanged files the server carries forward, by job id.
+#[derive(Default)]
+pub struct CarryForward {
+    runs: Mutex<HashMap<String, Run>>,
+}
+
+struct Run {
+    versions: Versions,
+    /// Changed and deleted paths: those not to link
+    skip: HashSet<String>,
+    linking: Option<Linking>,
+}
+
+/// Where files are carried from and to
+#[derive(Clone)]
+struct Versions {
+    current: PathBuf,
+    previous: PathBuf,
+    mode: LinkMode,
+}
+
+struct Linking {
+    task: JoinHandle<anyhow::Result<LinkStats>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl CarryForward {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect the changes of `job_id`'s run writing `current`, whose unchanged
+    /// files are then carried from `previous` with `mode`.
+    pub fn expect(&self, job_id: &str, current: PathBuf, previous: PathBuf, mode: LinkMode) {
+        self.lock().insert(
+            job_id.to_string(),
+            Run { versions: Versions { current, previous, mode }, skip: HashSet::new(), linking: None },
+        );
+    }
+
+    /// Record a batch of changes; the last one starts linking.
+    pub fn record(&self, ui: &UiBroadcaster, request: ChangesRequest) -> Result<(), String> {
+        let mut runs = self.lock();
+        let run = runs
+            .get_mut(&request.job_id)
+            .ok_or_else(|| format!("Job {} does not carry files forward", request.job_id))?;
+        if run.linking.is_some() {
+            return Err(format!("Changes of job {} are already complete", request.job_id));
+        }
+        run.skip.extend(request.changed);
+        run.skip.extend(request.deleted);
+        if !request.done {
+            return Ok(());
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let job_id = request.job_id;
+        let versions = run.versions.clone();
+        let skip = std::mem::take(&mut run.skip);
+        let (ui, task_stop) = (ui.clone(), Arc::clone(&stop));
+        tracing::info!(job_id = %job_id, changed_or_deleted = skip.len(), "Carrying unchanged files forward");
+        let task = tokio::task::spawn_blocking(move || {
+            link_unchanged(&job_id, &versions, &skip, &task_stop, &ui)
+        });
+        run.linking = Some(Linking { task, stop });
+        Ok(())
+    }
+
+    /// Wait until the unchanged files of `job_id`'s run are linked. None if
+    /// linking never started: the agent ran a full backup after all.
+    pub async fn finish(&self, job_id: &str) -> Option<anyhow::Result<LinkStats>> {
+        let run = self.lock().remove(job_id)?;
+        let linking = run.linking?;
+        Some(linking.task.await.unwrap_or_else(|e| Err(anyhow::anyhow!(e))))
+    }
+
+    /// Forget a run that failed, stopping its linking.
+    pub fn discard(&self, job_id: &str) {
+        if let Some(Run { linking: Some(linking), .. }) = self.lock().remove(job_id) {
+            linking.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Run>> {
+        self.runs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Carry every file of the previous version's manifest not in `skip` into
+/// the current version.
+fn link_unchanged(
+    job_id: &str,
+    versions: &Versions,
+    skip: &HashSet<String>,
+    stop: &AtomicBool,
+    ui: &UiBroadcaster,
+) -> anyhow::Result<LinkStats> {
+    // Counted here: the agent's own count predates its rename matching
+    let mut total = 0u64;
+    for entry in previous_files(&versions.previous)? {
+        if !skip.contains(&entry?.0) {
+            total += 1;
+        }
+    }
+
+    let current_dir = SafeDir::open(&versions.current)?;
+    let previous_dir = SafeDir::open(&versions.previous)?;
+    let mut stats = LinkStats::default();
+    let mut last_report = Instant::now();
+    let report = |stats: &LinkStats| {
+        ui.broadcast("backup:link:progress", serde_json::json!({
+            "jobId": job_id,
+            "linkedFiles": stats.linked.total(),
+            "failedFiles": stats.failed,
+            "totalFiles": total,
+        }));
+    };
+
+    for entry in previous_files(&versions.previous)? {
+        let (path, _) = entry?;
+        if stop.load(Ordering::Relaxed) {
+            anyhow::bail!("Stopped");
+        }
+        if skip.contains(&path) {
+            continue;
+        }
+        match current_dir.link_from(&previous_dir, path_encoding::decode_path(&path), versions.mode) {
+            Ok(used) => stats.linked.add(used),
+            Err(e) => {
+                stats.failed += 1;
+                if stats.failed <= MAX_LOGGED_FAILURES {
+                    tracing::warn!(job_id = %job_id, path = %path, error = %e, "Failed to carry file forward");
+                }
+            }
+        }
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            report(&stats);
+        }
+    }
+
+    report(&stats);
+    tracing::info!(job_id = %job_id, linked = stats.linked.total(), failed = stats.failed, "Carried unchanged files forward");
+    Ok(stats)
+}
+
+type ManifestEntries = Box<dyn Iterator<Item = std::io::Result<(String, manifest::ManifestEntry)>>>;
+
+/// The files of a version's manifest, binary or JSON.
+fn previous_files(version: &Path) -> anyhow::Result<ManifestEntries> {
+    match std::fs::File::open(version.join(manifest::FILE_NAME)) {
+        Ok(file) => Ok(Box::new(manifest::ManifestReader::new(file)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let file = std::fs::File::open(version.join(manifest::JSON_FILE_NAME))?;
+            let json: manifest::JsonManifest = serde_json::from_reader(std::io::BufReader::new(file))?;
+            Ok(Box::new(json.into_sorted().1.into_iter().map(Ok)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_links_files_not_reported_as_changed() {
+        let base = std::env::temp_dir().join(format!("carry-forward-{}", uuid::Uuid::new_v4()));
+        let (previous, current) = (base.join("v1"), base.join("v2"));
+        std::fs::create_dir_all(previous.join("etc/sub")).unwrap();
+        std::fs::create_dir_all(&current).unwrap();
+        let mut writer = manifest::ManifestWriter::new(Vec::new(), &Default::default()).unwrap();
+        for path in ["etc/a", "etc/changed", "etc/gone", "etc/missing", "etc/sub/b"] {
+            if path != "etc/missing" {
+                std::fs::write(previous.join(path), path).unwrap();
+            }
+            writer.add(path, manifest::ManifestEntry { size: 1, mtime: 1, id: None }).unwrap();
+        }
+        std::fs::write(previous.join(manifest::FILE_NAME), writer.finish().unwrap()).unwrap();
+
+        let carry = CarryForward::new();
+        let ui = UiBroadcaster::new();
+        assert!(carry.record(&ui, ChangesRequest { job_id: "j1".into(), ..Default::default() }).is_err());
+        carry.expect("j1", current.clone(), previous.clone(), LinkMode::Hardlink);
+        let batch = |changed: &[&str], deleted: &[&str], done| ChangesRequest {
+            job_id: "j1".into(),
+            changed: changed.iter().map(|p| p.to_string()).collect(),
+            deleted: deleted.iter().map(|p| p.to_string()).collect(),
+            done,
+            // Stale: the server counts what it carries itself
+            unchanged_files: done.then_some(99),
+        };
+        carry.record(&ui, batch(&["etc/changed"], &[], false)).unwrap();
+        carry.record(&ui, batch(&[], &["etc/gone"], true)).unwrap();
+        assert!(carry.record(&ui, batch(&[], &[], true)).is_err());
+
+        let stats = carry.finish("j1").await.unwrap().unwrap();
+        assert_eq!(stats, LinkStats { linked: LinkTally { reflinked: 0, hardlinked: 2 }, failed: 1 });
+        assert_eq!(std::fs::read_to_string(current.join("etc/sub/b")).unwrap(), "etc/sub/b");
+        assert!(current.join("etc/a").exists());
+        assert!(!current.join("etc/changed").exists());
+        assert!(!current.join("etc/gone").exists());
+        assert!(carry.finish("j1").await.is_none());
+
+        let progress = ui.get_queued_messages("j1", 0).into_iter().rfind(|m| m.event_type == "backup:link:progress").unwrap();
+        assert_eq!(progress.payload["totalFiles"], 3);
+        assert_eq!(progress.payload["linkedFiles"], 2);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,572 @@
+use crate::models::backup_queue::{self, QueueEntry};
+use crate::models::backup_job;
+use crate::services::agent_orchestrator::RunTrigger;
+use crate::services::backup_scheduler;
+use crate::state::AppState;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use tokio::sync::{oneshot, Notify, OwnedSemaphorePermit};
+use tokio_util::sync::CancellationToken;
+
+const PRIORITY_RECOVERY: i64 = 40;
+const PRIORITY_MANUAL: i64 = 30;
+const PRIORITY_SCHEDULED: i64 = 20;
+const PRIORITY_RETRY: i64 = 10;
+
+/// How often the dispatcher looks at the queue when nothing wakes it.
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The global and per-server capacity a run holds while it goes on.
+pub type RunPermits = [OwnedSemaphorePermit; 2];
+
+/// In-memory side of the persisted run queue: wakes the dispatcher,
+/// remembers when each server last had a run started, for fairness, and who
+/// waits to run a queued job itself.
+pub struct JobQueue {
+    wake: Notify,
+    last_started: Mutex<HashMap<String, Instant>>,
+    turns: Mutex<HashMap<String, oneshot::Sender<RunPermits>>>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        Self {
+            wake: Notify::new(),
+            last_started: Mutex::new(HashMap::new()),
+            turns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask the dispatcher to look at the queue again.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// `entries`, in queue order, in the order the dispatcher will start them.
+    pub fn service_order(&self, entries: Vec<QueueEntry>) -> Vec<QueueEntry> {
+        service_order(entries, self.last_started())
+    }
+
+    fn last_started(&self) -> HashMap<String, Instant> {
+        self.last_started.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn started(&self, server_id: &str) {
+        self.last_started.lock().unwrap_or_else(|e| e.into_inner()).insert(server_id.to_string(), Instant::now());
+    }
+
+    fn turns(&self) -> std::sync::MutexGuard<'_, HashMap<String, oneshot::Sender<RunPermits>>> {
+        self.turns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The entry to start next among those `eligible`.
+///
+/// The highest priority wins. Within a priority, servers take turns: the server
+/// that least recently had a run started goes first, so one server with a long
+/// backlog cannot starve the others. Ties keep queue order.
+fn next_entry(
+    entries: &[QueueEntry],
+    last_started: &HashMap<String, Instant>,
+    eligible: impl Fn(&QueueEntry) -> bool,
+) -> Option<usize> {
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| eligible(e))
+        .min_by_key(|(i, e)| (-e.priority, last_started.get(&e.server_id).copied(), *i))
+        .map(|(i, _)| i)
+}
+
+/// `entries` in the order [`next_entry`] takes them, each start counting for
+/// fairness as the dispatcher's do.
+fn service_order(mut entries: Vec<QueueEntry>, mut last_started: HashMap<String, Instant>) -> Vec<QueueEntry> {
+    let mut ordered = Vec::with_capacity(entries.len());
+    let mut now = Instant::now();
+    while let Some(index) = next_entry(&entries, &last_started, |_| true) {
+        let entry = entries.remove(index);
+        now += Duration::from_nanos(1);
+        last_started.insert(entry.server_id.clone(), now);
+        ordered.push(entry);
+    }
+    ordered
+}
+
+/// How a run is recorded in the queue.
+struct Placement {
+    kind: &'static str,
+    priority: i64,
+    attempt: i64,
+    retry_of: Option<String>,
+}
+
+/// A run that is already under way on its agent and only waits for capacity
+/// to be followed again.
+const RECOVERY: Placement = Placement {
+    kind: "recovery",
+    priority: PRIORITY_RECOVERY,
+    attempt: 1,
+    retry_of: None,
+};
+
+impl Placement {
+    fn of(trigger: &RunTrigger) -> Self {
+        let (kind, priority) = match trigger {
+            RunTrigger::Manual => ("manual", PRIORITY_MANUAL),
+            RunTrigger::Scheduled => ("scheduled", PRIORITY_SCHEDULED),
+            RunTrigger::Retry { .. } => ("retry", PRIORITY_RETRY),
+        };
+        let retry_of = match trigger {
+            RunTrigger::Retry { retry_of, .. } => Some(retry_of.clone()),
+            _ => None,
+        };
+        Self { kind, priority, attempt: trigger.attempt(), retry_of }
+    }
+}
+
+fn entry_trigger(entry: &QueueEntry) -> RunTrigger {
+    match (entry.trigger_kind.as_str(), &entry.retry_of) {
+        ("retry", Some(retry_of)) => RunTrigger::Retry {
+            attempt: entry.attempt,
+            retry_of: retry_of.clone(),
+        },
+        ("scheduled", _) => RunTrigger::Scheduled,
+        _ => RunTrigger::Manual,
+    }
+}
+
+/// Queue a run of `job_id`. Returns the entry and its 1-based position, or
+/// `None` if the job is running right now. A job that is already queued keeps
+/// its place; a higher-priority trigger takes the entry over, priority, attempt
+/// and all.
+pub async fn enqueue(
+    state: &AppState,
+    job_id: &str,
+    trigger: RunTrigger,
+) -> anyhow::Result<Option<(QueueEntry, usize)>> {
+    enqueue_with(state, job_id, trigger, |_| Ok(())).await
+}
+
+/// [`enqueue`], running `claim` in the transaction that queues the run, so
+/// whatever `claim` consumes is only gone once the run is queued.
+pub async fn enqueue_with(
+    state: &AppState,
+    job_id: &str,
+    trigger: RunTrigger,
+    claim: impl FnOnce(&Connection) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<Option<(QueueEntry, usize)>> {
+    if state.running_jobs.lock().await.contains(job_id) {
+        return Ok(None);
+    }
+    queue_run(state, job_id, Placement::of(&trigger), claim).await.map(Some)
+}
+
+/// Queue a run of `job_id` that the caller starts itself, and wait for its
+/// turn. Returns the capacity the run holds until it is dropped.
+pub async fn wait_for_turn(state: &AppState, job_id: &str, trigger: RunTrigger) -> anyhow::Result<RunPermits> {
+    if state.running_jobs.lock().await.contains(job_id) {
+        anyhow::bail!("Job is already running");
+    }
+    take_turn(state, job_id, Placement::of(&trigger)).await
+}
+
+/// Queue `job_id`, whose run survived a server restart on its agent, ahead of
+/// every other run, and wait for capacity to follow it again.
+pub async fn wait_to_reattach(state: &AppState, job_id: &str) -> anyhow::Result<RunPermits> {
+    take_turn(state, job_id, RECOVERY).await
+}
+
+async fn take_turn(state: &AppState, job_id: &str, placement: Placement) -> anyhow::Result<RunPermits> {
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut turns = state.queue.turns();
+        if turns.contains_key(job_id) {
+            anyhow::bail!("A run of the job is already waiting");
+        }
+        turns.insert(job_id.to_string(), tx);
+    }
+    if let Err(e) = queue_run(state, job_id, placement, |_| Ok(())).await {
+        state.queue.turns().remove(job_id);
+        return Err(e);
+    }
+    rx.await.map_err(|_| anyhow::anyhow!("The run was taken off the queue"))
+}
+
+async fn queue_run(
+    state: &AppState,
+    job_id: &str,
+    placement: Placement,
+    claim: impl FnOnce(&Connection) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<(QueueEntry, usize)> {
+    let kind = placement.kind;
+    let db = state.db.clone();
+    let jid = job_id.to_string();
+    let (entry, entries) = tokio::task::spawn_blocking(move || {
+        let Placement { kind, priority, attempt, retry_of } = placement;
+        let conn = db.get()?;
+        let tx = conn.unchecked_transaction()?;
+        let job = backup_job::find_by_id(&tx, &jid)?
+            .ok_or_else(|| anyhow::anyhow!("Job not found"))?;
+
+        let entry = match backup_queue::find_by_job_id(&tx, &jid)? {
+            Some(existing) if priority > existing.priority => {
+                backup_queue::update_trigger(&tx, &existing.id, kind, priority, attempt, retry_of.as_deref())?;
+                backup_queue::find_by_id(&tx, &existing.id)?
+                    .ok_or_else(|| anyhow::anyhow!("Queue entry disappeared"))?
+            }
+            Some(existing) => existing,
+            None => backup_queue::create(&tx, &backup_queue::CreateQueueEntryData {
+                job_id: jid.clone(),
+                server_id: job.server_id,
+                trigger_kind: kind.to_string(),
+                priority,
+                attempt,
+                retry_of,
+            })?,
+        };
+        // A recovered run is still running on its agent
+        if kind != RECOVERY.kind {
+            backup_job::update_status(&tx, &jid, "queued")?;
+        }
+        claim(&tx)?;
+        tx.commit()?;
+        Ok::<_, anyhow::Error>((entry, backup_queue::find_all(&conn)?))
+    })
+    .await??;
+
+    let entries = state.queue.service_order(entries);
+    let position = entries.iter().position(|e| e.id == entry.id).map_or(entries.len(), |i| i + 1);
+
+    state.ui.broadcast("backup:queued", serde_json::json!({
+        "jobId": job_id,
+        "trigger": kind,
+        "position": position,
+        "queueLength": entries.len(),
+    }));
+    broadcast_queue(state, &entries);
+    state.queue.wake();
+
+    tracing::info!(job_id, trigger = kind, position, "Backup run queued");
+    Ok((entry, position))
+}
+
+/// Take `job_id` out of the queue. Returns whether it was queued.
+pub async fn remove(state: &AppState, job_id: &str) -> anyhow::Result<bool> {
+    let db = state.db.clone();
+    let jid = job_id.to_string();
+    let (removed, entries) = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let removed = backup_queue::delete_by_job_id(&conn, &jid)?;
+        Ok::<_, anyhow::Error>((removed, backup_queue::find_all(&conn)?))
+    })
+    .await??;
+    // Whoever waited to run it learns the run is gone
+    state.queue.turns().remove(job_id);
+
+    if removed {
+        broadcast_queue(state, &entries);
+    }
+    Ok(removed)
+}
+
+/// Tell the UI where every queued run stands; `entries` are in queue order.
+pub fn broadcast_queue(state: &AppState, entries: &[QueueEntry]) {
+    let items: Vec<_> = state.queue.service_order(entries.to_vec())
+        .iter()
+        .enumerate()
+        .map(|(i, e)| serde_json::json!({
+            "id": e.id,
+            "jobId": e.job_id,
+            "serverId": e.server_id,
+            "trigger": e.trigger_kind,
+            "priority": e.priority,
+            "position": i + 1,
+        }))
+        .collect();
+    state.ui.broadcast("queue:updated", serde_json::json!({ "entries": items }));
+}
+
+/// Start queued runs as capacity frees up. The queue is persisted, so runs
+/// queued before a restart are picked up again.
+pub fn start_queue_dispatcher(state: Arc<AppState>, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let db = state.db.clone();
+        match tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            backup_queue::release_orphaned_jobs(&conn)
+        })
+        .await
+        {
+            Ok(Ok(n)) if n > 0 => tracing::warn!(jobs = n, "Reset queued jobs without a queue entry"),
+            Ok(Err(e)) => tracing::warn!(error = %e, "Failed to reset orphaned queued jobs"),
+            _ => {}
+        }
+
+        loop {
+            if let Err(e) = dispatch_ready(&state).await {
+                tracing::error!(error = %e, "Queue dispatch failed");
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = state.queue.wake.notified() => {}
+                _ = tokio::time::sleep(DISPATCH_INTERVAL) => {}
+            }
+        }
+        tracing::info!("Queue dispatcher stopped");
+    });
+}
+
+/// Start as many queued runs as the global and per-server limits allow, in
+/// the order of [`next_entry`].
+async fn dispatch_ready(state: &Arc<AppState>) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let mut entries = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        backup_queue::find_all(&conn)
+    })
+    .await??;
+
+    let mut started = false;
+    let mut full_servers: Vec<String> = Vec::new();
+
+    loop {
+        if state.global_semaphore.available_permits() == 0 {
+            break;
+        }
+
+        let running = state.running_jobs.lock().await.clone();
+        let waiting: Vec<String> = state.queue.turns().keys().cloned().collect();
+        let last_started = state.queue.last_started();
+        // A recovered run waits in the queue while its job is marked running
+        let next = next_entry(&entries, &last_started, |e| {
+            (!running.contains(&e.job_id) || waiting.contains(&e.job_id)) && !full_servers.contains(&e.server_id)
+        });
+        let Some(index) = next else { break };
+        let entry = entries[index].clone();
+
+        let server_sem = state.get_server_semaphore(&entry.server_id).await;
+        let Ok(server_permit) = server_sem.try_acquire_owned() else {
+            full_servers.push(entry.server_id.clone());
+            continue;
+        };
+        let Ok(global_permit) = state.global_semaphore.clone().try_acquire_owned() else {
+            break;
+        };
+
+        entries.remove(index);
+
+        // Claim the entry; it may have been cancelled since the queue was read
+        let db = state.db.clone();
+        let entry_id = entry.id.clone();
+        let job_id = entry.job_id.clone();
+        let job = tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            if !backup_queue::delete(&conn, &entry_id)? {
+                return Ok(None);
+            }
+            backup_job::find_by_id(&conn, &job_id)
+        })
+        .await??;
+        let Some(job) = job else { continue };
+        started = true;
+
+        let permits = [global_permit, server_permit];
+        let turn = state.queue.turns().remove(&job.id);
+        if let Some(turn) = turn {
+            if turn.send(permits).is_ok() {
+                state.queue.started(&entry.server_id);
+                tracing::info!(job_id = %job.id, trigger = %entry.trigger_kind, "Handed queued backup run to its caller");
+            }
+            continue;
+        }
+        if entry.trigger_kind == RECOVERY.kind {
+            // Left by a server process that is gone; recovery queues it again
+            tracing::info!(job_id = %job.id, "Dropped stale recovery from the queue");
+            continue;
+        }
+
+        state.queue.started(&entry.server_id);
+        tracing::info!(job_id = %job.id, trigger = %entry.trigger_kind, "Dispatching queued backup run");
+        spawn_run(state.clone(), job, entry_trigger(&entry), permits);
+    }
+
+    if started {
+        broadcast_queue(state, &entries);
+    }
+    Ok(())
+}
+
+fn spawn_run(
+    state: Arc<AppState>,
+    job: backup_job::BackupJob,
+    trigger: RunTrigger,
+    permits: RunPermits,
+) {
+    tokio::spawn(async move {
+        backup_scheduler::run_and_retry(state.clone(), job, trigger).await;
+        drop(permits);
+        state.queue.wake();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::backup_job::CreateBackupJobRequest;
+    use crate::models::server;
+
+    fn entry(id: &str, server_id: &str, priority: i64) -> QueueEntry {
+        QueueEntry {
+            id: id.into(),
+            job_id: format!("job-{}", id),
+            server_id: server_id.into(),
+            trigger_kind: "scheduled".into(),
+            priority,
+            sort_order: 0,
+            attempt: 1,
+            retry_of: None,
+            enqueued_at: String::new(),
+        }
+    }
+
+    fn ids(entries: &[QueueEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_priority_first_then_servers_take_turns() {
+        let queue = JobQueue::new();
+        let entries = vec![
+            entry("a1", "a", PRIORITY_SCHEDULED),
+            entry("a2", "a", PRIORITY_SCHEDULED),
+            entry("a3", "a", PRIORITY_SCHEDULED),
+            entry("b1", "b", PRIORITY_SCHEDULED),
+            entry("retry", "b", PRIORITY_RETRY),
+            entry("manual", "b", PRIORITY_MANUAL),
+        ];
+        // b's manual run goes first, so a's backlog and b's run then alternate
+        assert_eq!(ids(&queue.service_order(entries)), ["manual", "a1", "b1", "a2", "a3", "retry"]);
+    }
+
+    #[test]
+    fn test_least_recently_started_server_goes_first() {
+        let queue = JobQueue::new();
+        let entries = vec![entry("a1", "a", 20), entry("b1", "b", 20), entry("c1", "c", 20)];
+        queue.started("b");
+        queue.started("a");
+        // c never ran, then b before a
+        assert_eq!(ids(&queue.service_order(entries.clone())), ["c1", "b1", "a1"]);
+
+        // The dispatcher skips what cannot start and picks by the same order
+        let next = next_entry(&entries, &queue.last_started(), |e| e.server_id != "c");
+        assert_eq!(next, Some(1));
+    }
+
+    #[test]
+    fn test_move_to_reorders_the_queue() {
+        let pool = crate::db::test_pool();
+        let conn = pool.get().unwrap();
+        let srv = server::create_for_test(&conn, "web");
+        let mut queued = Vec::new();
+        for (name, kind, priority) in [("one", "scheduled", 20), ("two", "scheduled", 20), ("three", "retry", 10)] {
+            let job = backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, name, &["/etc"])).unwrap();
+            let entry = backup_queue::create(&conn, &backup_queue::CreateQueueEntryData {
+                job_id: job.id,
+                server_id: srv.id.clone(),
+                trigger_kind: kind.into(),
+                priority,
+                attempt: 1,
+                retry_of: None,
+            })
+            .unwrap();
+            queued.push(entry.id);
+        }
+        let queue = JobQueue::new();
+        let order = || -> Vec<String> {
+            queue.service_order(backup_queue::find_all(&conn).unwrap()).into_iter().map(|e| e.id).collect()
+        };
+        assert_eq!(order(), queued);
+
+        // The retry moved to the front takes the priority of the run it passes
+        assert!(backup_queue::move_to(&conn, &queued[2], 0).unwrap());
+        assert_eq!(order(), [queued[2].clone(), queued[0].clone(), queued[1].clone()]);
+        assert_eq!(backup_queue::find_by_id(&conn, &queued[2]).unwrap().unwrap().priority, 20);
+
+        // Positions past the end move it to the back
+        assert!(backup_queue::move_to(&conn, &queued[0], 10).unwrap());
+        assert_eq!(order(), [queued[2].clone(), queued[1].clone(), queued[0].clone()]);
+        assert!(!backup_queue::move_to(&conn, "missing", 0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_higher_priority_trigger_takes_over_queued_run() {
+        let state = crate::state::test_state();
+        let job = {
+            let conn = state.db.get().unwrap();
+            let srv = server::create_for_test(&conn, "web");
+            backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "etc", &["/etc"])).unwrap()
+        };
+        let retry = RunTrigger::Retry { attempt: 2, retry_of: "log-1".into() };
+
+        let (queued, _) = enqueue(&state, &job.id, retry.clone()).await.unwrap().unwrap();
+        assert_eq!(entry_trigger(&queued), retry);
+
+        // A manual run replaces the retry in place
+        let (merged, position) = enqueue(&state, &job.id, RunTrigger::Manual).await.unwrap().unwrap();
+        assert_eq!((merged.id.as_str(), position), (queued.id.as_str(), 1));
+        assert_eq!(entry_trigger(&merged), RunTrigger::Manual);
+        assert_eq!((merged.priority, merged.attempt, merged.retry_of), (PRIORITY_MANUAL, 1, None));
+
+        // A lower-priority trigger changes nothing
+        let (merged, _) = enqueue(&state, &job.id, retry).await.unwrap().unwrap();
+        assert_eq!(entry_trigger(&merged), RunTrigger::Manual);
+        let conn = state.db.get().unwrap();
+        assert_eq!(backup_queue::find_all(&conn).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recovered_run_waits_in_the_queue_for_capacity() {
+        let state = crate::state::test_state();
+        let (stale, job) = {
+            let conn = state.db.get().unwrap();
+            let srv = server::create_for_test(&conn, "web");
+            let stale = backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "old", &["/srv"])).unwrap();
+            let job = backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "etc", &["/etc"])).unwrap();
+            backup_job::update_status(&conn, &job.id, "running").unwrap();
+            (stale, job)
+        };
+        // Left behind by an earlier server process
+        queue_run(&state, &stale.id, RECOVERY, |_| Ok(())).await.unwrap();
+        // Recovery claims the job before it asks for capacity
+        state.running_jobs.lock().await.insert(job.id.clone());
+
+        let waiter = tokio::spawn({
+            let state = state.clone();
+            let job_id = job.id.clone();
+            async move { wait_to_reattach(&state, &job_id).await }
+        });
+        while !state.queue.turns().contains_key(&job.id)
+            || backup_queue::find_by_job_id(&state.db.get().unwrap(), &job.id).unwrap().is_none()
+        {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let conn = state.db.get().unwrap();
+        let entry = backup_queue::find_by_job_id(&conn, &job.id).unwrap().unwrap();
+        assert_eq!((entry.trigger_kind.as_str(), entry.priority), ("recovery", PRIORITY_RECOVERY));
+        assert_eq!(backup_job::find_by_id(&conn, &job.id).unwrap().unwrap().status, "running");
+        drop(conn);
+
+        let capacity = state.global_semaphore.available_permits();
+        dispatch_ready(&state).await.unwrap();
+        // Only the recovered run took capacity; the stale entry was dropped
+        assert_eq!(state.global_semaphore.available_permits(), capacity - 1);
+        assert!(backup_queue::find_all(&state.db.get().unwrap()).unwrap().is_empty());
+
+        let permits = waiter.await.unwrap().unwrap();
+        drop(permits);
+        assert_eq!(state.global_semaphore.available_permits(), capacity);
+    }
+}
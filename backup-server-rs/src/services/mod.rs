@@ -4,4 +4,12 @@ pub mod agent_orchestrator;
 pub mod agent_deployer;
 pub mod backup_scheduler;
 pub mod backup_migration;
+pub mod backup_roots;
+pub mod carry_forward;
 pub mod path_migration;
+pub mod run_recovery;
+pub mod job_queue;
+pub mod tls;
+pub mod version_links;
+pub mod agent_release;
+pub mod agent_rollout;
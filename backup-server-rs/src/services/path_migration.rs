@@ -79,12 +79,18 @@ pub fn migrate_server_folder_names(pool: &DbPool) -> anyhow::Result<()> {
                         let _ = backup_job::update(&conn, &job.id, &backup_job::UpdateBackupJobRequest {
                             name: None,
                             remote_paths: None,
+                            root_labels: None,
                             local_path: Some(new_job_path),
                             cron_schedule: None,
                             rsync_options: None,
                             max_parallel: None,
                             enabled: None,
                             max_versions: None,
+                            retry_max_attempts: None,
+                            retry_backoff_secs: None,
+                            max_file_errors: None,
+                            compression: None,
+                            compression_level: None,
                         });
 
                         if let Ok(versions) = backup_version::find_by_job_id(&conn, &job.id) {
@@ -0,0 +1,343 @@
+use crate::models::{backup_job, backup_version};
+use crate::services::agent_orchestrator::{self, ActiveRun};
+use crate::services::job_queue;
+use crate::state::AppState;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const INTERRUPTED_ERROR: &str = "Interrupted: the server restarted while this backup was running";
+
+/// A version left in `running` by a previous server process.
+struct OrphanedRun {
+    job: backup_job::BackupJob,
+    version: backup_version::BackupVersion,
+    log: backup_job::BackupLog,
+}
+
+/// The versions left in `running`, oldest first, with their job and log. A
+/// version whose log is gone gets a new one, for its outcome to be recorded.
+fn find_orphans(conn: &rusqlite::Connection) -> anyhow::Result<Vec<OrphanedRun>> {
+    let mut orphans = Vec::new();
+    for version in backup_version::find_by_status(conn, "running")? {
+        let Some(job) = backup_job::find_by_id(conn, &version.job_id)? else {
+            continue;
+        };
+        let log = match &version.log_id {
+            Some(log_id) => backup_job::find_log_by_id(conn, log_id)?,
+            None => None,
+        };
+        let log = match log {
+            Some(log) => log,
+            None => {
+                tracing::warn!(job_id = %job.id, version = %version.version_timestamp, "Running version has no log, creating one");
+                let log = backup_job::create_log(conn, &job.id, 1, None)?;
+                backup_version::update_fields(conn, &version.id, &[
+                    ("log_id", &log.id as &dyn rusqlite::types::ToSql),
+                ])?;
+                log
+            }
+        };
+        orphans.push(OrphanedRun { job, version, log });
+    }
+    Ok(orphans)
+}
+
+/// Reconcile runs left in `running` by a previous server process.
+///
+/// Orphaned jobs are claimed in `running_jobs` right away so the scheduler cannot
+/// start them a second time and uploads from a still-busy agent keep landing in
+/// the right version. Each owning agent then gets `recovery_grace_secs` to
+/// reconnect: if it reports the job as still active the run is reattached,
+/// otherwise the run is marked failed and its partial version directory is
+/// quarantined (or removed, with `REMOVE_INTERRUPTED_VERSIONS`).
+pub async fn start_run_recovery(state: Arc<AppState>) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let orphans = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let orphans = find_orphans(&conn)?;
+
+        // A crash between marking the job running and creating its version leaves
+        // nothing to reattach; just release the job and its log.
+        for job in backup_job::find_all(&conn)? {
+            if job.status != "running" || orphans.iter().any(|o| o.job.id == job.id) {
+                continue;
+            }
+            backup_job::update_status(&conn, &job.id, "failed")?;
+            for log in backup_job::find_logs_by_job_id(&conn, &job.id, 1)? {
+                if log.status == "running" {
+                    backup_job::update_log(&conn, &log.id, &[
+                        ("status", &"failed" as &dyn rusqlite::types::ToSql),
+                        ("error", &INTERRUPTED_ERROR as &dyn rusqlite::types::ToSql),
+                        ("finished_at", &chrono::Utc::now().to_rfc3339() as &dyn rusqlite::types::ToSql),
+                    ])?;
+                }
+            }
+            tracing::warn!(job_id = %job.id, "Reset job left running without a version");
+        }
+
+        Ok::<_, anyhow::Error>(orphans)
+    })
+    .await??;
+
+    if orphans.is_empty() {
+        return Ok(());
+    }
+
+    // Only the newest running version of a job can still be in progress on the agent.
+    let mut latest: HashMap<String, OrphanedRun> = HashMap::new();
+    let mut stale = Vec::new();
+    for orphan in orphans {
+        // Versions are ordered oldest first, so a later entry replaces an earlier one.
+        if let Some(older) = latest.insert(orphan.job.id.clone(), orphan) {
+            stale.push(older);
+        }
+    }
+
+    tracing::info!(runs = latest.len(), "Recovering backup runs interrupted by server restart");
+
+    {
+        let mut running = state.running_jobs.lock().await;
+        for job_id in latest.keys() {
+            running.insert(job_id.clone());
+        }
+    }
+
+    for orphan in stale {
+        if let Err(e) = mark_interrupted(&state, orphan).await {
+            tracing::error!(error = %e, "Failed to mark stale version as interrupted");
+        }
+    }
+
+    for (job_id, orphan) in latest {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = recover_run(state.clone(), orphan).await {
+                tracing::error!(job_id = %job_id, error = %e, "Run recovery failed");
+            }
+            state.running_jobs.lock().await.remove(&job_id);
+        });
+    }
+
+    Ok(())
+}
+
+async fn recover_run(state: Arc<AppState>, orphan: OrphanedRun) -> anyhow::Result<()> {
+    let job_id = orphan.job.id.clone();
+    let server_id = orphan.job.server_id.clone();
+    let grace = Duration::from_secs(state.config.recovery_grace_secs);
+
+    if !wait_for_agent(&state, &server_id, grace).await {
+        tracing::warn!(job_id = %job_id, server_id = %server_id, "Agent did not reconnect, marking run as interrupted");
+        return mark_interrupted(&state, orphan).await;
+    }
+
+    // Subscribe before asking so nothing the agent reports in between is lost
+    let events = state.job_events.subscribe(&job_id, &server_id);
+    let active = match state.agents.running_jobs(&server_id).await {
+        Ok(jobs) => jobs.contains(&job_id),
+        Err(e) => {
+            // Agents that predate `backup:status` never answer
+            tracing::warn!(job_id = %job_id, server_id = %server_id, error = %e, "Failed to query agent job status");
+            false
+        }
+    };
+    if !active {
+        state.job_events.close(&job_id);
+        tracing::warn!(job_id = %job_id, server_id = %server_id, "Agent is no longer running the job, marking run as interrupted");
+        return mark_interrupted(&state, orphan).await;
+    }
+
+    tracing::info!(job_id = %job_id, server_id = %server_id, "Reattaching to backup still running on agent");
+
+    // Ahead of every queued run, but within the same limits
+    let permits = match job_queue::wait_to_reattach(&state, &job_id).await {
+        Ok(permits) => permits,
+        Err(e) => {
+            state.job_events.close(&job_id);
+            tracing::warn!(job_id = %job_id, error = %e, "Recovered run left the queue, marking run as interrupted");
+            return mark_interrupted(&state, orphan).await;
+        }
+    };
+
+    let log_id = orphan.log.id.clone();
+    let elapsed = chrono::DateTime::parse_from_rfc3339(&orphan.log.started_at)
+        .ok()
+        .and_then(|started| (chrono::Utc::now() - started.with_timezone(&chrono::Utc)).to_std().ok())
+        .unwrap_or_default();
+    let started = std::time::Instant::now()
+        .checked_sub(elapsed)
+        .unwrap_or_else(std::time::Instant::now);
+
+    state.ui.broadcast("backup:started", serde_json::json!({
+        "jobId": job_id,
+        "serverId": server_id,
+        "reattached": true,
+    }));
+
+    let result = agent_orchestrator::await_run(state.clone(), ActiveRun {
+        job: orphan.job,
+        server_id,
+        log_id,
+        version_id: orphan.version.id,
+        version_path: PathBuf::from(orphan.version.local_path),
+        started,
+        events,
+    })
+    .await;
+    drop(permits);
+    state.queue.wake();
+    result
+}
+
+/// Poll the agent registry until the agent is connected or `grace` runs out.
+async fn wait_for_agent(state: &AppState, server_id: &str, grace: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + grace;
+    loop {
+        if state.agents.is_connected(server_id) {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn mark_interrupted(state: &AppState, orphan: OrphanedRun) -> anyhow::Result<()> {
+    let OrphanedRun { job, version, log } = orphan;
+    let remove = state.config.remove_interrupted_versions;
+
+    let version_path = PathBuf::from(&version.local_path);
+    let job_path = PathBuf::from(&job.local_path);
+    let timestamp = version.version_timestamp.clone();
+    let kept_at = tokio::task::spawn_blocking(move || {
+        set_aside_partial_version(&version_path, &job_path, &timestamp, remove)
+    })
+    .await??;
+
+    let db = state.db.clone();
+    let (jid, log_id, vid) = (job.id.clone(), log.id.clone(), version.id.clone());
+    tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        mark_failed(&conn, &jid, &log_id, &vid, kept_at.as_deref())
+    })
+    .await??;
+
+    state.ui.broadcast("backup:failed", serde_json::json!({
+        "jobId": job.id,
+        "error": INTERRUPTED_ERROR,
+    }));
+
+    tracing::warn!(
+        job_id = %job.id,
+        version = %version.version_timestamp,
+        removed = remove,
+        "Marked interrupted backup run as failed"
+    );
+
+    Ok(())
+}
+
+/// Record an interrupted run as failed; `kept_at` is where its partial
+/// version was set aside, if it was kept.
+fn mark_failed(
+    conn: &rusqlite::Connection,
+    job_id: &str,
+    log_id: &str,
+    version_id: &str,
+    kept_at: Option<&Path>,
+) -> anyhow::Result<()> {
+    // Leave the job alone if a newer run has already finished
+    if backup_job::find_by_id(conn, job_id)?.is_some_and(|j| j.status == "running") {
+        backup_job::update_status(conn, job_id, "failed")?;
+    }
+    backup_job::update_log(conn, log_id, &[
+        ("status", &"failed" as &dyn rusqlite::types::ToSql),
+        ("error", &INTERRUPTED_ERROR as &dyn rusqlite::types::ToSql),
+        ("finished_at", &chrono::Utc::now().to_rfc3339() as &dyn rusqlite::types::ToSql),
+    ])?;
+    backup_version::update_failed(conn, version_id)?;
+    if let Some(path) = kept_at {
+        let path = path.to_string_lossy().to_string();
+        backup_version::update_fields(conn, version_id, &[
+            ("local_path", &path as &dyn rusqlite::types::ToSql),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Move a partial version out of `versions/` into `quarantine/`, or delete it when
+/// `remove` is set, so it can never be mistaken for a snapshot. Returns the new
+/// location of the directory if it was kept.
+fn set_aside_partial_version(
+    version_path: &Path,
+    job_path: &Path,
+    timestamp: &str,
+    remove: bool,
+) -> anyhow::Result<Option<PathBuf>> {
+    if !version_path.exists() {
+        return Ok(None);
+    }
+    if remove {
+        std::fs::remove_dir_all(version_path)?;
+        return Ok(None);
+    }
+    let quarantine_dir = job_path.join("quarantine");
+    std::fs::create_dir_all(&quarantine_dir)?;
+    let dest = quarantine_dir.join(timestamp);
+    std::fs::rename(version_path, &dest)?;
+    Ok(Some(dest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::backup_job::CreateBackupJobRequest;
+    use crate::models::server;
+
+    #[test]
+    fn test_orphaned_runs_with_and_without_a_log_are_failed() {
+        let pool = crate::db::test_pool();
+        let conn = pool.get().unwrap();
+        let srv = server::create_for_test(&conn, "web");
+        let job = backup_job::create(&conn, &CreateBackupJobRequest::for_test(&srv.id, "etc", &["/etc"])).unwrap();
+        backup_job::update_status(&conn, &job.id, "running").unwrap();
+
+        let mut versions = Vec::new();
+        for timestamp in ["2026-01-01_00-00-00", "2026-01-02_00-00-00"] {
+            let log = backup_job::create_log(&conn, &job.id, 1, None).unwrap();
+            versions.push(backup_version::create(&conn, &backup_version::CreateVersionData {
+                job_id: job.id.clone(),
+                log_id: log.id,
+                version_timestamp: timestamp.into(),
+                local_path: format!("/backups/etc/{}", timestamp),
+                roots: None,
+            })
+            .unwrap());
+        }
+        // The second run's log was deleted
+        conn.execute("DELETE FROM backup_logs WHERE id = ?", [versions[1].log_id.as_deref().unwrap()]).unwrap();
+
+        let orphans = find_orphans(&conn).unwrap();
+        assert_eq!(orphans.len(), 2);
+        assert_eq!(orphans[0].log.id, versions[0].log_id.clone().unwrap());
+        let created = &orphans[1].log;
+        assert_eq!(created.job_id, job.id);
+        assert_eq!(
+            backup_version::find_by_id(&conn, &versions[1].id).unwrap().unwrap().log_id.as_deref(),
+            Some(created.id.as_str())
+        );
+
+        for orphan in &orphans {
+            mark_failed(&conn, &job.id, &orphan.log.id, &orphan.version.id, None).unwrap();
+            let log = backup_job::find_log_by_id(&conn, &orphan.log.id).unwrap().unwrap();
+            assert_eq!((log.status.as_str(), log.error.as_deref()), ("failed", Some(INTERRUPTED_ERROR)));
+            let version = backup_version::find_by_id(&conn, &orphan.version.id).unwrap().unwrap();
+            assert_eq!(version.status, "failed");
+        }
+        assert_eq!(backup_job::find_by_id(&conn, &job.id).unwrap().unwrap().status, "failed");
+        assert!(find_orphans(&conn).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::config::{AppConfig, ClientAuthMode};
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const SERVER_CERT_FILE: &str = "server.pem";
+const SERVER_KEY_FILE: &str = "server-key.pem";
+const AGENT_CA_CERT_FILE: &str = "agent-ca.pem";
+const AGENT_CA_KEY_FILE: &str = "agent-ca-key.pem";
+const AGENT_CA_NAME: &str = "Backup Server Agent CA";
+
+/// Connections that have not finished the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An agent client certificate issued at deploy time.
+pub struct IssuedCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+}
+
+/// The server's TLS certificate plus the CA that signs agent client certificates.
+pub struct TlsIdentity {
+    /// SHA-256 fingerprint of the server certificate, pinned by agents.
+    pub fingerprint: String,
+    server_config: Arc<rustls::ServerConfig>,
+    agent_ca: rcgen::Certificate,
+    agent_ca_key: KeyPair,
+}
+
+impl TlsIdentity {
+    pub fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        self.server_config.clone()
+    }
+
+    /// Issue a client certificate for the agent of `server_id`, signed by the agent CA.
+    pub fn issue_agent_certificate(&self, server_id: &str) -> anyhow::Result<IssuedCertificate> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(Vec::<String>::new())?;
+        params.distinguished_name = distinguished_name(&format!("backup-agent {}", server_id));
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.use_authority_key_identifier_extension = true;
+        let cert = params.signed_by(&key, &self.agent_ca, &self.agent_ca_key)?;
+
+        Ok(IssuedCertificate {
+            fingerprint: fingerprint(cert.der()),
+            cert_pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+        })
+    }
+}
+
+/// Lowercase, colon-separated SHA-256 of a DER certificate.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Load the server certificate from `TLS_CERT_PATH`/`TLS_KEY_PATH`, or from
+/// `<data>/tls`, generating a self-signed one there on first start. The agent
+/// CA always lives in `<data>/tls`.
+pub fn load_or_generate(config: &AppConfig) -> anyhow::Result<TlsIdentity> {
+    let tls_dir = config.data_dir.join("tls");
+    std::fs::create_dir_all(&tls_dir)?;
+
+    let (cert_path, key_path) = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) => {
+            let cert = tls_dir.join(SERVER_CERT_FILE);
+            let key = tls_dir.join(SERVER_KEY_FILE);
+            if !cert.exists() || !key.exists() {
+                generate_server_certificate(config, &cert, &key)?;
+            }
+            (cert, key)
+        }
+        _ => anyhow::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert_path.display(), e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("Invalid certificate in {}: {}", cert_path.display(), e))?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No certificate found in {}", cert_path.display()))?;
+    let server_fingerprint = fingerprint(leaf);
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read key {}: {}", key_path.display(), e))?;
+
+    let (agent_ca, agent_ca_key, agent_ca_der) = load_or_generate_agent_ca(&tls_dir)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if config.tls_client_auth == ClientAuthMode::Off {
+        builder.with_no_client_auth()
+    } else {
+        // Browsers share this listener, so the handshake accepts clients without
+        // a certificate; agent endpoints enforce the mode themselves.
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(agent_ca_der)?;
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()?;
+        builder.with_client_cert_verifier(verifier)
+    };
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    tracing::info!(cert = %cert_path.display(), fingerprint = %server_fingerprint, "TLS enabled");
+
+    Ok(TlsIdentity {
+        fingerprint: server_fingerprint,
+        server_config: Arc::new(server_config),
+        agent_ca,
+        agent_ca_key,
+    })
+}
+
+fn distinguished_name(common_name: &str) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, common_name);
+    dn
+}
+
+fn generate_server_certificate(config: &AppConfig, cert_path: &Path, key_path: &Path) -> anyhow::Result<()> {
+    let mut names = vec!["localhost".to_string()];
+    if let Some(host) = hostname() {
+        names.push(host);
+    }
+    if let Some(ip) = &config.backup_server_ip {
+        names.push(ip.clone());
+    }
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(names)?;
+    params.distinguished_name = distinguished_name("Backup Server");
+    let cert = params.self_signed(&key)?;
+
+    write_private(key_path, &key.serialize_pem())?;
+    std::fs::write(cert_path, cert.pem())?;
+    tracing::info!(cert = %cert_path.display(), "Generated self-signed TLS certificate");
+    Ok(())
+}
+
+/// The CA certificate is rebuilt from its saved key on each start. Issued agent
+/// certificates only depend on its name and key, and clients are verified
+/// against the copy on disk.
+fn load_or_generate_agent_ca(
+    tls_dir: &Path,
+) -> anyhow::Result<(rcgen::Certificate, KeyPair, CertificateDer<'static>)> {
+    let key_path = tls_dir.join(AGENT_CA_KEY_FILE);
+    let cert_path = tls_dir.join(AGENT_CA_CERT_FILE);
+
+    let existing = key_path.exists() && cert_path.exists();
+    let key = if existing {
+        KeyPair::from_pem(&std::fs::read_to_string(&key_path)?)?
+    } else {
+        KeyPair::generate()?
+    };
+
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.distinguished_name = distinguished_name(AGENT_CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca = params.self_signed(&key)?;
+
+    if !existing {
+        write_private(&key_path, &key.serialize_pem())?;
+        std::fs::write(&cert_path, ca.pem())?;
+        tracing::info!(cert = %cert_path.display(), "Generated agent client CA");
+    }
+
+    // Trust the CA as written on first start
+    let trusted = CertificateDer::from_pem_file(&cert_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert_path.display(), e))?;
+    Ok((ca, key, trusted))
+}
+
+fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    std::fs::write(path, content)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Peer of an accepted connection, available to handlers as `ConnectInfo<PeerInfo>`.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// Fingerprint of the client certificate, if one was presented and chains to the agent CA.
+    pub client_cert_fingerprint: Option<String>,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        PeerInfo {
+            addr: *stream.remote_addr(),
+            client_cert_fingerprint: None,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+/// TCP listener that completes TLS handshakes in the background, so a slow
+/// client cannot hold up `accept` for everyone else.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, PeerInfo)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<rustls::ServerConfig>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, accepted) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to accept connection");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => {
+                            let client_cert_fingerprint = tls
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|certs| certs.first())
+                                .map(|cert| fingerprint(cert));
+                            let _ = tx.send((tls, PeerInfo { addr, client_cert_fingerprint })).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(%addr, error = %e, "TLS handshake failed"),
+                        Err(_) => tracing::debug!(%addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self { local_addr, accepted })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = PeerInfo;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(PeerInfo {
+            addr: self.local_addr,
+            client_cert_fingerprint: None,
+        })
+    }
+}
+
+/// Check an agent's client certificate against the one issued for its server.
+pub fn check_agent_certificate(
+    mode: ClientAuthMode,
+    issued: Option<&str>,
+    presented: Option<&str>,
+) -> Result<(), &'static str> {
+    match (mode, issued, presented) {
+        (ClientAuthMode::Off, _, _) => Ok(()),
+        (_, Some(issued), Some(presented)) if issued == presented => Ok(()),
+        (_, _, Some(_)) => Err("Client certificate was not issued for this server"),
+        (ClientAuthMode::Required, _, None) => Err("Client certificate required"),
+        (ClientAuthMode::Optional, _, None) => Ok(()),
+    }
+}
+
+/// Like [`check_agent_certificate`], for requests on the jobs of a server.
+/// With optional client auth, a server issued a certificate must present it:
+/// only agents not enrolled yet may go without.
+pub fn check_job_certificate(
+    mode: ClientAuthMode,
+    issued: Option<&str>,
+    presented: Option<&str>,
+) -> Result<(), &'static str> {
+    match (mode, issued, presented) {
+        (ClientAuthMode::Optional, Some(_), None) => Err("Client certificate required"),
+        _ => check_agent_certificate(mode, issued, presented),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_colon_separated_sha256() {
+        let fp = fingerprint(b"");
+        assert_eq!(fp.len(), 32 * 3 - 1);
+        assert!(fp.starts_with("e3:b0:c4:42"));
+    }
+
+    #[test]
+    fn agent_certificate_modes() {
+        use ClientAuthMode::*;
+        assert!(check_agent_certificate(Off, Some("a"), Some("b")).is_ok());
+        assert!(check_agent_certificate(Optional, None, None).is_ok());
+        assert!(check_agent_certificate(Optional, Some("a"), Some("a")).is_ok());
+        assert!(check_agent_certificate(Optional, Some("a"), Some("b")).is_err());
+        assert!(check_agent_certificate(Required, Some("a"), None).is_err());
+        assert!(check_agent_certificate(Required, None, Some("a")).is_err());
+        assert!(check_agent_certificate(Required, Some("a"), Some("a")).is_ok());
+    }
+
+    #[test]
+    fn job_certificate_modes() {
+        use ClientAuthMode::*;
+        assert!(check_job_certificate(Off, Some("a"), None).is_ok());
+        assert!(check_job_certificate(Optional, None, None).is_ok());
+        assert!(check_job_certificate(Optional, Some("a"), None).is_err());
+        assert!(check_job_certificate(Optional, None, Some("a")).is_err());
+        assert!(check_job_certificate(Optional, Some("a"), Some("b")).is_err());
+        assert!(check_job_certificate(Optional, Some("a"), Some("a")).is_ok());
+        assert!(check_job_certificate(Required, Some("a"), Some("b")).is_err());
+        assert!(check_job_certificate(Required, Some("a"), Some("a")).is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+//! How unchanged files are carried into new versions, and how each run ended
+//! up carrying them.
+//!
+//! The storage's `link_mode` setting picks copy-on-write clones where the
+//! filesystem supports them (`auto`, the default), always clones (`reflink`)
+//! or always hardlinks (`hardlink`). The mode is resolved once per run and
+//! looked up by the routes linking files for it; what was actually used is
+//! recorded on the version.
+
+use crate::utils::safe_dir::{supports_reflink, LinkMode};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Settings key of the storage's link mode
+pub const SETTING: &str = "link_mode";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkSetting {
+    /// Reflinks where the storage supports them, hardlinks elsewhere
+    #[default]
+    Auto,
+    Reflink,
+    Hardlink,
+}
+
+impl LinkSetting {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Self::Auto),
+            "reflink" => Some(Self::Reflink),
+            "hardlink" => Some(Self::Hardlink),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Reflink => "reflink",
+            Self::Hardlink => "hardlink",
+        }
+    }
+
+    pub fn load(conn: &Connection) -> anyhow::Result<Self> {
+        let value = crate::models::settings::get(conn, SETTING)?;
+        Ok(value.as_deref().and_then(Self::parse).unwrap_or_default())
+    }
+
+    /// The mode for versions written in `dir`.
+    pub fn resolve(self, dir: &Path) -> Result<LinkMode, String> {
+        match self {
+            Self::Auto if supports_reflink(dir) => Ok(LinkMode::Reflink),
+            Self::Auto | Self::Hardlink => Ok(LinkMode::Hardlink),
+            Self::Reflink if supports_reflink(dir) => Ok(LinkMode::Reflink),
+            Self::Reflink => Err(format!("{} does not support reflinks", dir.display())),
+        }
+    }
+}
+
+/// Files a run carried forward, by mode
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkTally {
+    pub reflinked: u64,
+    pub hardlinked: u64,
+}
+
+impl LinkTally {
+    pub fn add(&mut self, mode: LinkMode) {
+        match mode {
+            LinkMode::Reflink => self.reflinked += 1,
+            LinkMode::Hardlink => self.hardlinked += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: LinkTally) {
+        self.reflinked += other.reflinked;
+        self.hardlinked += other.hardlinked;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.reflinked + self.hardlinked
+    }
+
+    /// What the version reports: `mixed` when some clones fell back to
+    /// hardlinks, None when nothing was carried forward.
+    pub fn mode(&self) -> Option<&'static str> {
+        match (self.reflinked, self.hardlinked) {
+            (0, 0) => None,
+            (_, 0) => Some(LinkMode::Reflink.as_str()),
+            (0, _) => Some(LinkMode::Hardlink.as_str()),
+            _ => Some("mixed"),
+        }
+    }
+}
+
+/// Link mode and tally of running jobs, by job id.
+#[derive(Default)]
+pub struct VersionLinks {
+    runs: Mutex<HashMap<String, (LinkMode, LinkTally)>>,
+}
+
+impl VersionLinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, job_id: &str, mode: LinkMode) {
+        self.lock().insert(job_id.to_string(), (mode, LinkTally::default()));
+    }
+
+    /// Runs reattached after a restart were not started here: they hardlink,
+    /// which works on every filesystem.
+    pub fn mode(&self, job_id: &str) -> LinkMode {
+        self.lock().get(job_id).map_or(LinkMode::Hardlink, |(mode, _)| *mode)
+    }
+
+    pub fn record(&self, job_id: &str, tally: LinkTally) {
+        self.lock()
+            .entry(job_id.to_string())
+            .or_insert((LinkMode::Hardlink, LinkTally::default()))
+            .1
+            .merge(tally);
+    }
+
+    /// Forget the run, returning what it carried forward.
+    pub fn finish(&self, job_id: &str) -> LinkTally {
+        self.lock().remove(job_id).map(|(_, tally)| tally).unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (LinkMode, LinkTally)>> {
+        self.runs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_report_the_modes_they_used() {
+        let links = VersionLinks::new();
+        links.start("j1", LinkMode::Reflink);
+        assert_eq!(links.mode("j1"), LinkMode::Reflink);
+        assert_eq!(links.mode("recovered"), LinkMode::Hardlink);
+
+        let mut tally = LinkTally::default();
+        tally.add(LinkMode::Reflink);
+        links.record("j1", tally);
+        assert_eq!(links.finish("j1").mode(), Some("reflink"));
+
+        tally.add(LinkMode::Hardlink);
+        links.record("recovered", tally);
+        assert_eq!(links.finish("recovered").mode(), Some("mixed"));
+        assert_eq!(links.finish("recovered").mode(), None);
+    }
+
+    #[test]
+    fn test_settings_resolve_without_reflink_support() {
+        let missing = Path::new("/nonexistent/storage");
+        assert_eq!(LinkSetting::Hardlink.resolve(missing), Ok(LinkMode::Hardlink));
+        assert_eq!(LinkSetting::Auto.resolve(missing), Ok(LinkMode::Hardlink));
+        assert!(LinkSetting::Reflink.resolve(missing).is_err());
+        assert_eq!(LinkSetting::parse("auto"), Some(LinkSetting::Auto));
+        assert_eq!(LinkSetting::parse("copy"), None);
+    }
+}
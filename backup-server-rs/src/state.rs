@@ -2,6 +2,13 @@ use crate::config::AppConfig;
 use crate::db::connection::DbPool;
 use crate::ws::ui::UiBroadcaster;
 use crate::ws::agent_registry::AgentRegistry;
+use crate::ws::job_events::JobEventBus;
+use crate::services::job_queue::JobQueue;
+use crate::services::agent_release::ReleaseSigner;
+use crate::services::agent_rollout::RolloutManager;
+use crate::services::carry_forward::CarryForward;
+use crate::services::tls::TlsIdentity;
+use crate::services::version_links::VersionLinks;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -11,13 +18,29 @@ pub struct AppState {
     pub config: AppConfig,
     pub ui: UiBroadcaster,
     pub agents: Arc<AgentRegistry>,
+    pub job_events: Arc<JobEventBus>,
+    pub queue: Arc<JobQueue>,
     pub running_jobs: Arc<Mutex<HashSet<String>>>,
     pub global_semaphore: Arc<tokio::sync::Semaphore>,
     pub server_semaphores: Arc<Mutex<HashMap<String, Arc<tokio::sync::Semaphore>>>>,
+    /// Set when the server listens with TLS.
+    pub tls: Option<Arc<TlsIdentity>>,
+    /// Signs the agent release manifest for self-updates.
+    pub release: Arc<ReleaseSigner>,
+    pub rollouts: Arc<RolloutManager>,
+    /// Unchanged files of incremental runs linked by the server.
+    pub carry_forward: Arc<CarryForward>,
+    /// How running jobs carry files into their versions.
+    pub version_links: Arc<VersionLinks>,
 }
 
 impl AppState {
-    pub fn new(db: DbPool, config: AppConfig) -> Self {
+    pub fn new(
+        db: DbPool,
+        config: AppConfig,
+        tls: Option<Arc<TlsIdentity>>,
+        release: Arc<ReleaseSigner>,
+    ) -> Self {
         let max_global = config.max_concurrent_global;
         let max_per_server = config.max_concurrent_per_server;
         Self {
@@ -25,9 +48,16 @@ impl AppState {
             config,
             ui: UiBroadcaster::new(),
             agents: Arc::new(AgentRegistry::new()),
+            job_events: Arc::new(JobEventBus::new()),
+            queue: Arc::new(JobQueue::new()),
             running_jobs: Arc::new(Mutex::new(HashSet::new())),
             global_semaphore: Arc::new(tokio::sync::Semaphore::new(max_global)),
             server_semaphores: Arc::new(Mutex::new(HashMap::new())),
+            tls,
+            release,
+            rollouts: Arc::new(RolloutManager::new()),
+            carry_forward: Arc::new(CarryForward::new()),
+            version_links: Arc::new(VersionLinks::new()),
         }
     }
 
@@ -38,3 +68,13 @@ impl AppState {
             .clone()
     }
 }
+
+/// State over a migrated database of its own, for tests
+#[cfg(test)]
+pub fn test_state() -> Arc<AppState> {
+    let db = crate::db::test_pool();
+    let keys_dir = std::env::temp_dir().join(format!("backup-keys-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&keys_dir).unwrap();
+    let release = ReleaseSigner::load_or_generate(&keys_dir).unwrap();
+    Arc::new(AppState::new(db, AppConfig::from_env(), None, Arc::new(release)))
+}
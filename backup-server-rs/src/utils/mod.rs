@@ -1 +1,2 @@
 pub mod semaphore;
+pub mod safe_dir;
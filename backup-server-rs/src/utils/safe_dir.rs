@@ -0,0 +1,483 @@
+//! Access to files below a storage directory (a version, the backup root)
+//! through paths that come from agents or API clients.
+//!
+//! Relative paths are resolved one component at a time with `openat` and
+//! `O_NOFOLLOW`, starting from a descriptor of the directory itself. `..`,
+//! absolute paths and symlinks anywhere along the way are refused, so nothing
+//! outside the directory can be read, written or linked, whatever the path
+//! says or whatever was planted inside the directory. Paths are handled as
+//! bytes: names need not be valid UTF-8.
+//!
+//! Files are carried from one version to the next either as copy-on-write
+//! clones (`FICLONE`, on btrfs or XFS), which later changes to one version
+//! cannot reach, or as hardlinks, which share the inode with every version.
+
+use crate::error::AppError;
+use nix::errno::Errno;
+use nix::fcntl::{openat, AtFlags, OFlag};
+use nix::sys::stat::{fchmod, fstat, fstatat, futimens, mkdirat, FileStat, Mode, SFlag};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{linkat, unlinkat, UnlinkatFlags};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SafePathError {
+    #[error("Invalid path: {0}")]
+    Invalid(String),
+    #[error("Path goes through a symlink: {0}")]
+    Symlink(String),
+    #[error("{path}: {source}")]
+    Io { path: String, source: std::io::Error },
+}
+
+impl SafePathError {
+    fn io(path: &Path, errno: Errno) -> Self {
+        SafePathError::Io { path: path.display().to_string(), source: errno.into() }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, SafePathError::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound)
+    }
+}
+
+impl From<SafePathError> for AppError {
+    fn from(e: SafePathError) -> Self {
+        match e {
+            SafePathError::Invalid(_) => AppError::BadRequest(e.to_string()),
+            SafePathError::Symlink(_) => AppError::BadRequest("Access denied".into()),
+            e if e.is_not_found() => AppError::NotFound("Not found".into()),
+            e => AppError::Internal(e.into()),
+        }
+    }
+}
+
+/// How a file is carried into another version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Copy-on-write clone: a separate inode sharing the data blocks
+    Reflink,
+    /// The same inode, metadata and contents included
+    Hardlink,
+}
+
+impl LinkMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkMode::Reflink => "reflink",
+            LinkMode::Hardlink => "hardlink",
+        }
+    }
+}
+
+// FICLONE is _IOW(0x94, 9, int): clone all of the source fd into the target
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Errors of `FICLONE` meaning the filesystem cannot clone these files
+fn clone_unsupported(errno: Errno) -> bool {
+    matches!(errno, Errno::EOPNOTSUPP | Errno::EXDEV | Errno::EINVAL | Errno::ENOTTY | Errno::ENOSYS)
+}
+
+/// Whether files in `dir` can be cloned, tried on a scratch file.
+pub fn supports_reflink(dir: &Path) -> bool {
+    let probe = format!(".reflink-probe-{}", uuid::Uuid::new_v4());
+    let (source, target) = (dir.join(&probe), dir.join(probe + ".clone"));
+    let cloned = std::fs::write(&source, [0u8; 4096]).is_ok()
+        && File::open(&source).and_then(|s| Ok((s, File::create(&target)?))).is_ok_and(|(source, target)| {
+            unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd() as _) }.is_ok()
+        });
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&target);
+    cloned
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// What `lstat` says about an entry; symlinks are reported, never followed.
+#[derive(Debug, Clone)]
+pub struct SafeEntry {
+    /// Raw file name; use `to_string_lossy` to show it.
+    pub name: OsString,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl SafeEntry {
+    fn from_stat(name: OsString, stat: &FileStat) -> Self {
+        let kind = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFREG => EntryKind::File,
+            SFlag::S_IFDIR => EntryKind::Directory,
+            SFlag::S_IFLNK => EntryKind::Symlink,
+            _ => EntryKind::Other,
+        };
+        let modified = (stat.st_mtime >= 0).then(|| {
+            SystemTime::UNIX_EPOCH + Duration::new(stat.st_mtime as u64, stat.st_mtime_nsec as u32)
+        });
+        Self { name, kind, size: stat.st_size.max(0) as u64, modified }
+    }
+}
+
+/// Split a path relative to a storage directory into its components.
+/// `.` components are dropped; an empty path is the directory itself.
+pub fn split_relative(path: &Path) -> Result<Vec<&OsStr>, SafePathError> {
+    if path.as_os_str().as_bytes().contains(&0) {
+        return Err(SafePathError::Invalid("path contains a NUL byte".into()));
+    }
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => components.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(SafePathError::Invalid(format!("{} leaves its directory", path.display())))
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(SafePathError::Invalid(format!("{} is absolute", path.display())))
+            }
+        }
+    }
+    Ok(components)
+}
+
+const DIR_FLAGS: OFlag = OFlag::O_RDONLY
+    .union(OFlag::O_DIRECTORY)
+    .union(OFlag::O_NOFOLLOW)
+    .union(OFlag::O_CLOEXEC);
+
+/// A directory that relative paths are confined to.
+pub struct SafeDir {
+    fd: OwnedFd,
+}
+
+impl SafeDir {
+    /// Open `root` itself. It comes from the database or configuration and is
+    /// trusted, symlinks included; only what is below it is confined.
+    pub fn open(root: impl AsRef<Path>) -> Result<Self, SafePathError> {
+        let root = root.as_ref();
+        let fd = nix::fcntl::open(root, DIR_FLAGS.difference(OFlag::O_NOFOLLOW), Mode::empty())
+            .map_err(|e| SafePathError::io(root, e))?;
+        Ok(Self { fd })
+    }
+
+    /// Open the directory `components` leads to, optionally creating the
+    /// missing ones.
+    fn open_dir(&self, path: &Path, components: &[&OsStr], create: bool) -> Result<OwnedFd, SafePathError> {
+        let mut dir = openat(&self.fd, ".", DIR_FLAGS, Mode::empty()).map_err(|e| SafePathError::io(path, e))?;
+        for component in components {
+            let next = match openat(&dir, *component, DIR_FLAGS, Mode::empty()) {
+                Err(Errno::ENOENT) if create => {
+                    match mkdirat(&dir, *component, Mode::from_bits_truncate(0o755)) {
+                        Ok(()) | Err(Errno::EEXIST) => {}
+                        Err(e) => return Err(SafePathError::io(path, e)),
+                    }
+                    openat(&dir, *component, DIR_FLAGS, Mode::empty())
+                }
+                other => other,
+            };
+            dir = next.map_err(|e| Self::classify(&dir, component, path, e))?;
+        }
+        Ok(dir)
+    }
+
+    /// `O_NOFOLLOW` fails with `ELOOP` on a symlink, and `O_DIRECTORY` with
+    /// `ENOTDIR` on anything that is not a directory, symlinks included.
+    fn classify(dir: &OwnedFd, component: &OsStr, path: &Path, errno: Errno) -> SafePathError {
+        let is_symlink = || {
+            fstatat(dir, component, AtFlags::AT_SYMLINK_NOFOLLOW)
+                .is_ok_and(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK)
+        };
+        match errno {
+            Errno::ELOOP => SafePathError::Symlink(path.display().to_string()),
+            Errno::ENOTDIR if is_symlink() => SafePathError::Symlink(path.display().to_string()),
+            e => SafePathError::io(path, e),
+        }
+    }
+
+    /// Split `path` into its parent directory components and a file name.
+    fn split_file(path: &Path) -> Result<(Vec<&OsStr>, &OsStr), SafePathError> {
+        let mut components = split_relative(path)?;
+        let name = components.pop().ok_or_else(|| SafePathError::Invalid("path is empty".into()))?;
+        Ok((components, name))
+    }
+
+    fn open_leaf(&self, path: &Path, flags: OFlag, mode: Mode, create_parents: bool) -> Result<File, SafePathError> {
+        let (parents, name) = Self::split_file(path)?;
+        let dir = self.open_dir(path, &parents, create_parents)?;
+        // O_NONBLOCK keeps a FIFO from blocking the open; it does nothing for regular files
+        let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+        let fd = openat(&dir, name, flags, mode)
+            .map_err(|e| Self::classify(&dir, name, path, e))?;
+        Ok(File::from(fd))
+    }
+
+    /// Create (or truncate) a file for writing, creating missing parent
+    /// directories. A file hardlinked elsewhere (a moved file linked from the
+    /// previous version) is replaced rather than truncated through the link.
+    pub fn create_file(&self, path: impl AsRef<Path>) -> Result<File, SafePathError> {
+        let path = path.as_ref();
+        let (parents, name) = Self::split_file(path)?;
+        let dir = self.open_dir(path, &parents, true)?;
+        if let Ok(stat) = fstatat(&dir, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG && stat.st_nlink > 1 {
+                unlinkat(&dir, name, UnlinkatFlags::NoRemoveDir).map_err(|e| SafePathError::io(path, e))?;
+            }
+        }
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC;
+        self.open_leaf(path, flags, Mode::from_bits_truncate(0o644), true)
+    }
+
+    /// Open an existing regular file for reading.
+    pub fn open_file(&self, path: impl AsRef<Path>) -> Result<File, SafePathError> {
+        let path = path.as_ref();
+        let file = self.open_leaf(path, OFlag::O_RDONLY, Mode::empty(), false)?;
+        let stat = fstat(&file).map_err(|e| SafePathError::io(path, e))?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFREG {
+            return Err(SafePathError::Invalid(format!("{} is not a file", path.display())));
+        }
+        Ok(file)
+    }
+
+    /// Describe the entry at `path` (the directory itself for an empty path).
+    pub fn metadata(&self, path: impl AsRef<Path>) -> Result<SafeEntry, SafePathError> {
+        let path = path.as_ref();
+        let components = split_relative(path)?;
+        let Some((name, parents)) = components.split_last() else {
+            let stat = fstat(&self.fd).map_err(|e| SafePathError::io(path, e))?;
+            return Ok(SafeEntry::from_stat(OsString::new(), &stat));
+        };
+        let dir = self.open_dir(path, parents, false)?;
+        let stat = fstatat(&dir, *name, AtFlags::AT_SYMLINK_NOFOLLOW).map_err(|e| SafePathError::io(path, e))?;
+        Ok(SafeEntry::from_stat(name.to_os_string(), &stat))
+    }
+
+    /// List the directory at `path`, unsorted.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<SafeEntry>, SafePathError> {
+        let path = path.as_ref();
+        let components = split_relative(path)?;
+        let dir = self.open_dir(path, &components, false)?;
+        let mut dir = nix::dir::Dir::from_fd(dir).map_err(|e| SafePathError::io(path, e))?;
+
+        let names: Vec<_> = dir
+            .iter()
+            .filter_map(Result::ok)
+            .map(|entry| OsStr::from_bytes(entry.file_name().to_bytes()).to_os_string())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                // Entries can vanish between listing and stat
+                let stat = fstatat(&dir, name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW).ok()?;
+                Some(SafeEntry::from_stat(name, &stat))
+            })
+            .collect())
+    }
+
+    /// Carry the regular file at `path` in `source` to the same path here,
+    /// creating missing parent directories. A [`LinkMode::Reflink`] falls
+    /// back to a hardlink where the filesystem cannot clone; returns the mode
+    /// used.
+    pub fn link_from(&self, source: &SafeDir, path: impl AsRef<Path>, mode: LinkMode) -> Result<LinkMode, SafePathError> {
+        let path = path.as_ref();
+        self.link_moved(source, path, path, mode)
+    }
+
+    /// Like [`SafeDir::link_from`], for a file that was at `from` in `source`
+    /// and goes to `to` here.
+    pub fn link_moved(
+        &self,
+        source: &SafeDir,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        mode: LinkMode,
+    ) -> Result<LinkMode, SafePathError> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let (from_parents, from_name) = Self::split_file(from)?;
+        let (to_parents, to_name) = Self::split_file(to)?;
+        let from_dir = source.open_dir(from, &from_parents, false)?;
+        match source.metadata(from)?.kind {
+            EntryKind::File => {}
+            EntryKind::Symlink => return Err(SafePathError::Symlink(from.display().to_string())),
+            _ => return Err(SafePathError::Invalid(format!("{} is not a file", from.display()))),
+        }
+        let to_dir = self.open_dir(to, &to_parents, true)?;
+        if mode == LinkMode::Reflink {
+            match Self::clone_file(&from_dir, from_name, &to_dir, to_name) {
+                Ok(()) => return Ok(LinkMode::Reflink),
+                Err(e) if clone_unsupported(e) => {}
+                Err(e) => return Err(SafePathError::io(to, e)),
+            }
+        }
+        // Without AT_SYMLINK_FOLLOW, linkat never dereferences `from_name`
+        linkat(&from_dir, from_name, &to_dir, to_name, AtFlags::empty()).map_err(|e| SafePathError::io(to, e))?;
+        Ok(LinkMode::Hardlink)
+    }
+
+    /// Clone `from_name` in `from` to `to_name` in `to` with the same
+    /// permissions and times. Like linkat, fails if `to_name` exists.
+    fn clone_file(from: &OwnedFd, from_name: &OsStr, to: &OwnedFd, to_name: &OsStr) -> Result<(), Errno> {
+        let source = openat(from, from_name, OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC, Mode::empty())?;
+        let stat = fstat(&source)?;
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let target = openat(to, to_name, flags, Mode::from_bits_truncate(0o600))?;
+        let cloned = unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd() as _) }.and_then(|_| {
+            fchmod(&target, Mode::from_bits_truncate(stat.st_mode))?;
+            let atime = TimeSpec::new(stat.st_atime, stat.st_atime_nsec);
+            let mtime = TimeSpec::new(stat.st_mtime, stat.st_mtime_nsec);
+            futimens(&target, &atime, &mtime)
+        });
+        if cloned.is_err() {
+            let _ = unlinkat(to, to_name, UnlinkatFlags::NoRemoveDir);
+        }
+        cloned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    /// A version directory next to a `secret` file outside of it.
+    fn fixture() -> (PathBuf, SafeDir) {
+        let base = std::env::temp_dir().join(format!("safe-dir-{}", uuid::Uuid::new_v4()));
+        let root = base.join("version");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.txt"), "inside").unwrap();
+        std::fs::write(base.join("secret"), "outside").unwrap();
+        std::os::unix::fs::symlink(&base, root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), root.join("docs/secret-link")).unwrap();
+        let dir = SafeDir::open(&root).unwrap();
+        (base, dir)
+    }
+
+    #[test]
+    fn test_split_relative_rejects_hostile_paths() {
+        assert_eq!(split_relative(Path::new("a//./b/")).unwrap(), vec!["a", "b"]);
+        assert!(split_relative(Path::new("")).unwrap().is_empty());
+        for hostile in ["../secret", "a/../../secret", "a/..", "/etc/passwd", "a\0b"] {
+            assert!(matches!(split_relative(Path::new(hostile)), Err(SafePathError::Invalid(_))), "{}", hostile);
+        }
+    }
+
+    #[test]
+    fn test_names_are_bytes() {
+        let (base, dir) = fixture();
+        let name = OsStr::from_bytes(b"bad\xff\nname");
+
+        dir.create_file(Path::new("docs").join(name)).unwrap().write_all(b"raw").unwrap();
+        let entry = dir.read_dir("docs").unwrap().into_iter().find(|e| e.name == name).unwrap();
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(std::fs::read(base.join("version/docs").join(name)).unwrap(), b"raw");
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_reads_stay_inside() {
+        let (base, dir) = fixture();
+
+        let mut content = String::new();
+        dir.open_file("docs/a.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "inside");
+
+        assert!(matches!(dir.open_file("escape/secret"), Err(SafePathError::Symlink(_))));
+        assert!(matches!(dir.open_file("docs/secret-link"), Err(SafePathError::Symlink(_))));
+        assert!(matches!(dir.open_file("../secret"), Err(SafePathError::Invalid(_))));
+        assert!(matches!(dir.read_dir("escape"), Err(SafePathError::Symlink(_))));
+        assert!(dir.open_file("docs/missing").unwrap_err().is_not_found());
+
+        let mut names: Vec<_> = dir.read_dir("docs").unwrap().into_iter().map(|e| (e.name, e.kind)).collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names, vec![("a.txt".into(), EntryKind::File), ("secret-link".into(), EntryKind::Symlink)]);
+        assert_eq!(dir.metadata("escape").unwrap().kind, EntryKind::Symlink);
+        assert_eq!(dir.metadata("").unwrap().kind, EntryKind::Directory);
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_writes_stay_inside() {
+        let (base, dir) = fixture();
+
+        dir.create_file("new/deep/file.txt").unwrap().write_all(b"data").unwrap();
+        assert_eq!(std::fs::read_to_string(base.join("version/new/deep/file.txt")).unwrap(), "data");
+
+        assert!(matches!(dir.create_file("escape/planted"), Err(SafePathError::Symlink(_))));
+        assert!(matches!(dir.create_file("docs/secret-link"), Err(SafePathError::Symlink(_))));
+        assert!(matches!(dir.create_file("../planted"), Err(SafePathError::Invalid(_))));
+        assert!(matches!(dir.create_file("/tmp/planted"), Err(SafePathError::Invalid(_))));
+        assert!(matches!(dir.create_file(""), Err(SafePathError::Invalid(_))));
+        assert!(!base.join("planted").exists());
+        assert_eq!(std::fs::read_to_string(base.join("secret")).unwrap(), "outside");
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_links_stay_inside() {
+        let (base, previous) = fixture();
+        std::fs::create_dir(base.join("next")).unwrap();
+        let current = SafeDir::open(base.join("next")).unwrap();
+
+        assert_eq!(current.link_from(&previous, "docs/a.txt", LinkMode::Hardlink).unwrap(), LinkMode::Hardlink);
+        assert_eq!(std::fs::read_to_string(base.join("next/docs/a.txt")).unwrap(), "inside");
+        // Uploading over a linked file leaves the previous version alone
+        current.create_file("docs/a.txt").unwrap().write_all(b"uploaded").unwrap();
+        assert_eq!(std::fs::read_to_string(base.join("version/docs/a.txt")).unwrap(), "inside");
+
+        assert!(matches!(current.link_from(&previous, "docs/secret-link", LinkMode::Hardlink), Err(SafePathError::Symlink(_))));
+        assert!(matches!(current.link_from(&previous, "escape/secret", LinkMode::Hardlink), Err(SafePathError::Symlink(_))));
+        assert!(matches!(current.link_from(&previous, "../secret", LinkMode::Hardlink), Err(SafePathError::Invalid(_))));
+        assert!(current.link_from(&previous, "docs/missing", LinkMode::Hardlink).unwrap_err().is_not_found());
+
+        // A symlink planted in the destination is not followed either
+        std::os::unix::fs::symlink(&base, base.join("next/out")).unwrap();
+        std::fs::create_dir(base.join("version/out")).unwrap();
+        std::fs::write(base.join("version/out/secret"), "x").unwrap();
+        assert!(matches!(current.link_from(&previous, "out/secret", LinkMode::Hardlink), Err(SafePathError::Symlink(_))));
+        assert_eq!(std::fs::read_to_string(base.join("secret")).unwrap(), "outside");
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_reflinks_are_independent_or_fall_back() {
+        let (base, previous) = fixture();
+        std::fs::create_dir(base.join("next")).unwrap();
+        let current = SafeDir::open(base.join("next")).unwrap();
+        let supported = supports_reflink(&base);
+        assert_eq!(std::fs::read_dir(&base).unwrap().count(), 3, "probe files are removed");
+
+        let mode = current.link_from(&previous, "docs/a.txt", LinkMode::Reflink).unwrap();
+        assert_eq!(mode == LinkMode::Reflink, supported);
+        std::fs::write(base.join("next/docs/a.txt"), "changed").unwrap();
+        let original = std::fs::read_to_string(base.join("version/docs/a.txt")).unwrap();
+        assert_eq!(original, if supported { "inside" } else { "changed" });
+
+        assert!(matches!(current.link_from(&previous, "docs/secret-link", LinkMode::Reflink), Err(SafePathError::Symlink(_))));
+        assert!(current.link_from(&previous, "docs/a.txt", LinkMode::Reflink).is_err(), "existing files are kept");
+
+        current.link_moved(&previous, "docs/a.txt", "moved/b.txt", LinkMode::Reflink).unwrap();
+        assert_eq!(std::fs::read_to_string(base.join("next/moved/b.txt")).unwrap(), original);
+        assert!(matches!(
+            current.link_moved(&previous, "docs/a.txt", "../planted", LinkMode::Hardlink),
+            Err(SafePathError::Invalid(_))
+        ));
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -1,13 +1,17 @@
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
 use axum::response::IntoResponse;
+use backup_protocol::{AgentMessage, ServerMessage};
 use dashmap::DashMap;
 use futures_util::{SinkExt, StreamExt};
 use serde_json::Value;
 use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
 
+use crate::services::tls::{self, PeerInfo};
 use crate::state::AppState;
+use crate::ws::job_events::{self, JobEvent};
+use crate::ws::protocol::AgentProtocol;
 
 #[derive(Debug)]
 pub struct AgentConnection {
@@ -19,7 +23,8 @@ pub struct AgentConnection {
 
 pub struct AgentRegistry {
     agents: DashMap<String, AgentConnection>,
-    pending_requests: DashMap<String, oneshot::Sender<Value>>,
+    /// Requests awaiting a response, with the server whose agent must answer.
+    pending_requests: DashMap<String, (String, oneshot::Sender<AgentMessage>)>,
 }
 
 impl AgentRegistry {
@@ -42,14 +47,23 @@ impl AgentRegistry {
         );
     }
 
-    pub fn unregister(&self, server_id: &str) {
-        self.agents.remove(server_id);
+    /// Remove the agent's connection, unless it has already been replaced by a
+    /// newer one. Returns whether the connection was removed.
+    pub fn unregister(&self, server_id: &str, tx: &mpsc::UnboundedSender<String>) -> bool {
+        self.agents
+            .remove_if(server_id, |_, conn| conn.tx.same_channel(tx))
+            .is_some()
     }
 
     pub fn is_connected(&self, server_id: &str) -> bool {
         self.agents.contains_key(server_id)
     }
 
+    /// Version the agent reported when it registered, if it is connected.
+    pub fn agent_version(&self, server_id: &str) -> Option<String> {
+        self.agents.get(server_id).map(|conn| conn.version.clone())
+    }
+
     pub fn get_connected_agents(&self) -> Vec<(String, String, String)> {
         self.agents
             .iter()
@@ -60,33 +74,30 @@ impl AgentRegistry {
             .collect()
     }
 
-    pub fn send_to_agent(&self, server_id: &str, message: Value) -> bool {
-        if let Some(agent) = self.agents.get(server_id) {
-            agent.tx.send(message.to_string()).is_ok()
-        } else {
-            false
+    pub fn send_to_agent(&self, server_id: &str, message: &ServerMessage) -> bool {
+        let Some(agent) = self.agents.get(server_id) else {
+            return false;
+        };
+        match serde_json::to_string(message) {
+            Ok(text) => agent.tx.send(text).is_ok(),
+            Err(_) => false,
         }
     }
 
+    /// Send the message built by `message` from a fresh request id and wait
+    /// for the agent's response carrying that id.
     pub async fn request_from_agent(
         &self,
         server_id: &str,
-        mut message: Value,
+        message: impl FnOnce(String) -> ServerMessage,
         timeout_ms: u64,
-    ) -> anyhow::Result<Value> {
+    ) -> anyhow::Result<AgentMessage> {
         let request_id = uuid::Uuid::new_v4().to_string();
 
-        // Inject request_id into message payload
-        if let Some(payload) = message.get_mut("payload") {
-            if let Some(obj) = payload.as_object_mut() {
-                obj.insert("request_id".into(), Value::String(request_id.clone()));
-            }
-        }
-
         let (tx, rx) = oneshot::channel();
-        self.pending_requests.insert(request_id.clone(), tx);
+        self.pending_requests.insert(request_id.clone(), (server_id.to_string(), tx));
 
-        if !self.send_to_agent(server_id, message) {
+        if !self.send_to_agent(server_id, &message(request_id.clone())) {
             self.pending_requests.remove(&request_id);
             anyhow::bail!("Agent not connected");
         }
@@ -109,9 +120,28 @@ impl AgentRegistry {
         }
     }
 
-    pub fn resolve_request(&self, request_id: &str, response: Value) {
-        if let Some((_, tx)) = self.pending_requests.remove(request_id) {
-            let _ = tx.send(response);
+    /// Ask the agent which backup jobs it is currently executing.
+    pub async fn running_jobs(&self, server_id: &str) -> anyhow::Result<Vec<String>> {
+        let response = self
+            .request_from_agent(server_id, |request_id| ServerMessage::JobStatus { request_id }, 10_000)
+            .await?;
+        match response {
+            AgentMessage::JobStatusResponse { running_jobs, .. } => Ok(running_jobs),
+            other => anyhow::bail!("Unexpected response to backup:status: {}", other.message_type()),
+        }
+    }
+
+    /// Hand `response` from the agent of `server_id` to the request it answers.
+    /// A response from any other agent leaves the request pending.
+    pub fn resolve_request(&self, server_id: &str, request_id: &str, response: AgentMessage) {
+        match self.pending_requests.remove_if(request_id, |_, (sid, _)| sid == server_id) {
+            Some((_, (_, tx))) => {
+                let _ = tx.send(response);
+            }
+            None if self.pending_requests.contains_key(request_id) => {
+                tracing::warn!(server_id, request_id, "Dropped response to a request sent to another server");
+            }
+            None => {}
         }
     }
 }
@@ -119,11 +149,12 @@ impl AgentRegistry {
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerInfo>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_agent_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_agent_socket(socket, state, peer))
 }
 
-async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>, peer: PeerInfo) {
     let (mut sender, mut receiver) = socket.split();
     let (tx, mut rx) = mpsc::unbounded_channel::<String>();
 
@@ -148,68 +179,61 @@ async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
             _ => continue,
         };
 
-        let parsed: Value = match serde_json::from_str(&text) {
-            Ok(v) => v,
-            Err(_) => continue,
-        };
-
-        // Support both formats:
-        // 1. {"type": "event_name", "payload": {...}} (standard)
-        // 2. {"event_name": {...}} (serde externally-tagged enum from agent)
-        let (msg_type, msg_payload) = if let Some(t) = parsed.get("type").and_then(|t| t.as_str()) {
-            (t.to_string(), parsed.get("payload").cloned())
-        } else if let Some(obj) = parsed.as_object() {
-            if let Some((key, value)) = obj.iter().next() {
-                if obj.len() == 1 {
-                    (key.clone(), Some(value.clone()))
-                } else {
-                    (String::new(), None)
-                }
-            } else {
-                (String::new(), None)
+        let message: AgentMessage = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("Ignoring malformed agent message: {}", e);
+                continue;
             }
-        } else {
-            (String::new(), None)
         };
 
-        match msg_type.as_str() {
-            "agent:register" => {
-                let payload = msg_payload.clone().unwrap_or_default();
-                let sid = payload.get("server_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let hostname = payload.get("hostname").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                let version = payload.get("version").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        match message {
+            AgentMessage::Register(payload) => {
+                let sid = payload.server_id.clone().unwrap_or_default();
+                let hostname = payload.hostname.clone();
+                let version = payload.version.clone();
+                let protocol = AgentProtocol::from_register(&payload);
 
                 if sid.is_empty() {
-                    let err_msg = serde_json::json!({
-                        "type": "agent:register:error",
-                        "payload": { "error": "server_id is required" }
-                    });
-                    let _ = tx.send(err_msg.to_string());
+                    send(&tx, &ServerMessage::RegisterError { error: "server_id is required".into() });
                     continue;
                 }
 
                 // Verify server exists
                 let db = state.db.clone();
                 let sid2 = sid.clone();
-                let exists = tokio::task::spawn_blocking(move || {
+                let srv = tokio::task::spawn_blocking(move || {
                     let conn = db.get()?;
-                    Ok::<_, anyhow::Error>(crate::models::server::find_by_id(&conn, &sid2)?.is_some())
+                    crate::models::server::find_by_id(&conn, &sid2)
                 })
                 .await
                 .ok()
                 .and_then(|r| r.ok())
-                .unwrap_or(false);
-
-                if !exists {
-                    let err_msg = serde_json::json!({
-                        "type": "agent:register:error",
-                        "payload": { "error": "Server not found in database" }
-                    });
-                    let _ = tx.send(err_msg.to_string());
+                .flatten();
+
+                let Some(srv) = srv else {
+                    send(&tx, &ServerMessage::RegisterError { error: "Server not found in database".into() });
+                    continue;
+                };
+
+                // The client certificate must be the one issued for this server
+                if let Err(error) = tls::check_agent_certificate(
+                    state.config.tls_client_auth,
+                    srv.agent_cert_fingerprint.as_deref(),
+                    peer.client_cert_fingerprint.as_deref(),
+                ) {
+                    tracing::warn!(server_id = %sid, peer = %peer.addr, error, "Agent registration rejected");
+                    send(&tx, &ServerMessage::RegisterError { error: error.to_string() });
                     continue;
                 }
 
-                tracing::info!("Agent registered: server_id={}, hostname={}, version={}", sid, hostname, version);
+                tracing::info!(
+                    "Agent registered: server_id={}, hostname={}, version={}, protocol={}, capabilities={:?}",
+                    sid, hostname, version, protocol.version, protocol.capabilities
+                );
+                if let Err(reason) = protocol.check_backup_support() {
+                    tracing::warn!(server_id = %sid, "{}", reason);
+                }
                 state.agents.register(sid.clone(), hostname.clone(), version.clone(), tx.clone());
                 server_id = Some(sid.clone());
 
@@ -217,6 +241,9 @@ async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
                 let db = state.db.clone();
                 let sid3 = sid.clone();
                 let ver = version.clone();
+                // Legacy agents leave the version NULL so they keep counting as legacy
+                let protocol_version = payload.protocol_version.map(|_| protocol.version as i64);
+                let capabilities = serde_json::to_string(&protocol.capabilities).unwrap_or_else(|_| "[]".into());
                 let _ = tokio::task::spawn_blocking(move || {
                     let conn = db.get()?;
                     let now = chrono::Utc::now().to_rfc3339();
@@ -224,34 +251,54 @@ async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
                         ("agent_status", &"connected" as &dyn rusqlite::types::ToSql),
                         ("agent_version", &ver as &dyn rusqlite::types::ToSql),
                         ("agent_last_seen", &now as &dyn rusqlite::types::ToSql),
+                        ("agent_protocol_version", &protocol_version as &dyn rusqlite::types::ToSql),
+                        ("agent_capabilities", &capabilities as &dyn rusqlite::types::ToSql),
                     ])
                 }).await;
 
-                let ok_msg = serde_json::json!({
-                    "type": "agent:register:ok",
-                    "payload": { "server_id": sid }
+                send(&tx, &ServerMessage::RegisterOk {
+                    server_id: sid.clone(),
+                    protocol_version: Some(protocol.negotiated()),
                 });
-                let _ = tx.send(ok_msg.to_string());
 
                 state.ui.broadcast("agent:connected", serde_json::json!({
                     "serverId": sid,
                     "version": version,
+                    "protocolVersion": protocol.version,
+                    "capabilities": protocol.capabilities,
                 }));
+
+                state.job_events.publish_to_server(&sid, JobEvent::AgentReconnected);
+            }
+            AgentMessage::PolicyViolation { action, path, reason, job_id } => {
+                let Some(sid) = server_id.clone() else { continue };
+                record_policy_violation(&state, sid, action, path, reason, job_id).await;
+            }
+            AgentMessage::LogMessage { level, message, job_id, timestamp } => {
+                let Some(sid) = server_id.clone() else { continue };
+                record_agent_log(&state, sid, level, message, job_id, timestamp).await;
             }
-            _ => {
+            AgentMessage::BackupFileError(payload) => {
+                let Some(sid) = server_id.clone() else { continue };
+                record_file_error(&state, sid, payload).await;
+            }
+            message => {
                 // Check if this is a response to a pending request
-                if let Some(ref payload) = msg_payload {
-                    if let Some(request_id) = payload.get("request_id").and_then(|v| v.as_str()) {
-                        state.agents.resolve_request(request_id, payload.clone());
-                        continue;
-                    }
+                if let Some(request_id) = message.request_id().map(str::to_string) {
+                    let Some(sid) = server_id.as_deref() else { continue };
+                    state.agents.resolve_request(sid, &request_id, message);
+                    continue;
                 }
 
-                // Forward other agent messages as UI broadcasts if relevant
-                if msg_type.starts_with("backup:") {
-                    if let Some(payload) = msg_payload {
-                        let camel = snake_to_camel_keys(payload);
-                        state.ui.broadcast(&msg_type, camel);
+                // Publish job lifecycle events and forward backup messages to the UI
+                if let Some((job_id, event)) = job_events::parse_agent_event(&message) {
+                    let Some(sid) = server_id.as_deref() else { continue };
+                    if !state.job_events.publish(sid, &job_id, event) {
+                        continue;
+                    }
+                    if let Ok(Value::Object(mut envelope)) = serde_json::to_value(&message) {
+                        let payload = envelope.remove("payload").unwrap_or_default();
+                        state.ui.broadcast(message.message_type(), snake_to_camel_keys(payload));
                     }
                 }
             }
@@ -259,9 +306,9 @@ async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
     }
 
     // Cleanup on disconnect
-    if let Some(sid) = &server_id {
+    if let Some(sid) = server_id.as_ref().filter(|sid| state.agents.unregister(sid, &tx)) {
         tracing::info!("Agent disconnected: server_id={}", sid);
-        state.agents.unregister(sid);
+        state.job_events.publish_to_server(sid, JobEvent::AgentDisconnected);
 
         // Update DB
         let db = state.db.clone();
@@ -281,6 +328,151 @@ async fn handle_agent_socket(socket: WebSocket, state: Arc<AppState>) {
     send_task.abort();
 }
 
+/// Store a path the agent refused in the audit log and show it in the UI.
+async fn record_policy_violation(
+    state: &AppState,
+    server_id: String,
+    action: String,
+    path: String,
+    reason: String,
+    job_id: Option<String>,
+) {
+    tracing::warn!(server_id = %server_id, action = %action, path = %path, "Agent refused path: {}", reason);
+    let db = state.db.clone();
+    let event = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        crate::models::agent_audit::create(&conn, &crate::models::agent_audit::CreateAuditEventData {
+            server_id: &server_id,
+            action: &action,
+            path: &path,
+            reason: &reason,
+            job_id: job_id.as_deref(),
+        })
+    })
+    .await;
+    match event {
+        Ok(Ok(event)) => state.ui.broadcast("agent:policy:violation", serde_json::json!({ "event": event })),
+        Ok(Err(e)) => tracing::error!("Failed to store agent audit event: {}", e),
+        Err(e) => tracing::error!("Failed to store agent audit event: {}", e),
+    }
+}
+
+/// Whether `job_id` is a job of `server_id`. Agents only report on their own
+/// server's jobs; reports on any other are dropped with a warning.
+async fn owns_job(state: &AppState, server_id: &str, job_id: &str) -> bool {
+    let db = state.db.clone();
+    let jid = job_id.to_string();
+    let job = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        crate::models::backup_job::find_by_id(&conn, &jid)
+    })
+    .await;
+    match job {
+        Ok(Ok(Some(job))) if job.server_id == server_id => true,
+        Ok(Ok(_)) => {
+            tracing::warn!(server_id, job_id, "Dropped agent report on a job of another server");
+            false
+        }
+        Ok(Err(e)) => {
+            tracing::error!("Failed to load job {}: {}", job_id, e);
+            false
+        }
+        Err(e) => {
+            tracing::error!("Failed to load job {}: {}", job_id, e);
+            false
+        }
+    }
+}
+
+/// Store a file the agent could not back up on the job's current run, and show
+/// it in the UI.
+async fn record_file_error(state: &AppState, server_id: String, payload: backup_protocol::FileErrorPayload) {
+    if !owns_job(state, &server_id, &payload.job_id).await {
+        return;
+    }
+
+    // Agent activity: keeps a run that hits many unreadable files from stalling
+    state.job_events.publish(&server_id, &payload.job_id, JobEvent::Progress);
+
+    let db = state.db.clone();
+    let job_id = payload.job_id.clone();
+    let stored = tokio::task::spawn_blocking(move || {
+        let conn = db.get()?;
+        let Some(log_id) = crate::models::backup_job::latest_log_id(&conn, &payload.job_id)? else {
+            return Ok(None);
+        };
+        crate::models::backup_file_error::create(&conn, &crate::models::backup_file_error::CreateFileErrorData {
+            log_id: &log_id,
+            job_id: &payload.job_id,
+            path: &payload.path,
+            operation: &payload.operation,
+            errno: payload.errno.map(i64::from),
+            message: &payload.message,
+        })
+        .map(Some)
+    })
+    .await;
+    match stored {
+        Ok(Ok(Some(error))) => state.ui.broadcast("backup:file:error", serde_json::json!({
+            "jobId": error.job_id,
+            "logId": error.log_id,
+            "error": error,
+        })),
+        Ok(Ok(None)) => tracing::warn!(job_id = %job_id, "File error reported for a job without a run log"),
+        Ok(Err(e)) => tracing::error!("Failed to store file error: {}", e),
+        Err(e) => tracing::error!("Failed to store file error: {}", e),
+    }
+}
+
+/// Append a line the agent logged during a job to the run's output, and show
+/// it in the UI.
+async fn record_agent_log(
+    state: &AppState,
+    server_id: String,
+    level: String,
+    message: String,
+    job_id: Option<String>,
+    timestamp: Option<String>,
+) {
+    if let Some(jid) = job_id.as_deref() {
+        if !owns_job(state, &server_id, jid).await {
+            return;
+        }
+    }
+
+    let timestamp = timestamp.unwrap_or_else(|| chrono::Utc::now().to_rfc3339());
+    let mut log_id = None;
+    if let Some(jid) = job_id.clone() {
+        let db = state.db.clone();
+        let line = format!("{} {} {}", timestamp, level, message);
+        match tokio::task::spawn_blocking(move || {
+            let conn = db.get()?;
+            crate::models::backup_job::append_log_output(&conn, &jid, &line)
+        })
+        .await
+        {
+            Ok(Ok(id)) => log_id = id,
+            Ok(Err(e)) => tracing::error!("Failed to store agent log line: {}", e),
+            Err(e) => tracing::error!("Failed to store agent log line: {}", e),
+        }
+    }
+
+    state.ui.broadcast("agent:log", serde_json::json!({
+        "serverId": server_id,
+        "jobId": job_id,
+        "logId": log_id,
+        "level": level,
+        "message": message,
+        "timestamp": timestamp,
+    }));
+}
+
+fn send(tx: &mpsc::UnboundedSender<String>, message: &ServerMessage) {
+    if let Ok(text) = serde_json::to_string(message) {
+        let _ = tx.send(text);
+    }
+}
+
 /// Convert all keys in a JSON Value from snake_case to camelCase (recursive)
 fn snake_to_camel_keys(value: Value) -> Value {
     match value {
@@ -0,0 +1,205 @@
+use backup_protocol::{AgentMessage, BackupCompletedPayload};
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+
+const JOB_CHANNEL_CAPACITY: usize = 256;
+
+/// Final statistics reported by the agent in `backup:completed`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionStats {
+    pub total_bytes: i64,
+    pub total_files: i64,
+    pub transferred_bytes: i64,
+    pub transferred_files: i64,
+    pub unchanged_files: i64,
+    pub unchanged_bytes: i64,
+    pub deleted_files: i64,
+    pub backup_type: String,
+    /// Files that could not be backed up; the run completed with warnings.
+    pub failed_files: i64,
+}
+
+impl From<&BackupCompletedPayload> for CompletionStats {
+    fn from(payload: &BackupCompletedPayload) -> Self {
+        Self {
+            total_bytes: payload.total_bytes as i64,
+            total_files: payload.total_files as i64,
+            transferred_bytes: payload.transferred_bytes as i64,
+            transferred_files: payload.transferred_files as i64,
+            unchanged_files: payload.unchanged_files as i64,
+            unchanged_bytes: payload.unchanged_bytes as i64,
+            deleted_files: payload.deleted_files as i64,
+            backup_type: match payload.backup_type.as_str() {
+                "" => "full".to_string(),
+                other => other.to_string(),
+            },
+            failed_files: payload.failed_files as i64,
+        }
+    }
+}
+
+/// Lifecycle events for a single backup job.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobEvent {
+    /// The agent began executing the job.
+    Started,
+    /// The agent reported progress.
+    Progress,
+    /// The agent finished the job.
+    Completed(CompletionStats),
+    /// The agent gave up on the job.
+    Failed(String),
+    /// The job was cancelled from the API.
+    Cancelled,
+    /// The agent running the job dropped its WebSocket connection.
+    AgentDisconnected,
+    /// The agent running the job registered again.
+    AgentReconnected,
+}
+
+struct JobChannel {
+    server_id: String,
+    tx: broadcast::Sender<JobEvent>,
+}
+
+/// Per-job event bus. The agent registry publishes what agents report and the
+/// orchestrator subscribes for each run it follows. Events for jobs nobody is
+/// subscribed to are dropped.
+pub struct JobEventBus {
+    channels: DashMap<String, JobChannel>,
+}
+
+impl JobEventBus {
+    pub fn new() -> Self {
+        Self {
+            channels: DashMap::new(),
+        }
+    }
+
+    /// Subscribe to `job_id`, which runs on the agent of `server_id`.
+    pub fn subscribe(&self, job_id: &str, server_id: &str) -> broadcast::Receiver<JobEvent> {
+        self.channels
+            .entry(job_id.to_string())
+            .or_insert_with(|| JobChannel {
+                server_id: server_id.to_string(),
+                tx: broadcast::channel(JOB_CHANNEL_CAPACITY).0,
+            })
+            .tx
+            .subscribe()
+    }
+
+    /// Publish `event` for `job_id` as reported by the agent of `server_id`.
+    /// Events from any other server are dropped and `false` is returned.
+    pub fn publish(&self, server_id: &str, job_id: &str, event: JobEvent) -> bool {
+        let Some(channel) = self.channels.get(job_id) else {
+            return true;
+        };
+        if channel.server_id != server_id {
+            tracing::warn!(server_id, job_id, "Dropped job event from an agent of another server");
+            return false;
+        }
+        let _ = channel.tx.send(event);
+        true
+    }
+
+    /// Publish `event` to every subscribed job running on `server_id`.
+    pub fn publish_to_server(&self, server_id: &str, event: JobEvent) {
+        for channel in self.channels.iter().filter(|c| c.server_id == server_id) {
+            let _ = channel.tx.send(event.clone());
+        }
+    }
+
+    /// Drop the channel for `job_id` once its run is over.
+    pub fn close(&self, job_id: &str) {
+        self.channels.remove(job_id);
+    }
+}
+
+/// Translate an agent backup lifecycle message into the job it concerns and its event.
+pub fn parse_agent_event(message: &AgentMessage) -> Option<(String, JobEvent)> {
+    let event = match message {
+        AgentMessage::BackupStarted { .. } => JobEvent::Started,
+        // A long scan counts as progress, so the run is not taken for stalled
+        AgentMessage::BackupProgress(_)
+        | AgentMessage::BackupScanProgress(_)
+        | AgentMessage::BackupScanCompleted(_) => JobEvent::Progress,
+        AgentMessage::BackupCompleted(payload) => JobEvent::Completed(payload.into()),
+        AgentMessage::BackupFailed { error, .. } => JobEvent::Failed(error.clone()),
+        _ => return None,
+    };
+    Some((message.job_id()?.to_string(), event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_agent_event() {
+        let (job_id, event) = parse_agent_event(&AgentMessage::BackupCompleted(BackupCompletedPayload {
+            job_id: "j1".into(),
+            total_bytes: 10,
+            total_files: 2,
+            transferred_bytes: 4,
+            transferred_files: 1,
+            unchanged_files: 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(job_id, "j1");
+        assert_eq!(event, JobEvent::Completed(CompletionStats {
+            total_bytes: 10,
+            total_files: 2,
+            transferred_bytes: 4,
+            transferred_files: 1,
+            unchanged_files: 1,
+            backup_type: "full".into(),
+            ..Default::default()
+        }));
+
+        let failed = AgentMessage::BackupFailed { job_id: "j1".into(), error: "disk full".into() };
+        assert_eq!(parse_agent_event(&failed).unwrap().1, JobEvent::Failed("disk full".into()));
+
+        let scan = AgentMessage::BackupScanProgress(backup_protocol::ScanProgressPayload {
+            job_id: "j1".into(),
+            scanned_files: 100,
+            ..Default::default()
+        });
+        assert_eq!(parse_agent_event(&scan).unwrap(), ("j1".to_string(), JobEvent::Progress));
+
+        let response = AgentMessage::JobStatusResponse { request_id: "r1".into(), running_jobs: vec![] };
+        assert!(parse_agent_event(&response).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_server_only_reaches_its_jobs() {
+        let bus = JobEventBus::new();
+        let mut a = bus.subscribe("j1", "s1");
+        let mut b = bus.subscribe("j2", "s2");
+
+        bus.publish_to_server("s1", JobEvent::AgentDisconnected);
+        bus.publish("s2", "j2", JobEvent::Progress);
+
+        assert_eq!(a.recv().await.unwrap(), JobEvent::AgentDisconnected);
+        assert_eq!(b.recv().await.unwrap(), JobEvent::Progress);
+        assert!(a.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_ignores_events_from_another_server() {
+        let bus = JobEventBus::new();
+        let mut rx = bus.subscribe("j1", "s1");
+
+        let completed = parse_agent_event(&AgentMessage::BackupCompleted(BackupCompletedPayload {
+            job_id: "j1".into(),
+            ..Default::default()
+        }))
+        .unwrap()
+        .1;
+        assert!(!bus.publish("s2", "j1", completed.clone()));
+        assert!(rx.try_recv().is_err());
+
+        assert!(bus.publish("s1", "j1", completed.clone()));
+        assert_eq!(rx.recv().await.unwrap(), completed);
+    }
+}
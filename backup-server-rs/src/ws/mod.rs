@@ -1,2 +1,4 @@
 pub mod ui;
 pub mod agent_registry;
+pub mod job_events;
+pub mod protocol;
@@ -0,0 +1,135 @@
+use backup_protocol::RegisterPayload;
+pub use backup_protocol::{
+    CAP_BINARY_MANIFEST, CAP_CARRY_FORWARD, CAP_COMPRESSION_ZSTD, CAP_INCREMENTAL, CAP_RENAMES, CAP_ROOTS,
+    PROTOCOL_VERSION,
+};
+
+/// Oldest agent protocol the orchestrator starts backups on. The server
+/// decodes every path agents send, and agents before protocol 3 send them
+/// raw: a file named `100%41.txt` would be stored as `100A.txt`.
+pub const MIN_BACKUP_PROTOCOL: u32 = 3;
+
+/// Agents from before versioning send no version and support exactly these.
+const LEGACY_PROTOCOL: u32 = 1;
+const LEGACY_CAPABILITIES: &[&str] = &[CAP_INCREMENTAL, CAP_COMPRESSION_ZSTD];
+
+/// What an agent said it speaks when it registered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentProtocol {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl AgentProtocol {
+    /// Read the protocol fields of an `agent:register` payload.
+    pub fn from_register(payload: &RegisterPayload) -> Self {
+        match payload.protocol_version {
+            Some(version) => Self {
+                version,
+                capabilities: payload.capabilities.clone(),
+            },
+            None => Self::legacy(),
+        }
+    }
+
+    /// Rebuild from the columns stored on `source_servers`. Servers whose agent
+    /// never registered with a version count as legacy.
+    pub fn from_stored(version: Option<i64>, capabilities: &str) -> Self {
+        match version {
+            Some(version) => Self {
+                version: version as u32,
+                capabilities: serde_json::from_str(capabilities).unwrap_or_default(),
+            },
+            None => Self::legacy(),
+        }
+    }
+
+    fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL,
+            capabilities: LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Protocol used with this agent: the older of the two.
+    pub fn negotiated(&self) -> u32 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    /// Why backups cannot run on this agent, if they cannot.
+    pub fn check_backup_support(&self) -> Result<(), String> {
+        if self.version < MIN_BACKUP_PROTOCOL {
+            return Err(format!(
+                "Agent speaks protocol {} but backups need at least protocol {}; update the agent",
+                self.version, MIN_BACKUP_PROTOCOL
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backup_protocol::AgentMessage;
+
+    fn register(protocol_version: Option<u32>, capabilities: &[&str]) -> RegisterPayload {
+        RegisterPayload {
+            hostname: "web-01".into(),
+            version: "1.0.0".into(),
+            server_id: Some("s1".into()),
+            agent_id: String::new(),
+            protocol_version,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_register_without_version_is_legacy() {
+        let protocol = AgentProtocol::from_register(&register(None, &[]));
+        assert_eq!(protocol.version, 1);
+        assert!(protocol.has(CAP_INCREMENTAL));
+        assert_eq!(protocol, AgentProtocol::from_stored(None, "[]"));
+    }
+
+    #[test]
+    fn test_register_with_capabilities() {
+        let protocol = AgentProtocol::from_register(&register(Some(7), &["compression:zstd", "restore"]));
+        assert_eq!(protocol.version, 7);
+        assert_eq!(protocol.negotiated(), PROTOCOL_VERSION);
+        assert!(!protocol.has(CAP_INCREMENTAL));
+        assert!(protocol.has("restore"));
+        assert!(protocol.check_backup_support().is_ok());
+
+        let stored = AgentProtocol::from_stored(Some(7), r#"["compression:zstd","restore"]"#);
+        assert_eq!(stored, protocol);
+    }
+
+    #[test]
+    fn test_legacy_agent_is_refused() {
+        // What agents before protocol versioning send
+        let hello = r#"{"type":"agent:register","payload":{"hostname":"web-01","version":"0.1.0","server_id":"s1","agent_id":"agent-web-01"}}"#;
+        let AgentMessage::Register(payload) = serde_json::from_str(hello).unwrap() else {
+            panic!("not a register message");
+        };
+        let protocol = AgentProtocol::from_register(&payload);
+        assert_eq!(protocol.version, LEGACY_PROTOCOL);
+        assert!(protocol.check_backup_support().unwrap_err().contains("update the agent"));
+
+        let typed = AgentProtocol::from_register(&register(Some(2), &[CAP_INCREMENTAL, CAP_ROOTS]));
+        assert!(typed.check_backup_support().is_err());
+        let current = AgentProtocol::from_register(&register(Some(PROTOCOL_VERSION), &[]));
+        assert!(current.check_backup_support().is_ok());
+    }
+
+    #[test]
+    fn test_too_old_agent_is_refused() {
+        let protocol = AgentProtocol { version: 0, capabilities: vec![] };
+        let err = protocol.check_backup_support().unwrap_err();
+        assert!(err.contains("update the agent"));
+    }
+}
@@ -14,6 +14,10 @@ export interface Server {
   agent_status: 'disconnected' | 'connected' | 'updating' | 'error';
   agent_version: string | null;
   agent_last_seen: string | null;
+  agent_cert_fingerprint: string | null;
+  agent_protocol_version: number | null;
+  agent_capabilities: string; // JSON
+  tags: string; // JSON
   last_seen_at: string | null;
   created_at: string;
   updated_at: string;
@@ -24,12 +28,18 @@ export interface BackupJob {
   server_id: string;
   name: string;
   remote_paths: string; // JSON
+  root_labels: string; // JSON object: path -> label (paths without one get a derived label)
   local_path: string;
   cron_schedule: string | null;
-  status: 'idle' | 'running' | 'completed' | 'failed' | 'cancelled';
+  status: 'idle' | 'queued' | 'running' | 'completed' | 'completed_with_warnings' | 'failed' | 'cancelled';
   rsync_options: string;
   max_parallel: number;
   enabled: number;
+  retry_max_attempts: number;
+  retry_backoff_secs: number;
+  max_file_errors: number | null;
+  compression: 'zstd' | 'lz4' | 'gzip' | 'none' | null; // null: zstd
+  compression_level: number | null;
   last_run_at: string | null;
   created_at: string;
   updated_at: string;
@@ -40,11 +50,25 @@ export interface BackupLog {
   job_id: string;
   started_at: string;
   finished_at: string | null;
-  status: 'running' | 'completed' | 'failed' | 'cancelled';
+  status: 'running' | 'completed' | 'completed_with_warnings' | 'failed' | 'cancelled';
   bytes_transferred: number;
   files_transferred: number;
   output: string;
   error: string | null;
+  attempt: number;
+  retry_of: string | null;
+  file_errors: number;
+}
+
+export interface FileError {
+  id: string;
+  log_id: string;
+  job_id: string;
+  path: string;
+  operation: 'scan' | 'open' | 'read' | 'upload';
+  errno: number | null;
+  message: string;
+  created_at: string;
 }
 
 export interface BackupVersion {
@@ -64,50 +88,95 @@ export interface BackupVersion {
   files_unchanged: number;
   bytes_unchanged: number;
   files_deleted: number;
+  roots: string | null; // JSON array of BackupRoot; null when files are stored relative to their own root
+  link_mode: 'reflink' | 'hardlink' | 'mixed' | null; // how unchanged files were carried forward
+}
+
+export interface BackupRoot {
+  path: string;
+  label: string;
 }
 
 export interface RemoteEntry {
   name: string;
+  /** Encoded path (non-UTF-8 bytes as %XX); pass it back to explore */
   path: string;
+  /** Path to show and to back up */
+  display_path: string;
   type: 'file' | 'directory' | 'symlink' | 'other';
   size: number;
   modifiedAt: string;
   permissions: number;
 }
 
+// A path the agent refused because of its local path policy
+export interface AgentAuditEvent {
+  id: string;
+  server_id: string;
+  action: 'browse' | 'backup';
+  path: string;
+  reason: string;
+  job_id: string | null;
+  created_at: string;
+}
+
 // Server endpoints
 export const serversApi = {
   list: () => api.get<Server[]>('/servers').then(r => r.data),
   get: (id: string) => api.get<Server>(`/servers/${id}`).then(r => r.data),
   create: (data: { name: string; hostname: string; port?: number; ssh_user?: string; password?: string }) =>
     api.post<Server>('/servers', data).then(r => r.data),
-  update: (id: string, data: Partial<Server>) =>
+  update: (id: string, data: Partial<Omit<Server, 'tags'>> & { tags?: string[] }) =>
     api.put<Server>(`/servers/${id}`, data).then(r => r.data),
   delete: (id: string) => api.delete(`/servers/${id}`),
   pingStatus: () =>
     api.get<Array<{ serverId: string; reachable: boolean; latencyMs: number | null; lastCheckedAt: string }>>('/servers/ping-status').then(r => r.data),
   explore: (id: string, path: string) =>
     api.get<RemoteEntry[]>(`/servers/${id}/explore`, { params: { path } }).then(r => r.data),
+  auditEvents: (id: string, limit?: number) =>
+    api.get<AgentAuditEvent[]>(`/servers/${id}/audit`, { params: { limit } }).then(r => r.data),
   // Agent management
   updateAgent: (id: string) =>
     api.post<{ status: string }>(`/agent/update/${id}`).then(r => r.data),
 };
 
+// Agent enrollment
+export interface EnrollmentToken {
+  id: string;
+  name: string | null;
+  expires_at: string;
+  used_at: string | null;
+  server_id: string | null;
+  created_at: string;
+}
+
+export const enrollmentApi = {
+  list: () => api.get<EnrollmentToken[]>('/agent/enrollment-tokens').then(r => r.data),
+  create: (data: { name?: string; ttl_secs?: number }) =>
+    api.post<{ id: string; token: string; name: string | null; expiresAt: string; command: string }>(
+      '/agent/enrollment-tokens', data).then(r => r.data),
+  revoke: (id: string) => api.delete(`/agent/enrollment-tokens/${id}`),
+};
+
 // Storage types
 export interface BackupMeta {
   server: { name: string; hostname: string; port: number };
-  job: { id: string; name: string; remotePaths: string[] };
+  job: { id: string; name: string; remotePaths: string[]; roots?: BackupRoot[] };
   createdAt: string;
   lastRunAt: string;
 }
 
 export interface LocalEntry {
   name: string;
+  /** Encoded path (non-UTF-8 bytes as %XX); pass it back to browse */
   path: string;
+  displayPath: string;
   type: 'file' | 'directory' | 'symlink' | 'other';
   size: number;
   modifiedAt: string;
   backupMeta?: BackupMeta;
+  /** Where the entry was backed up from (version browsing) */
+  sourcePath?: string;
 }
 
 export interface DiskUsage {
@@ -117,8 +186,12 @@ export interface DiskUsage {
   usedPercent: number;
 }
 
+export type LinkMode = 'auto' | 'reflink' | 'hardlink';
+
 export interface StorageSettings {
   backup_root: string | null;
+  link_mode: LinkMode;
+  reflink_supported: boolean;
 }
 
 export interface JobWithVersions {
@@ -146,7 +219,7 @@ export interface StorageHierarchy {
 // Storage endpoints
 export const storageApi = {
   getSettings: () => api.get<StorageSettings>('/storage/settings').then(r => r.data),
-  updateSettings: (data: { backup_root: string }) =>
+  updateSettings: (data: { backup_root?: string; link_mode?: LinkMode }) =>
     api.put<StorageSettings>('/storage/settings', data).then(r => r.data),
   browse: (path?: string) =>
     api.get<LocalEntry[]>('/storage/browse', { params: { path } }).then(r => r.data),
@@ -173,6 +246,7 @@ export const jobsApi = {
     server_id: string;
     name: string;
     remote_paths: string[];
+    root_labels?: Record<string, string>;
     cron_schedule?: string | null;
     rsync_options?: string;
     max_parallel?: number;
@@ -180,7 +254,79 @@ export const jobsApi = {
   update: (id: string, data: Partial<BackupJob>) =>
     api.put<BackupJob>(`/jobs/${id}`, data).then(r => r.data),
   delete: (id: string) => api.delete(`/jobs/${id}`),
-  run: (id: string) => api.post<{ started: boolean }>(`/jobs/${id}/run`).then(r => r.data),
+  run: (id: string) => api.post<{ queued: boolean; queueId: string; position: number }>(`/jobs/${id}/run`).then(r => r.data),
   cancel: (id: string) => api.post<{ cancelled: boolean }>(`/jobs/${id}/cancel`).then(r => r.data),
   logs: (id: string) => api.get<BackupLog[]>(`/jobs/${id}/logs`).then(r => r.data),
+  log: (id: string, logId: string) => api.get<BackupLog>(`/jobs/${id}/logs/${logId}`).then(r => r.data),
+  fileErrors: (id: string, logId: string, params?: { limit?: number; offset?: number }) =>
+    api.get<FileError[]>(`/jobs/${id}/logs/${logId}/errors`, { params }).then(r => r.data),
+};
+
+// Run queue endpoints
+export interface QueueEntry {
+  id: string;
+  job_id: string;
+  server_id: string;
+  trigger_kind: 'manual' | 'scheduled' | 'retry' | 'recovery';
+  priority: number;
+  sort_order: number;
+  attempt: number;
+  retry_of: string | null;
+  enqueued_at: string;
+  position: number;
+}
+
+export const queueApi = {
+  list: () => api.get<QueueEntry[]>('/queue').then(r => r.data),
+  update: (id: string, data: { priority?: number; position?: number }) =>
+    api.put<QueueEntry[]>(`/queue/${id}`, data).then(r => r.data),
+};
+
+// Agent rollouts
+export interface RolloutTarget {
+  id: string;
+  rollout_id: string;
+  server_id: string;
+  wave: number;
+  status: 'pending' | 'updating' | 'verifying' | 'succeeded' | 'unverified' | 'failed' | 'skipped';
+  from_version: string | null;
+  test_job_id: string | null;
+  error: string | null;
+  started_at: string | null;
+  finished_at: string | null;
+}
+
+export interface Rollout {
+  id: string;
+  target_version: string;
+  target_sha256: string;
+  selector: string; // JSON
+  canary_percent: number | null;
+  wave_size: number;
+  max_failures: number;
+  test_backup: number;
+  status: 'running' | 'completed' | 'halted';
+  halt_reason: string | null;
+  current_wave: number;
+  created_at: string;
+  finished_at: string | null;
+}
+
+export interface RolloutStatus extends Rollout {
+  targets: RolloutTarget[];
+}
+
+export const rolloutsApi = {
+  list: () => api.get<Rollout[]>('/rollouts').then(r => r.data),
+  get: (id: string) => api.get<RolloutStatus>(`/rollouts/${id}`).then(r => r.data),
+  create: (data: {
+    target_version?: string;
+    tag?: string;
+    server_ids?: string[];
+    canary_percent?: number;
+    wave_size?: number;
+    max_failures?: number;
+    test_backup?: boolean;
+  }) => api.post<RolloutStatus>('/rollouts', data).then(r => r.data),
+  halt: (id: string) => api.post<RolloutStatus>(`/rollouts/${id}/halt`).then(r => r.data),
 };
@@ -21,6 +21,17 @@ interface ProgressData {
   activeFiles?: ActiveFile[];
 }
 
+interface ScanData {
+  scannedFiles: number;
+  changedFiles: number;
+  done: boolean;
+}
+
+interface LinkData {
+  linkedFiles: number;
+  totalFiles: number | null;
+}
+
 interface Props {
   jobId: string;
 }
@@ -28,6 +39,8 @@ interface Props {
 export default function BackupProgress({ jobId }: Props) {
   const { subscribe } = useWebSocket();
   const [progress, setProgress] = useState<ProgressData | null>(null);
+  const [scan, setScan] = useState<ScanData | null>(null);
+  const [link, setLink] = useState<LinkData | null>(null);
   const [status, setStatus] = useState<'running' | 'completed' | 'failed'>('running');
   const [summary, setSummary] = useState<{ duration: number; totalBytes: number; totalFiles: number } | null>(null);
 
@@ -48,6 +61,21 @@ export default function BackupProgress({ jobId }: Props) {
           activeFiles: p.activeFiles || [],
         });
       }),
+      subscribe('backup:scan:progress', (payload) => {
+        const p = payload as { jobId: string; scannedFiles: number; changedFiles: number };
+        if (p.jobId !== jobId) return;
+        setScan({ scannedFiles: p.scannedFiles, changedFiles: p.changedFiles, done: false });
+      }),
+      subscribe('backup:scan:completed', (payload) => {
+        const p = payload as { jobId: string; scannedFiles: number; changedFiles: number };
+        if (p.jobId !== jobId) return;
+        setScan({ scannedFiles: p.scannedFiles, changedFiles: p.changedFiles, done: true });
+      }),
+      subscribe('backup:link:progress', (payload) => {
+        const p = payload as { jobId: string; linkedFiles: number; totalFiles: number | null };
+        if (p.jobId !== jobId) return;
+        setLink({ linkedFiles: p.linkedFiles, totalFiles: p.totalFiles });
+      }),
       subscribe('backup:completed', (payload) => {
         if ((payload as { jobId: string }).jobId !== jobId) return;
         const p = payload as { duration: number; totalBytes: number; totalFiles: number };
@@ -119,6 +147,8 @@ export default function BackupProgress({ jobId }: Props) {
           <div className="progress-info">
             <span className="progress-count">
               {progress.checkedFiles}/{progress.totalFiles} files
+              {scan && !scan.done && ` (scanning: ${scan.scannedFiles} files, ${scan.changedFiles} changed)`}
+              {link && ` (carrying forward: ${link.linkedFiles}${link.totalFiles != null ? `/${link.totalFiles}` : ''} unchanged)`}
             </span>
           </div>
         </>
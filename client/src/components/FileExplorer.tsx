@@ -65,7 +65,7 @@ export default function FileExplorer({ serverId, onSelect, selectable, selectedP
     return entries.map(entry => {
       const isDir = entry.type === 'directory';
       const node = dirs.get(entry.path);
-      const isSelected = selectedPaths.includes(entry.path);
+      const isSelected = selectedPaths.includes(entry.display_path);
 
       return (
         <div key={entry.path}>
@@ -91,7 +91,7 @@ export default function FileExplorer({ serverId, onSelect, selectable, selectedP
               <button
                 type="button"
                 className="btn btn-sm select-btn"
-                onClick={e => { e.stopPropagation(); onSelect(entry.path); }}
+                onClick={e => { e.stopPropagation(); onSelect(entry.display_path); }}
               >
                 Select
               </button>
@@ -27,6 +27,8 @@ interface ProgressData {
   skippedFiles: number;
   skippedBytes: number;
   backupType: string;
+  // Uploads the agent allows at once (0 from older agents)
+  concurrency: number;
 }
 
 interface JobDetailPanelProps {
@@ -63,6 +65,7 @@ export default function JobDetailPanel({ job, serverName }: JobDetailPanelProps)
           skippedFiles: p.skippedFiles || 0,
           skippedBytes: p.skippedBytes || 0,
           backupType: p.backupType || 'full',
+          concurrency: p.concurrency || 0,
         });
         setStatus('running');
       }),
@@ -145,7 +148,9 @@ export default function JobDetailPanel({ job, serverName }: JobDetailPanelProps)
       {/* Active files (parallel transfers) */}
       {activeFiles.length > 0 && status === 'running' && (
         <div className="panel-section">
-          <h4>Active Transfers ({activeFiles.length})</h4>
+          <h4>
+            Active Transfers ({activeFiles.length}{progress?.concurrency ? ` of ${progress.concurrency}` : ''})
+          </h4>
           <div className="active-files-list">
             {[...activeFiles].sort((a, b) => b.totalBytes - a.totalBytes).map((file, idx) => (
               <div key={idx} className="active-file-item">
@@ -4,6 +4,7 @@ import { useStorageSettings, useUpdateStorageSettings, useDiskUsage } from '../h
 import { useStorageHierarchy } from '../hooks/useStorageHierarchy.js';
 import PageHeader from '../components/PageHeader.js';
 import StorageHierarchyTree from '../components/StorageHierarchyTree.js';
+import type { LinkMode } from '../api/endpoints.js';
 import LocalFileExplorer from '../components/LocalFileExplorer.js';
 import './Storage.scss';
 
@@ -12,6 +13,7 @@ export default function Storage() {
   const updateSettings = useUpdateStorageSettings();
   const [showForm, setShowForm] = useState(false);
   const [formPath, setFormPath] = useState('');
+  const [formLinkMode, setFormLinkMode] = useState<LinkMode>('auto');
   const [selectedVersion, setSelectedVersion] = useState<string | null>(null);
 
   const backupRoot = settings?.backup_root;
@@ -21,7 +23,7 @@ export default function Storage() {
   const handleSaveSettings = () => {
     if (!formPath.trim()) return;
     updateSettings.mutate(
-      { backup_root: formPath.trim() },
+      { backup_root: formPath.trim(), link_mode: formLinkMode },
       {
         onSuccess: () => {
           setShowForm(false);
@@ -65,10 +67,11 @@ export default function Storage() {
             className="btn"
             onClick={() => {
               setFormPath(backupRoot || '');
+              setFormLinkMode(settings?.link_mode ?? 'auto');
               setShowForm(true);
             }}
           >
-            <Settings size={16} /> {backupRoot ? 'Change Path' : 'Configure'}
+            <Settings size={16} /> {backupRoot ? 'Settings' : 'Configure'}
           </button>
         }
       />
@@ -88,6 +91,20 @@ export default function Storage() {
               />
               <span className="form-hint">Absolute path to the directory where backups are stored.</span>
             </div>
+            <div className="form-group">
+              <label>Unchanged files</label>
+              <select value={formLinkMode} onChange={e => setFormLinkMode(e.target.value as LinkMode)}>
+                <option value="auto">Clone when supported, otherwise hardlink</option>
+                <option value="reflink">Always clone (reflink)</option>
+                <option value="hardlink">Always hardlink</option>
+              </select>
+              <span className="form-hint">
+                Clones (btrfs, XFS) keep versions independent; hardlinked files share metadata and contents
+                across versions.{' '}
+                {settings?.backup_root &&
+                  (settings.reflink_supported ? 'The current storage supports clones.' : 'The current storage does not support clones.')}
+              </span>
+            </div>
             <div className="modal-actions">
               <button className="btn btn-secondary" onClick={() => setShowForm(false)}>
                 Cancel
@@ -150,6 +167,11 @@ export default function Storage() {
                     <span className="detail-item">
                       <strong>Type:</strong> {selectedVersionData.backup_type === 'incremental' ? 'Incremental' : 'Full'}
                     </span>
+                    {selectedVersionData.link_mode && (
+                      <span className="detail-item">
+                        <strong>Unchanged files:</strong> {LINK_MODE_LABELS[selectedVersionData.link_mode]}
+                      </span>
+                    )}
                     <span className="detail-item">
                       <strong>Size:</strong> {formatBytes(selectedVersionData.bytes_transferred)}
                     </span>
@@ -168,6 +190,12 @@ export default function Storage() {
   );
 }
 
+const LINK_MODE_LABELS = {
+  reflink: 'Cloned',
+  hardlink: 'Hardlinked',
+  mixed: 'Cloned and hardlinked',
+};
+
 function parseVersionTimestamp(ts: string): string {
   const iso = ts.replace('_', 'T').replace(/-(\d{2})-(\d{2})$/, ':$1:$2');
   const date = new Date(iso);